@@ -2,6 +2,7 @@ import {
   BadRequestException,
   Body,
   Controller,
+  Headers,
   Post,
   UploadedFiles,
   UseGuards,
@@ -40,6 +41,7 @@ export class GatewayGrpcVisionController {
    *
    * @param {Express.Multer.File[]} files Array of uploaded files to be processed.
    * @param {ModelType} model The model type to be used for processing the images.
+   * @param {string} [token] Access token of the user, forwarded to the vision service.
    * @returns {Promise<ImgProcResponse | ImgProcResponse[]>} A promise that resolves to the processing result(s).
    * @throws {BadRequestException} If no files are uploaded.
    */
@@ -49,6 +51,7 @@ export class GatewayGrpcVisionController {
   async processImage(
     @UploadedFiles() files: Express.Multer.File[],
     @Body('model') model: ModelType,
+    @Headers('token') token?: string,
   ): Promise<ImgProcResponse | ImgProcResponse[]> {
     // Handle single image upload
     if (files.length === 1) {
@@ -60,7 +63,7 @@ export class GatewayGrpcVisionController {
 
       console.log('Processing image:', file.originalname);
 
-      return await this.gatewayVisionService.processImage(data);
+      return await this.gatewayVisionService.processImage(data, token);
     }
     // Handle batch image upload
     else if (files.length > 1) {
@@ -70,7 +73,9 @@ export class GatewayGrpcVisionController {
       }));
 
       const responses: ImgProcResponse[] = await lastValueFrom(
-        this.gatewayVisionService.processImageBatch(requests).pipe(toArray()),
+        this.gatewayVisionService
+          .processImageBatch(requests, token)
+          .pipe(toArray()),
       );
       return responses;
     }
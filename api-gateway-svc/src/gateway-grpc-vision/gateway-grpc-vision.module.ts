@@ -3,7 +3,9 @@ import { GatewayGrpcVisionService } from './gateway-grpc-vision.service';
 import { GatewayGrpcVisionController } from './gateway-grpc-vision.controller';
 import { ConfigService } from '@nestjs/config';
 import { ClientProxyFactory, Transport } from '@nestjs/microservices';
+import { credentials } from '@grpc/grpc-js';
 import { resolve } from 'path';
+import * as fs from 'fs';
 import { GatewayAuthModule } from 'src/gateway-auth/gateway-auth.module';
 
 @Module({
@@ -15,6 +17,18 @@ import { GatewayAuthModule } from 'src/gateway-auth/gateway-auth.module';
       useFactory: (configService: ConfigService) => {
         const VISION_HOST = configService.get<string>('VISION_HOST');
         const VISION_PORT = configService.get<number>('VISION_PORT');
+        const CA_PATH = configService.get<string>('VISION_CA_PATH');
+        const CERT_PATH = configService.get<string>('VISION_CERT_PATH');
+        const KEY_PATH = configService.get<string>('VISION_KEY_PATH');
+
+        // Use TLS when a CA is configured, presenting the gateway certificate for mutual TLS.
+        const channelCredentials = CA_PATH
+          ? credentials.createSsl(
+              fs.readFileSync(CA_PATH),
+              KEY_PATH ? fs.readFileSync(KEY_PATH) : null,
+              CERT_PATH ? fs.readFileSync(CERT_PATH) : null,
+            )
+          : credentials.createInsecure();
 
         return ClientProxyFactory.create({
           transport: Transport.GRPC,
@@ -22,6 +36,7 @@ import { GatewayAuthModule } from 'src/gateway-auth/gateway-auth.module';
             url: `${VISION_HOST}:${VISION_PORT}`,
             package: 'computer_vision',
             protoPath: resolve(__dirname, 'proto', 'computer_vision.proto'),
+            credentials: channelCredentials,
           },
         });
       },
@@ -1,5 +1,6 @@
 import { Inject, Injectable, OnModuleInit } from '@nestjs/common';
 import { ClientGrpc } from '@nestjs/microservices';
+import { Metadata } from '@grpc/grpc-js';
 import {
   ComputerVisionClient,
   ImgProcRequest,
@@ -37,10 +38,13 @@ export class GatewayGrpcVisionService implements OnModuleInit {
    * Processes a single image based on the provided ImgProcRequest and returns the processing result.
    *
    * @param {ImgProcRequest} data Request data for image processing which includes the image and model type.
+   * @param {string} [token] Access token of the user, forwarded to the vision service.
    * @returns {Promise<ImgProcResponse>} A Promise that resolves to the processing results.
    */
-  processImage(data: ImgProcRequest): Promise<ImgProcResponse> {
-    return lastValueFrom(this.computerVisionService.processImage(data));
+  processImage(data: ImgProcRequest, token?: string): Promise<ImgProcResponse> {
+    return lastValueFrom(
+      this.computerVisionService.processImage(data, this.metadata(token)),
+    );
   }
 
   /**
@@ -48,15 +52,36 @@ export class GatewayGrpcVisionService implements OnModuleInit {
    * a ReplaySubject to emulate a stream of data.
    *
    * @param {ImgProcRequest[]} data Array of request data for batch image processing.
+   * @param {string} [token] Access token of the user, forwarded to the vision service.
    * @returns {Observable<ImgProcResponse>} An Observable that emits processing results for the batch.
    */
-  processImageBatch(data: ImgProcRequest[]): Observable<ImgProcResponse> {
+  processImageBatch(
+    data: ImgProcRequest[],
+    token?: string,
+  ): Observable<ImgProcResponse> {
     const subject = new ReplaySubject<ImgProcRequest>();
 
     data.forEach((request) => subject.next(request));
     subject.complete();
 
     // Initiates the batch processing using the gRPC service.
-    return this.computerVisionService.processImageBatch(subject.asObservable());
+    return this.computerVisionService.processImageBatch(
+      subject.asObservable(),
+      this.metadata(token),
+    );
+  }
+
+  /**
+   * Builds the gRPC metadata of a call, carrying the access token as a bearer token.
+   *
+   * @param {string} [token] Access token of the user.
+   * @returns {Metadata} The metadata of the call.
+   */
+  private metadata(token?: string): Metadata {
+    const metadata = new Metadata();
+    if (token) {
+      metadata.set('authorization', `Bearer ${token}`);
+    }
+    return metadata;
   }
 }
@@ -64,7 +64,7 @@ describe('GatewayGrpcVisionController', () => {
 
       // Then
       expect(result).toEqual(response);
-      expect(visionService.processImage).toHaveBeenCalledWith(data);
+      expect(visionService.processImage).toHaveBeenCalledWith(data, undefined);
     });
 
     it('should process multiple images', async () => {
@@ -86,7 +86,7 @@ describe('GatewayGrpcVisionController', () => {
 
       // Then
       expect(result).toEqual(responses);
-      expect(visionService.processImageBatch).toHaveBeenCalledWith(requests);
+      expect(visionService.processImageBatch).toHaveBeenCalledWith(requests, undefined);
     });
 
     it('should throw BadRequestException if no files are uploaded', async () => {
@@ -1,3 +1,4 @@
+import { Metadata } from '@grpc/grpc-js';
 import { Test, TestingModule } from '@nestjs/testing';
 import { GatewayGrpcVisionService } from '../gateway-grpc-vision.service';
 import { ClientGrpc } from '@nestjs/microservices';
@@ -54,7 +55,10 @@ describe('GatewayGrpcVisionService', () => {
       const result = await service.processImage(data);
 
       // Then
-      expect(computerVisionClient.processImage).toHaveBeenCalledWith(data);
+      expect(computerVisionClient.processImage).toHaveBeenCalledWith(
+        data,
+        expect.any(Metadata),
+      );
       expect(result).toEqual(response);
     });
   });
@@ -182,7 +182,8 @@ export class AuthService {
       throw new ConflictException('Password is incorrect');
     }
 
-    const payload = { email: user.email, sub: user.id };
+    // The vision service only serves users that verified their email address
+    const payload = { email: user.email, sub: user.id, verified: user.isVerified };
     const accessToken = this.jwtService.sign(payload);
     const refreshToken = this.jwtService.sign({ isRefreshToken: true, ...payload }, { expiresIn: '1d' });
 
@@ -234,7 +235,9 @@ export class AuthService {
       // Delete the old refresh token from the cache
       await this.cacheManager.del(`JWT-<${oldPayload.email}>`);
 
-      const payload = { email: oldCachedPayload.email, sub: oldCachedPayload.sub };
+      // Read the verification status again, as the user may have verified their email since signing in
+      const user = await this.userRepository.findOneBy({ email: oldCachedPayload.email });
+      const payload = { email: oldCachedPayload.email, sub: oldCachedPayload.sub, verified: user?.isVerified ?? false };
       const accessToken = this.jwtService.sign(payload, { expiresIn: '12h' });
       const refreshToken = this.jwtService.sign({ isRefreshToken: true, ...payload }, { expiresIn: '1d' });
 
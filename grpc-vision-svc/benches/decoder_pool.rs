@@ -0,0 +1,132 @@
+//! Compares the per-request cost of cloning a whole captioning model to get a private key-value
+//! cache with borrowing a text decoder from a [`DecoderPool`].
+//!
+//! The model has the layer layout of `Salesforce/blip-image-captioning-large`, which drives the
+//! cost of cloning, but narrow hidden sizes and zero weights, so the benchmark runs without
+//! downloading any weights. A request decodes a few tokens against fixed image embeddings.
+//!
+//! Both the setup alone (getting a decoder with an empty key-value cache) and whole requests are
+//! measured. Run with `cargo bench --bench decoder_pool`. The bytes allocated per request are
+//! printed before the latency measurements.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use criterion::{black_box, Criterion};
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{blip, blip_text};
+use grpc_vision_svc::image_captioning::blip_decoder::TextDecoder;
+use grpc_vision_svc::image_captioning::decoder_pool::{DecoderPool, PooledDecoder};
+
+/// Number of tokens decoded by a request.
+const TOKENS_PER_REQUEST: usize = 8;
+
+/// A global allocator counting the bytes and the number of allocations.
+struct CountingAllocator;
+
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// The layer layout of the large captioning model with narrow hidden sizes.
+fn bench_config() -> blip::Config {
+    let mut config: blip::Config = blip::Config::image_captioning_large();
+    config.text_config = blip_text::Config {
+        hidden_size: 64,
+        encoder_hidden_size: 64,
+        intermediate_size: 256,
+        num_attention_heads: 4,
+        ..config.text_config
+    };
+    config.vision_config = blip::VisionConfig {
+        hidden_size: 64,
+        intermediate_size: 256,
+        num_attention_heads: 4,
+        ..config.vision_config
+    };
+    config
+}
+
+/// Decodes [`TOKENS_PER_REQUEST`] tokens, feeding back the same token at every step.
+fn decode(mut forward: impl FnMut(&Tensor) -> Result<Tensor>, device: &Device) -> Result<Tensor> {
+    let mut logits: Tensor = forward(&Tensor::new(&[[30522u32]], device)?)?;
+    for _ in 1..TOKENS_PER_REQUEST {
+        logits = forward(&Tensor::new(&[[1037u32]], device)?)?;
+    }
+    Ok(logits)
+}
+
+/// One request with a clone of the whole model, as done before the decoder pool.
+fn request_with_model_clone(model: &blip::BlipForConditionalGeneration, image_embeds: &Tensor) -> Result<Tensor> {
+    let mut model: blip::BlipForConditionalGeneration = model.clone();
+    decode(|input_ids| model.text_decoder().forward(input_ids, image_embeds), image_embeds.device())
+}
+
+/// One request with a decoder borrowed from the pool.
+fn request_with_pool(pool: &DecoderPool, image_embeds: &Tensor) -> Result<Tensor> {
+    let mut decoder: PooledDecoder = pool.acquire();
+    decode(|input_ids| decoder.forward(input_ids, image_embeds), image_embeds.device())
+}
+
+/// Returns the bytes and the number of allocations made by `f`.
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (usize, usize) {
+    let bytes: usize = ALLOCATED_BYTES.load(Ordering::Relaxed);
+    let allocations: usize = ALLOCATIONS.load(Ordering::Relaxed);
+    black_box(f());
+    (
+        ALLOCATED_BYTES.load(Ordering::Relaxed) - bytes,
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+    )
+}
+
+fn main() -> Result<()> {
+    let device = Device::Cpu;
+    let config: blip::Config = bench_config();
+    let vb: VarBuilder = VarBuilder::zeros(DType::F32, &device);
+    let model = blip::BlipForConditionalGeneration::new(&config, vb.clone())?;
+    let pool = DecoderPool::new(TextDecoder::new(&config.text_config, vb.pp("text_decoder"))?, 1);
+    let patches: usize = (config.vision_config.image_size / config.vision_config.patch_size).pow(2) + 1;
+    let image_embeds: Tensor = Tensor::zeros((1, patches, config.vision_config.hidden_size), DType::F32, &device)?;
+
+    // Warm up the pool, so that the measured requests reuse its decoder
+    request_with_pool(&pool, &image_embeds)?;
+
+    println!("Allocated per request ({} tokens):", TOKENS_PER_REQUEST);
+    let measurements: [(&str, (usize, usize)); 4] = [
+        ("model clone (setup only)", count_allocations(|| model.clone())),
+        ("pooled decoder (setup only)", count_allocations(|| pool.acquire().reset_kv_cache())),
+        ("model clone", count_allocations(|| request_with_model_clone(&model, &image_embeds))),
+        ("pooled decoder", count_allocations(|| request_with_pool(&pool, &image_embeds))),
+    ];
+    for (name, (bytes, allocations)) in measurements {
+        println!("  {:<28} {:>10} bytes in {:>6} allocations", name, bytes, allocations);
+    }
+
+    let mut criterion: Criterion = Criterion::default().configure_from_args();
+    let mut setup = criterion.benchmark_group("decoder_setup");
+    setup.bench_function("model_clone", |b| b.iter(|| black_box(model.clone())));
+    setup.bench_function("pooled_decoder", |b| b.iter(|| pool.acquire().reset_kv_cache()));
+    setup.finish();
+
+    let mut request = criterion.benchmark_group("caption_request");
+    request.sample_size(20);
+    request.bench_function("model_clone", |b| b.iter(|| request_with_model_clone(&model, &image_embeds).unwrap()));
+    request.bench_function("pooled_decoder", |b| b.iter(|| request_with_pool(&pool, &image_embeds).unwrap()));
+    request.finish();
+    criterion.final_summary();
+
+    Ok(())
+}
@@ -6,7 +6,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tonic_build::configure()
         .file_descriptor_set_path(out_dir.join("vision_svc_descriptor.bin"))
-        .compile(&["proto/computer_vision.proto"], &["proto"])?;
+        .compile(
+            &["proto/computer_vision.proto", "proto/google/rpc/status.proto", "proto/google/rpc/error_details.proto"],
+            &["proto"],
+        )?;
 
     Ok(())
 }
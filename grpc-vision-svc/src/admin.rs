@@ -0,0 +1,162 @@
+//! This module provides the [`ModelAdminSvc`], the gRPC service operators use to manage the loaded
+//! models of a running server.
+//!
+//! The `ReloadModels` call applies the `[[model]]` entries of the models configuration file right
+//! away with the [`ModelReloader`], instead of waiting for the file to be checked for changes.
+//! Only the authenticated subjects listed as administrators may call the service. Callers without
+//! a subject, i.e. every caller when authentication is disabled, are denied unless anonymous
+//! administration is explicitly allowed.
+use std::collections::HashSet;
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+use crate::auth::Subject;
+use crate::metrics::RpcMetrics;
+use crate::proto::{ReloadModelsRequest, ReloadModelsResponse};
+use crate::proto::model_admin_server::ModelAdmin;
+use crate::reload::{ModelReloader, ReloadSummary};
+
+/// [`ModelAdminSvc`] implements the `ModelAdmin` gRPC service.
+pub struct ModelAdminSvc {
+    reloader: Arc<ModelReloader>,
+    admins: HashSet<String>,
+    allow_anonymous: bool,
+}
+
+impl ModelAdminSvc {
+    /// Creates a new instance of [`ModelAdminSvc`].
+    ///
+    /// # Arguments
+    ///
+    /// * `reloader` - The [`ModelReloader`] of the models served by the vision service.
+    /// * `admins` - The authenticated subjects allowed to call the service.
+    /// * `allow_anonymous` - Whether callers without a subject may call the service. Only meant for
+    ///   deployments without authentication, where the service is not reachable by untrusted callers.
+    ///
+    /// # Returns
+    ///
+    /// A new [`ModelAdminSvc`] instance.
+    pub fn new(reloader: Arc<ModelReloader>, admins: impl IntoIterator<Item = String>, allow_anonymous: bool) -> Self {
+        Self { reloader, admins: admins.into_iter().collect(), allow_anonymous }
+    }
+
+    /// Checks that the caller may administrate the models.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`Status::permission_denied`] if the caller is not an administrator, or has no
+    /// subject while anonymous administration is not allowed.
+    // The `Status` is returned to the client as is
+    #[allow(clippy::result_large_err)]
+    fn authorize(&self, subject: Option<&Subject>) -> Result<(), Status> {
+        match subject {
+            None if self.allow_anonymous => Ok(()),
+            None => {
+                tracing::warn!("Anonymous model administration denied");
+                Err(Status::permission_denied("Anonymous callers are not allowed to administrate the models"))
+            }
+            Some(subject) if self.admins.contains(subject.id()) => Ok(()),
+            Some(subject) => {
+                tracing::warn!(subject = subject.id(), "Model administration denied");
+                Err(Status::permission_denied("Caller is not allowed to administrate the models"))
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ModelAdmin for ModelAdminSvc {
+    /// Reloads the models of the configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - A [`Request`] containing a [`ReloadModelsRequest`].
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing a [`ReloadModelsResponse`] listing the added, updated, removed and
+    /// unchanged models.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`Status::permission_denied`] if the caller is not an administrator, and the status
+    /// of the [`ReloadError`](crate::reload::ReloadError) if the reload fails, in which case the
+    /// current models are kept.
+    async fn reload_models(&self, request: Request<ReloadModelsRequest>) -> Result<Response<ReloadModelsResponse>, Status> {
+        let subject: Option<&Subject> = Subject::from_request(&request);
+        tracing::info!(peer_addr = ?request.remote_addr(), subject = subject.map(Subject::id), "ReloadModels Invoked");
+        let rpc: RpcMetrics = RpcMetrics::start("ReloadModels");
+
+        let result: Result<Response<ReloadModelsResponse>, Status> = match self.authorize(subject) {
+            Ok(()) => self.reloader
+                .reload()
+                .await
+                .map(|summary: ReloadSummary| Response::new(summary.into()))
+                .map_err(|e| {
+                    tracing::error!("Failed to reload models: {}", e);
+                    Status::from(e)
+                }),
+            Err(status) => Err(status),
+        };
+
+        rpc.finish(&result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+    use tonic::Code;
+    use crate::concurrency::ConcurrencyConfig;
+    use crate::health::ModelReadiness;
+    use crate::image_captioning::cache::CacheConfig;
+    use crate::service_impl::ProcessorSlot;
+
+    fn admin_svc(admins: &[&str], allow_anonymous: bool) -> ModelAdminSvc {
+        let (reporter, _) = tonic_health::server::health_reporter();
+        let reloader = ModelReloader::new(
+            "models.toml",
+            true,
+            Device::Cpu,
+            CacheConfig::default(),
+            ConcurrencyConfig::default(),
+            ProcessorSlot::default(),
+            ModelReadiness::new(reporter),
+        );
+
+        ModelAdminSvc::new(Arc::new(reloader), admins.iter().map(ToString::to_string), allow_anonymous)
+    }
+
+    #[test]
+    fn test_authorize_denies_anonymous_callers_by_default() {
+        // GIVEN
+        let svc: ModelAdminSvc = admin_svc(&["ops@example.com"], false);
+        // WHEN
+        let result: Result<(), Status> = svc.authorize(None);
+        // THEN
+        assert_eq!(result.unwrap_err().code(), Code::PermissionDenied);
+    }
+
+    #[test]
+    fn test_authorize_anonymous_callers_when_allowed() {
+        // GIVEN
+        let svc: ModelAdminSvc = admin_svc(&[], true);
+        // WHEN
+        let result: Result<(), Status> = svc.authorize(None);
+        // THEN
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_authorize_admin_subjects() {
+        // GIVEN
+        let svc: ModelAdminSvc = admin_svc(&["ops@example.com"], true);
+        // WHEN
+        let admin: Result<(), Status> = svc.authorize(Some(&Subject("ops@example.com".to_string())));
+        let user: Result<(), Status> = svc.authorize(Some(&Subject("user@example.com".to_string())));
+        // THEN
+        assert!(admin.is_ok());
+        assert_eq!(user.unwrap_err().code(), Code::PermissionDenied);
+    }
+}
@@ -0,0 +1,539 @@
+//! This module provides the authentication of the callers of the vision service.
+//!
+//! The auth service issues JSON Web Tokens to signed-in users. Callers send the access token in
+//! the `authorization` metadata as `Bearer <token>`, and the [`AuthInterceptor`] validates it:
+//!
+//! * the signature, with the shared secret of the auth service (HS256) or with the public keys of
+//!   a JWKS file (RS256),
+//! * the expiry (`exp`), which is required,
+//! * the `verified` claim, which must be `true`, as unverified users may not use the service,
+//! * that the token is not a refresh token.
+//!
+//! The subject of a valid token is added to the request extensions as a [`Subject`], so that
+//! handlers can use it for logging and quotas. The [`AuthLayer`] applies the interceptor to every
+//! service of the server except the public ones (health checks and reflection by default).
+use std::pin::Pin;
+use std::sync::Arc;
+use std::future::Future;
+use std::task::{Context, Poll};
+use jsonwebtoken::{Algorithm, DecodingKey, Header, Validation};
+use jsonwebtoken::jwk::JwkSet;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+use tonic::{Request, Status};
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::metadata::MetadataMap;
+use tonic::service::Interceptor;
+use tonic::transport::Body;
+use tower::{Layer, Service};
+use tracing::Span;
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Metadata key carrying the access token.
+pub const AUTHORIZATION_KEY: &str = "authorization";
+
+/// Services callable without a token: health checks, used by orchestrators, and reflection.
+pub const PUBLIC_SERVICES: [&str; 3] = [
+    "grpc.health.v1.Health",
+    "grpc.reflection.v1alpha.ServerReflection",
+    "grpc.reflection.v1.ServerReflection",
+];
+
+/// Scheme of the `authorization` metadata.
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// [`AuthError`] is returned when a call does not carry a valid access token.
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Missing access token")]
+    MissingToken,
+    #[error(r#"The "authorization" metadata must be a bearer token"#)]
+    MalformedAuthorization,
+    #[error("Invalid access token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+    #[error("Access token signed with an unknown key {0:?}")]
+    UnknownKey(Option<String>),
+    #[error("Refresh tokens cannot be used as access tokens")]
+    RefreshToken,
+    #[error("The user has not verified their email address")]
+    NotVerified,
+    #[error("Invalid JWKS: {0}")]
+    InvalidJwks(String),
+}
+
+impl From<AuthError> for Status {
+    fn from(error: AuthError) -> Self {
+        match error {
+            AuthError::NotVerified => Status::permission_denied(error.to_string()),
+            AuthError::InvalidJwks(_) => Status::internal(error.to_string()),
+            _ => Status::unauthenticated(error.to_string()),
+        }
+    }
+}
+
+/// [`Claims`] holds the claims of an access token used by the service.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Claims {
+    /// The id of the user, which the auth service issues as a number.
+    #[serde(deserialize_with = "string_or_number")]
+    pub sub: String,
+    pub email: Option<String>,
+    /// The expiry of the token, in seconds since the Unix epoch.
+    pub exp: u64,
+    #[serde(default)]
+    pub verified: bool,
+    #[serde(default, rename = "isRefreshToken")]
+    pub is_refresh_token: bool,
+}
+
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber {
+        String(String),
+        Number(serde_json::Number),
+    }
+
+    Ok(match StringOrNumber::deserialize(deserializer)? {
+        StringOrNumber::String(value) => value,
+        StringOrNumber::Number(value) => value.to_string(),
+    })
+}
+
+/// The authenticated caller of a request, added to the request extensions by the [`AuthInterceptor`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Subject(pub String);
+
+impl Subject {
+    /// Returns the subject of an authenticated request, or `None` if authentication is disabled.
+    pub fn from_request<T>(request: &Request<T>) -> Option<&Subject> {
+        request.extensions().get::<Subject>()
+    }
+
+    /// Returns the id of the user, the `sub` claim of the access token.
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The keys verifying the signature of the access tokens.
+enum VerificationKeys {
+    /// The secret shared with the auth service.
+    Secret(DecodingKey),
+    /// Public keys by key id (`kid`). Tokens without a key id are checked against the first key.
+    Jwks(Vec<(Option<String>, DecodingKey)>),
+}
+
+/// [`JwtValidator`] validates access tokens and extracts their [`Claims`].
+pub struct JwtValidator {
+    keys: VerificationKeys,
+    validation: Validation,
+}
+
+impl std::fmt::Debug for JwtValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtValidator").field("algorithms", &self.validation.algorithms).finish_non_exhaustive()
+    }
+}
+
+impl JwtValidator {
+    /// Creates a new instance of [`JwtValidator`] for tokens signed with HS256.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - The secret the auth service signs the tokens with (`JWT_SECRET`).
+    ///
+    /// # Returns
+    ///
+    /// A new [`JwtValidator`] instance.
+    pub fn from_secret(secret: &[u8]) -> Self {
+        Self::new(VerificationKeys::Secret(DecodingKey::from_secret(secret)), Algorithm::HS256)
+    }
+
+    /// Creates a new instance of [`JwtValidator`] for tokens signed with RS256.
+    ///
+    /// # Arguments
+    ///
+    /// * `jwks` - The JSON Web Key Set with the public keys of the auth service.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the new [`JwtValidator`] instance.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AuthError::InvalidJwks`] if the key set cannot be parsed or holds no RSA key.
+    pub fn from_jwks(jwks: &str) -> Result<Self, AuthError> {
+        let jwks: JwkSet = serde_json::from_str(jwks).map_err(|e| AuthError::InvalidJwks(e.to_string()))?;
+        let keys: Vec<(Option<String>, DecodingKey)> = jwks
+            .keys
+            .iter()
+            .filter(|jwk| matches!(jwk.algorithm, jsonwebtoken::jwk::AlgorithmParameters::RSA(_)))
+            .map(|jwk| Ok((jwk.common.key_id.clone(), DecodingKey::from_jwk(jwk)?)))
+            .collect::<Result<_, jsonwebtoken::errors::Error>>()
+            .map_err(|e| AuthError::InvalidJwks(e.to_string()))?;
+        if keys.is_empty() {
+            return Err(AuthError::InvalidJwks("No RSA key found".to_string()));
+        }
+
+        Ok(Self::new(VerificationKeys::Jwks(keys), Algorithm::RS256))
+    }
+
+    fn new(keys: VerificationKeys, algorithm: Algorithm) -> Self {
+        let mut validation = Validation::new(algorithm);
+        // `sub` is required by `Claims`, as the library only recognizes string subjects
+        validation.set_required_spec_claims(&["exp"]);
+        validation.leeway = 0;
+
+        Self { keys, validation }
+    }
+
+    /// Validates an access token.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The encoded token, without the `Bearer ` prefix.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the [`Claims`] of the token.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`AuthError`] if the token is malformed, expired, signed with another key, a
+    /// refresh token, or issued to an unverified user.
+    pub fn validate(&self, token: &str) -> Result<Claims, AuthError> {
+        let key: &DecodingKey = match &self.keys {
+            VerificationKeys::Secret(key) => key,
+            VerificationKeys::Jwks(keys) => {
+                let header: Header = jsonwebtoken::decode_header(token)?;
+                keys.iter()
+                    .find(|(kid, _)| header.kid.is_none() || *kid == header.kid)
+                    .map(|(_, key)| key)
+                    .ok_or(AuthError::UnknownKey(header.kid))?
+            }
+        };
+        let claims: Claims = jsonwebtoken::decode::<Claims>(token, key, &self.validation)?.claims;
+
+        if claims.is_refresh_token {
+            return Err(AuthError::RefreshToken);
+        }
+        if !claims.verified {
+            return Err(AuthError::NotVerified);
+        }
+
+        Ok(claims)
+    }
+}
+
+/// [`AuthInterceptor`] is a tonic [`Interceptor`] rejecting calls without a valid access token.
+///
+/// Accepted calls get the [`Subject`] of the token in their extensions. Missing or invalid tokens
+/// are rejected with `UNAUTHENTICATED`, and tokens of unverified users with `PERMISSION_DENIED`.
+#[derive(Debug, Clone)]
+pub struct AuthInterceptor {
+    validator: Arc<JwtValidator>,
+}
+
+impl AuthInterceptor {
+    /// Creates a new instance of [`AuthInterceptor`] validating tokens with the given validator.
+    pub fn new(validator: JwtValidator) -> Self {
+        Self { validator: Arc::new(validator) }
+    }
+
+    fn authenticate(&self, metadata: &MetadataMap) -> Result<Subject, AuthError> {
+        let authorization: &str = metadata
+            .get(AUTHORIZATION_KEY)
+            .ok_or(AuthError::MissingToken)?
+            .to_str()
+            .map_err(|_| AuthError::MalformedAuthorization)?;
+        let token: &str = authorization
+            .strip_prefix(BEARER_PREFIX)
+            .ok_or(AuthError::MalformedAuthorization)?;
+
+        Ok(Subject(self.validator.validate(token.trim())?.sub))
+    }
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let subject: Subject = self.authenticate(request.metadata()).map_err(|e| {
+            tracing::warn!("Request rejected by authentication: {}", e);
+            Status::from(e)
+        })?;
+        Span::current().record("enduser.id", subject.id());
+        request.extensions_mut().insert(subject);
+
+        Ok(request)
+    }
+}
+
+/// [`AuthLayer`] is a tower [`Layer`] applying an [`AuthInterceptor`] to the calls of every service
+/// except the public ones. It is meant to wrap all services of the server.
+///
+/// # Example
+///
+/// ```ignore
+/// let validator = JwtValidator::from_secret(b"secret");
+/// let server = Server::builder()
+///     .layer(AuthLayer::new(AuthInterceptor::new(validator)))
+///     .add_service(health_svc)
+///     .add_service(vision_svc);
+/// ```
+#[derive(Debug, Clone)]
+pub struct AuthLayer {
+    interceptor: AuthInterceptor,
+    public_services: Arc<Vec<String>>,
+}
+
+impl AuthLayer {
+    /// Creates a new instance of [`AuthLayer`] letting the [`PUBLIC_SERVICES`] through.
+    pub fn new(interceptor: AuthInterceptor) -> Self {
+        Self {
+            interceptor,
+            public_services: Arc::new(PUBLIC_SERVICES.iter().map(ToString::to_string).collect()),
+        }
+    }
+
+    /// Adds a fully qualified service name (e.g. `grpc.health.v1.Health`) callable without a token.
+    pub fn allow_service(mut self, service: &str) -> Self {
+        Arc::make_mut(&mut self.public_services).push(service.to_string());
+        self
+    }
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthMiddleware<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        AuthMiddleware {
+            inner: service,
+            interceptor: self.interceptor.clone(),
+            public_services: Arc::clone(&self.public_services),
+        }
+    }
+}
+
+/// [`AuthMiddleware`] is a tower [`Service`] authenticating calls before forwarding them to the
+/// inner service. Rejected calls never reach the inner service.
+#[derive(Debug, Clone)]
+pub struct AuthMiddleware<S> {
+    inner: S,
+    interceptor: AuthInterceptor,
+    public_services: Arc<Vec<String>>,
+}
+
+impl<S> AuthMiddleware<S> {
+    /// Returns whether the call goes to a public service, from its path `/<service>/<method>`.
+    fn is_public(&self, path: &str) -> bool {
+        let service: &str = path.trim_start_matches('/').split('/').next().unwrap_or_default();
+        self.public_services.iter().any(|public| public == service)
+    }
+}
+
+impl<S> Service<http::Request<Body>> for AuthMiddleware<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<Body>) -> Self::Future {
+        if !self.is_public(req.uri().path()) {
+            let mut request: Request<()> = Request::new(());
+            *request.metadata_mut() = MetadataMap::from_headers(req.headers().clone());
+            match self.interceptor.call(request) {
+                Ok(request) => req.extensions_mut().extend(request.into_parts().1.into_http()),
+                Err(status) => return Box::pin(async move { Ok(status.to_http()) }),
+            }
+        }
+
+        let clone: S = self.inner.clone();
+        let mut inner: S = std::mem::replace(&mut self.inner, clone);
+        Box::pin(inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::sync::Mutex;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use jsonwebtoken::EncodingKey;
+    use serde_json::{json, Value};
+    use tonic::body::empty_body;
+    use tonic::metadata::MetadataValue;
+    use tower::ServiceExt;
+
+    const SECRET: &[u8] = b"jwt_secret";
+    const JWKS: &str = include_str!("../tests/fixtures/jwks.json");
+    const RSA_KEY: &str = include_str!("../tests/fixtures/jwt_rs256.pem");
+
+    fn claims(verified: bool, expires_in: i64) -> Value {
+        let now: i64 = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        json!({ "email": "user@example.com", "sub": 42, "verified": verified, "exp": now + expires_in })
+    }
+
+    fn hs256(claims: &Value) -> String {
+        jsonwebtoken::encode(&Header::default(), claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    fn request_with_token(token: &str) -> Request<()> {
+        let mut request: Request<()> = Request::new(());
+        let value: MetadataValue<_> = format!("Bearer {}", token).parse().unwrap();
+        request.metadata_mut().insert(AUTHORIZATION_KEY, value);
+        request
+    }
+
+    #[test]
+    fn test_jwt_validator_accepts_verified_user() {
+        // GIVEN
+        let validator: JwtValidator = JwtValidator::from_secret(SECRET);
+        let token: String = hs256(&claims(true, 60));
+        // WHEN
+        let claims: Claims = validator.validate(&token).unwrap();
+        // THEN
+        assert_eq!(claims.sub, "42");
+        assert_eq!(claims.email.as_deref(), Some("user@example.com"));
+        assert!(claims.verified);
+    }
+
+    #[test]
+    fn test_jwt_validator_rejects_invalid_tokens() {
+        // GIVEN
+        let validator: JwtValidator = JwtValidator::from_secret(SECRET);
+        let mut refresh: Value = claims(true, 60);
+        refresh["isRefreshToken"] = json!(true);
+        let other_secret: String =
+            jsonwebtoken::encode(&Header::default(), &claims(true, 60), &EncodingKey::from_secret(b"other")).unwrap();
+        // WHEN
+        let expired: Result<Claims, AuthError> = validator.validate(&hs256(&claims(true, -60)));
+        let unverified: Result<Claims, AuthError> = validator.validate(&hs256(&claims(false, 60)));
+        let refresh: Result<Claims, AuthError> = validator.validate(&hs256(&refresh));
+        let forged: Result<Claims, AuthError> = validator.validate(&other_secret);
+        let malformed: Result<Claims, AuthError> = validator.validate("not.a.token");
+        // THEN
+        assert!(matches!(expired, Err(AuthError::InvalidToken(_))));
+        assert!(matches!(unverified, Err(AuthError::NotVerified)));
+        assert!(matches!(refresh, Err(AuthError::RefreshToken)));
+        assert!(matches!(forged, Err(AuthError::InvalidToken(_))));
+        assert!(matches!(malformed, Err(AuthError::InvalidToken(_))));
+    }
+
+    #[test]
+    fn test_jwt_validator_with_jwks() {
+        // GIVEN
+        let validator: JwtValidator = JwtValidator::from_jwks(JWKS).unwrap();
+        let key: EncodingKey = EncodingKey::from_rsa_pem(RSA_KEY.as_bytes()).unwrap();
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("auth-svc-1".to_string());
+        let mut unknown_header: Header = header.clone();
+        unknown_header.kid = Some("rotated".to_string());
+        // WHEN
+        let valid: Result<Claims, AuthError> = validator.validate(&jsonwebtoken::encode(&header, &claims(true, 60), &key).unwrap());
+        let unknown: Result<Claims, AuthError> = validator.validate(&jsonwebtoken::encode(&unknown_header, &claims(true, 60), &key).unwrap());
+        let hs256: Result<Claims, AuthError> = validator.validate(&hs256(&claims(true, 60)));
+        // THEN
+        assert_eq!(valid.unwrap().sub, "42");
+        assert!(matches!(unknown, Err(AuthError::UnknownKey(Some(kid))) if kid == "rotated"));
+        assert!(matches!(hs256, Err(AuthError::InvalidToken(_))));
+    }
+
+    #[test]
+    fn test_jwt_validator_rejects_jwks_without_rsa_keys() {
+        // GIVEN
+        let jwks: &str = r#"{ "keys": [] }"#;
+        // WHEN
+        let result: Result<JwtValidator, AuthError> = JwtValidator::from_jwks(jwks);
+        // THEN
+        assert!(matches!(result, Err(AuthError::InvalidJwks(_))));
+    }
+
+    #[test]
+    fn test_auth_interceptor_adds_subject() {
+        // GIVEN
+        let mut interceptor: AuthInterceptor = AuthInterceptor::new(JwtValidator::from_secret(SECRET));
+        let request: Request<()> = request_with_token(&hs256(&claims(true, 60)));
+        // WHEN
+        let request: Request<()> = interceptor.call(request).unwrap();
+        // THEN
+        assert_eq!(Subject::from_request(&request), Some(&Subject("42".to_string())));
+    }
+
+    #[test]
+    fn test_auth_interceptor_status_codes() {
+        // GIVEN
+        let mut interceptor: AuthInterceptor = AuthInterceptor::new(JwtValidator::from_secret(SECRET));
+        let mut basic: Request<()> = Request::new(());
+        basic.metadata_mut().insert(AUTHORIZATION_KEY, MetadataValue::from_static("Basic dXNlcjpwYXNz"));
+        // WHEN
+        let missing: Status = interceptor.call(Request::new(())).unwrap_err();
+        let basic: Status = interceptor.call(basic).unwrap_err();
+        let unverified: Status = interceptor.call(request_with_token(&hs256(&claims(false, 60)))).unwrap_err();
+        // THEN
+        assert_eq!(missing.code(), tonic::Code::Unauthenticated);
+        assert_eq!(basic.code(), tonic::Code::Unauthenticated);
+        assert_eq!(unverified.code(), tonic::Code::PermissionDenied);
+    }
+
+    /// Calls an [`AuthMiddleware`] wrapping a mock service, and returns the response and the
+    /// subjects of the requests that reached the mock service.
+    async fn call_middleware(path: &str, token: Option<&str>) -> (http::Response<BoxBody>, Vec<Option<Subject>>) {
+        let subjects: Arc<Mutex<Vec<Option<Subject>>>> = Arc::default();
+        let inner_subjects: Arc<Mutex<Vec<Option<Subject>>>> = Arc::clone(&subjects);
+        let inner = tower::service_fn(move |request: http::Request<Body>| {
+            inner_subjects.lock().unwrap().push(request.extensions().get::<Subject>().cloned());
+            async move { Ok::<_, Infallible>(http::Response::new(empty_body())) }
+        });
+        let service = AuthLayer::new(AuthInterceptor::new(JwtValidator::from_secret(SECRET))).layer(inner);
+        let mut request = http::Request::builder().uri(path);
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION_KEY, format!("Bearer {}", token));
+        }
+        let response: http::Response<BoxBody> = service.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        let subjects: Vec<Option<Subject>> = subjects.lock().unwrap().clone();
+
+        (response, subjects)
+    }
+
+    #[tokio::test]
+    async fn test_auth_middleware_authenticates_vision_calls() {
+        // GIVEN
+        let token: String = hs256(&claims(true, 60));
+        // WHEN
+        let (authenticated, authenticated_subjects) =
+            call_middleware("/computer_vision.ComputerVision/ProcessImage", Some(&token)).await;
+        let (anonymous, anonymous_subjects) =
+            call_middleware("/computer_vision.ComputerVision/ProcessImage", None).await;
+        // THEN
+        assert!(Status::from_header_map(authenticated.headers()).is_none());
+        assert_eq!(authenticated_subjects, vec![Some(Subject("42".to_string()))]);
+        assert_eq!(Status::from_header_map(anonymous.headers()).unwrap().code(), tonic::Code::Unauthenticated);
+        assert!(anonymous_subjects.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_auth_middleware_lets_public_services_through() {
+        // GIVEN
+        let paths: [&str; 2] = [
+            "/grpc.health.v1.Health/Check",
+            "/grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo",
+        ];
+        for path in paths {
+            // WHEN
+            let (response, subjects) = call_middleware(path, None).await;
+            // THEN
+            assert!(Status::from_header_map(response.headers()).is_none(), "{}", path);
+            assert_eq!(subjects, vec![None]);
+        }
+    }
+}
@@ -0,0 +1,546 @@
+//! This module provides the [`DynamicBatcher`], which groups concurrent captioning requests into
+//! batches to make better use of the hardware.
+//!
+//! Requests for the same model, prompt and cache policy that arrive within a short window are
+//! collected, and the whole batch is processed at once by [`CaptionBatchProcessor::process_images`]:
+//! the images are stacked into a single vision model pass, and their captions are decoded
+//! together. A batch is dispatched as soon as it reaches `max_batch_size` requests, or when its
+//! oldest request has been waiting for `max_wait_ms` milliseconds.
+//!
+//! Every request keeps its own [`CancellationToken`] within the batch, so an abandoned request
+//! stops its own decoding without affecting the other requests of its batch.
+//!
+//! A batch is processed in a `caption_batch` span, which is a child of the span of its first
+//! request and follows from the spans of the other requests.
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::time::Duration;
+use serde::Deserialize;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::Span;
+use crate::image_captioning::ImageProcessor;
+use crate::image_captioning::error::{ProcessingError, ProcessingResult};
+use crate::image_captioning::generation::GenerationParams;
+
+/// Capacity of the channel of requests waiting to be batched.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// [`BatchingConfig`] holds the parameters of the [`DynamicBatcher`].
+///
+/// It corresponds to the optional `[batching]` table of the models configuration file. Every
+/// field can be omitted, in which case the value from [`BatchingConfig::default`] is used.
+///
+/// # Example TOML config
+///
+/// ```toml
+/// [batching]
+/// max_batch_size = 8 # 1 disables batching
+/// max_wait_ms = 10
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BatchingConfig {
+    pub max_batch_size: usize,
+    pub max_wait_ms: u64,
+}
+
+impl Default for BatchingConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 8,
+            max_wait_ms: 10,
+        }
+    }
+}
+
+/// Helper struct used to deserialize the `[batching]` table of the models configuration file.
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    batching: BatchingConfig,
+}
+
+impl BatchingConfig {
+    /// Parses the `[batching]` table from the contents of a TOML configuration file.
+    /// Other tables are ignored. If the table is missing, the defaults are returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `toml_str` - The contents of the TOML configuration file.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the parsed [`BatchingConfig`] or a [`toml::de::Error`] if parsing
+    /// fails or `max_batch_size` is zero.
+    pub fn from_toml_str(toml_str: &str) -> Result<Self, toml::de::Error> {
+        let config: Self = toml::from_str::<ConfigFile>(toml_str)?.batching;
+        if config.max_batch_size == 0 {
+            return Err(serde::de::Error::custom("batching.max_batch_size must be greater than zero"));
+        }
+
+        Ok(config)
+    }
+}
+
+/// `CaptionBatchProcessor` abstracts the batched inference behind the [`DynamicBatcher`].
+/// It is implemented by [`ImageProcessor`], and by fakes in tests.
+pub trait CaptionBatchProcessor: Send + Sync + 'static {
+    /// Generates the captions of several images with the same model and prompt, passing each
+    /// result to `on_result` as soon as it is known. See [`ImageProcessor::process_images`].
+    fn process_images(
+        &self,
+        model_id: &str,
+        images: &[Vec<u8>],
+        params: &[GenerationParams],
+        bypass_cache: bool,
+        cancellations: &[CancellationToken],
+        on_result: &mut dyn FnMut(usize, ProcessingResult<String>),
+    ) -> ProcessingResult<()>;
+}
+
+impl CaptionBatchProcessor for ImageProcessor {
+    fn process_images(
+        &self,
+        model_id: &str,
+        images: &[Vec<u8>],
+        params: &[GenerationParams],
+        bypass_cache: bool,
+        cancellations: &[CancellationToken],
+        on_result: &mut dyn FnMut(usize, ProcessingResult<String>),
+    ) -> ProcessingResult<()> {
+        ImageProcessor::process_images(self, model_id, images, params, bypass_cache, cancellations, on_result)
+    }
+}
+
+/// A captioning request waiting to be batched.
+struct Job<P> {
+    processor: Arc<P>,
+    model_id: String,
+    image: Vec<u8>,
+    params: GenerationParams,
+    bypass_cache: bool,
+    cancel: CancellationToken,
+    span: Span,
+    respond_to: oneshot::Sender<ProcessingResult<String>>,
+}
+
+/// Requests can only be batched together if they run on the same processor and model, with the
+/// same prompt and cache policy. The processor is identified by its address.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BatchKey {
+    processor: usize,
+    model_id: String,
+    prompt: String,
+    bypass_cache: bool,
+}
+
+/// The requests collected for a batch so far.
+struct PendingBatch<P> {
+    jobs: Vec<Job<P>>,
+    deadline: Instant,
+}
+
+/// [`DynamicBatcher`] collects concurrent captioning requests into batches.
+///
+/// The batcher is a cheap handle to a background task; clones share the same task. The task stops
+/// once all handles are dropped, after dispatching the requests it still holds.
+pub struct DynamicBatcher<P> {
+    tx: mpsc::Sender<Job<P>>,
+}
+
+impl<P> Clone for DynamicBatcher<P> {
+    fn clone(&self) -> Self {
+        Self { tx: self.tx.clone() }
+    }
+}
+
+impl<P: CaptionBatchProcessor> DynamicBatcher<P> {
+    /// Creates a new instance of [`DynamicBatcher`] and spawns its background task.
+    /// Must be called from within a tokio runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The [`BatchingConfig`] with the maximum batch size and waiting time.
+    ///
+    /// # Returns
+    ///
+    /// A new [`DynamicBatcher`] instance.
+    pub fn new(config: BatchingConfig) -> Self {
+        let (tx, rx): (mpsc::Sender<Job<P>>, mpsc::Receiver<Job<P>>) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run(rx, config));
+
+        Self { tx }
+    }
+
+    /// Generates the caption of an image as part of a batch.
+    ///
+    /// # Arguments
+    ///
+    /// * `processor` - The processor running the inference.
+    /// * `model_id` - The registry id of the captioning model.
+    /// * `image` - The image data.
+    /// * `params` - The [`GenerationParams`] of the request.
+    /// * `bypass_cache` - Whether the processor should ignore its cache for this request.
+    /// * `cancel` - The [`CancellationToken`] of the request, checked while its caption is decoded.
+    ///
+    /// # Returns
+    ///
+    /// A [`ProcessingResult`] containing the generated caption or the [`ProcessingError`] of the request.
+    pub async fn caption(
+        &self,
+        processor: Arc<P>,
+        model_id: String,
+        image: Vec<u8>,
+        params: GenerationParams,
+        bypass_cache: bool,
+        cancel: CancellationToken,
+    ) -> ProcessingResult<String> {
+        let (respond_to, response): (oneshot::Sender<_>, oneshot::Receiver<_>) = oneshot::channel();
+        let span: Span = Span::current();
+        let job = Job { processor, model_id, image, params, bypass_cache, cancel, span, respond_to };
+
+        self.tx
+            .send(job)
+            .await
+            .map_err(|_| ProcessingError::Generation("Batcher is not running".into()))?;
+        response
+            .await
+            .map_err(|_| ProcessingError::Generation("Batch was dropped before completion".into()))?
+    }
+}
+
+/// The background task of the [`DynamicBatcher`].
+///
+/// # Arguments
+///
+/// * `rx` - The receiver of the requests to batch.
+/// * `config` - The [`BatchingConfig`] with the maximum batch size and waiting time.
+async fn run<P: CaptionBatchProcessor>(mut rx: mpsc::Receiver<Job<P>>, config: BatchingConfig) {
+    let max_wait: Duration = Duration::from_millis(config.max_wait_ms);
+    let mut batches: HashMap<BatchKey, PendingBatch<P>> = HashMap::new();
+
+    loop {
+        let next_deadline: Option<Instant> = batches.values().map(|batch| batch.deadline).min();
+
+        tokio::select! {
+            job = rx.recv() => {
+                let Some(job) = job else { break };
+                let key = BatchKey {
+                    processor: Arc::as_ptr(&job.processor) as *const () as usize,
+                    model_id: job.model_id.clone(),
+                    prompt: job.params.prompt.clone(),
+                    bypass_cache: job.bypass_cache,
+                };
+                let batch: &mut PendingBatch<P> = batches.entry(key.clone()).or_insert_with(|| PendingBatch {
+                    jobs: Vec::with_capacity(config.max_batch_size),
+                    deadline: Instant::now() + max_wait,
+                });
+                batch.jobs.push(job);
+
+                if batch.jobs.len() >= config.max_batch_size {
+                    if let Some(batch) = batches.remove(&key) {
+                        dispatch(batch.jobs);
+                    }
+                }
+            }
+            _ = time::sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                let now: Instant = Instant::now();
+                let expired: Vec<BatchKey> = batches
+                    .iter()
+                    .filter(|(_, batch)| batch.deadline <= now)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in expired {
+                    if let Some(batch) = batches.remove(&key) {
+                        dispatch(batch.jobs);
+                    }
+                }
+            }
+        }
+    }
+
+    for (_, batch) in batches.drain() {
+        dispatch(batch.jobs);
+    }
+}
+
+/// Runs a batch on the blocking thread pool and sends each result to its requester as soon as it
+/// is known, so short captions do not wait for the longest caption of the batch.
+///
+/// # Arguments
+///
+/// * `jobs` - The requests of the batch. They all share the same [`BatchKey`].
+fn dispatch<P: CaptionBatchProcessor>(jobs: Vec<Job<P>>) {
+    let Some(first) = jobs.first() else { return };
+    let processor: Arc<P> = Arc::clone(&first.processor);
+    let model_id: String = first.model_id.clone();
+    let bypass_cache: bool = first.bypass_cache;
+    tracing::debug!(model_id = %model_id, batch_size = jobs.len(), "Dispatching batch");
+    let batch_span: Span = tracing::info_span!(parent: &first.span, "caption_batch", model_id = %model_id, batch_size = jobs.len());
+
+    let mut images: Vec<Vec<u8>> = Vec::with_capacity(jobs.len());
+    let mut params: Vec<GenerationParams> = Vec::with_capacity(jobs.len());
+    let mut cancellations: Vec<CancellationToken> = Vec::with_capacity(jobs.len());
+    let mut responders: Vec<Option<oneshot::Sender<ProcessingResult<String>>>> = Vec::with_capacity(jobs.len());
+    for job in jobs {
+        batch_span.follows_from(&job.span);
+        images.push(job.image);
+        params.push(job.params);
+        cancellations.push(job.cancel);
+        responders.push(Some(job.respond_to));
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let _batch_span = batch_span.enter();
+        let processed: ProcessingResult<()> =
+            processor.process_images(&model_id, &images, &params, bypass_cache, &cancellations, &mut |index, result| {
+                if let Some(respond_to) = responders[index].take() {
+                    // The requester may have gone away, in which case nobody needs the result
+                    let _ = respond_to.send(result);
+                }
+            });
+        if let Err(e) = processed {
+            tracing::error!("Error processing batch: {:?}", e);
+            for respond_to in responders.into_iter().flatten() {
+                let _ = respond_to.send(Err(e.clone()));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::task::JoinHandle;
+
+    /// A fake processor returning `"<model id>:<image>"` captions and recording the batch sizes.
+    /// Images of cancelled requests get an error, and batches of the `missing` model fail as a whole.
+    /// `slow` images are processed last, and only finish once their request is cancelled.
+    #[derive(Default)]
+    struct FakeProcessor {
+        batches: Mutex<Vec<(String, usize)>>,
+    }
+
+    impl CaptionBatchProcessor for FakeProcessor {
+        fn process_images(
+            &self,
+            model_id: &str,
+            images: &[Vec<u8>],
+            _params: &[GenerationParams],
+            _bypass_cache: bool,
+            cancellations: &[CancellationToken],
+            on_result: &mut dyn FnMut(usize, ProcessingResult<String>),
+        ) -> ProcessingResult<()> {
+            if model_id == "missing" {
+                return Err(ProcessingError::ModelNotLoaded(model_id.to_string()));
+            }
+            self.batches.lock().unwrap().push((model_id.to_string(), images.len()));
+            let mut order: Vec<usize> = (0..images.len()).collect();
+            order.sort_by_key(|&index| images[index] == b"slow");
+            for index in order {
+                let cancel: &CancellationToken = &cancellations[index];
+                let result: ProcessingResult<String> = match images[index].as_slice() {
+                    b"slow" => {
+                        while !cancel.is_cancelled() {
+                            std::thread::sleep(Duration::from_millis(1));
+                        }
+                        Err(ProcessingError::Cancelled)
+                    }
+                    _ if cancel.is_cancelled() => Err(ProcessingError::Cancelled),
+                    b"bad" => Err(ProcessingError::Decode("bad image".into())),
+                    image => Ok(format!("{}:{}", model_id, String::from_utf8_lossy(image))),
+                };
+                on_result(index, result);
+            }
+            Ok(())
+        }
+    }
+
+    fn spawn_caption(
+        batcher: &DynamicBatcher<FakeProcessor>,
+        processor: &Arc<FakeProcessor>,
+        model_id: &str,
+        image: &str,
+        prompt: &str,
+        bypass_cache: bool,
+        cancel: CancellationToken,
+    ) -> JoinHandle<ProcessingResult<String>> {
+        let batcher: DynamicBatcher<FakeProcessor> = batcher.clone();
+        let processor: Arc<FakeProcessor> = Arc::clone(processor);
+        let model_id: String = model_id.to_string();
+        let image: Vec<u8> = image.as_bytes().to_vec();
+        let params = GenerationParams { prompt: prompt.to_string(), ..Default::default() };
+
+        tokio::spawn(async move { batcher.caption(processor, model_id, image, params, bypass_cache, cancel).await })
+    }
+
+    fn sorted_batches(processor: &FakeProcessor) -> Vec<(String, usize)> {
+        let mut batches: Vec<(String, usize)> = processor.batches.lock().unwrap().clone();
+        batches.sort();
+        batches
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_batcher_groups_concurrent_requests() {
+        // GIVEN
+        let batcher: DynamicBatcher<FakeProcessor> = DynamicBatcher::new(BatchingConfig { max_batch_size: 8, max_wait_ms: 50 });
+        let processor: Arc<FakeProcessor> = Arc::new(FakeProcessor::default());
+        // WHEN
+        let handles: Vec<JoinHandle<ProcessingResult<String>>> = (0..3)
+            .map(|i| spawn_caption(&batcher, &processor, "blip", &i.to_string(), "", false, CancellationToken::new()))
+            .collect();
+        let mut captions: Vec<String> = Vec::new();
+        for handle in handles {
+            captions.push(handle.await.unwrap().unwrap());
+        }
+        // THEN
+        assert_eq!(captions, vec!["blip:0", "blip:1", "blip:2"]);
+        assert_eq!(sorted_batches(&processor), vec![(String::from("blip"), 3)]);
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_batcher_respects_max_batch_size() {
+        // GIVEN
+        let batcher: DynamicBatcher<FakeProcessor> = DynamicBatcher::new(BatchingConfig { max_batch_size: 2, max_wait_ms: 50 });
+        let processor: Arc<FakeProcessor> = Arc::new(FakeProcessor::default());
+        // WHEN
+        let handles: Vec<JoinHandle<ProcessingResult<String>>> = (0..5)
+            .map(|i| spawn_caption(&batcher, &processor, "blip", &i.to_string(), "", false, CancellationToken::new()))
+            .collect();
+        for handle in handles {
+            handle.await.unwrap().unwrap();
+        }
+        // THEN
+        let batch_sizes: Vec<usize> = sorted_batches(&processor).into_iter().map(|(_, size)| size).collect();
+        assert_eq!(batch_sizes, vec![1, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_batcher_separates_models_prompts_and_cache_policies() {
+        // GIVEN
+        let batcher: DynamicBatcher<FakeProcessor> = DynamicBatcher::new(BatchingConfig { max_batch_size: 8, max_wait_ms: 50 });
+        let processor: Arc<FakeProcessor> = Arc::new(FakeProcessor::default());
+        // WHEN
+        let handles: Vec<JoinHandle<ProcessingResult<String>>> = vec![
+            spawn_caption(&batcher, &processor, "blip", "a", "", false, CancellationToken::new()),
+            spawn_caption(&batcher, &processor, "blip_quantized", "b", "", false, CancellationToken::new()),
+            spawn_caption(&batcher, &processor, "blip", "c", "a photography of", false, CancellationToken::new()),
+            spawn_caption(&batcher, &processor, "blip", "d", "", false, CancellationToken::new()),
+            spawn_caption(&batcher, &processor, "blip", "e", "", true, CancellationToken::new()),
+        ];
+        let mut captions: Vec<String> = Vec::new();
+        for handle in handles {
+            captions.push(handle.await.unwrap().unwrap());
+        }
+        // THEN
+        assert_eq!(captions, vec!["blip:a", "blip_quantized:b", "blip:c", "blip:d", "blip:e"]);
+        assert_eq!(
+            sorted_batches(&processor),
+            vec![
+                (String::from("blip"), 1),
+                (String::from("blip"), 1),
+                (String::from("blip"), 2),
+                (String::from("blip_quantized"), 1),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_batcher_isolates_item_errors() {
+        // GIVEN
+        let batcher: DynamicBatcher<FakeProcessor> = DynamicBatcher::new(BatchingConfig { max_batch_size: 2, max_wait_ms: 50 });
+        let processor: Arc<FakeProcessor> = Arc::new(FakeProcessor::default());
+        // WHEN
+        let bad: JoinHandle<ProcessingResult<String>> = spawn_caption(&batcher, &processor, "blip", "bad", "", false, CancellationToken::new());
+        let good: JoinHandle<ProcessingResult<String>> = spawn_caption(&batcher, &processor, "blip", "good", "", false, CancellationToken::new());
+        // THEN
+        assert_eq!(bad.await.unwrap().unwrap_err(), ProcessingError::Decode("bad image".into()));
+        assert_eq!(good.await.unwrap().unwrap(), "blip:good");
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_batcher_sends_batch_error_to_every_request() {
+        // GIVEN
+        let batcher: DynamicBatcher<FakeProcessor> = DynamicBatcher::new(BatchingConfig { max_batch_size: 2, max_wait_ms: 50 });
+        let processor: Arc<FakeProcessor> = Arc::new(FakeProcessor::default());
+        // WHEN
+        let handles: Vec<JoinHandle<ProcessingResult<String>>> = (0..2)
+            .map(|i| spawn_caption(&batcher, &processor, "missing", &i.to_string(), "", false, CancellationToken::new()))
+            .collect();
+        // THEN
+        for handle in handles {
+            assert_eq!(handle.await.unwrap().unwrap_err(), ProcessingError::ModelNotLoaded("missing".into()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_batcher_isolates_cancelled_requests() {
+        // GIVEN
+        let batcher: DynamicBatcher<FakeProcessor> = DynamicBatcher::new(BatchingConfig { max_batch_size: 2, max_wait_ms: 50 });
+        let processor: Arc<FakeProcessor> = Arc::new(FakeProcessor::default());
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        // WHEN
+        let cancelled: JoinHandle<ProcessingResult<String>> = spawn_caption(&batcher, &processor, "blip", "a", "", false, cancel);
+        let kept: JoinHandle<ProcessingResult<String>> =
+            spawn_caption(&batcher, &processor, "blip", "b", "", false, CancellationToken::new());
+        // THEN
+        assert_eq!(cancelled.await.unwrap().unwrap_err(), ProcessingError::Cancelled);
+        assert_eq!(kept.await.unwrap().unwrap(), "blip:b");
+        assert_eq!(sorted_batches(&processor), vec![(String::from("blip"), 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_batcher_sends_each_result_when_ready() {
+        // GIVEN
+        let batcher: DynamicBatcher<FakeProcessor> = DynamicBatcher::new(BatchingConfig { max_batch_size: 2, max_wait_ms: 50 });
+        let processor: Arc<FakeProcessor> = Arc::new(FakeProcessor::default());
+        let slow_cancel = CancellationToken::new();
+        // WHEN
+        let slow: JoinHandle<ProcessingResult<String>> =
+            spawn_caption(&batcher, &processor, "blip", "slow", "", false, slow_cancel.clone());
+        let fast: JoinHandle<ProcessingResult<String>> =
+            spawn_caption(&batcher, &processor, "blip", "fast", "", false, CancellationToken::new());
+        let fast_caption = time::timeout(Duration::from_secs(5), fast).await;
+        slow_cancel.cancel();
+        // THEN
+        // The slow image only finishes once it is cancelled, so the fast one must not wait for it
+        assert_eq!(fast_caption.unwrap().unwrap().unwrap(), "blip:fast");
+        assert_eq!(slow.await.unwrap().unwrap_err(), ProcessingError::Cancelled);
+        assert_eq!(sorted_batches(&processor), vec![(String::from("blip"), 2)]);
+    }
+
+    #[test]
+    fn test_batching_config_from_toml_str() {
+        // GIVEN
+        let toml_str: &str = r#"
+            [batching]
+            max_batch_size = 4
+
+            [[model]]
+            repository = "some-repo/test-model"
+            model = "model.safetensors"
+            tokenizer = "tokenizer.json"
+        "#;
+        // WHEN
+        let config: BatchingConfig = BatchingConfig::from_toml_str(toml_str).unwrap();
+        // THEN
+        assert_eq!(config, BatchingConfig { max_batch_size: 4, ..Default::default() });
+    }
+
+    #[test]
+    fn test_batching_config_from_toml_str_zero_batch_size() {
+        // GIVEN
+        let toml_str: &str = r#"
+            [batching]
+            max_batch_size = 0
+        "#;
+        // WHEN
+        let result: Result<BatchingConfig, toml::de::Error> = BatchingConfig::from_toml_str(toml_str);
+        // THEN
+        assert!(result.is_err());
+    }
+}
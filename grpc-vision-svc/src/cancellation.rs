@@ -0,0 +1,284 @@
+//! This module provides the [`RequestCancellation`], which stops the generation of abandoned requests.
+//!
+//! Text generation runs on the blocking thread pool, where dropping the request future does not
+//! stop it. Instead, every request gets a [`CancellationToken`] that the [`ImageProcessor`] checks
+//! before each decoding step. The token is cancelled when:
+//!
+//! * the deadline of the client, sent in the `grpc-timeout` metadata, expires,
+//! * the request future is dropped, which is how tonic reports a client disconnect (see
+//!   [`RequestCancellation::cancel_on_drop`]),
+//! * the response stream of a streaming request is closed (see [`RequestCancellation::cancel_when_closed`]).
+//!
+//! [`ImageProcessor`]: crate::image_captioning::ImageProcessor
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tonic::Status;
+use tonic::metadata::MetadataMap;
+
+/// Metadata key carrying the deadline of a call, relative to the time the call was sent.
+pub const GRPC_TIMEOUT_KEY: &str = "grpc-timeout";
+
+/// Maximum number of digits of a `grpc-timeout` value, as defined by the gRPC over HTTP/2 protocol.
+const MAX_TIMEOUT_DIGITS: usize = 8;
+
+/// Interval at which [`RequestCancellation::cancel_when_closed`] checks whether a response stream was closed.
+const CLOSED_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Parses the value of the `grpc-timeout` metadata.
+///
+/// The value is a positive integer of at most eight digits followed by a unit: `H` (hours),
+/// `M` (minutes), `S` (seconds), `m` (milliseconds), `u` (microseconds) or `n` (nanoseconds).
+///
+/// # Arguments
+///
+/// * `value` - The value of the `grpc-timeout` metadata, e.g. `"500m"`.
+///
+/// # Returns
+///
+/// An [`Option`] containing the timeout, or `None` if the value is malformed.
+pub fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || !value.is_ascii() {
+        return None;
+    }
+    let (amount, unit): (&str, &str) = value.split_at(value.len() - 1);
+    if amount.len() > MAX_TIMEOUT_DIGITS || !amount.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// [`RequestCancellation`] tracks whether the result of a request is still wanted.
+///
+/// Clones share the same [`CancellationToken`].
+#[derive(Debug, Clone)]
+pub struct RequestCancellation {
+    token: CancellationToken,
+    deadline: Option<Instant>,
+}
+
+impl RequestCancellation {
+    /// Creates a new instance of [`RequestCancellation`] for the request with the given metadata.
+    /// Must be called from within a tokio runtime.
+    ///
+    /// If the metadata carries a valid `grpc-timeout`, a background task cancels the token when
+    /// the deadline expires. The task stops as soon as the token is cancelled for another reason.
+    ///
+    /// # Arguments
+    ///
+    /// * `metadata` - The [`MetadataMap`] of the request.
+    ///
+    /// # Returns
+    ///
+    /// A new [`RequestCancellation`] instance.
+    pub fn from_metadata(metadata: &MetadataMap) -> Self {
+        let timeout: Option<Duration> = metadata
+            .get(GRPC_TIMEOUT_KEY)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_grpc_timeout);
+
+        Self::with_deadline(timeout.and_then(|timeout| Instant::now().checked_add(timeout)))
+    }
+
+    /// Creates a new instance of [`RequestCancellation`] with an optional deadline.
+    /// Must be called from within a tokio runtime if a deadline is given.
+    ///
+    /// # Arguments
+    ///
+    /// * `deadline` - The [`Instant`] at which the token is cancelled, if any.
+    ///
+    /// # Returns
+    ///
+    /// A new [`RequestCancellation`] instance.
+    pub fn with_deadline(deadline: Option<Instant>) -> Self {
+        let token = CancellationToken::new();
+        if let Some(deadline) = deadline {
+            let token: CancellationToken = token.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = time::sleep_until(deadline) => token.cancel(),
+                    _ = token.cancelled() => {}
+                }
+            });
+        }
+
+        Self { token, deadline }
+    }
+
+    /// Returns the [`CancellationToken`] checked by the generation.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Returns a child of this cancellation. It is cancelled along with its parent, but cancelling
+    /// it leaves the parent untouched. Used for the items of a batch stream.
+    pub fn child(&self) -> Self {
+        Self { token: self.token.child_token(), deadline: self.deadline }
+    }
+
+    /// Returns whether the request was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Returns a guard cancelling the token when dropped.
+    ///
+    /// Keep the guard in the request future, so that dropping the future, as tonic does when the
+    /// client disconnects, stops the generation. Dropping the guard once the request completed is
+    /// harmless, and stops the deadline task.
+    pub fn cancel_on_drop(&self) -> DropGuard {
+        self.token.clone().drop_guard()
+    }
+
+    /// Cancels the token when the receiver of a response stream is dropped, which happens when the
+    /// client goes away or when tonic stops polling the stream. Must be called from within a tokio
+    /// runtime.
+    ///
+    /// The background task only keeps a weak reference to the sender, which it upgrades for each
+    /// check, so the stream still ends once the other senders are dropped. The task stops then, or
+    /// as soon as the token is cancelled.
+    ///
+    /// # Arguments
+    ///
+    /// * `tx` - The sender of the response stream.
+    pub fn cancel_when_closed<T: Send + 'static>(&self, tx: &mpsc::Sender<T>) {
+        let token: CancellationToken = self.token.clone();
+        let tx: mpsc::WeakSender<T> = tx.downgrade();
+        tokio::spawn(async move {
+            let mut interval: time::Interval = time::interval(CLOSED_CHECK_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = token.cancelled() => return,
+                }
+                match tx.upgrade() {
+                    Some(tx) if tx.is_closed() => return token.cancel(),
+                    Some(_) => {}
+                    // Every sender was dropped, so the stream ended with its last response
+                    None => return,
+                }
+            }
+        });
+    }
+
+    /// Returns the [`Status`] of a request whose generation was stopped by this cancellation:
+    /// [`Status::deadline_exceeded`] if the deadline expired, or [`Status::cancelled`] otherwise.
+    pub fn status(&self) -> Status {
+        match self.deadline {
+            Some(deadline) if deadline <= Instant::now() => Status::deadline_exceeded("Deadline exceeded during generation"),
+            _ => Status::cancelled("Request cancelled during generation"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::metadata::MetadataValue;
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        // GIVEN
+        let values: [(&str, Option<Duration>); 10] = [
+            ("1H", Some(Duration::from_secs(3600))),
+            ("2M", Some(Duration::from_secs(120))),
+            ("3S", Some(Duration::from_secs(3))),
+            ("500m", Some(Duration::from_millis(500))),
+            ("250u", Some(Duration::from_micros(250))),
+            ("99999999n", Some(Duration::from_nanos(99_999_999))),
+            ("123456789m", None),
+            ("10", None),
+            ("m", None),
+            ("-5S", None),
+        ];
+        for (value, expected) in values {
+            // WHEN
+            let timeout: Option<Duration> = parse_grpc_timeout(value);
+            // THEN
+            assert_eq!(timeout, expected, "{}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_cancellation_expires_at_deadline() {
+        // GIVEN
+        let mut metadata = MetadataMap::new();
+        metadata.insert(GRPC_TIMEOUT_KEY, MetadataValue::from_static("20m"));
+        let cancellation: RequestCancellation = RequestCancellation::from_metadata(&metadata);
+        let cancelled_before_deadline: bool = cancellation.is_cancelled();
+        // WHEN
+        time::timeout(Duration::from_secs(5), cancellation.token().cancelled()).await.unwrap();
+        // THEN
+        assert!(!cancelled_before_deadline);
+        assert_eq!(cancellation.status().code(), tonic::Code::DeadlineExceeded);
+    }
+
+    #[tokio::test]
+    async fn test_request_cancellation_without_deadline() {
+        // GIVEN
+        let mut metadata = MetadataMap::new();
+        metadata.insert(GRPC_TIMEOUT_KEY, MetadataValue::from_static("soon"));
+        let cancellation: RequestCancellation = RequestCancellation::from_metadata(&metadata);
+        // WHEN
+        let guard: DropGuard = cancellation.cancel_on_drop();
+        let cancelled_before_drop: bool = cancellation.is_cancelled();
+        drop(guard);
+        // THEN
+        assert!(!cancelled_before_drop);
+        assert!(cancellation.is_cancelled());
+        assert_eq!(cancellation.status().code(), tonic::Code::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_request_cancellation_when_stream_is_closed() {
+        // GIVEN
+        let cancellation: RequestCancellation = RequestCancellation::with_deadline(None);
+        let (tx, rx): (mpsc::Sender<u32>, mpsc::Receiver<u32>) = mpsc::channel(1);
+        cancellation.cancel_when_closed(&tx);
+        // WHEN
+        drop(rx);
+        // THEN
+        time::timeout(Duration::from_secs(5), cancellation.token().cancelled()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_request_cancellation_lets_stream_end() {
+        // GIVEN
+        let cancellation: RequestCancellation = RequestCancellation::with_deadline(None);
+        let (tx, mut rx): (mpsc::Sender<u32>, mpsc::Receiver<u32>) = mpsc::channel(1);
+        cancellation.cancel_when_closed(&tx);
+        // WHEN
+        tx.send(7).await.unwrap();
+        drop(tx);
+        // THEN
+        assert_eq!(rx.recv().await, Some(7));
+        assert_eq!(time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap(), None);
+        assert!(!cancellation.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_request_cancellation_child_follows_parent() {
+        // GIVEN
+        let parent: RequestCancellation = RequestCancellation::with_deadline(None);
+        let first: RequestCancellation = parent.child();
+        let second: RequestCancellation = parent.child();
+        // WHEN
+        first.token().cancel();
+        let parent_cancelled_by_child: bool = parent.is_cancelled();
+        parent.token().cancel();
+        // THEN
+        assert!(!parent_cancelled_by_child);
+        assert!(second.is_cancelled());
+    }
+}
@@ -0,0 +1,419 @@
+//! This module provides the [`ConcurrencyLimiter`], which limits the number of requests processed
+//! at the same time and sheds the load the server cannot absorb.
+//!
+//! A request first needs a slot of its model (if the model has its own limit), then a slot of the
+//! server. When no slot is free, the request waits in a bounded queue for at most
+//! `max_queue_wait_ms` milliseconds. Requests arriving when the queue is full, and requests that
+//! waited too long, are rejected with `RESOURCE_EXHAUSTED` and a hint of when to retry.
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::time::Duration;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{self, Instant};
+use prometheus::IntGauge;
+use tonic::Status;
+use tonic::metadata::MetadataValue;
+use crate::metrics::metrics;
+
+/// Trailer carrying the number of milliseconds the client should wait before retrying, as used by
+/// the gRPC retry policy.
+pub const RETRY_PUSHBACK_KEY: &str = "grpc-retry-pushback-ms";
+
+/// [`ConcurrencyConfig`] holds the parameters of the [`ConcurrencyLimiter`].
+///
+/// It corresponds to the optional `[concurrency]` table of the models configuration file. Every
+/// field can be omitted, in which case the value from [`ConcurrencyConfig::default`] is used.
+/// The `models` sub-table limits the number of concurrent requests of individual models, on top
+/// of the limit of the server.
+///
+/// # Example TOML config
+///
+/// ```toml
+/// [concurrency]
+/// max_concurrent_requests = 16
+/// max_queue_depth = 64 # 0 rejects requests as soon as all slots are taken
+/// max_queue_wait_ms = 5000
+/// retry_after_ms = 1000
+///
+/// [concurrency.models]
+/// blip = 4
+/// blip_quantized = 16
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ConcurrencyConfig {
+    pub max_concurrent_requests: usize,
+    pub max_queue_depth: usize,
+    pub max_queue_wait_ms: u64,
+    pub retry_after_ms: u64,
+    pub models: HashMap<String, usize>,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_requests: 16,
+            max_queue_depth: 64,
+            max_queue_wait_ms: 5000,
+            retry_after_ms: 1000,
+            models: HashMap::new(),
+        }
+    }
+}
+
+/// Helper struct used to deserialize the `[concurrency]` table of the models configuration file.
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    concurrency: ConcurrencyConfig,
+}
+
+impl ConcurrencyConfig {
+    /// Parses the `[concurrency]` table from the contents of a TOML configuration file.
+    /// Other tables are ignored. If the table is missing, the defaults are returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `toml_str` - The contents of the TOML configuration file.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the parsed [`ConcurrencyConfig`] or a [`toml::de::Error`] if parsing
+    /// fails or any of the concurrency limits is zero.
+    pub fn from_toml_str(toml_str: &str) -> Result<Self, toml::de::Error> {
+        let config: Self = toml::from_str::<ConfigFile>(toml_str)?.concurrency;
+        if config.max_concurrent_requests == 0 {
+            return Err(serde::de::Error::custom("concurrency.max_concurrent_requests must be greater than zero"));
+        }
+        if let Some(model_id) = config.models.iter().find(|(_, limit)| **limit == 0).map(|(id, _)| id) {
+            return Err(serde::de::Error::custom(format!("concurrency.models.{} must be greater than zero", model_id)));
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the maximum number of requests a model processes at once: its own limit if it has
+    /// one, bounded by the limit of the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_id` - The registry id of the model.
+    pub fn max_concurrent_model_requests(&self, model_id: &str) -> usize {
+        self.models
+            .get(model_id)
+            .map_or(self.max_concurrent_requests, |limit| (*limit).min(self.max_concurrent_requests))
+    }
+}
+
+/// [`AdmissionError`] describes why a request was not admitted by the [`ConcurrencyLimiter`].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum AdmissionError {
+    #[error("The request queue of {scope} is full")]
+    QueueFull { scope: String, retry_after: Duration },
+
+    #[error("Timed out after {} ms in the request queue of {scope}", waited.as_millis())]
+    QueueTimeout { scope: String, waited: Duration, retry_after: Duration },
+}
+
+impl AdmissionError {
+    /// Returns how long the client should wait before retrying the request.
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            Self::QueueFull { retry_after, .. } | Self::QueueTimeout { retry_after, .. } => *retry_after,
+        }
+    }
+}
+
+impl From<AdmissionError> for Status {
+    /// Converts the error into a [`Status::resource_exhausted`] carrying the retry hint, both in the
+    /// message and in the [`RETRY_PUSHBACK_KEY`] metadata.
+    fn from(error: AdmissionError) -> Self {
+        let retry_after_ms: u64 = error.retry_after().as_millis() as u64;
+        let mut status = Status::resource_exhausted(format!("{}, retry after {} ms", error, retry_after_ms));
+        status.metadata_mut().insert(RETRY_PUSHBACK_KEY, MetadataValue::from(retry_after_ms));
+
+        status
+    }
+}
+
+/// A permit to process a request, released when dropped.
+///
+/// Permits are counted by the `vision_in_flight_requests` metric.
+#[derive(Debug)]
+pub struct Permit {
+    _model: Option<OwnedSemaphorePermit>,
+    _server: OwnedSemaphorePermit,
+}
+
+impl Permit {
+    fn new(model: Option<OwnedSemaphorePermit>, server: OwnedSemaphorePermit) -> Self {
+        metrics().in_flight_requests().inc();
+        Self { _model: model, _server: server }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        metrics().in_flight_requests().dec();
+    }
+}
+
+/// Removes a request from the queue count when it stops waiting, even if it is cancelled.
+struct QueueSlot<'a>(&'a Limiter);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.queued.fetch_sub(1, Ordering::SeqCst);
+        self.0.queue_depth.dec();
+    }
+}
+
+/// The slots and the wait queue of a single scope (the server or a model).
+///
+/// The queue is mirrored by the `vision_queued_requests` metric, labelled with `server` or the model id.
+#[derive(Debug)]
+struct Limiter {
+    scope: String,
+    semaphore: Arc<Semaphore>,
+    queued: AtomicUsize,
+    queue_depth: IntGauge,
+}
+
+impl Limiter {
+    fn new(scope: String, metric_scope: &str, max_concurrent_requests: usize) -> Self {
+        Self {
+            scope,
+            semaphore: Arc::new(Semaphore::new(max_concurrent_requests)),
+            queued: AtomicUsize::new(0),
+            queue_depth: metrics().queued_requests(metric_scope),
+        }
+    }
+
+    /// Takes a free slot, or waits in the queue for one until `deadline`.
+    async fn acquire(&self, config: &ConcurrencyConfig, deadline: Instant) -> Result<OwnedSemaphorePermit, AdmissionError> {
+        if let Ok(permit) = Arc::clone(&self.semaphore).try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        let retry_after: Duration = Duration::from_millis(config.retry_after_ms);
+        let queued = self.queued.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+            (queued < config.max_queue_depth).then_some(queued + 1)
+        });
+        if queued.is_err() {
+            return Err(AdmissionError::QueueFull { scope: self.scope.clone(), retry_after });
+        }
+        self.queue_depth.inc();
+        let _slot = QueueSlot(self);
+
+        let started: Instant = Instant::now();
+        match time::timeout_at(deadline, Arc::clone(&self.semaphore).acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            // The semaphore is never closed, so only the timeout can fail the wait
+            Ok(Err(_)) | Err(_) => Err(AdmissionError::QueueTimeout {
+                scope: self.scope.clone(),
+                waited: started.elapsed(),
+                retry_after,
+            }),
+        }
+    }
+}
+
+/// The state shared by the clones of a [`ConcurrencyLimiter`].
+#[derive(Debug)]
+struct LimiterState {
+    config: ConcurrencyConfig,
+    server: Limiter,
+    models: HashMap<String, Limiter>,
+}
+
+/// [`ConcurrencyLimiter`] admits requests according to a [`ConcurrencyConfig`].
+///
+/// The limiter is a cheap handle; clones share the same slots and queues.
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimiter {
+    state: Arc<LimiterState>,
+}
+
+impl ConcurrencyLimiter {
+    /// Creates a new instance of [`ConcurrencyLimiter`].
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The [`ConcurrencyConfig`] with the limits of the server and of the models.
+    ///
+    /// # Returns
+    ///
+    /// A new [`ConcurrencyLimiter`] instance.
+    pub fn new(config: ConcurrencyConfig) -> Self {
+        let server = Limiter::new(String::from("the server"), "server", config.max_concurrent_requests);
+        let models: HashMap<String, Limiter> = config
+            .models
+            .iter()
+            .map(|(id, limit)| (id.clone(), Limiter::new(format!("model {:?}", id), id, *limit)))
+            .collect();
+
+        Self { state: Arc::new(LimiterState { config, server, models }) }
+    }
+
+    /// Waits for a permit to process a request for the given model.
+    ///
+    /// The whole wait, for the slot of the model and then for the slot of the server, is bounded
+    /// by `max_queue_wait_ms`.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_id` - The registry id of the model processing the request.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the [`Permit`], which must be held while the request is processed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`AdmissionError`] if a queue is full or the request waited too long.
+    pub async fn acquire(&self, model_id: &str) -> Result<Permit, AdmissionError> {
+        let state: &LimiterState = &self.state;
+        let deadline: Instant = Instant::now() + Duration::from_millis(state.config.max_queue_wait_ms);
+
+        let model: Option<OwnedSemaphorePermit> = match state.models.get(model_id) {
+            Some(limiter) => Some(limiter.acquire(&state.config, deadline).await?),
+            None => None,
+        };
+        let server: OwnedSemaphorePermit = state.server.acquire(&state.config, deadline).await?;
+
+        Ok(Permit::new(model, server))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_concurrent_requests: usize, max_queue_depth: usize) -> ConcurrencyConfig {
+        ConcurrencyConfig {
+            max_concurrent_requests,
+            max_queue_depth,
+            max_queue_wait_ms: 50,
+            retry_after_ms: 200,
+            models: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limiter_rejects_when_queue_is_full() {
+        // GIVEN
+        let limiter = ConcurrencyLimiter::new(config(1, 0));
+        let _permit: Permit = limiter.acquire("blip").await.unwrap();
+        // WHEN
+        let result: Result<Permit, AdmissionError> = limiter.acquire("blip").await;
+        // THEN
+        assert_eq!(
+            result.unwrap_err(),
+            AdmissionError::QueueFull { scope: String::from("the server"), retry_after: Duration::from_millis(200) },
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limiter_times_out_queued_requests() {
+        // GIVEN
+        let limiter = ConcurrencyLimiter::new(config(1, 1));
+        let _permit: Permit = limiter.acquire("blip").await.unwrap();
+        // WHEN
+        let result: Result<Permit, AdmissionError> = limiter.acquire("blip").await;
+        // THEN
+        assert!(matches!(result, Err(AdmissionError::QueueTimeout { .. })));
+        assert_eq!(limiter.state.server.queued.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limiter_admits_queued_request_when_slot_is_released() {
+        // GIVEN
+        let limiter = ConcurrencyLimiter::new(ConcurrencyConfig { max_queue_wait_ms: 5000, ..config(1, 1) });
+        let permit: Permit = limiter.acquire("blip").await.unwrap();
+        let queued = tokio::spawn({
+            let limiter: ConcurrencyLimiter = limiter.clone();
+            async move { limiter.acquire("blip").await }
+        });
+        // WHEN
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(permit);
+        // THEN
+        assert!(queued.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limiter_applies_model_limits() {
+        // GIVEN
+        let mut config: ConcurrencyConfig = config(4, 0);
+        config.models.insert(String::from("blip"), 1);
+        let limiter = ConcurrencyLimiter::new(config);
+        let _permit: Permit = limiter.acquire("blip").await.unwrap();
+        // WHEN
+        let blip: Result<Permit, AdmissionError> = limiter.acquire("blip").await;
+        let quantized: Result<Permit, AdmissionError> = limiter.acquire("blip_quantized").await;
+        // THEN
+        assert!(matches!(blip, Err(AdmissionError::QueueFull { scope, .. }) if scope == r#"model "blip""#));
+        assert!(quantized.is_ok());
+    }
+
+    #[test]
+    fn test_admission_error_into_status() {
+        // GIVEN
+        let error = AdmissionError::QueueFull { scope: String::from("the server"), retry_after: Duration::from_millis(250) };
+        // WHEN
+        let status: Status = error.into();
+        // THEN
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(status.message(), "The request queue of the server is full, retry after 250 ms");
+        assert_eq!(status.metadata().get(RETRY_PUSHBACK_KEY).unwrap(), "250");
+    }
+
+    #[test]
+    fn test_concurrency_config_from_toml_str() {
+        // GIVEN
+        let toml_str: &str = r#"
+            [concurrency]
+            max_concurrent_requests = 8
+
+            [concurrency.models]
+            blip = 2
+        "#;
+        // WHEN
+        let config: ConcurrencyConfig = ConcurrencyConfig::from_toml_str(toml_str).unwrap();
+        // THEN
+        assert_eq!(config.max_concurrent_requests, 8);
+        assert_eq!(config.max_queue_depth, ConcurrencyConfig::default().max_queue_depth);
+        assert_eq!(config.models, HashMap::from([(String::from("blip"), 2)]));
+    }
+
+    #[test]
+    fn test_concurrency_config_from_toml_str_zero_model_limit() {
+        // GIVEN
+        let toml_str: &str = r#"
+            [concurrency.models]
+            blip = 0
+        "#;
+        // WHEN
+        let result: Result<ConcurrencyConfig, toml::de::Error> = ConcurrencyConfig::from_toml_str(toml_str);
+        // THEN
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_max_concurrent_model_requests() {
+        // GIVEN
+        let mut config: ConcurrencyConfig = config(8, 0);
+        config.models = HashMap::from([(String::from("blip"), 2), (String::from("blip_vqa"), 32)]);
+        // WHEN
+        let blip: usize = config.max_concurrent_model_requests("blip");
+        let blip_vqa: usize = config.max_concurrent_model_requests("blip_vqa");
+        let blip_quantized: usize = config.max_concurrent_model_requests("blip_quantized");
+        // THEN
+        assert_eq!(blip, 2);
+        assert_eq!(blip_vqa, 8);
+        assert_eq!(blip_quantized, 8);
+    }
+}
@@ -0,0 +1,179 @@
+//! This module provides [`ErrorDetails`], which attaches the structured details of the
+//! [`google.rpc` error model](https://cloud.google.com/apis/design/errors#error_model) to a [`Status`].
+//!
+//! The details are encoded as a `google.rpc.Status` message in the `grpc-status-details-bin`
+//! trailer, where clients of any language can read them with the `google.rpc` messages. An
+//! [`ErrorInfo`] carries a machine readable reason of the error, and a [`BadRequest`] names the
+//! request fields that were rejected.
+use std::collections::HashMap;
+use prost::{DecodeError, Message};
+use prost_types::Any;
+use tonic::{Code, Status};
+use tonic::codegen::Bytes;
+use crate::proto::google::rpc::{self, BadRequest, ErrorInfo};
+use crate::proto::google::rpc::bad_request::FieldViolation;
+
+/// The domain of the [`ErrorInfo`] reasons of the vision service.
+pub const ERROR_DOMAIN: &str = "vision.intelligent-image-analyzer";
+
+/// Type URL of an [`ErrorInfo`] packed into a [`prost_types::Any`].
+const ERROR_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.ErrorInfo";
+
+/// Type URL of a [`BadRequest`] packed into a [`prost_types::Any`].
+const BAD_REQUEST_TYPE_URL: &str = "type.googleapis.com/google.rpc.BadRequest";
+
+/// [`ErrorDetails`] holds the structured details of an error returned to the client.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ErrorDetails {
+    pub error_info: Option<ErrorInfo>,
+    pub bad_request: Option<BadRequest>,
+}
+
+impl ErrorDetails {
+    /// Creates error details with an [`ErrorInfo`] in the [`ERROR_DOMAIN`].
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - The `UPPER_SNAKE_CASE` reason of the error (e.g. `IMAGE_DECODE_FAILED`).
+    /// * `metadata` - Additional key-value pairs describing the error (e.g. the model id).
+    ///
+    /// # Returns
+    ///
+    /// A new [`ErrorDetails`] instance without field violations.
+    pub fn with_error_info<K, V>(reason: &str, metadata: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let metadata: HashMap<String, String> = metadata
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.into()))
+            .collect();
+
+        Self {
+            error_info: Some(ErrorInfo {
+                reason: reason.to_string(),
+                domain: ERROR_DOMAIN.to_string(),
+                metadata,
+            }),
+            bad_request: None,
+        }
+    }
+
+    /// Adds a [`FieldViolation`] to the [`BadRequest`] of the details.
+    ///
+    /// # Arguments
+    ///
+    /// * `field` - The path of the rejected field in the request message (e.g. `image`).
+    /// * `description` - Why the field was rejected.
+    pub fn with_field_violation(mut self, field: &str, description: impl Into<String>) -> Self {
+        self.bad_request
+            .get_or_insert_with(BadRequest::default)
+            .field_violations
+            .push(FieldViolation { field: field.to_string(), description: description.into() });
+        self
+    }
+
+    /// Returns the reason of the [`ErrorInfo`], if any.
+    pub fn reason(&self) -> Option<&str> {
+        self.error_info.as_ref().map(|info| info.reason.as_str())
+    }
+
+    /// Creates a [`Status`] carrying the details.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The gRPC status code.
+    /// * `message` - The developer facing error message.
+    ///
+    /// # Returns
+    ///
+    /// A [`Status`] whose details are the encoded `google.rpc.Status` message.
+    pub fn into_status(self, code: Code, message: impl Into<String>) -> Status {
+        let message: String = message.into();
+        let mut details: Vec<Any> = Vec::with_capacity(2);
+        if let Some(error_info) = self.error_info {
+            details.push(Any { type_url: ERROR_INFO_TYPE_URL.to_string(), value: error_info.encode_to_vec() });
+        }
+        if let Some(bad_request) = self.bad_request {
+            details.push(Any { type_url: BAD_REQUEST_TYPE_URL.to_string(), value: bad_request.encode_to_vec() });
+        }
+        let status = rpc::Status { code: code as i32, message: message.clone(), details };
+
+        Status::with_details(code, message, Bytes::from(status.encode_to_vec()))
+    }
+
+    /// Reads the details of a [`Status`].
+    ///
+    /// Details of other types than [`ErrorInfo`] and [`BadRequest`] are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - The [`Status`] to read the details of.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the [`ErrorDetails`], which are empty if the status has no details,
+    /// or a [`DecodeError`] if the details are malformed.
+    pub fn from_status(status: &Status) -> Result<Self, DecodeError> {
+        let mut details = Self::default();
+        if status.details().is_empty() {
+            return Ok(details);
+        }
+
+        for any in rpc::Status::decode(status.details())?.details {
+            match any.type_url.as_str() {
+                ERROR_INFO_TYPE_URL => details.error_info = Some(ErrorInfo::decode(any.value.as_slice())?),
+                BAD_REQUEST_TYPE_URL => details.bad_request = Some(BadRequest::decode(any.value.as_slice())?),
+                _ => {}
+            }
+        }
+
+        Ok(details)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_details_round_trip() {
+        // GIVEN
+        let details: ErrorDetails = ErrorDetails::with_error_info("IMAGE_TOO_LARGE", [("max_dimension", "8192")])
+            .with_field_violation("image", "Image is too large");
+        // WHEN
+        let status: Status = details.clone().into_status(Code::InvalidArgument, "Image is too large");
+        let decoded: ErrorDetails = ErrorDetails::from_status(&status).unwrap();
+        // THEN
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "Image is too large");
+        assert_eq!(decoded, details);
+        assert_eq!(decoded.reason(), Some("IMAGE_TOO_LARGE"));
+        assert_eq!(decoded.error_info.unwrap().domain, ERROR_DOMAIN);
+        assert_eq!(decoded.bad_request.unwrap().field_violations[0].field, "image");
+    }
+
+    #[test]
+    fn test_error_details_from_status_without_details() {
+        // GIVEN
+        let status: Status = Status::internal("Error processing image");
+        // WHEN
+        let details: ErrorDetails = ErrorDetails::from_status(&status).unwrap();
+        // THEN
+        assert_eq!(details, ErrorDetails::default());
+        assert_eq!(details.reason(), None);
+    }
+
+    #[test]
+    fn test_error_details_from_status_ignores_unknown_details() {
+        // GIVEN
+        let unknown = Any { type_url: "type.googleapis.com/google.rpc.RetryInfo".to_string(), value: vec![0x0a, 0x00] };
+        let encoded: Vec<u8> = rpc::Status { code: Code::Unavailable as i32, message: String::new(), details: vec![unknown] }.encode_to_vec();
+        let status: Status = Status::with_details(Code::Unavailable, "", Bytes::from(encoded));
+        // WHEN
+        let details: ErrorDetails = ErrorDetails::from_status(&status).unwrap();
+        // THEN
+        assert_eq!(details, ErrorDetails::default());
+    }
+}
@@ -0,0 +1,135 @@
+//! This module wires the standard gRPC health checking service (`grpc.health.v1.Health`) to the
+//! readiness of the models.
+//!
+//! The server starts serving before the models are downloaded and built. During that warm-up, the
+//! overall server status (the empty service name), the `computer_vision.ComputerVision` service and
+//! the entry of every configured model, named `computer_vision.ComputerVision/<model id>`, are
+//! reported as `NOT_SERVING`. Once the models are built, they all switch to `SERVING`. Models added
+//! by a reload of the configuration are reported as `NOT_SERVING` while they are loaded, and the
+//! entries of removed models are cleared, so they become unknown services.
+use tonic::server::NamedService;
+use tonic_health::ServingStatus;
+use tonic_health::server::HealthReporter;
+use crate::proto::computer_vision_server::ComputerVisionServer;
+use crate::service_impl::ComputerVisionSvc;
+
+/// The name of the health entry describing the overall status of the server.
+const SERVER_SERVICE_NAME: &str = "";
+
+/// The name of the health entry describing the status of the vision service.
+pub const VISION_SERVICE_NAME: &str = <ComputerVisionServer<ComputerVisionSvc> as NamedService>::NAME;
+
+/// Returns the name of the health entry describing the status of a single model.
+///
+/// # Arguments
+///
+/// * `model_id` - The registry id of the model.
+///
+/// # Returns
+///
+/// The service name to use in a `HealthCheckRequest`, e.g. `computer_vision.ComputerVision/blip`.
+pub fn model_service_name(model_id: &str) -> String {
+    format!("{}/{}", VISION_SERVICE_NAME, model_id)
+}
+
+/// [`ModelReadiness`] reports the readiness of the models through a [`HealthReporter`].
+#[derive(Debug, Clone)]
+pub struct ModelReadiness {
+    reporter: HealthReporter,
+}
+
+impl ModelReadiness {
+    /// Creates a new instance of [`ModelReadiness`].
+    ///
+    /// # Arguments
+    ///
+    /// * `reporter` - The [`HealthReporter`] linked to the health service of the server.
+    ///
+    /// # Returns
+    ///
+    /// A new [`ModelReadiness`] instance.
+    pub fn new(reporter: HealthReporter) -> Self {
+        Self { reporter }
+    }
+
+    /// Reports the server, the vision service and each of the given models as `NOT_SERVING` while
+    /// the models are warming up.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_ids` - The registry ids of the models being loaded.
+    pub async fn set_warming_up<'a, I>(&mut self, model_ids: I)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        self.set_status(SERVER_SERVICE_NAME, ServingStatus::NotServing).await;
+        self.set_status(VISION_SERVICE_NAME, ServingStatus::NotServing).await;
+        self.set_not_serving(model_ids).await;
+    }
+
+    /// Reports each of the given models as `NOT_SERVING`, without changing the status of the server
+    /// and of the vision service, e.g. while models added by a reload are loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_ids` - The registry ids of the models being loaded.
+    pub async fn set_not_serving<'a, I>(&mut self, model_ids: I)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        for model_id in model_ids {
+            self.set_status(&model_service_name(model_id), ServingStatus::NotServing).await;
+        }
+    }
+
+    /// Reports the server, the vision service and each of the given models as `SERVING`.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_ids` - The registry ids of the loaded models.
+    pub async fn set_ready<'a, I>(&mut self, model_ids: I)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        for model_id in model_ids {
+            self.set_status(&model_service_name(model_id), ServingStatus::Serving).await;
+        }
+        self.set_status(VISION_SERVICE_NAME, ServingStatus::Serving).await;
+        self.set_status(SERVER_SERVICE_NAME, ServingStatus::Serving).await;
+    }
+
+    /// Clears the health entries of models that are no longer loaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_ids` - The registry ids of the removed models.
+    pub async fn set_removed<'a, I>(&mut self, model_ids: I)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        for model_id in model_ids {
+            let service_name: String = model_service_name(model_id);
+            tracing::info!(service = %service_name, "Health status cleared");
+            self.reporter.clear_service_status(&service_name).await;
+        }
+    }
+
+    /// Sets the status of a single health entry and logs the change.
+    async fn set_status(&mut self, service_name: &str, status: ServingStatus) {
+        tracing::info!(service = service_name, status = ?status, "Health status changed");
+        self.reporter.set_service_status(service_name, status).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_service_name() {
+        // WHEN
+        let service_name: String = model_service_name("blip_quantized");
+        // THEN
+        assert_eq!(service_name, "computer_vision.ComputerVision/blip_quantized");
+    }
+}
@@ -0,0 +1,395 @@
+//! This module provides the [`TextDecoder`] of the BLIP captioning and VQA models.
+//!
+//! The decoder mirrors `blip_text::TextLMHeadModel` of [`candle_transformers`] and its quantized
+//! variant, and is loaded from the same `text_decoder` weights. The decoder of [`candle_transformers`]
+//! adds the position embeddings to the word embeddings of a single sequence, so its forward pass
+//! fails for a batch of several sequences. This one broadcasts them over the batch, so that the
+//! captions of a batch of images are decoded together, one forward pass per token.
+use candle_core::{Module, Result, Tensor, D};
+use candle_nn::{Activation, Embedding, LayerNorm};
+use candle_transformers::models::{blip_text, quantized_blip};
+use candle_transformers::quantized_nn;
+
+/// A linear layer with full precision or quantized weights.
+#[derive(Debug, Clone)]
+enum Linear {
+    Full(candle_nn::Linear),
+    Quantized(quantized_nn::Linear),
+}
+
+impl Module for Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Full(linear) => linear.forward(xs),
+            Self::Quantized(linear) => linear.forward(xs),
+        }
+    }
+}
+
+/// `Weights` abstracts the storage of the decoder weights: safetensors files loaded through a
+/// [`candle_nn::VarBuilder`], or a `gguf` file loaded through a [`quantized_blip::VarBuilder`].
+trait Weights: Sized {
+    /// Returns the weights found under the `name` prefix.
+    fn pp<S: ToString>(&self, name: S) -> Self;
+
+    /// Loads a linear layer with a bias.
+    fn linear(&self, in_dim: usize, out_dim: usize) -> Result<Linear>;
+
+    /// Loads an embedding table. Quantized tables are dequantized.
+    fn embedding(&self, size: usize, hidden_size: usize) -> Result<Embedding>;
+
+    /// Loads a layer norm with a bias.
+    fn layer_norm(&self, size: usize, eps: f64) -> Result<LayerNorm>;
+
+    /// Loads the projection to the vocabulary, whose bias is stored next to it as `bias`.
+    fn lm_head(&self, hidden_size: usize, vocab_size: usize) -> Result<Linear>;
+}
+
+impl Weights for candle_nn::VarBuilder<'_> {
+    fn pp<S: ToString>(&self, name: S) -> Self {
+        candle_nn::VarBuilder::pp(self, name)
+    }
+
+    fn linear(&self, in_dim: usize, out_dim: usize) -> Result<Linear> {
+        Ok(Linear::Full(candle_nn::linear(in_dim, out_dim, self.clone())?))
+    }
+
+    fn embedding(&self, size: usize, hidden_size: usize) -> Result<Embedding> {
+        candle_nn::embedding(size, hidden_size, self.clone())
+    }
+
+    fn layer_norm(&self, size: usize, eps: f64) -> Result<LayerNorm> {
+        candle_nn::layer_norm(size, eps, self.clone())
+    }
+
+    fn lm_head(&self, hidden_size: usize, vocab_size: usize) -> Result<Linear> {
+        let weight: Tensor = self.get((vocab_size, hidden_size), "decoder.weight")?;
+        let bias: Tensor = self.get(vocab_size, "bias")?;
+        Ok(Linear::Full(candle_nn::Linear::new(weight, Some(bias))))
+    }
+}
+
+impl Weights for quantized_blip::VarBuilder {
+    fn pp<S: ToString>(&self, name: S) -> Self {
+        quantized_blip::VarBuilder::pp(self, name)
+    }
+
+    fn linear(&self, in_dim: usize, out_dim: usize) -> Result<Linear> {
+        Ok(Linear::Quantized(quantized_nn::linear(in_dim, out_dim, self.clone())?))
+    }
+
+    fn embedding(&self, size: usize, hidden_size: usize) -> Result<Embedding> {
+        let embeddings: Tensor = self.get((size, hidden_size), "weight")?.dequantize(self.device())?;
+        Ok(Embedding::new(embeddings, hidden_size))
+    }
+
+    fn layer_norm(&self, size: usize, eps: f64) -> Result<LayerNorm> {
+        quantized_nn::layer_norm(size, eps, self.clone())
+    }
+
+    fn lm_head(&self, hidden_size: usize, vocab_size: usize) -> Result<Linear> {
+        let weight = self.get((vocab_size, hidden_size), "decoder.weight")?;
+        let bias: Tensor = self.get(vocab_size, "bias")?.dequantize(self.device())?;
+        Ok(Linear::Quantized(quantized_nn::Linear::from_arc(weight, Some(bias))?))
+    }
+}
+
+/// Word and position embeddings of the decoder.
+#[derive(Debug, Clone)]
+struct TextEmbeddings {
+    word_embeddings: Embedding,
+    position_embeddings: Embedding,
+    layer_norm: LayerNorm,
+}
+
+impl TextEmbeddings {
+    fn new(cfg: &blip_text::Config, vb: &impl Weights) -> Result<Self> {
+        Ok(Self {
+            word_embeddings: vb.pp("word_embeddings").embedding(cfg.vocab_size, cfg.hidden_size)?,
+            position_embeddings: vb.pp("position_embeddings").embedding(cfg.max_position_embeddings, cfg.hidden_size)?,
+            layer_norm: vb.pp("LayerNorm").layer_norm(cfg.hidden_size, cfg.layer_norm_eps)?,
+        })
+    }
+
+    /// Embeds the tokens `input_ids` of shape `(batch, seq_len)`, which follow `past_kv_len` tokens
+    /// in every sequence.
+    fn forward(&self, input_ids: &Tensor, past_kv_len: usize) -> Result<Tensor> {
+        let seq_len: usize = input_ids.dim(1)?;
+        let position_ids: Tensor =
+            Tensor::arange(past_kv_len as u32, (past_kv_len + seq_len) as u32, input_ids.device())?.unsqueeze(0)?;
+        let embeddings: Tensor = input_ids.apply(&self.word_embeddings)?;
+        let position_embeddings: Tensor = position_ids.apply(&self.position_embeddings)?;
+
+        embeddings.broadcast_add(&position_embeddings)?.apply(&self.layer_norm)
+    }
+}
+
+/// Multi-head attention followed by the output projection and the residual layer norm.
+///
+/// Used both for self-attention, whose keys and values are cached across forward passes, and for
+/// cross-attention to the image (or question) embeddings.
+#[derive(Debug, Clone)]
+struct TextAttention {
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    dense: Linear,
+    layer_norm: LayerNorm,
+    num_attention_heads: usize,
+    attention_head_size: usize,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl TextAttention {
+    fn new(cfg: &blip_text::Config, context_size: usize, vb: &impl Weights) -> Result<Self> {
+        let self_vb = vb.pp("self");
+        let output_vb = vb.pp("output");
+
+        Ok(Self {
+            query: self_vb.pp("query").linear(cfg.hidden_size, cfg.hidden_size)?,
+            key: self_vb.pp("key").linear(context_size, cfg.hidden_size)?,
+            value: self_vb.pp("value").linear(context_size, cfg.hidden_size)?,
+            dense: output_vb.pp("dense").linear(cfg.hidden_size, cfg.hidden_size)?,
+            layer_norm: output_vb.pp("LayerNorm").layer_norm(cfg.hidden_size, cfg.layer_norm_eps)?,
+            num_attention_heads: cfg.num_attention_heads,
+            attention_head_size: cfg.hidden_size / cfg.num_attention_heads,
+            kv_cache: None,
+        })
+    }
+
+    fn transpose_for_scores(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, seq_len, _) = xs.dims3()?;
+        xs.reshape((b_size, seq_len, self.num_attention_heads, self.attention_head_size))?
+            .permute((0, 2, 1, 3))?
+            .contiguous()
+    }
+
+    /// Attends to `context`, or to the cached and new tokens if `context` is `None`.
+    fn forward(&mut self, xs: &Tensor, context: Option<&Tensor>, attention_mask: Option<&Tensor>) -> Result<Tensor> {
+        let query: Tensor = self.transpose_for_scores(&xs.apply(&self.query)?)?;
+        let (key, value): (Tensor, Tensor) = match context {
+            Some(context) => (
+                self.transpose_for_scores(&context.apply(&self.key)?)?,
+                self.transpose_for_scores(&context.apply(&self.value)?)?,
+            ),
+            None => {
+                let key: Tensor = self.transpose_for_scores(&xs.apply(&self.key)?)?;
+                let value: Tensor = self.transpose_for_scores(&xs.apply(&self.value)?)?;
+                let (key, value): (Tensor, Tensor) = match &self.kv_cache {
+                    None => (key, value),
+                    Some((prev_key, prev_value)) => (Tensor::cat(&[prev_key, &key], 2)?, Tensor::cat(&[prev_value, &value], 2)?),
+                };
+                self.kv_cache = Some((key.clone(), value.clone()));
+                (key, value)
+            }
+        };
+
+        let scale: f64 = 1f64 / (self.attention_head_size as f64).sqrt();
+        let attention_scores: Tensor = (query.matmul(&key.t()?)? * scale)?;
+        let attention_scores: Tensor = match attention_mask {
+            Some(mask) => attention_scores.broadcast_add(mask)?,
+            None => attention_scores,
+        };
+        let attention_probs: Tensor = candle_nn::ops::softmax_last_dim(&attention_scores)?;
+        let attention_output: Tensor = attention_probs
+            .matmul(&value)?
+            .permute((0, 2, 1, 3))?
+            .flatten_from(D::Minus2)?;
+
+        (attention_output.apply(&self.dense)? + xs)?.apply(&self.layer_norm)
+    }
+}
+
+/// A single layer of the decoder: causal self-attention, cross-attention and a feed-forward block.
+#[derive(Debug, Clone)]
+struct TextLayer {
+    attention: TextAttention,
+    cross_attention: TextAttention,
+    intermediate: Linear,
+    output: Linear,
+    output_layer_norm: LayerNorm,
+    activation: Activation,
+}
+
+impl TextLayer {
+    fn new(cfg: &blip_text::Config, vb: &impl Weights) -> Result<Self> {
+        Ok(Self {
+            attention: TextAttention::new(cfg, cfg.hidden_size, &vb.pp("attention"))?,
+            cross_attention: TextAttention::new(cfg, cfg.encoder_hidden_size, &vb.pp("crossattention"))?,
+            intermediate: vb.pp("intermediate").pp("dense").linear(cfg.hidden_size, cfg.intermediate_size)?,
+            output: vb.pp("output").pp("dense").linear(cfg.intermediate_size, cfg.hidden_size)?,
+            output_layer_norm: vb.pp("output").pp("LayerNorm").layer_norm(cfg.hidden_size, cfg.layer_norm_eps)?,
+            activation: cfg.hidden_act,
+        })
+    }
+
+    fn forward(&mut self, xs: &Tensor, image_embeds: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let xs: Tensor = self.attention.forward(xs, None, Some(attention_mask))?;
+        let xs: Tensor = self.cross_attention.forward(&xs, Some(image_embeds), None)?;
+        let intermediate: Tensor = xs.apply(&self.intermediate)?.apply(&self.activation)?;
+
+        (intermediate.apply(&self.output)? + xs)?.apply(&self.output_layer_norm)
+    }
+}
+
+/// The BLIP text decoder. Predicts the next token of a batch of sequences while attending to their
+/// image (or question) embeddings.
+///
+/// The weights are reference counted tensors, so clones of a decoder share them and only have their
+/// own key-value cache.
+#[derive(Debug, Clone)]
+pub struct TextDecoder {
+    embeddings: TextEmbeddings,
+    layers: Vec<TextLayer>,
+    transform: Linear,
+    transform_activation: Activation,
+    transform_layer_norm: LayerNorm,
+    lm_head: Linear,
+    past_kv_len: usize,
+}
+
+impl TextDecoder {
+    /// Creates a new [`TextDecoder`] from the full precision weights found under the `vb` prefix
+    /// (usually `text_decoder`).
+    pub fn new(cfg: &blip_text::Config, vb: candle_nn::VarBuilder) -> Result<Self> {
+        Self::load(cfg, &vb)
+    }
+
+    /// Creates a new [`TextDecoder`] from the quantized weights found under the `vb` prefix
+    /// (usually `text_decoder`).
+    pub fn new_quantized(cfg: &blip_text::Config, vb: quantized_blip::VarBuilder) -> Result<Self> {
+        Self::load(cfg, &vb)
+    }
+
+    fn load(cfg: &blip_text::Config, vb: &impl Weights) -> Result<Self> {
+        let bert_vb = vb.pp("bert");
+        let layers_vb = bert_vb.pp("encoder").pp("layer");
+        let layers: Vec<TextLayer> = (0..cfg.num_hidden_layers)
+            .map(|index| TextLayer::new(cfg, &layers_vb.pp(index)))
+            .collect::<Result<_>>()?;
+        let predictions_vb = vb.pp("cls").pp("predictions");
+        let transform_vb = predictions_vb.pp("transform");
+
+        Ok(Self {
+            embeddings: TextEmbeddings::new(cfg, &bert_vb.pp("embeddings"))?,
+            layers,
+            transform: transform_vb.pp("dense").linear(cfg.hidden_size, cfg.hidden_size)?,
+            transform_activation: cfg.hidden_act,
+            transform_layer_norm: transform_vb.pp("LayerNorm").layer_norm(cfg.hidden_size, cfg.layer_norm_eps)?,
+            lm_head: predictions_vb.lm_head(cfg.hidden_size, cfg.vocab_size)?,
+            past_kv_len: 0,
+        })
+    }
+
+    /// Performs a forward pass of the text decoder.
+    ///
+    /// The tokens are appended to the sequences of the previous forward passes, whose keys and values
+    /// are cached, until [`TextDecoder::reset_kv_cache`] is called.
+    ///
+    /// # Arguments
+    ///
+    /// * `input_ids` - A reference to the `(batch, seq_len)` tensor of the new tokens of each sequence.
+    /// * `image_embeds` - A reference to the `(batch, tokens, hidden)` tensor the decoder attends to:
+    ///   the image embeddings for captioning models, or the question embeddings for VQA models.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the `(batch, seq_len, vocab_size)` logits tensor, or an error if the
+    /// forward pass fails.
+    pub fn forward(&mut self, input_ids: &Tensor, image_embeds: &Tensor) -> Result<Tensor> {
+        let seq_len: usize = input_ids.dim(1)?;
+        let mut xs: Tensor = self.embeddings.forward(input_ids, self.past_kv_len)?;
+        let mask: Vec<f32> = (0..seq_len)
+            .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0f32 }))
+            .collect();
+        let mask: Tensor = Tensor::from_vec(mask, (seq_len, seq_len), input_ids.device())?.to_dtype(xs.dtype())?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, image_embeds, &mask)?;
+        }
+        self.past_kv_len += seq_len;
+
+        xs.apply(&self.transform)?
+            .apply(&self.transform_activation)?
+            .apply(&self.transform_layer_norm)?
+            .apply(&self.lm_head)
+    }
+
+    /// Resets the key-value cache of the decoder, so that it can start new sequences.
+    pub fn reset_kv_cache(&mut self) {
+        self.past_kv_len = 0;
+        for layer in self.layers.iter_mut() {
+            layer.attention.kv_cache = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device, IndexOp};
+    use candle_nn::{VarBuilder, VarMap};
+
+    fn tiny_config() -> blip_text::Config {
+        blip_text::Config {
+            vocab_size: 16,
+            hidden_size: 8,
+            encoder_hidden_size: 8,
+            intermediate_size: 16,
+            projection_dim: 8,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            max_position_embeddings: 32,
+            hidden_act: Activation::Gelu,
+            layer_norm_eps: 1e-12,
+            is_decoder: true,
+        }
+    }
+
+    /// Returns the largest absolute difference between two tensors of the same shape.
+    fn max_difference(a: &Tensor, b: &Tensor) -> f32 {
+        (a - b).unwrap().abs().unwrap().flatten_all().unwrap().max(0).unwrap().to_scalar().unwrap()
+    }
+
+    #[test]
+    fn test_text_decoder_forward_shape() {
+        // GIVEN
+        let vb: VarBuilder = VarBuilder::zeros(DType::F32, &Device::Cpu);
+        let mut decoder: TextDecoder = TextDecoder::new(&tiny_config(), vb).unwrap();
+        let input_ids: Tensor = Tensor::new(&[[1u32, 2, 3], [4, 5, 6]], &Device::Cpu).unwrap();
+        let image_embeds: Tensor = Tensor::zeros((2, 5, 8), DType::F32, &Device::Cpu).unwrap();
+        // WHEN
+        let prefill: Tensor = decoder.forward(&input_ids, &image_embeds).unwrap();
+        let step: Tensor = decoder.forward(&input_ids.narrow(1, 0, 1).unwrap(), &image_embeds).unwrap();
+        // THEN
+        assert_eq!(prefill.dims(), &[2, 3, 16]);
+        assert_eq!(step.dims(), &[2, 1, 16]);
+    }
+
+    #[test]
+    fn test_text_decoder_batch_matches_single_sequences() {
+        // GIVEN
+        let varmap = VarMap::new();
+        let vb: VarBuilder = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let mut decoder: TextDecoder = TextDecoder::new(&tiny_config(), vb).unwrap();
+        let prompts: Tensor = Tensor::new(&[[1u32, 2], [3, 4]], &Device::Cpu).unwrap();
+        let next_tokens: Tensor = Tensor::new(&[[5u32], [6]], &Device::Cpu).unwrap();
+        let image_embeds: Tensor = Tensor::randn(0f32, 1f32, (2, 5, 8), &Device::Cpu).unwrap();
+        // WHEN
+        decoder.forward(&prompts, &image_embeds).unwrap();
+        let batched: Tensor = decoder.forward(&next_tokens, &image_embeds).unwrap();
+        decoder.reset_kv_cache();
+        let singles: Vec<Tensor> = (0..2)
+            .map(|row| {
+                let image_embeds: Tensor = image_embeds.narrow(0, row, 1).unwrap();
+                decoder.forward(&prompts.narrow(0, row, 1).unwrap(), &image_embeds).unwrap();
+                let logits: Tensor = decoder.forward(&next_tokens.narrow(0, row, 1).unwrap(), &image_embeds).unwrap();
+                decoder.reset_kv_cache();
+                logits
+            })
+            .collect();
+        // THEN
+        for (row, single) in singles.iter().enumerate() {
+            assert!(max_difference(&batched.i(row).unwrap(), &single.i(0).unwrap()) < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+//! This module provides the BLIP model for visual question answering (VQA).
+//!
+//! BLIP VQA checkpoints consist of a vision model, a question encoder and an answer decoder. The
+//! vision model and the answer decoder share their architecture (and weight names) with the
+//! captioning model, so they are loaded through [`blip::BlipForConditionalGeneration`]. The question
+//! encoder is a BERT encoder with cross-attention to the image embeddings, which is not provided by
+//! [`candle_transformers`], so it is implemented here.
+use candle_core::{Module, Result, Tensor, D};
+use candle_nn::{embedding, layer_norm, linear, Activation, Embedding, LayerNorm, Linear, VarBuilder};
+use candle_transformers::models::{blip, blip_text};
+
+/// Returns the configuration of the `Salesforce/blip-vqa-base` model.
+pub fn vqa_base_config() -> blip::Config {
+    let text_config = blip_text::Config {
+        vocab_size: 30524,
+        hidden_size: 768,
+        encoder_hidden_size: 768,
+        intermediate_size: 3072,
+        projection_dim: 768,
+        num_hidden_layers: 12,
+        num_attention_heads: 12,
+        max_position_embeddings: 512,
+        hidden_act: Activation::Gelu,
+        layer_norm_eps: 1e-12,
+        is_decoder: true,
+    };
+    let vision_config = blip::VisionConfig {
+        hidden_size: 768,
+        intermediate_size: 3072,
+        projection_dim: 512,
+        num_hidden_layers: 12,
+        num_attention_heads: 12,
+        image_size: 384,
+        patch_size: 16,
+        hidden_act: Activation::Gelu,
+        layer_norm_eps: 1e-5,
+    };
+    blip::Config {
+        text_config,
+        vision_config,
+        projection_dim: 512,
+        image_text_hidden_size: 256,
+    }
+}
+
+/// Word and position embeddings of the question encoder.
+#[derive(Debug, Clone)]
+struct TextEmbeddings {
+    word_embeddings: Embedding,
+    position_embeddings: Embedding,
+    layer_norm: LayerNorm,
+}
+
+impl TextEmbeddings {
+    fn new(cfg: &blip_text::Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            word_embeddings: embedding(cfg.vocab_size, cfg.hidden_size, vb.pp("word_embeddings"))?,
+            position_embeddings: embedding(cfg.max_position_embeddings, cfg.hidden_size, vb.pp("position_embeddings"))?,
+            layer_norm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("LayerNorm"))?,
+        })
+    }
+}
+
+impl Module for TextEmbeddings {
+    fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        let seq_len: usize = input_ids.dim(1)?;
+        let position_ids: Tensor = Tensor::arange(0, seq_len as u32, input_ids.device())?.unsqueeze(0)?;
+        let embeddings: Tensor = input_ids.apply(&self.word_embeddings)?;
+        let position_embeddings: Tensor = position_ids.apply(&self.position_embeddings)?;
+
+        embeddings.broadcast_add(&position_embeddings)?.apply(&self.layer_norm)
+    }
+}
+
+/// Multi-head attention followed by the output projection and the residual layer norm.
+///
+/// Used both for self-attention (the context is the input itself) and for cross-attention
+/// (the context is the image embeddings).
+#[derive(Debug, Clone)]
+struct TextAttention {
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    dense: Linear,
+    layer_norm: LayerNorm,
+    num_attention_heads: usize,
+    attention_head_size: usize,
+}
+
+impl TextAttention {
+    fn new(cfg: &blip_text::Config, context_size: usize, vb: VarBuilder) -> Result<Self> {
+        let attention_head_size: usize = cfg.hidden_size / cfg.num_attention_heads;
+        let self_vb: VarBuilder = vb.pp("self");
+        let output_vb: VarBuilder = vb.pp("output");
+
+        Ok(Self {
+            query: linear(cfg.hidden_size, cfg.hidden_size, self_vb.pp("query"))?,
+            key: linear(context_size, cfg.hidden_size, self_vb.pp("key"))?,
+            value: linear(context_size, cfg.hidden_size, self_vb.pp("value"))?,
+            dense: linear(cfg.hidden_size, cfg.hidden_size, output_vb.pp("dense"))?,
+            layer_norm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, output_vb.pp("LayerNorm"))?,
+            num_attention_heads: cfg.num_attention_heads,
+            attention_head_size,
+        })
+    }
+
+    fn transpose_for_scores(&self, xs: &Tensor) -> Result<Tensor> {
+        let (b_size, seq_len, _) = xs.dims3()?;
+        xs.reshape((b_size, seq_len, self.num_attention_heads, self.attention_head_size))?
+            .permute((0, 2, 1, 3))?
+            .contiguous()
+    }
+
+    fn forward(&self, xs: &Tensor, context: &Tensor) -> Result<Tensor> {
+        let query: Tensor = self.transpose_for_scores(&xs.apply(&self.query)?)?;
+        let key: Tensor = self.transpose_for_scores(&context.apply(&self.key)?)?;
+        let value: Tensor = self.transpose_for_scores(&context.apply(&self.value)?)?;
+
+        let scale: f64 = 1f64 / (self.attention_head_size as f64).sqrt();
+        let attention_scores: Tensor = (query.matmul(&key.t()?)? * scale)?;
+        let attention_probs: Tensor = candle_nn::ops::softmax_last_dim(&attention_scores)?;
+        let attention_output: Tensor = attention_probs
+            .matmul(&value)?
+            .permute((0, 2, 1, 3))?
+            .flatten_from(D::Minus2)?;
+
+        (attention_output.apply(&self.dense)? + xs)?.apply(&self.layer_norm)
+    }
+}
+
+/// A single layer of the question encoder: self-attention, cross-attention and a feed-forward block.
+#[derive(Debug, Clone)]
+struct TextLayer {
+    attention: TextAttention,
+    cross_attention: TextAttention,
+    intermediate: Linear,
+    output: Linear,
+    output_layer_norm: LayerNorm,
+    activation: Activation,
+}
+
+impl TextLayer {
+    fn new(cfg: &blip_text::Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            attention: TextAttention::new(cfg, cfg.hidden_size, vb.pp("attention"))?,
+            cross_attention: TextAttention::new(cfg, cfg.encoder_hidden_size, vb.pp("crossattention"))?,
+            intermediate: linear(cfg.hidden_size, cfg.intermediate_size, vb.pp("intermediate").pp("dense"))?,
+            output: linear(cfg.intermediate_size, cfg.hidden_size, vb.pp("output").pp("dense"))?,
+            output_layer_norm: layer_norm(cfg.hidden_size, cfg.layer_norm_eps, vb.pp("output").pp("LayerNorm"))?,
+            activation: cfg.hidden_act,
+        })
+    }
+
+    fn forward(&self, xs: &Tensor, image_embeds: &Tensor) -> Result<Tensor> {
+        let xs: Tensor = self.attention.forward(xs, xs)?;
+        let xs: Tensor = self.cross_attention.forward(&xs, image_embeds)?;
+        let intermediate: Tensor = xs.apply(&self.intermediate)?.apply(&self.activation)?;
+
+        (intermediate.apply(&self.output)? + xs)?.apply(&self.output_layer_norm)
+    }
+}
+
+/// The BLIP question encoder. Encodes the tokens of a question while attending to the image embeddings.
+#[derive(Debug, Clone)]
+pub struct QuestionEncoder {
+    embeddings: TextEmbeddings,
+    layers: Vec<TextLayer>,
+}
+
+impl QuestionEncoder {
+    /// Creates a new [`QuestionEncoder`] from the weights found under the `vb` prefix.
+    pub fn new(cfg: &blip_text::Config, vb: VarBuilder) -> Result<Self> {
+        let embeddings = TextEmbeddings::new(cfg, vb.pp("embeddings"))?;
+        let layers_vb: VarBuilder = vb.pp("encoder").pp("layer");
+        let layers: Vec<TextLayer> = (0..cfg.num_hidden_layers)
+            .map(|index| TextLayer::new(cfg, layers_vb.pp(index)))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { embeddings, layers })
+    }
+
+    /// Encodes the question tokens `input_ids` of shape `(batch, seq_len)` using the image embeddings
+    /// `image_embeds` of shape `(batch, num_patches, encoder_hidden_size)`.
+    ///
+    /// Returns the question embeddings of shape `(batch, seq_len, hidden_size)`.
+    pub fn forward(&self, input_ids: &Tensor, image_embeds: &Tensor) -> Result<Tensor> {
+        self.layers
+            .iter()
+            .try_fold(input_ids.apply(&self.embeddings)?, |xs, layer| layer.forward(&xs, image_embeds))
+    }
+}
+
+/// BLIP model for visual question answering.
+#[derive(Debug, Clone)]
+pub struct BlipForQuestionAnswering {
+    model: blip::BlipForConditionalGeneration,
+    text_encoder: QuestionEncoder,
+}
+
+impl BlipForQuestionAnswering {
+    /// Creates a new [`BlipForQuestionAnswering`] model.
+    ///
+    /// # Arguments
+    ///
+    /// * `cfg` - The configuration of the model (e.g. [`vqa_base_config`]).
+    /// * `vb` - The [`VarBuilder`] with the weights of the `vision_model`, `text_encoder` and `text_decoder`.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the new model or an error if any of the weights is missing.
+    pub fn new(cfg: &blip::Config, vb: VarBuilder) -> Result<Self> {
+        Ok(Self {
+            model: blip::BlipForConditionalGeneration::new(cfg, vb.clone())?,
+            text_encoder: QuestionEncoder::new(&cfg.text_config, vb.pp("text_encoder"))?,
+        })
+    }
+
+    /// Returns a reference to the vision model.
+    pub fn vision_model(&self) -> &blip::VisionModel {
+        self.model.vision_model()
+    }
+
+    /// Returns a reference to the question encoder.
+    pub fn text_encoder(&self) -> &QuestionEncoder {
+        &self.text_encoder
+    }
+
+    /// Returns a mutable reference to the answer decoder.
+    pub fn text_decoder(&mut self) -> &mut blip_text::TextLMHeadModel {
+        self.model.text_decoder()
+    }
+
+    /// Resets the key-value cache of the answer decoder.
+    pub fn reset_kv_cache(&mut self) {
+        self.model.reset_kv_cache()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device};
+
+    /// The question embeddings are fed to the decoder cross-attention, so like in the real config
+    /// the vision and text hidden sizes must match.
+    fn tiny_config() -> blip::Config {
+        let mut config: blip::Config = vqa_base_config();
+        config.text_config = blip_text::Config {
+            vocab_size: 16,
+            hidden_size: 8,
+            encoder_hidden_size: 8,
+            intermediate_size: 16,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            max_position_embeddings: 32,
+            ..config.text_config
+        };
+        config.vision_config = blip::VisionConfig {
+            hidden_size: 8,
+            intermediate_size: 16,
+            num_hidden_layers: 1,
+            num_attention_heads: 2,
+            image_size: 32,
+            ..config.vision_config
+        };
+        config
+    }
+
+    #[test]
+    fn test_question_encoder_forward_shape() {
+        // GIVEN
+        let config: blip::Config = tiny_config();
+        let vb: VarBuilder = VarBuilder::zeros(DType::F32, &Device::Cpu);
+        let encoder: QuestionEncoder = QuestionEncoder::new(&config.text_config, vb).unwrap();
+        let input_ids: Tensor = Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap();
+        let image_embeds: Tensor = Tensor::zeros((1, 5, 8), DType::F32, &Device::Cpu).unwrap();
+        // WHEN
+        let question_embeds: Tensor = encoder.forward(&input_ids, &image_embeds).unwrap();
+        // THEN
+        assert_eq!(question_embeds.dims(), &[1, 3, 8]);
+    }
+
+    #[test]
+    fn test_blip_for_question_answering_forward_shapes() {
+        // GIVEN
+        let config: blip::Config = tiny_config();
+        let vb: VarBuilder = VarBuilder::zeros(DType::F32, &Device::Cpu);
+        let mut model: BlipForQuestionAnswering = BlipForQuestionAnswering::new(&config, vb).unwrap();
+        let pixels: Tensor = Tensor::zeros((1, 3, 32, 32), DType::F32, &Device::Cpu).unwrap();
+        let question_ids: Tensor = Tensor::new(&[[1u32, 2]], &Device::Cpu).unwrap();
+        let answer_ids: Tensor = Tensor::new(&[[3u32]], &Device::Cpu).unwrap();
+        // WHEN
+        let image_embeds: Tensor = model.vision_model().forward(&pixels).unwrap();
+        let question_embeds: Tensor = model.text_encoder().forward(&question_ids, &image_embeds).unwrap();
+        let logits: Tensor = model.text_decoder().forward(&answer_ids, &question_embeds).unwrap();
+        // THEN
+        assert_eq!(image_embeds.dims(), &[1, 5, 8]);
+        assert_eq!(question_embeds.dims(), &[1, 2, 8]);
+        assert_eq!(logits.dims(), &[1, 1, 16]);
+    }
+}
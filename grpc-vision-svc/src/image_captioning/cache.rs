@@ -0,0 +1,441 @@
+//! This module provides the [`InferenceCache`], which keeps the results of recent captioning
+//! requests so that resubmitted images do not have to go through the models again.
+//!
+//! Two kinds of entries share the cache, both keyed by the SHA-256 hash of the decoded image and
+//! the registry id of the model:
+//!
+//! - The vision model outputs (image embeddings), which only depend on the image and the model, so
+//!   they are reused when the same image is captioned with a different prompt or options.
+//! - The final captions, whose key also includes the resolved generation parameters.
+//!
+//! The cache is bounded by the approximate number of bytes held by its entries, and evicts the
+//! least recently used entries first.
+use std::sync::Mutex;
+use lru::LruCache;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use candle_core::{Result, Tensor};
+use candle_transformers::generation::Sampling;
+use crate::image_captioning::generation::GenerationParams;
+use crate::metrics::{metrics, CacheKind};
+
+/// SHA-256 hash of the pixels of a decoded image.
+pub type ImageHash = [u8; 32];
+
+/// Computes the [`ImageHash`] of the pixels of a decoded image.
+///
+/// The hash is computed on the decoded pixels rather than on the encoded file, so the same picture
+/// hits the cache even if it was encoded differently (e.g. with different metadata).
+pub fn hash_image(pixels: &[u8]) -> ImageHash {
+    Sha256::digest(pixels).into()
+}
+
+/// [`CacheConfig`] holds the parameters of the [`InferenceCache`].
+///
+/// It corresponds to the optional `[cache]` table of the models configuration file. Every field
+/// can be omitted, in which case the value from [`CacheConfig::default`] is used.
+///
+/// # Example TOML config
+///
+/// ```toml
+/// [cache]
+/// max_bytes = 268435456 # 256 MiB, 0 disables the cache
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub max_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Helper struct used to deserialize the `[cache]` table of the models configuration file.
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    cache: CacheConfig,
+}
+
+impl CacheConfig {
+    /// Parses the `[cache]` table from the contents of a TOML configuration file.
+    /// Other tables are ignored. If the table is missing, the defaults are returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `toml_str` - The contents of the TOML configuration file.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the parsed [`CacheConfig`] or a [`toml::de::Error`] if parsing fails.
+    pub fn from_toml_str(toml_str: &str) -> std::result::Result<Self, toml::de::Error> {
+        toml::from_str::<ConfigFile>(toml_str).map(|file| file.cache)
+    }
+}
+
+/// The key of a cache entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum CacheKey {
+    Embeddings {
+        image: ImageHash,
+        model_id: String,
+    },
+    Caption {
+        image: ImageHash,
+        model_id: String,
+        params: CaptionParams,
+    },
+}
+
+impl CacheKey {
+    /// Returns the approximate number of bytes held by the key.
+    fn size(&self) -> usize {
+        match self {
+            Self::Embeddings { image, model_id } => image.len() + model_id.len(),
+            Self::Caption { image, model_id, params } => image.len() + model_id.len() + params.size(),
+        }
+    }
+}
+
+/// The [`GenerationParams`] of a caption entry. Floats are stored as their bits, so that the
+/// parameters can be hashed and compared exactly.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CaptionParams {
+    prompt: String,
+    sampling: SamplingKey,
+    seed: u64,
+    max_new_tokens: usize,
+    repetition_penalty: u32,
+}
+
+impl CaptionParams {
+    /// Returns the approximate number of bytes held by the parameters.
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.prompt.len()
+    }
+}
+
+impl From<&GenerationParams> for CaptionParams {
+    fn from(params: &GenerationParams) -> Self {
+        // Destructured, so that a new field cannot be left out of the key
+        let GenerationParams { prompt, sampling, seed, max_new_tokens, repetition_penalty } = params;
+
+        Self {
+            prompt: prompt.clone(),
+            sampling: sampling.into(),
+            seed: *seed,
+            max_new_tokens: *max_new_tokens,
+            repetition_penalty: repetition_penalty.to_bits(),
+        }
+    }
+}
+
+/// The [`Sampling`] strategy of a [`CaptionParams`], with the floats stored as their bits.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum SamplingKey {
+    ArgMax,
+    All { temperature: u64 },
+    TopK { k: usize, temperature: u64 },
+    TopP { p: u64, temperature: u64 },
+    TopKThenTopP { k: usize, p: u64, temperature: u64 },
+}
+
+impl From<&Sampling> for SamplingKey {
+    fn from(sampling: &Sampling) -> Self {
+        match *sampling {
+            Sampling::ArgMax => Self::ArgMax,
+            Sampling::All { temperature } => Self::All { temperature: temperature.to_bits() },
+            Sampling::TopK { k, temperature } => Self::TopK { k, temperature: temperature.to_bits() },
+            Sampling::TopP { p, temperature } => Self::TopP { p: p.to_bits(), temperature: temperature.to_bits() },
+            Sampling::TopKThenTopP { k, p, temperature } => {
+                Self::TopKThenTopP { k, p: p.to_bits(), temperature: temperature.to_bits() }
+            }
+        }
+    }
+}
+
+/// The value of a cache entry.
+#[derive(Debug, Clone)]
+enum CacheValue {
+    Embeddings(Tensor),
+    Caption(String),
+}
+
+impl CacheValue {
+    /// Returns the approximate number of bytes held by the value.
+    fn size(&self) -> usize {
+        match self {
+            Self::Embeddings(tensor) => tensor.elem_count() * tensor.dtype().size_in_bytes(),
+            Self::Caption(caption) => caption.len(),
+        }
+    }
+}
+
+/// The entries of the cache and their total size.
+struct CacheState {
+    entries: LruCache<CacheKey, CacheValue>,
+    bytes: usize,
+}
+
+/// [`InferenceCache`] is a least recently used cache of image embeddings and captions.
+///
+/// The cache is safe to share between threads. Lookups are counted as hits or misses by the
+/// `vision_cache_lookups_total` metric.
+pub struct InferenceCache {
+    max_bytes: usize,
+    state: Mutex<CacheState>,
+}
+
+impl InferenceCache {
+    /// Creates a new, empty instance of [`InferenceCache`].
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The [`CacheConfig`] with the maximum size of the cache.
+    ///
+    /// # Returns
+    ///
+    /// A new [`InferenceCache`] instance.
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            max_bytes: config.max_bytes,
+            state: Mutex::new(CacheState { entries: LruCache::unbounded(), bytes: 0 }),
+        }
+    }
+
+    /// Looks up the image embeddings computed by a model for an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The [`ImageHash`] of the image.
+    /// * `model_id` - The registry id of the model.
+    ///
+    /// # Returns
+    ///
+    /// The cached embeddings tensor, or `None` on a miss.
+    pub fn embeddings(&self, image: &ImageHash, model_id: &str) -> Option<Tensor> {
+        let key = CacheKey::Embeddings { image: *image, model_id: model_id.to_string() };
+        let value: Option<CacheValue> = self.get(&key);
+        metrics().observe_cache_lookup(CacheKind::Embeddings, model_id, value.is_some());
+
+        match value {
+            Some(CacheValue::Embeddings(tensor)) => Some(tensor),
+            _ => None,
+        }
+    }
+
+    /// Stores the image embeddings computed by a model for an image.
+    ///
+    /// The tensor is copied, so that the cache does not keep alive a larger tensor it is a view of
+    /// (e.g. the embeddings of a whole batch).
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The [`ImageHash`] of the image.
+    /// * `model_id` - The registry id of the model.
+    /// * `embeddings` - The embeddings tensor.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tensor cannot be copied.
+    pub fn insert_embeddings(&self, image: &ImageHash, model_id: &str, embeddings: &Tensor) -> Result<()> {
+        let key = CacheKey::Embeddings { image: *image, model_id: model_id.to_string() };
+        self.insert(key, CacheValue::Embeddings(embeddings.copy()?));
+
+        Ok(())
+    }
+
+    /// Looks up the caption generated by a model for an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The [`ImageHash`] of the image.
+    /// * `model_id` - The registry id of the model.
+    /// * `params` - The [`GenerationParams`] the caption was generated with.
+    ///
+    /// # Returns
+    ///
+    /// The cached caption, or `None` on a miss.
+    pub fn caption(&self, image: &ImageHash, model_id: &str, params: &GenerationParams) -> Option<String> {
+        let key: CacheKey = Self::caption_key(image, model_id, params);
+        let value: Option<CacheValue> = self.get(&key);
+        metrics().observe_cache_lookup(CacheKind::Caption, model_id, value.is_some());
+
+        match value {
+            Some(CacheValue::Caption(caption)) => Some(caption),
+            _ => None,
+        }
+    }
+
+    /// Stores the caption generated by a model for an image.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The [`ImageHash`] of the image.
+    /// * `model_id` - The registry id of the model.
+    /// * `params` - The [`GenerationParams`] the caption was generated with.
+    /// * `caption` - The generated caption.
+    pub fn insert_caption(&self, image: &ImageHash, model_id: &str, params: &GenerationParams, caption: &str) {
+        self.insert(Self::caption_key(image, model_id, params), CacheValue::Caption(caption.to_string()));
+    }
+
+    fn caption_key(image: &ImageHash, model_id: &str, params: &GenerationParams) -> CacheKey {
+        CacheKey::Caption { image: *image, model_id: model_id.to_string(), params: params.into() }
+    }
+
+    fn get(&self, key: &CacheKey) -> Option<CacheValue> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.entries.get(key).cloned()
+    }
+
+    /// Inserts an entry, then evicts the least recently used entries until the cache fits in
+    /// `max_bytes`. Entries larger than the whole cache are not stored.
+    fn insert(&self, key: CacheKey, value: CacheValue) {
+        let size: usize = key.size() + value.size();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((old_key, old_value)) = state.entries.push(key, value) {
+            state.bytes -= old_key.size() + old_value.size();
+        }
+        state.bytes += size;
+        while state.bytes > self.max_bytes {
+            let Some((old_key, old_value)) = state.entries.pop_lru() else { break };
+            state.bytes -= old_key.size() + old_value.size();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device};
+    use crate::image_captioning::testing;
+
+    fn params(prompt: &str) -> GenerationParams {
+        GenerationParams { prompt: prompt.to_string(), ..Default::default() }
+    }
+
+    #[test]
+    fn test_hash_image() {
+        // GIVEN
+        let pixels: Vec<u8> = vec![1, 2, 3];
+        // WHEN
+        let hash: ImageHash = hash_image(&pixels);
+        // THEN
+        assert_eq!(hash, hash_image(&[1, 2, 3]));
+        assert_ne!(hash, hash_image(&[1, 2, 4]));
+    }
+
+    #[test]
+    fn test_inference_cache_captions() {
+        // GIVEN
+        let cache = InferenceCache::new(&CacheConfig::default());
+        let image: ImageHash = hash_image(b"image");
+        cache.insert_caption(&image, "cache_captions", &params(""), "a cat");
+        // WHEN
+        let hit: Option<String> = cache.caption(&image, "cache_captions", &params(""));
+        let other_prompt: Option<String> = cache.caption(&image, "cache_captions", &params("a photography of"));
+        let other_model: Option<String> = cache.caption(&image, "cache_captions_other", &params(""));
+        // THEN
+        assert_eq!(hit, Some(String::from("a cat")));
+        assert_eq!(other_prompt, None);
+        assert_eq!(other_model, None);
+        assert_eq!(testing::cache_lookups(CacheKind::Caption, "cache_captions", "hit"), 1);
+        assert_eq!(testing::cache_lookups(CacheKind::Caption, "cache_captions", "miss"), 1);
+        assert_eq!(testing::cache_lookups(CacheKind::Caption, "cache_captions_other", "miss"), 1);
+    }
+
+    #[test]
+    fn test_inference_cache_captions_keyed_by_generation_params() {
+        // GIVEN
+        let cache = InferenceCache::new(&CacheConfig::default());
+        let image: ImageHash = hash_image(b"image");
+        let top_k = GenerationParams { sampling: Sampling::TopK { k: 10, temperature: 0.8 }, ..params("") };
+        cache.insert_caption(&image, "cache_params", &top_k, "a cat");
+        // WHEN
+        let hit: Option<String> = cache.caption(&image, "cache_params", &top_k);
+        let other_temperature: Option<String> = cache.caption(
+            &image,
+            "cache_params",
+            &GenerationParams { sampling: Sampling::TopK { k: 10, temperature: 0.9 }, ..params("") },
+        );
+        let other_penalty: Option<String> =
+            cache.caption(&image, "cache_params", &GenerationParams { repetition_penalty: 1.1, ..top_k.clone() });
+        let other_seed: Option<String> = cache.caption(&image, "cache_params", &GenerationParams { seed: 1, ..top_k });
+        // THEN
+        assert_eq!(hit, Some(String::from("a cat")));
+        assert_eq!(other_temperature, None);
+        assert_eq!(other_penalty, None);
+        assert_eq!(other_seed, None);
+    }
+
+    #[test]
+    fn test_inference_cache_embeddings() {
+        // GIVEN
+        let cache = InferenceCache::new(&CacheConfig::default());
+        let image: ImageHash = hash_image(b"image");
+        let embeddings: Tensor = Tensor::ones((1, 4, 8), DType::F32, &Device::Cpu).unwrap();
+        cache.insert_embeddings(&image, "cache_embeddings", &embeddings).unwrap();
+        // WHEN
+        let hit: Option<Tensor> = cache.embeddings(&image, "cache_embeddings");
+        let miss: Option<Tensor> = cache.embeddings(&hash_image(b"other"), "cache_embeddings");
+        // THEN
+        assert_eq!(hit.unwrap().dims(), &[1, 4, 8]);
+        assert!(miss.is_none());
+        assert_eq!(testing::cache_lookups(CacheKind::Embeddings, "cache_embeddings", "hit"), 1);
+        assert_eq!(testing::cache_lookups(CacheKind::Embeddings, "cache_embeddings", "miss"), 1);
+    }
+
+    #[test]
+    fn test_inference_cache_evicts_least_recently_used() {
+        // GIVEN
+        // Room for exactly two entries with a one byte caption
+        let entry_size: usize = InferenceCache::caption_key(&hash_image(&[0]), "cache_eviction", &params("")).size() + 1;
+        let cache = InferenceCache::new(&CacheConfig { max_bytes: 2 * entry_size });
+        let images: Vec<ImageHash> = (0..3u8).map(|i| hash_image(&[i])).collect();
+        cache.insert_caption(&images[0], "cache_eviction", &params(""), "0");
+        cache.insert_caption(&images[1], "cache_eviction", &params(""), "1");
+        // WHEN
+        cache.caption(&images[0], "cache_eviction", &params(""));
+        cache.insert_caption(&images[2], "cache_eviction", &params(""), "2");
+        // THEN
+        assert_eq!(cache.caption(&images[0], "cache_eviction", &params("")), Some(String::from("0")));
+        assert_eq!(cache.caption(&images[1], "cache_eviction", &params("")), None);
+        assert_eq!(cache.caption(&images[2], "cache_eviction", &params("")), Some(String::from("2")));
+    }
+
+    #[test]
+    fn test_inference_cache_disabled() {
+        // GIVEN
+        let cache = InferenceCache::new(&CacheConfig { max_bytes: 0 });
+        let image: ImageHash = hash_image(b"image");
+        // WHEN
+        cache.insert_caption(&image, "cache_disabled", &params(""), "a cat");
+        // THEN
+        assert_eq!(cache.caption(&image, "cache_disabled", &params("")), None);
+    }
+
+    #[test]
+    fn test_cache_config_from_toml_str() {
+        // GIVEN
+        let toml_str: &str = r#"
+            [cache]
+            max_bytes = 1024
+
+            [batching]
+            max_batch_size = 4
+        "#;
+        // WHEN
+        let config: CacheConfig = CacheConfig::from_toml_str(toml_str).unwrap();
+        // THEN
+        assert_eq!(config, CacheConfig { max_bytes: 1024 });
+    }
+}
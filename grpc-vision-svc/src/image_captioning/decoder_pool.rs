@@ -0,0 +1,174 @@
+//! This module provides the [`DecoderPool`], which hands out text decoders to generation requests.
+//!
+//! Text generation needs a private key-value cache, so a request cannot share the decoder of its
+//! model with concurrent requests. Instead of cloning the whole model for every request, the pool
+//! keeps the text decoders that are not in use and resets their key-value cache when they are
+//! returned. The weights are reference counted tensors, so all decoders of a pool share them; only
+//! the decoder structure and its key-value cache are per request.
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use crate::image_captioning::blip_decoder::TextDecoder;
+
+/// [`DecoderPool`] keeps the idle text decoders of a model.
+///
+/// Decoders are created from a prototype when the pool is empty, so the pool grows up to the peak
+/// number of concurrent generations. At most `max_idle` decoders are kept once they are returned.
+#[derive(Debug)]
+pub struct DecoderPool {
+    prototype: TextDecoder,
+    idle: Mutex<Vec<TextDecoder>>,
+    max_idle: usize,
+}
+
+impl DecoderPool {
+    /// Creates a new, empty instance of [`DecoderPool`].
+    ///
+    /// # Arguments
+    ///
+    /// * `prototype` - The decoder new decoders are cloned from. Its key-value cache must be empty.
+    /// * `max_idle` - The maximum number of idle decoders kept by the pool.
+    ///
+    /// # Returns
+    ///
+    /// A new [`DecoderPool`] instance.
+    pub fn new(prototype: TextDecoder, max_idle: usize) -> Self {
+        Self {
+            prototype,
+            idle: Mutex::new(Vec::with_capacity(max_idle)),
+            max_idle,
+        }
+    }
+
+    /// Takes an idle decoder out of the pool, or creates a new one if none is idle.
+    ///
+    /// # Returns
+    ///
+    /// A [`PooledDecoder`] with an empty key-value cache, which goes back to the pool when dropped.
+    pub fn acquire(&self) -> PooledDecoder<'_> {
+        let decoder: Option<TextDecoder> = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+
+        PooledDecoder {
+            pool: self,
+            decoder: Some(decoder.unwrap_or_else(|| self.prototype.clone())),
+        }
+    }
+
+    /// Returns the number of idle decoders in the pool.
+    pub fn idle_count(&self) -> usize {
+        self.idle.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    fn release(&self, mut decoder: TextDecoder) {
+        decoder.reset_kv_cache();
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < self.max_idle {
+            idle.push(decoder);
+        }
+    }
+}
+
+/// A text decoder borrowed from a [`DecoderPool`].
+///
+/// Dereferences to the [`TextDecoder`]. When dropped, its key-value cache is reset and it goes back
+/// to the pool.
+pub struct PooledDecoder<'a> {
+    pool: &'a DecoderPool,
+    decoder: Option<TextDecoder>,
+}
+
+impl Deref for PooledDecoder<'_> {
+    type Target = TextDecoder;
+
+    fn deref(&self) -> &Self::Target {
+        self.decoder.as_ref().expect("decoder is only taken on drop")
+    }
+}
+
+impl DerefMut for PooledDecoder<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.decoder.as_mut().expect("decoder is only taken on drop")
+    }
+}
+
+impl Drop for PooledDecoder<'_> {
+    fn drop(&mut self) {
+        if let Some(decoder) = self.decoder.take() {
+            self.pool.release(decoder);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device, Tensor};
+    use candle_nn::{Activation, VarBuilder};
+    use candle_transformers::models::blip_text;
+
+    fn tiny_decoder() -> TextDecoder {
+        let config = blip_text::Config {
+            vocab_size: 16,
+            hidden_size: 8,
+            encoder_hidden_size: 8,
+            intermediate_size: 16,
+            projection_dim: 8,
+            num_hidden_layers: 1,
+            num_attention_heads: 2,
+            max_position_embeddings: 32,
+            hidden_act: Activation::Gelu,
+            layer_norm_eps: 1e-12,
+            is_decoder: true,
+        };
+        let vb: VarBuilder = VarBuilder::zeros(DType::F32, &Device::Cpu);
+        TextDecoder::new(&config, vb).unwrap()
+    }
+
+    fn forward(decoder: &mut TextDecoder) -> Tensor {
+        let input_ids: Tensor = Tensor::new(&[[1u32, 2]], &Device::Cpu).unwrap();
+        let image_embeds: Tensor = Tensor::zeros((1, 4, 8), DType::F32, &Device::Cpu).unwrap();
+        decoder.forward(&input_ids, &image_embeds).unwrap()
+    }
+
+    #[test]
+    fn test_decoder_pool_reuses_released_decoders() {
+        // GIVEN
+        let pool = DecoderPool::new(tiny_decoder(), 4);
+        // WHEN
+        let first: PooledDecoder = pool.acquire();
+        let second: PooledDecoder = pool.acquire();
+        drop(first);
+        drop(second);
+        let idle_after_release: usize = pool.idle_count();
+        let _third: PooledDecoder = pool.acquire();
+        // THEN
+        assert_eq!(idle_after_release, 2);
+        assert_eq!(pool.idle_count(), 1);
+    }
+
+    #[test]
+    fn test_decoder_pool_limits_idle_decoders() {
+        // GIVEN
+        let pool = DecoderPool::new(tiny_decoder(), 1);
+        // WHEN
+        let decoders: Vec<PooledDecoder> = (0..3).map(|_| pool.acquire()).collect();
+        drop(decoders);
+        // THEN
+        assert_eq!(pool.idle_count(), 1);
+    }
+
+    #[test]
+    fn test_pooled_decoder_starts_with_empty_kv_cache() {
+        // GIVEN
+        let pool = DecoderPool::new(tiny_decoder(), 1);
+        let mut decoder: PooledDecoder = pool.acquire();
+        forward(&mut decoder);
+        drop(decoder);
+        // WHEN
+        let mut decoder: PooledDecoder = pool.acquire();
+        let logits: Tensor = forward(&mut decoder);
+        // THEN
+        // A stale key-value cache would make the attention context longer than the causal mask of
+        // the new tokens, and the forward pass would fail
+        assert_eq!(logits.dims(), &[1, 2, 16]);
+    }
+}
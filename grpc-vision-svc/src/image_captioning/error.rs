@@ -0,0 +1,186 @@
+//! This module provides the [`ProcessingError`] returned by the [`ImageProcessor`] when an image
+//! cannot be captioned or a question cannot be answered.
+//!
+//! Every error is mapped to its own gRPC status code and `google.rpc.ErrorInfo` reason, so that
+//! clients can tell an image they should not retry (e.g. a corrupted file) from a failure of the
+//! server. Errors caused by a field of the request also carry a `google.rpc.BadRequest` naming
+//! the field.
+//!
+//! [`ImageProcessor`]: crate::image_captioning::ImageProcessor
+use candle_core::Error as CandleError;
+use image::ImageError;
+use thiserror::Error;
+use tonic::{Code, Status};
+use crate::error_details::ErrorDetails;
+
+/// Type alias for a result that returns a value or a [`ProcessingError`].
+pub type ProcessingResult<T> = Result<T, ProcessingError>;
+
+/// [`ProcessingError`] is the error of processing a single request with the [`ImageProcessor`].
+///
+/// Errors are cloned when a whole batch fails, so the inner errors are kept as messages.
+///
+/// [`ImageProcessor`]: crate::image_captioning::ImageProcessor
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ProcessingError {
+    #[error("Unsupported image format: {0}")]
+    UnsupportedFormat(String),
+    #[error("Failed to decode image: {0}")]
+    Decode(String),
+    #[error("Image of {width}x{height} pixels exceeds the maximum of {max_dimension} pixels per side")]
+    ImageTooLarge { width: u32, height: u32, max_dimension: u32 },
+    #[error("Model {0:?} is not loaded")]
+    ModelNotLoaded(String),
+    #[error("Model {model_id:?} does not support {task}")]
+    UnsupportedTask { model_id: String, task: &'static str },
+    #[error("Generation failed: {0}")]
+    Generation(String),
+    #[error("Request was cancelled")]
+    Cancelled,
+}
+
+impl From<ImageError> for ProcessingError {
+    fn from(error: ImageError) -> Self {
+        match error {
+            ImageError::Unsupported(e) => Self::UnsupportedFormat(e.to_string()),
+            e => Self::Decode(e.to_string()),
+        }
+    }
+}
+
+impl From<CandleError> for ProcessingError {
+    fn from(error: CandleError) -> Self {
+        Self::Generation(error.to_string())
+    }
+}
+
+impl ProcessingError {
+    /// Returns the gRPC status code of the error.
+    ///
+    /// Errors caused by the request are `INVALID_ARGUMENT` (or `NOT_FOUND` for an unknown model),
+    /// while generation failures are `INTERNAL`.
+    pub fn code(&self) -> Code {
+        match self {
+            Self::UnsupportedFormat(_) | Self::Decode(_) | Self::ImageTooLarge { .. } => Code::InvalidArgument,
+            Self::UnsupportedTask { .. } => Code::InvalidArgument,
+            Self::ModelNotLoaded(_) => Code::NotFound,
+            Self::Generation(_) => Code::Internal,
+            Self::Cancelled => Code::Cancelled,
+        }
+    }
+
+    /// Returns the `google.rpc.ErrorInfo` reason of the error.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::UnsupportedFormat(_) => "UNSUPPORTED_IMAGE_FORMAT",
+            Self::Decode(_) => "IMAGE_DECODE_FAILED",
+            Self::ImageTooLarge { .. } => "IMAGE_TOO_LARGE",
+            Self::ModelNotLoaded(_) => "MODEL_NOT_LOADED",
+            Self::UnsupportedTask { .. } => "UNSUPPORTED_TASK",
+            Self::Generation(_) => "GENERATION_FAILED",
+            Self::Cancelled => "CANCELLED",
+        }
+    }
+
+    /// Returns the request field causing the error, or `None` if the request is not at fault.
+    pub fn field(&self) -> Option<&'static str> {
+        match self {
+            Self::UnsupportedFormat(_) | Self::Decode(_) | Self::ImageTooLarge { .. } => Some("image"),
+            Self::UnsupportedTask { .. } => Some("model_id"),
+            Self::ModelNotLoaded(_) | Self::Generation(_) | Self::Cancelled => None,
+        }
+    }
+
+    /// Returns the structured details of the error.
+    pub fn details(&self) -> ErrorDetails {
+        let metadata: Vec<(&str, String)> = match self {
+            Self::ImageTooLarge { width, height, max_dimension } => vec![
+                ("width", width.to_string()),
+                ("height", height.to_string()),
+                ("max_dimension", max_dimension.to_string()),
+            ],
+            Self::ModelNotLoaded(model_id) => vec![("model_id", model_id.clone())],
+            Self::UnsupportedTask { model_id, task } => vec![("model_id", model_id.clone()), ("task", task.to_string())],
+            _ => Vec::new(),
+        };
+        let details: ErrorDetails = ErrorDetails::with_error_info(self.reason(), metadata);
+
+        match self.field() {
+            Some(field) => details.with_field_violation(field, self.to_string()),
+            None => details,
+        }
+    }
+}
+
+impl From<ProcessingError> for Status {
+    fn from(error: ProcessingError) -> Self {
+        error.details().into_status(error.code(), error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::error::{ImageFormatHint, UnsupportedError};
+
+    #[test]
+    fn test_processing_error_from_image_error() {
+        // GIVEN
+        let unsupported: ImageError = ImageError::Unsupported(UnsupportedError::from(ImageFormatHint::Unknown));
+        let truncated: ImageError = ImageError::IoError(std::io::ErrorKind::UnexpectedEof.into());
+        // WHEN
+        let unsupported: ProcessingError = ProcessingError::from(unsupported);
+        let truncated: ProcessingError = ProcessingError::from(truncated);
+        // THEN
+        assert!(matches!(unsupported, ProcessingError::UnsupportedFormat(_)));
+        assert!(matches!(truncated, ProcessingError::Decode(_)));
+    }
+
+    #[test]
+    fn test_processing_error_into_status_with_bad_request() {
+        // GIVEN
+        let error = ProcessingError::ImageTooLarge { width: 10000, height: 600, max_dimension: 8192 };
+        // WHEN
+        let status: Status = Status::from(error.clone());
+        let details: ErrorDetails = ErrorDetails::from_status(&status).unwrap();
+        // THEN
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), error.to_string());
+        let error_info = details.error_info.unwrap();
+        assert_eq!(error_info.reason, "IMAGE_TOO_LARGE");
+        assert_eq!(error_info.metadata["width"], "10000");
+        assert_eq!(error_info.metadata["max_dimension"], "8192");
+        let violations = details.bad_request.unwrap().field_violations;
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].field, "image");
+    }
+
+    #[test]
+    fn test_processing_error_into_status_codes() {
+        // GIVEN
+        let errors: Vec<ProcessingError> = vec![
+            ProcessingError::Decode("corrupt".to_string()),
+            ProcessingError::ModelNotLoaded("blip".to_string()),
+            ProcessingError::UnsupportedTask { model_id: "blip_vqa".to_string(), task: "image captioning" },
+            CandleError::Msg("out of memory".to_string()).into(),
+            ProcessingError::Cancelled,
+        ];
+        // WHEN
+        let statuses: Vec<Status> = errors.into_iter().map(Status::from).collect();
+        // THEN
+        let codes: Vec<Code> = statuses.iter().map(Status::code).collect();
+        assert_eq!(codes, [Code::InvalidArgument, Code::NotFound, Code::InvalidArgument, Code::Internal, Code::Cancelled]);
+        let reasons: Vec<Option<String>> = statuses
+            .iter()
+            .map(|status| ErrorDetails::from_status(status).unwrap().reason().map(str::to_string))
+            .collect();
+        assert_eq!(reasons, [
+            Some("IMAGE_DECODE_FAILED".to_string()),
+            Some("MODEL_NOT_LOADED".to_string()),
+            Some("UNSUPPORTED_TASK".to_string()),
+            Some("GENERATION_FAILED".to_string()),
+            Some("CANCELLED".to_string()),
+        ]);
+        assert!(ErrorDetails::from_status(&statuses[1]).unwrap().bad_request.is_none());
+    }
+}
@@ -0,0 +1,325 @@
+//! This module provides the parameters that control caption generation.
+//!
+//! [`GenerationConfig`] holds the server-side defaults (read from the `[generation]` table of the
+//! models configuration file), while [`GenerationParams`] is the fully resolved set of parameters
+//! used for a single request after applying the client supplied [`GenerationOptions`] on top of
+//! the defaults.
+use serde::Deserialize;
+use thiserror::Error;
+use candle_transformers::generation::Sampling;
+use crate::proto::GenerationOptions;
+
+/// Temperatures below this threshold are treated as zero, i.e. greedy (argmax) decoding.
+const MIN_TEMPERATURE: f64 = 1e-7;
+
+/// [`GenerationOptionsError`] describes why a set of [`GenerationOptions`] was rejected.
+#[derive(Error, Debug, PartialEq)]
+pub enum GenerationOptionsError {
+    #[error("temperature must be a finite non-negative number, got {0}")]
+    InvalidTemperature(f64),
+
+    #[error("top_k must be greater than zero")]
+    InvalidTopK,
+
+    #[error("top_p must be in the range (0, 1], got {0}")]
+    InvalidTopP(f64),
+
+    #[error("max_new_tokens must be in the range [1, {max}], got {value}")]
+    InvalidMaxNewTokens { value: usize, max: usize },
+
+    #[error("repetition_penalty must be a finite positive number, got {0}")]
+    InvalidRepetitionPenalty(f32),
+}
+
+/// [`GenerationConfig`] holds the default generation parameters of the server.
+///
+/// It corresponds to the optional `[generation]` table of the models configuration file. Every
+/// field can be omitted, in which case the value from [`GenerationConfig::default`] is used.
+///
+/// # Example TOML config
+///
+/// ```toml
+/// [generation]
+/// temperature = 0.0 # Greedy decoding
+/// seed = 1337
+/// max_new_tokens = 1000
+/// max_new_tokens_limit = 1000 # Upper bound for the value requested by clients
+/// repetition_penalty = 1.0
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct GenerationConfig {
+    pub temperature: f64,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub seed: u64,
+    pub max_new_tokens: usize,
+    pub max_new_tokens_limit: usize,
+    pub repetition_penalty: f32,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self {
+            temperature: 0.0,
+            top_k: None,
+            top_p: None,
+            seed: 1337,
+            max_new_tokens: 1000,
+            max_new_tokens_limit: 1000,
+            repetition_penalty: 1.0,
+        }
+    }
+}
+
+/// Helper struct used to deserialize the `[generation]` table of the models configuration file.
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    generation: GenerationConfig,
+}
+
+impl GenerationConfig {
+    /// Parses the `[generation]` table from the contents of a TOML configuration file.
+    /// Other tables (e.g. `[[model]]`) are ignored. If the table is missing, the defaults are returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `toml_str` - The contents of the TOML configuration file.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the parsed [`GenerationConfig`] or a [`toml::de::Error`] if parsing fails.
+    pub fn from_toml_str(toml_str: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str::<ConfigFile>(toml_str).map(|file| file.generation)
+    }
+
+    /// Resolves the generation parameters of a request.
+    ///
+    /// Every option that is set in `options` overrides the corresponding server default. The
+    /// resulting values are validated before they are returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - The optional [`GenerationOptions`] supplied by the client.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the resolved [`GenerationParams`].
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`GenerationOptionsError`] if any of the resolved values is out of range.
+    pub fn resolve(&self, options: Option<&GenerationOptions>) -> Result<GenerationParams, GenerationOptionsError> {
+        let default_options = GenerationOptions::default();
+        let options: &GenerationOptions = options.unwrap_or(&default_options);
+
+        let temperature: f64 = options.temperature.map_or(self.temperature, f64::from);
+        let top_k: Option<usize> = options.top_k.map(|k| k as usize).or(self.top_k);
+        let top_p: Option<f64> = options.top_p.map(f64::from).or(self.top_p);
+        let max_new_tokens: usize = options.max_new_tokens.map_or(self.max_new_tokens, |n| n as usize);
+        let repetition_penalty: f32 = options.repetition_penalty.unwrap_or(self.repetition_penalty);
+
+        if !temperature.is_finite() || temperature < 0.0 {
+            return Err(GenerationOptionsError::InvalidTemperature(temperature));
+        }
+        if top_k == Some(0) {
+            return Err(GenerationOptionsError::InvalidTopK);
+        }
+        if let Some(p) = top_p.filter(|p| !(*p > 0.0 && *p <= 1.0)) {
+            return Err(GenerationOptionsError::InvalidTopP(p));
+        }
+        if max_new_tokens == 0 || max_new_tokens > self.max_new_tokens_limit {
+            return Err(GenerationOptionsError::InvalidMaxNewTokens {
+                value: max_new_tokens,
+                max: self.max_new_tokens_limit,
+            });
+        }
+        if !repetition_penalty.is_finite() || repetition_penalty <= 0.0 {
+            return Err(GenerationOptionsError::InvalidRepetitionPenalty(repetition_penalty));
+        }
+
+        let sampling: Sampling = if temperature < MIN_TEMPERATURE {
+            Sampling::ArgMax
+        } else {
+            match (top_k, top_p) {
+                (None, None) => Sampling::All { temperature },
+                (Some(k), None) => Sampling::TopK { k, temperature },
+                (None, Some(p)) => Sampling::TopP { p, temperature },
+                (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+            }
+        };
+
+        Ok(GenerationParams {
+            prompt: String::new(),
+            sampling,
+            seed: options.seed.unwrap_or(self.seed),
+            max_new_tokens,
+            repetition_penalty,
+        })
+    }
+}
+
+/// [`GenerationParams`] is the resolved set of parameters used to generate a single caption.
+///
+/// The `prompt` is used for conditional captioning: it is tokenized and fed to the decoder before
+/// generation starts, so the caption continues the prompt (e.g. "a photography of"). An empty
+/// prompt results in unconditional captioning.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationParams {
+    pub prompt: String,
+    pub sampling: Sampling,
+    pub seed: u64,
+    pub max_new_tokens: usize,
+    pub repetition_penalty: f32,
+}
+
+impl Default for GenerationParams {
+    fn default() -> Self {
+        Self {
+            prompt: String::new(),
+            sampling: Sampling::ArgMax,
+            seed: 1337,
+            max_new_tokens: 1000,
+            repetition_penalty: 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generation_config_resolve_defaults() {
+        // GIVEN
+        let config = GenerationConfig::default();
+        // WHEN
+        let params: GenerationParams = config.resolve(None).unwrap();
+        // THEN
+        assert_eq!(params, GenerationParams::default());
+    }
+
+    #[test]
+    fn test_generation_config_resolve_overrides() {
+        // GIVEN
+        let config = GenerationConfig::default();
+        let options = GenerationOptions {
+            temperature: Some(0.5),
+            top_k: Some(40),
+            top_p: Some(0.5),
+            seed: Some(42),
+            max_new_tokens: Some(30),
+            repetition_penalty: Some(1.5),
+        };
+        // WHEN
+        let params: GenerationParams = config.resolve(Some(&options)).unwrap();
+        // THEN
+        assert_eq!(
+            params,
+            GenerationParams {
+                prompt: String::new(),
+                sampling: Sampling::TopKThenTopP { k: 40, p: 0.5, temperature: 0.5 },
+                seed: 42,
+                max_new_tokens: 30,
+                repetition_penalty: 1.5,
+            },
+        );
+    }
+
+    #[test]
+    fn test_generation_config_resolve_sampling_variants() {
+        // GIVEN
+        let config = GenerationConfig {
+            temperature: 0.5,
+            ..Default::default()
+        };
+        let top_k = GenerationOptions { top_k: Some(10), ..Default::default() };
+        let top_p = GenerationOptions { top_p: Some(0.5), ..Default::default() };
+        let argmax = GenerationOptions { temperature: Some(0.0), top_k: Some(10), ..Default::default() };
+        // WHEN + THEN
+        assert_eq!(config.resolve(None).unwrap().sampling, Sampling::All { temperature: 0.5 });
+        assert_eq!(config.resolve(Some(&top_k)).unwrap().sampling, Sampling::TopK { k: 10, temperature: 0.5 });
+        assert_eq!(config.resolve(Some(&top_p)).unwrap().sampling, Sampling::TopP { p: 0.5, temperature: 0.5 });
+        assert_eq!(config.resolve(Some(&argmax)).unwrap().sampling, Sampling::ArgMax);
+    }
+
+    #[test]
+    fn test_generation_config_resolve_invalid_options() {
+        // GIVEN
+        let config = GenerationConfig::default();
+        let cases: Vec<(GenerationOptions, GenerationOptionsError)> = vec![
+            (
+                GenerationOptions { temperature: Some(-1.0), ..Default::default() },
+                GenerationOptionsError::InvalidTemperature(-1.0),
+            ),
+            (
+                GenerationOptions { top_k: Some(0), ..Default::default() },
+                GenerationOptionsError::InvalidTopK,
+            ),
+            (
+                GenerationOptions { top_p: Some(1.5), ..Default::default() },
+                GenerationOptionsError::InvalidTopP(1.5),
+            ),
+            (
+                GenerationOptions { max_new_tokens: Some(0), ..Default::default() },
+                GenerationOptionsError::InvalidMaxNewTokens { value: 0, max: 1000 },
+            ),
+            (
+                GenerationOptions { max_new_tokens: Some(1001), ..Default::default() },
+                GenerationOptionsError::InvalidMaxNewTokens { value: 1001, max: 1000 },
+            ),
+            (
+                GenerationOptions { repetition_penalty: Some(0.0), ..Default::default() },
+                GenerationOptionsError::InvalidRepetitionPenalty(0.0),
+            ),
+        ];
+        // WHEN + THEN
+        for (options, expected) in cases {
+            assert_eq!(config.resolve(Some(&options)), Err(expected));
+        }
+    }
+
+    #[test]
+    fn test_generation_config_from_toml_str() {
+        // GIVEN
+        let toml_str: &str = r#"
+            [generation]
+            temperature = 0.7
+            top_p = 0.9
+            max_new_tokens = 50
+
+            [[model]]
+            repository = "some-repo/test-model"
+            model = "model.safetensors"
+            tokenizer = "tokenizer.json"
+        "#;
+        // WHEN
+        let config: GenerationConfig = GenerationConfig::from_toml_str(toml_str).unwrap();
+        // THEN
+        assert_eq!(
+            config,
+            GenerationConfig {
+                temperature: 0.7,
+                top_p: Some(0.9),
+                max_new_tokens: 50,
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_generation_config_from_toml_str_missing_table() {
+        // GIVEN
+        let toml_str: &str = r#"
+            [[model]]
+            repository = "some-repo/test-model"
+            model = "model.safetensors"
+            tokenizer = "tokenizer.json"
+        "#;
+        // WHEN
+        let config: GenerationConfig = GenerationConfig::from_toml_str(toml_str).unwrap();
+        // THEN
+        assert_eq!(config, GenerationConfig::default());
+    }
+}
@@ -1,29 +1,65 @@
-//! This module provides functionality for loading and processing models used for image captioning.
-//! It supports different model variants including BLIP and quantized BLIP models.
-#![allow(unused)]
+//! This module provides functionality for loading and processing models used for image captioning
+//! and visual question answering. It supports different model variants including BLIP, quantized BLIP
+//! and BLIP VQA models.
+pub mod blip_decoder;
+pub mod blip_vqa;
+pub mod cache;
+pub mod decoder_pool;
+pub mod error;
+pub mod generation;
 pub mod model_loader;
+pub mod registry;
 pub mod token_output_stream;
 pub mod utils;
+#[cfg(test)]
+pub(crate) mod testing;
 
 use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokenizers::Tokenizer;
 use image::{ImageBuffer, Rgb};
-use candle_core::{Result, Tensor, DType, Device, Error, Module};
+use candle_core::{Result, Tensor, DType, Device, DeviceLocation, Error, Module, Shape};
+use candle_core::quantized::{gguf_file, GgmlDType};
+use candle_core::safetensors::MmapedSafetensors;
+use candle_nn::Init;
 use candle_nn::var_builder::{VarBuilder, VarBuilderArgs, SimpleBackend};
 use candle_transformers::models::{blip, quantized_blip};
-use candle_transformers::generation::{Sampling, LogitsProcessor};
-use crate::proto::ModelType;
-use crate::image_captioning::model_loader::{Models, Model};
+use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::utils::apply_repeat_penalty;
+use tokio_util::sync::CancellationToken;
+use tracing::Span;
+use crate::concurrency::ConcurrencyConfig;
+use crate::image_captioning::blip_decoder::TextDecoder;
+use crate::image_captioning::blip_vqa::BlipForQuestionAnswering;
+use crate::image_captioning::cache::{CacheConfig, ImageHash, InferenceCache};
+use crate::image_captioning::decoder_pool::{DecoderPool, PooledDecoder};
+use crate::image_captioning::error::{ProcessingError, ProcessingResult};
+use crate::image_captioning::generation::GenerationParams;
+use crate::image_captioning::model_loader::{Models, Model, ModelConfig};
+use crate::image_captioning::registry::Architecture;
+use crate::image_captioning::token_output_stream::TokenOutputStream;
+use crate::metrics::{metrics, Stage};
+
+/// The beginning-of-sequence token ID used for starting generated sequences.
+const BOS_TOKEN_ID: u32 = 30522;
 
 /// The separator token ID used for ending generated sequences.
 const SEP_TOKEN_ID: u32 = 102;
 
-/// Represents different variants of image captioning models.
+/// Maximum width and height of an image, in pixels. Larger images are rejected before they are
+/// decoded, as a small compressed file can expand to gigabytes of pixels.
+pub const MAX_IMAGE_DIMENSION: u32 = 8192;
+
+/// Represents different variants of image captioning and visual question answering models.
 #[non_exhaustive]
 #[derive(Debug, Clone)]
 pub enum ModelVariant {
     Blip(blip::BlipForConditionalGeneration),
     QuantizedBlip(quantized_blip::BlipForConditionalGeneration),
+    BlipVqa(BlipForQuestionAnswering),
 }
 
 impl Module for ModelVariant {
@@ -48,68 +84,180 @@ impl Module for ModelVariant {
         match self {
             Self::Blip(m) => m.vision_model().forward(xs),
             Self::QuantizedBlip(m) => m.vision_model().forward(xs),
+            Self::BlipVqa(m) => m.vision_model().forward(xs),
         }
     }
 }
 
 impl ModelVariant {
-    /// Performs a forward pass for the text decoder model.
-    ///
-    /// This function takes an input tensor and an image embeddings tensor, passes them through the
-    /// text decoder model, and returns the resulting tensor.
+    /// Builds a model from its downloaded weights, as described by its registry entry.
     ///
     /// # Arguments
     ///
-    /// * `xs` - A reference to the input tensor for the text decoder.
-    /// * `img_xs` - A reference to the input tensor containing the image embeddings.
+    /// * `model` - A reference to the downloaded [`Model`].
+    /// * `device` - The device on which the model will be loaded.
     ///
     /// # Returns
     ///
-    /// A [`Result`] containing the output tensor if the forward pass is successful, or an error if
-    /// the forward pass fails.
-    ///
-    /// # Errors
-    ///
-    /// Returns an error if the text decoder model's forward pass encounters any issues.
-    fn text_decoder_forward(&mut self, xs: &Tensor, img_xs: &Tensor) -> Result<Tensor> {
-        match self {
-            Self::Blip(m) => m.text_decoder().forward(xs, img_xs),
-            Self::QuantizedBlip(m) => m.text_decoder().forward(xs, img_xs),
+    /// A [`Result`] containing the model and its batched [`TextDecoder`], which share their weights,
+    /// or an error if the weights cannot be loaded.
+    fn load(model: &Model, device: &Device) -> Result<(Self, TextDecoder)> {
+        let model_cfg: &ModelConfig = model.config();
+        let config: blip::Config = model_cfg.preset.blip_config();
+
+        if model_cfg.architecture.is_quantized() {
+            let vb = quantized_blip::VarBuilder::from_gguf(model.model_path(), device)?;
+            let decoder: TextDecoder = TextDecoder::new_quantized(&config.text_config, vb.pp("text_decoder"))?;
+            let variant = Self::QuantizedBlip(quantized_blip::BlipForConditionalGeneration::new(&config, vb)?);
+            return Ok((variant, decoder));
         }
+
+        let tensors: MmapedSafetensors = unsafe { MmapedSafetensors::new(model.model_path())? };
+        let backend: Box<dyn SimpleBackend> = Box::new(SharedTensors::new(tensors));
+        let vb: VarBuilderArgs<Box<dyn SimpleBackend>> =
+            VarBuilder::from_backend(backend, model_cfg.dtype.into(), device.clone());
+        let decoder: TextDecoder = TextDecoder::new(&config.text_config, vb.pp("text_decoder"))?;
+        let variant: Self = match model_cfg.architecture {
+            Architecture::BlipVqa => Self::BlipVqa(BlipForQuestionAnswering::new(&config, vb)?),
+            _ => Self::Blip(blip::BlipForConditionalGeneration::new(&config, vb)?),
+        };
+
+        Ok((variant, decoder))
     }
+}
 
-    /// Resets the key-value cache of the model.
-    ///
-    /// This function resets any cached key-value pairs in the model to ensure that new predictions
-    /// do not rely on previous state.
-    fn reset_kv_cache(&mut self) {
-        match self {
-            Self::Blip(m) => m.reset_kv_cache(),
-            Self::QuantizedBlip(m) => m.reset_kv_cache(),
+/// [`SharedTensors`] loads each tensor of safetensors files once, so that the text decoder built
+/// by [`candle_transformers`] and the batched [`TextDecoder`] share their weights instead of each
+/// holding a copy.
+struct SharedTensors {
+    tensors: MmapedSafetensors,
+    loaded: Mutex<HashMap<String, Tensor>>,
+}
+
+impl SharedTensors {
+    fn new(tensors: MmapedSafetensors) -> Self {
+        Self {
+            tensors,
+            loaded: Mutex::new(HashMap::new()),
         }
     }
 }
 
-/// Struct for processing images and generating captions.
+impl SimpleBackend for SharedTensors {
+    fn get(&self, shape: Shape, name: &str, hints: Init, dtype: DType, device: &Device) -> Result<Tensor> {
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        let shared: Option<&Tensor> = loaded
+            .get(name)
+            .filter(|t| t.shape() == &shape && t.dtype() == dtype && t.device().same_device(device));
+        if let Some(tensor) = shared {
+            return Ok(tensor.clone());
+        }
+        let tensor: Tensor = SimpleBackend::get(&self.tensors, shape, name, hints, dtype, device)?;
+        loaded.insert(name.to_string(), tensor.clone());
+        Ok(tensor)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.tensors.contains_tensor(name)
+    }
+}
+
+/// Reads the quantization of the weights stored in a `gguf` file.
+///
+/// Quantized checkpoints usually keep small tensors (e.g. biases and layer norms) in full precision,
+/// so the most common non-`f32` data type of the file is reported.
+///
+/// # Arguments
+///
+/// * `path` - The path to the `gguf` file.
+///
+/// # Returns
+///
+/// A [`Result`] containing the name of the quantization (e.g. `q8_0`) or an error if the file cannot
+/// be read.
+fn gguf_quantization(path: &Path) -> Result<String> {
+    let mut file: File = File::open(path)?;
+    let content: gguf_file::Content = gguf_file::Content::read(&mut file)?;
+
+    let mut counts: HashMap<GgmlDType, usize> = HashMap::new();
+    for info in content.tensor_infos.values() {
+        *counts.entry(info.ggml_dtype).or_default() += 1;
+    }
+    let dtype: GgmlDType = counts
+        .into_iter()
+        .filter(|(dtype, _)| *dtype != GgmlDType::F32)
+        .max_by_key(|(_, count)| *count)
+        .map_or(GgmlDType::F32, |(dtype, _)| dtype);
+
+    Ok(format!("{:?}", dtype).to_lowercase())
+}
+
+/// Returns a short name of the device (e.g. `cpu` or `cuda:0`).
+fn device_name(device: &Device) -> String {
+    match device.location() {
+        DeviceLocation::Cpu => "cpu".to_string(),
+        DeviceLocation::Cuda { gpu_id } => format!("cuda:{}", gpu_id),
+        DeviceLocation::Metal { gpu_id } => format!("metal:{}", gpu_id),
+    }
+}
+
+/// A model built from a registry entry, together with its tokenizer and the pool of text decoders
+/// used to generate text.
+#[derive(Clone)]
+struct LoadedModel {
+    id: String,
+    variant: ModelVariant,
+    decoders: Arc<DecoderPool>,
+    tokenizer: Tokenizer,
+    architecture: Architecture,
+    dtype: DType,
+    source: Model,
+    quantization: Option<String>,
+    input_resolution: usize,
+}
+
+/// [`ModelDescription`] describes a loaded model: where it comes from, how it was built and what it can do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelDescription {
+    pub id: String,
+    pub repository: String,
+    pub revision: Option<String>,
+    pub model_path: PathBuf,
+    pub tokenizer_path: PathBuf,
+    pub architecture: Architecture,
+    pub dtype: DType,
+    pub quantization: Option<String>,
+    pub device: String,
+    pub input_resolution: usize,
+}
+
+/// Struct for processing images, generating captions and answering questions about images.
+///
+/// The processor holds every model declared in the models configuration file, keyed by its
+/// registry id (see [`ModelConfig::id`]). Captioning results are kept in an [`InferenceCache`]
+/// shared by all clones of the processor. The latency of each processing stage and the number of
+/// generated tokens are recorded in the process wide [`Metrics`](crate::metrics::Metrics).
 #[derive(Clone)]
 pub struct ImageProcessor {
-    models: HashMap<ModelType, ModelVariant>,
+    models: HashMap<String, LoadedModel>,
     device: Device,
-    tokenizer: Tokenizer,
-    sampling: Sampling,
+    cache: Arc<InferenceCache>,
 }
 
 impl ImageProcessor {
     /// Creates a new instance of [`ImageProcessor`].
     ///
-    /// This function initializes the [`ImageProcessor`] with the provided models and device. It loads
-    /// the BLIP and quantized BLIP models, sets up the tokenizer, and prepares the processor for
-    /// image captioning tasks.
+    /// This function initializes the [`ImageProcessor`] with the provided models and device. For every
+    /// entry of the registry it builds the model declared by its architecture, config preset and dtype,
+    /// and loads its tokenizer.
     ///
     /// # Arguments
     ///
     /// * `models` - A reference to a `Models` struct containing model configurations.
     /// * `device` - The device on which the models will be loaded (e.g., CPU or GPU).
+    /// * `cache` - The [`CacheConfig`] of the cache of image embeddings and captions.
+    /// * `concurrency` - The [`ConcurrencyConfig`] of the service, which bounds the number of idle
+    ///   text decoders kept per model.
     ///
     /// # Returns
     ///
@@ -117,43 +265,153 @@ impl ImageProcessor {
     ///
     /// # Errors
     ///
-    /// Returns an error if any of the required models cannot be found or initialized.
-    pub fn new(models: &Models, device: Device) -> Result<Self> {
-        let blip_cfg: &Model = models
-            .get("Salesforce/blip-image-captioning-large")
-            .ok_or_else(|| Error::Msg("BLIP Model not found".into()))?;
-
-        let blip_quantized_cfg: &Model = models
-            .get("lmz/candle-blip")
-            .ok_or_else(|| Error::Msg("Quantized BLIP Model not found".into()))?;
+    /// Returns an error if any of the models or tokenizers cannot be initialized.
+    pub fn new(models: &Models, device: Device, cache: &CacheConfig, concurrency: &ConcurrencyConfig) -> Result<Self> {
+        let mut model_map: HashMap<String, LoadedModel> = HashMap::with_capacity(models.len());
 
-        let config = blip::Config::image_captioning_large();
-        let mut model_map: HashMap<ModelType, ModelVariant> = HashMap::new();
+        for (id, model) in models {
+            model_map.insert(id.clone(), Self::build_model(id, model, &device, concurrency)?);
+        }
 
-        let vb: VarBuilderArgs<Box<dyn SimpleBackend>> = unsafe {
-            VarBuilder::from_mmaped_safetensors(&[blip_cfg.model_path()], DType::F32, &device)?
-        };
-        model_map.insert(
-            ModelType::Blip,
-            ModelVariant::Blip(blip::BlipForConditionalGeneration::new(&config, vb)?),
-        );
+        Ok(Self {
+            models: model_map,
+            device,
+            cache: Arc::new(InferenceCache::new(cache)),
+        })
+    }
 
-        let vb = quantized_blip::VarBuilder::from_gguf(blip_quantized_cfg.model_path(), &device)?;
-        model_map.insert(
-            ModelType::BlipQuantized,
-            ModelVariant::QuantizedBlip(quantized_blip::BlipForConditionalGeneration::new(&config, vb)?),
-        );
+    /// Creates a processor with another set of models, reusing the models of this processor that
+    /// did not change.
+    ///
+    /// The models named in `kept` are shared with this processor, so they are neither loaded nor
+    /// built again, while `models` are built as in [`ImageProcessor::new`]. This processor keeps
+    /// working, so requests using it can finish while the new processor takes over. The new
+    /// processor starts with an empty cache, as the cached results of a replaced model would not
+    /// match its outputs.
+    ///
+    /// # Arguments
+    ///
+    /// * `kept` - The registry ids of the models of this processor to keep.
+    /// * `models` - The new and changed models to build. They replace kept models with the same id.
+    /// * `cache` - The [`CacheConfig`] of the cache of the new processor.
+    /// * `concurrency` - The [`ConcurrencyConfig`] of the service, which bounds the number of idle
+    ///   text decoders kept per new model.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the new [`ImageProcessor`] instance or an error if initialization fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a kept model is not loaded, or if any of the new models or tokenizers
+    /// cannot be initialized.
+    pub fn reload(&self, kept: &[String], models: &Models, cache: &CacheConfig, concurrency: &ConcurrencyConfig) -> Result<Self> {
+        let mut model_map: HashMap<String, LoadedModel> = HashMap::with_capacity(kept.len() + models.len());
 
-        let tokenizer = Tokenizer::from_file(blip_cfg.tokenizer_path()).unwrap();
+        for id in kept {
+            let model: &LoadedModel = self.models
+                .get(id)
+                .ok_or_else(|| Error::Msg(format!("Model {:?} is not loaded", id)))?;
+            model_map.insert(id.clone(), model.clone());
+        }
+        for (id, model) in models {
+            model_map.insert(id.clone(), Self::build_model(id, model, &self.device, concurrency)?);
+        }
 
         Ok(Self {
             models: model_map,
-            device,
+            device: self.device.clone(),
+            cache: Arc::new(InferenceCache::new(cache)),
+        })
+    }
+
+    /// Builds the model declared by a registry entry, and loads its tokenizer.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The registry id of the model.
+    /// * `model` - A reference to the downloaded [`Model`].
+    /// * `device` - The device on which the model will be loaded.
+    /// * `concurrency` - The [`ConcurrencyConfig`] of the service. The decoder pool of the model
+    ///   keeps as many idle decoders as the model processes requests at once, so that decoders are
+    ///   not created again under a steady load.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the [`LoadedModel`] or an error if the model or its tokenizer cannot
+    /// be initialized.
+    fn build_model(id: &str, model: &Model, device: &Device, concurrency: &ConcurrencyConfig) -> Result<LoadedModel> {
+        let model_cfg: &ModelConfig = model.config();
+        tracing::info!(
+            model_id = %id,
+            architecture = ?model_cfg.architecture,
+            config = ?model_cfg.preset,
+            dtype = ?model_cfg.dtype,
+            "Building model",
+        );
+
+        let (variant, decoder): (ModelVariant, TextDecoder) = ModelVariant::load(model, device)?;
+        let decoders = DecoderPool::new(decoder, concurrency.max_concurrent_model_requests(id));
+        let tokenizer: Tokenizer = Tokenizer::from_file(model.tokenizer_path()).map_err(Error::Wrapped)?;
+        let (dtype, quantization): (DType, Option<String>) = if model_cfg.architecture.is_quantized() {
+            (DType::F32, Some(gguf_quantization(model.model_path())?))
+        } else {
+            (model_cfg.dtype.into(), None)
+        };
+
+        Ok(LoadedModel {
+            id: id.to_string(),
+            variant,
+            decoders: Arc::new(decoders),
             tokenizer,
-            sampling: Sampling::ArgMax,
+            architecture: model_cfg.architecture,
+            dtype,
+            source: model.clone(),
+            quantization,
+            input_resolution: model_cfg.preset.blip_config().vision_config.image_size,
+        })
+    }
+
+    /// Returns the architecture of the model with the given registry id, or `None` if no such
+    /// model is loaded.
+    pub fn architecture(&self, model_id: &str) -> Option<Architecture> {
+        self.models.get(model_id).map(|model| model.architecture)
+    }
+
+    /// Returns the config entries the loaded models were built from, keyed by registry id.
+    pub fn model_configs(&self) -> HashMap<String, ModelConfig> {
+        self.models
+            .iter()
+            .map(|(id, model)| (id.clone(), model.source.config().clone()))
+            .collect()
+    }
+
+    /// Describes the model with the given registry id, or returns `None` if no such model is loaded.
+    pub fn describe_model(&self, model_id: &str) -> Option<ModelDescription> {
+        self.models.get(model_id).map(|model| ModelDescription {
+            id: model_id.to_string(),
+            repository: model.source.config().repository.clone(),
+            revision: model.source.config().revision.clone(),
+            model_path: model.source.model_path().clone(),
+            tokenizer_path: model.source.tokenizer_path().clone(),
+            architecture: model.architecture,
+            dtype: model.dtype,
+            quantization: model.quantization.clone(),
+            device: device_name(&self.device),
+            input_resolution: model.input_resolution,
         })
     }
 
+    /// Describes all loaded models, ordered by their registry id.
+    pub fn list_models(&self) -> Vec<ModelDescription> {
+        let mut ids: Vec<&String> = self.models.keys().collect();
+        ids.sort();
+
+        ids.into_iter()
+            .filter_map(|id| self.describe_model(id))
+            .collect()
+    }
+
     /// Processes an image and generates a caption.
     ///
     /// This function processes the input image using the specified model and generates a textual
@@ -161,67 +419,831 @@ impl ImageProcessor {
     /// passing it through the model to get image embeddings, and then generating text based on
     /// these embeddings.
     ///
+    /// The decoded image is looked up in the cache first: a cached caption for the same model and
+    /// parameters is returned as is, and cached image embeddings of the same model skip the vision
+    /// model. The results are stored in the cache for later requests.
+    ///
     /// # Arguments
     ///
-    /// * `model` - The type of model to use for processing the image.
+    /// * `model_id` - The registry id of the captioning model to use for processing the image.
     /// * `image` - A byte slice containing the image data.
+    /// * `params` - The [`GenerationParams`] controlling how the caption is generated.
+    /// * `bypass_cache` - Whether to ignore the cache, neither reading nor storing results.
+    /// * `cancel` - The [`CancellationToken`] of the request. Generation stops at the next decoding
+    ///   step once it is cancelled.
     ///
     /// # Returns
     ///
-    /// A [`Result`] containing the generated caption as a [`String`] or an error if processing fails.
+    /// A [`ProcessingResult`] containing the generated caption as a [`String`] or the
+    /// [`ProcessingError`] if processing fails.
     ///
     /// # Errors
     ///
-    /// Returns an error if image processing or caption generation fails.
-    pub fn process_image(&self, model: ModelType, image: &[u8]) -> Result<String> {
-        let model_var: &ModelVariant = self.models.get(&model).unwrap(); // TODO: Handle error
-        let image: ImageBuffer<Rgb<u8>, Vec<u8>> = utils::process_image(image).map_err(Error::wrap)?;
-        let tensor: Tensor = utils::create_tensor(&image.into_raw(), &Device::Cpu)?.to_device(&self.device)?;
+    /// Returns an error if the model is not a loaded captioning model, if the image cannot be
+    /// decoded or is too large, if caption generation fails, or if the request is cancelled.
+    pub fn process_image(
+        &self,
+        model_id: &str,
+        image: &[u8],
+        params: &GenerationParams,
+        bypass_cache: bool,
+        cancel: &CancellationToken,
+    ) -> ProcessingResult<String> {
+        let model: &LoadedModel = self.captioning_model(model_id)?;
+        let pixels: Vec<u8> = metrics().time_stage(Stage::Decode, model_id, || Self::decode_image(image))?;
+        let image_hash: ImageHash = cache::hash_image(&pixels);
+        if let Some(caption) = self.cached_caption(&image_hash, model_id, params, bypass_cache) {
+            return Ok(caption);
+        }
+
+        let image_embeddings: Tensor = match self.cached_embeddings(&image_hash, model_id, bypass_cache) {
+            Some(image_embeddings) => image_embeddings,
+            None => {
+                let tensor: Tensor = self.image_tensor(model, &pixels)?.unsqueeze(0)?;
+                let image_embeddings: Tensor = self.encode_images(model, &tensor)?;
+                if !bypass_cache {
+                    self.cache.insert_embeddings(&image_hash, model_id, &image_embeddings)?;
+                }
+                image_embeddings
+            }
+        };
+
+        let caption: String = self.generate_text(model, &image_embeddings, params, cancel, |_| Ok(()))?;
+        if !bypass_cache {
+            self.cache.insert_caption(&image_hash, model_id, params, &caption);
+        }
+
+        Ok(caption)
+    }
+
+    /// Processes several images at once and generates their captions.
+    ///
+    /// Works like [`ImageProcessor::process_image`] for every image, but the images are stacked into
+    /// a single batch so the vision model runs once for the whole batch. The captions are then
+    /// decoded together, with one forward pass of the text decoder per token for the whole batch.
+    /// Each image keeps its own generation parameters (sampling, seed, maximum length and repetition
+    /// penalty), so the captions are the same as when the images are processed one by one.
+    ///
+    /// The cache is used as in [`ImageProcessor::process_image`]: images with a cached caption are
+    /// not processed at all, and only the images without cached embeddings go through the vision
+    /// model.
+    ///
+    /// Each image has its own [`CancellationToken`]: images whose request is cancelled before the
+    /// batch is processed are skipped, and the decoding of a caption stops once its request is
+    /// cancelled, without affecting the other images of the batch.
+    ///
+    /// Each result is passed to `on_result` as soon as it is known, so a short caption does not wait
+    /// for the longest caption of the batch. A failure of the text decoder fails the whole call, as
+    /// the rows of a batch share every forward pass.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_id` - The registry id of the captioning model to use for processing the images.
+    /// * `images` - The image data of each image.
+    /// * `params` - The [`GenerationParams`] of each image. All of them must share the same prompt.
+    /// * `bypass_cache` - Whether to ignore the cache, neither reading nor storing results.
+    /// * `cancellations` - The [`CancellationToken`] of the request of each image.
+    /// * `on_result` - A callback invoked once per image with its index and its caption, or the
+    ///   [`ProcessingError`] of the image. Images that cannot be decoded or whose request is
+    ///   cancelled get an error without failing the rest of the batch.
+    ///
+    /// # Returns
+    ///
+    /// An empty [`ProcessingResult`] once every image has been passed to `on_result`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the model is not a loaded captioning model, if the numbers of images,
+    /// parameters and cancellation tokens differ, if the prompts differ, or if the batched inference
+    /// fails. The images that were not passed to `on_result` yet are not passed afterwards.
+    pub fn process_images<F>(
+        &self,
+        model_id: &str,
+        images: &[Vec<u8>],
+        params: &[GenerationParams],
+        bypass_cache: bool,
+        cancellations: &[CancellationToken],
+        mut on_result: F,
+    ) -> ProcessingResult<()>
+    where
+        F: FnMut(usize, ProcessingResult<String>),
+    {
+        let model: &LoadedModel = self.captioning_model(model_id)?;
+        if images.len() != params.len() || images.len() != cancellations.len() {
+            return Err(ProcessingError::Generation(format!(
+                "Got {} images but {} generation parameters and {} cancellation tokens",
+                images.len(),
+                params.len(),
+                cancellations.len(),
+            )));
+        }
+        if params.windows(2).any(|pair| pair[0].prompt != pair[1].prompt) {
+            return Err(ProcessingError::Generation("All images of a batch must share the same prompt".to_string()));
+        }
+
+        // (index, image hash, image embeddings) of the images that need a caption
+        let mut embedded: Vec<(usize, ImageHash, Tensor)> = Vec::with_capacity(images.len());
+        // (index, image hash) of the images that need to go through the vision model, and their tensors
+        let mut to_encode: Vec<(usize, ImageHash)> = Vec::with_capacity(images.len());
+        let mut tensors: Vec<Tensor> = Vec::with_capacity(images.len());
+        for (index, (image, params)) in images.iter().zip(params).enumerate() {
+            if let Err(e) = Self::check_cancelled(&cancellations[index]) {
+                on_result(index, Err(e));
+                continue;
+            }
+            let pixels: Vec<u8> = match metrics().time_stage(Stage::Decode, model_id, || Self::decode_image(image)) {
+                Ok(pixels) => pixels,
+                Err(e) => {
+                    on_result(index, Err(e));
+                    continue;
+                }
+            };
+            let image_hash: ImageHash = cache::hash_image(&pixels);
+            if let Some(caption) = self.cached_caption(&image_hash, model_id, params, bypass_cache) {
+                on_result(index, Ok(caption));
+                continue;
+            }
+
+            if let Some(image_embeddings) = self.cached_embeddings(&image_hash, model_id, bypass_cache) {
+                embedded.push((index, image_hash, image_embeddings));
+            } else {
+                match self.image_tensor(model, &pixels) {
+                    Ok(tensor) => {
+                        to_encode.push((index, image_hash));
+                        tensors.push(tensor);
+                    }
+                    Err(e) => on_result(index, Err(e.into())),
+                }
+            }
+        }
+
+        if !tensors.is_empty() {
+            let image_embeddings: Tensor = self.encode_images(model, &Tensor::stack(&tensors, 0)?)?;
+            for (row, (index, image_hash)) in to_encode.into_iter().enumerate() {
+                let row_embeddings: Tensor = image_embeddings.narrow(0, row, 1)?;
+                if !bypass_cache {
+                    self.cache.insert_embeddings(&image_hash, model_id, &row_embeddings)?;
+                }
+                embedded.push((index, image_hash, row_embeddings));
+            }
+        }
+
+        if !embedded.is_empty() {
+            let image_embeddings: Vec<Tensor> = embedded.iter().map(|(_, _, embeddings)| embeddings.clone()).collect();
+            let batch_params: Vec<&GenerationParams> = embedded.iter().map(|(index, _, _)| &params[*index]).collect();
+            let batch_cancellations: Vec<&CancellationToken> =
+                embedded.iter().map(|(index, _, _)| &cancellations[*index]).collect();
+            self.generate_texts(model, &image_embeddings, &batch_params, &batch_cancellations, |row, result| {
+                let (index, image_hash, _): &(usize, ImageHash, Tensor) = &embedded[row];
+                if !bypass_cache {
+                    if let Ok(caption) = &result {
+                        self.cache.insert_caption(image_hash, model_id, &params[*index], caption);
+                    }
+                }
+                on_result(*index, result);
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Processes an image and streams the caption while it is being generated.
+    ///
+    /// Works like [`ImageProcessor::process_image`], but every time the decoder completes a new
+    /// word, the decoded text fragment is passed to `on_text`. Concatenating all the fragments
+    /// yields the full caption, which is also returned once generation finishes.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_id` - The registry id of the captioning model to use for processing the image.
+    /// * `image` - A byte slice containing the image data.
+    /// * `params` - The [`GenerationParams`] controlling how the caption is generated.
+    /// * `cancel` - The [`CancellationToken`] of the request. Generation stops at the next decoding
+    ///   step once it is cancelled.
+    /// * `on_text` - A callback invoked with each newly decoded text fragment. Returning an error
+    ///   from the callback stops the generation.
+    ///
+    /// # Returns
+    ///
+    /// A [`ProcessingResult`] containing the generated caption as a [`String`] or the
+    /// [`ProcessingError`] if processing fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the model is not a loaded captioning model, if the image cannot be
+    /// decoded or is too large, if caption generation fails, if the request is cancelled, or if
+    /// `on_text` fails.
+    pub fn process_image_streaming<F>(
+        &self,
+        model_id: &str,
+        image: &[u8],
+        params: &GenerationParams,
+        cancel: &CancellationToken,
+        mut on_text: F,
+    ) -> ProcessingResult<String>
+    where
+        F: FnMut(String) -> ProcessingResult<()>,
+    {
+        let model: &LoadedModel = self.captioning_model(model_id)?;
+        let image_embeddings: Tensor = self.embed_image(model, image)?;
+        let mut token_stream: TokenOutputStream = TokenOutputStream::new(model.tokenizer.clone());
+
+        let description: String = self.generate_text(model, &image_embeddings, params, cancel, |token| {
+            match token_stream.next_token(token)? {
+                Some(text) => on_text(text),
+                None => Ok(()),
+            }
+        })?;
+        if let Some(text) = token_stream.decode_rest()? {
+            on_text(text)?;
+        }
+
+        Ok(description)
+    }
+
+    /// Answers a question about an image.
+    ///
+    /// This function runs the image through the vision model of the VQA model, encodes the
+    /// question while attending to the image embeddings, and then generates the answer from the
+    /// question embeddings.
+    ///
+    /// # Arguments
+    ///
+    /// * `model_id` - The registry id of the VQA model to use.
+    /// * `image` - A byte slice containing the image data.
+    /// * `question` - The question about the image.
+    /// * `params` - The [`GenerationParams`] controlling how the answer is generated.
+    /// * `cancel` - The [`CancellationToken`] of the request. Generation stops at the next decoding
+    ///   step once it is cancelled.
+    ///
+    /// # Returns
+    ///
+    /// A [`ProcessingResult`] containing the generated answer as a [`String`] or the
+    /// [`ProcessingError`] if processing fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the model is not a loaded VQA model, if the image cannot be decoded or
+    /// is too large, if question encoding or answer generation fails, or if the request is cancelled.
+    pub fn answer_question(
+        &self,
+        model_id: &str,
+        image: &[u8],
+        question: &str,
+        params: &GenerationParams,
+        cancel: &CancellationToken,
+    ) -> ProcessingResult<String> {
+        let model: &LoadedModel = self.model(model_id)?;
+        let ModelVariant::BlipVqa(vqa_model) = &model.variant else {
+            return Err(ProcessingError::UnsupportedTask { model_id: model_id.to_string(), task: "question answering" });
+        };
+        let image_embeddings: Tensor = self.embed_image(model, image)?;
+
+        let encoding = model.tokenizer.encode(question, true).map_err(Error::Wrapped)?;
+        let input_ids: Tensor = Tensor::new(encoding.get_ids(), &self.device)?.unsqueeze(0)?;
+        let question_embeddings: Tensor = vqa_model.text_encoder().forward(&input_ids, &image_embeddings)?;
+
+        self.generate_text(model, &question_embeddings, params, cancel, |_| Ok(()))
+    }
+
+    /// Looks up a loaded model by its registry id.
+    fn model(&self, model_id: &str) -> ProcessingResult<&LoadedModel> {
+        self.models
+            .get(model_id)
+            .ok_or_else(|| ProcessingError::ModelNotLoaded(model_id.to_string()))
+    }
+
+    /// Looks up a loaded model by its registry id and checks that it generates image captions.
+    fn captioning_model(&self, model_id: &str) -> ProcessingResult<&LoadedModel> {
+        let model: &LoadedModel = self.model(model_id)?;
+        if !model.architecture.supports_captioning() {
+            return Err(ProcessingError::UnsupportedTask { model_id: model_id.to_string(), task: "image captioning" });
+        }
+
+        Ok(model)
+    }
+
+    /// Decodes an image and runs it through the vision model of the specified model.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model to use for processing the image.
+    /// * `image` - A byte slice containing the image data.
+    ///
+    /// # Returns
+    ///
+    /// A [`ProcessingResult`] containing the image embeddings tensor or the [`ProcessingError`] if
+    /// processing fails.
+    fn embed_image(&self, model: &LoadedModel, image: &[u8]) -> ProcessingResult<Tensor> {
+        let pixels: Vec<u8> = metrics().time_stage(Stage::Decode, &model.id, || Self::decode_image(image))?;
+        let tensor: Tensor = self.image_tensor(model, &pixels)?.unsqueeze(0)?;
+
+        Ok(self.encode_images(model, &tensor)?)
+    }
+
+    /// Runs a batch of image tensors through the vision model of the specified model.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model whose vision model is used.
+    /// * `tensor` - The `(batch, channels, height, width)` tensor of the images.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the image embeddings tensor or an error if the forward pass fails.
+    #[tracing::instrument(name = "vision_forward", skip_all, fields(model_id = %model.id, batch_size = tensor.dims()[0]))]
+    fn encode_images(&self, model: &LoadedModel, tensor: &Tensor) -> Result<Tensor> {
+        metrics().time_stage(Stage::VisionEncoding, &model.id, || tensor.apply(&model.variant))
+    }
+
+    /// Decodes and resizes an image.
+    ///
+    /// The dimensions of the image are read from its header first, so images larger than
+    /// [`MAX_IMAGE_DIMENSION`] are rejected without decoding their pixels.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - A byte slice containing the image data.
+    ///
+    /// # Returns
+    ///
+    /// A [`ProcessingResult`] containing the raw RGB pixels of the resized image, or the
+    /// [`ProcessingError`] if the format is not supported, the image is too large or it cannot be
+    /// decoded.
+    #[tracing::instrument(skip_all, fields(bytes = image.len()))]
+    fn decode_image(image: &[u8]) -> ProcessingResult<Vec<u8>> {
+        let (width, height): (u32, u32) = utils::image_dimensions(image)?;
+        if width > MAX_IMAGE_DIMENSION || height > MAX_IMAGE_DIMENSION {
+            return Err(ProcessingError::ImageTooLarge { width, height, max_dimension: MAX_IMAGE_DIMENSION });
+        }
+        let image: ImageBuffer<Rgb<u8>, Vec<u8>> = utils::process_image(image)?;
+
+        Ok(image.into_raw())
+    }
+
+    /// Converts decoded pixels into the input tensor of the vision model of the specified model.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model the tensor is prepared for.
+    /// * `pixels` - The raw RGB pixels returned by [`ImageProcessor::decode_image`].
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the `(channels, height, width)` image tensor, converted to the dtype
+    /// of the model and moved to the device, or an error if the conversion fails.
+    #[tracing::instrument(name = "create_tensor", skip_all, fields(model_id = %model.id))]
+    fn image_tensor(&self, model: &LoadedModel, pixels: &[u8]) -> Result<Tensor> {
+        let tensor: Tensor = metrics().time_stage(Stage::Preprocessing, &model.id, || {
+            utils::create_tensor(pixels, &Device::Cpu)?
+                .to_dtype(model.dtype)?
+                .to_device(&self.device)
+        })?;
 
         tracing::debug!("Image tensor: {:?}", tensor);
-        let image_embeddings: Tensor = tensor.unsqueeze(0)?.apply(model_var)?;
+        Ok(tensor)
+    }
+
+    /// Looks up the cached caption of an image, unless the cache is bypassed.
+    fn cached_caption(&self, image_hash: &ImageHash, model_id: &str, params: &GenerationParams, bypass_cache: bool) -> Option<String> {
+        if bypass_cache {
+            return None;
+        }
+        let caption: Option<String> = self.cache.caption(image_hash, model_id, params);
+        if caption.is_some() {
+            tracing::debug!(model_id, "Caption cache hit");
+        }
+        caption
+    }
+
+    /// Looks up the cached image embeddings of an image, unless the cache is bypassed.
+    fn cached_embeddings(&self, image_hash: &ImageHash, model_id: &str, bypass_cache: bool) -> Option<Tensor> {
+        if bypass_cache {
+            return None;
+        }
+        let image_embeddings: Option<Tensor> = self.cache.embeddings(image_hash, model_id);
+        if image_embeddings.is_some() {
+            tracing::debug!(model_id, "Image embeddings cache hit");
+        }
+        image_embeddings
+    }
+
+    /// Generates text from image (or question) embeddings.
+    ///
+    /// This function generates a caption by running the embeddings through the text decoder
+    /// model and using a logits processor to sample tokens until the end of sequence token is encountered
+    /// or `max_new_tokens` tokens have been generated. It uses the sampling strategy (e.g., argmax) and
+    /// the repetition penalty from `params` to decide the next token at each step. If `params` contains
+    /// a prompt, its tokens are fed to the decoder first and are not part of the returned text.
+    ///
+    /// The text decoder is borrowed from the decoder pool of the model for the duration of the
+    /// generation, so concurrent generations never share a key-value cache.
+    ///
+    /// `cancel` is checked before every decoding step, so an abandoned request gives its decoder
+    /// and its thread back after at most one more forward pass.
+    ///
+    /// The generation is traced as a `generate_text` span with a child span per phase: `prefill`
+    /// (the first forward pass over the prompt), `decode` (the following tokens) and `detokenize`.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model to use for generating text.
+    /// * `image_embeds` - A reference to the tensor the decoder attends to: the image embeddings for
+    ///   captioning, or the question embeddings for question answering.
+    /// * `params` - The [`GenerationParams`] controlling how the caption is generated.
+    /// * `cancel` - The [`CancellationToken`] of the request.
+    /// * `on_token` - A callback invoked with each sampled token. Returning an error from the
+    ///   callback stops the generation.
+    ///
+    /// # Returns
+    ///
+    /// A [`ProcessingResult`] containing the generated text as a [`String`] or the
+    /// [`ProcessingError`] if generation fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ProcessingError::Cancelled`] if the request is cancelled, or
+    /// [`ProcessingError::Generation`] if text generation fails.
+    #[tracing::instrument(skip_all, fields(model_id = %model.id, generated_tokens = tracing::field::Empty))]
+    fn generate_text<F>(
+        &self,
+        model: &LoadedModel,
+        image_embeds: &Tensor,
+        params: &GenerationParams,
+        cancel: &CancellationToken,
+        mut on_token: F,
+    ) -> ProcessingResult<String>
+    where
+        F: FnMut(u32) -> ProcessingResult<()>,
+    {
+        let tokenizer: &Tokenizer = &model.tokenizer;
+        let mut decoder: PooledDecoder = model.decoders.acquire();
+
+        let mut logits_processor: LogitsProcessor =
+            LogitsProcessor::from_sampling(params.seed, params.sampling.clone());
+        let mut token_ids: Vec<u32> = vec![BOS_TOKEN_ID];
+        token_ids.extend(Self::encode_prompt(tokenizer, &params.prompt)?);
+        let prompt_len: usize = token_ids.len();
+
+        let started: Instant = Instant::now();
+        let generation: ProcessingResult<()> = (|| {
+            let mut phase: Span = tracing::info_span!("prefill", prompt_tokens = prompt_len);
+            for index in 0..params.max_new_tokens {
+                if index == 1 {
+                    // Closes the prefill span
+                    phase = tracing::info_span!("decode");
+                }
+                let _phase = phase.enter();
+                Self::check_cancelled(cancel)?;
+                let context_size: usize = if index > 0 { 1 } else { token_ids.len() };
+                let start_pos: usize = token_ids.len().saturating_sub(context_size);
+                let input_ids: Tensor = Tensor::new(&token_ids[start_pos..], &self.device)?.unsqueeze(0)?;
+                let logits: Tensor = decoder.forward(&input_ids, image_embeds)?.squeeze(0)?;
+                let logits: Tensor = logits.get(logits.dim(0)? - 1)?;
+                let logits: Tensor = if params.repetition_penalty == 1.0 {
+                    logits
+                } else {
+                    apply_repeat_penalty(&logits, params.repetition_penalty, &token_ids[1..])?
+                };
+                let token: u32 = logits_processor.sample(&logits)?;
+                if token == SEP_TOKEN_ID {
+                    break;
+                }
+                token_ids.push(token);
+                on_token(token)?;
+            }
+            Ok(())
+        })();
+        // Tokens generated before an error or a cancellation are counted as well
+        metrics().observe_stage(Stage::TextGeneration, &model.id, started.elapsed());
+        metrics().add_generated_tokens(&model.id, token_ids.len() - prompt_len);
+        Span::current().record("generated_tokens", token_ids.len() - prompt_len);
+        generation?;
 
-        self.generate_text(model, &image_embeddings)
+        let text: String = tracing::info_span!("detokenize")
+            .in_scope(|| tokenizer.decode(&token_ids[prompt_len..], true).map_err(Error::Wrapped))?;
+
+        Ok(text)
     }
 
-    /// Generates text from image embeddings.
+    /// Generates the texts of a batch of image (or question) embeddings.
+    ///
+    /// Works like [`ImageProcessor::generate_text`] for every row, but the rows are decoded together:
+    /// every decoding step is a single forward pass of the text decoder over the whole batch. Each
+    /// row keeps its own logits processor, repetition penalty and maximum length. A row finishes at
+    /// its end-of-sequence token, at its maximum length, or once its request is cancelled; finished
+    /// rows are fed separator tokens until the whole batch is finished, and their logits are ignored.
     ///
-    /// This function generates a caption by running the image embeddings through the text decoder
-    /// model and using a logits processor to sample tokens until the end of sequence token is encountered.
-    /// It uses a sampling strategy (e.g., argmax) to decide the next token at each step.
+    /// The generation is traced as a `generate_texts` span with `prefill`, `decode` and `detokenize`
+    /// child spans.
     ///
     /// # Arguments
     ///
-    /// * `model` - The type of model to use for generating text.
-    /// * `image_embeds` - A reference to the tensor containing image embeddings.
+    /// * `model` - The model to use for generating text.
+    /// * `image_embeds` - The tensor the decoder attends to for each row, with a batch size of one.
+    /// * `params` - The [`GenerationParams`] of each row. All of them must share the same prompt.
+    /// * `cancellations` - The [`CancellationToken`] of the request of each row.
+    /// * `on_finished` - A callback invoked with the index of a row and its text, or
+    ///   [`ProcessingError::Cancelled`], as soon as the row finishes.
     ///
     /// # Returns
     ///
-    /// A [`Result`] containing the generated text as a [`String`] or an error if generation fails.
+    /// An empty [`ProcessingResult`] once every row is finished.
     ///
     /// # Errors
     ///
-    /// Returns an error if text generation fails.
-    fn generate_text(&self, model: ModelType, image_embeds: &Tensor) -> Result<String> {
-        let mut model: ModelVariant = self.models.get(&model)
-            .unwrap()
-            .clone();
-
-        let mut logits_processor: LogitsProcessor = LogitsProcessor::from_sampling(1337, self.sampling.clone());
-        let mut token_ids: Vec<u32> = vec![30522];
-
-        for index in 0..1000 {
-            let context_size: usize = if index > 0 { 1 } else { token_ids.len() };
-            let start_pos: usize = token_ids.len().saturating_sub(context_size);
-            let input_ids: Tensor = Tensor::new(&token_ids[start_pos..], &self.device)?.unsqueeze(0)?;
-            let logits: Tensor = model.text_decoder_forward(&input_ids, image_embeds)?.squeeze(0)?;
-            let logits: Tensor = logits.get(logits.dim(0)? - 1)?;
-            let token: u32 = logits_processor.sample(&logits)?;
-            if token == SEP_TOKEN_ID {
-                break;
+    /// Returns [`ProcessingError::Generation`] if a forward pass fails. The rows that are not
+    /// finished yet are not passed to `on_finished`.
+    #[tracing::instrument(
+        skip_all,
+        fields(model_id = %model.id, batch_size = image_embeds.len(), generated_tokens = tracing::field::Empty),
+    )]
+    fn generate_texts<F>(
+        &self,
+        model: &LoadedModel,
+        image_embeds: &[Tensor],
+        params: &[&GenerationParams],
+        cancellations: &[&CancellationToken],
+        mut on_finished: F,
+    ) -> ProcessingResult<()>
+    where
+        F: FnMut(usize, ProcessingResult<String>),
+    {
+        let tokenizer: &Tokenizer = &model.tokenizer;
+        let mut prompt_ids: Vec<u32> = vec![BOS_TOKEN_ID];
+        prompt_ids.extend(Self::encode_prompt(tokenizer, &params[0].prompt)?);
+        let prompt_len: usize = prompt_ids.len();
+        let mut sequences: Vec<Sequence> = params
+            .iter()
+            .zip(cancellations)
+            .map(|(params, cancel)| Sequence::new(&prompt_ids, params, cancel))
+            .collect();
+        let image_embeds: Tensor = Tensor::cat(image_embeds, 0)?;
+        let mut decoder: PooledDecoder = model.decoders.acquire();
+        let detokenize = |sequence: &Sequence| -> ProcessingResult<String> {
+            let text: String = tracing::info_span!("detokenize").in_scope(|| {
+                tokenizer.decode(&sequence.token_ids[prompt_len..], true).map_err(Error::Wrapped)
+            })?;
+            Ok(text)
+        };
+
+        let started: Instant = Instant::now();
+        let generation: ProcessingResult<()> = (|| {
+            let mut phase: Span = tracing::info_span!("prefill", prompt_tokens = prompt_len);
+            for index in 0.. {
+                for (row, sequence) in sequences.iter_mut().enumerate() {
+                    if let Some(outcome) = sequence.check_finished(prompt_len) {
+                        on_finished(row, outcome.and_then(|()| detokenize(sequence)));
+                    }
+                }
+                if sequences.iter().all(Sequence::is_finished) {
+                    break;
+                }
+                if index == 1 {
+                    // Closes the prefill span
+                    phase = tracing::info_span!("decode");
+                }
+                let _phase = phase.enter();
+                let context_size: usize = if index > 0 { 1 } else { prompt_len };
+                let mut input_ids: Vec<u32> = Vec::with_capacity(sequences.len() * context_size);
+                for sequence in &sequences {
+                    if sequence.is_finished() {
+                        input_ids.resize(input_ids.len() + context_size, SEP_TOKEN_ID);
+                    } else {
+                        input_ids.extend(&sequence.token_ids[sequence.token_ids.len() - context_size..]);
+                    }
+                }
+                let input_ids: Tensor = Tensor::from_vec(input_ids, (sequences.len(), context_size), &self.device)?;
+                let logits: Tensor = decoder.forward(&input_ids, &image_embeds)?;
+                let logits: Tensor = logits.narrow(1, context_size - 1, 1)?.squeeze(1)?;
+                for (row, sequence) in sequences.iter_mut().enumerate() {
+                    if sequence.is_finished() {
+                        continue;
+                    }
+                    let logits: Tensor = logits.get(row)?;
+                    let logits: Tensor = if sequence.params.repetition_penalty == 1.0 {
+                        logits
+                    } else {
+                        apply_repeat_penalty(&logits, sequence.params.repetition_penalty, &sequence.token_ids[1..])?
+                    };
+                    let token: u32 = sequence.logits_processor.sample(&logits)?;
+                    if token == SEP_TOKEN_ID {
+                        sequence.finished = true;
+                        on_finished(row, detokenize(sequence));
+                    } else {
+                        sequence.token_ids.push(token);
+                    }
+                }
             }
-            token_ids.push(token);
+            Ok(())
+        })();
+        // Tokens generated before an error or a cancellation are counted as well
+        let generated_tokens: usize = sequences.iter().map(|sequence| sequence.token_ids.len() - prompt_len).sum();
+        metrics().observe_stage(Stage::TextGeneration, &model.id, started.elapsed());
+        metrics().add_generated_tokens(&model.id, generated_tokens);
+        Span::current().record("generated_tokens", generated_tokens);
+
+        generation
+    }
+
+    /// Returns [`ProcessingError::Cancelled`] if the request of `cancel` was cancelled.
+    fn check_cancelled(cancel: &CancellationToken) -> ProcessingResult<()> {
+        if cancel.is_cancelled() {
+            return Err(ProcessingError::Cancelled);
+        }
+
+        Ok(())
+    }
+
+    /// Tokenizes a conditional captioning prompt.
+    ///
+    /// # Arguments
+    ///
+    /// * `tokenizer` - The tokenizer of the model the prompt is fed to.
+    /// * `prompt` - The prompt to tokenize. Leading and trailing whitespace is ignored.
+    ///
+    /// # Returns
+    ///
+    /// A [`ProcessingResult`] containing the token IDs of the prompt (without special tokens), which
+    /// is empty for an empty prompt, or an error if tokenization fails.
+    fn encode_prompt(tokenizer: &Tokenizer, prompt: &str) -> ProcessingResult<Vec<u32>> {
+        let prompt: &str = prompt.trim();
+        if prompt.is_empty() {
+            return Ok(Vec::new());
         }
-        self.tokenizer.decode(&token_ids, true).map_err(Error::Wrapped)
+        let encoding = tokenizer.encode(prompt, false).map_err(Error::Wrapped)?;
+
+        Ok(encoding.get_ids().to_vec())
+    }
+}
+
+/// [`Sequence`] is the state of a row of [`ImageProcessor::generate_texts`].
+struct Sequence<'a> {
+    params: &'a GenerationParams,
+    cancel: &'a CancellationToken,
+    logits_processor: LogitsProcessor,
+    /// The BOS token, the prompt and the generated tokens.
+    token_ids: Vec<u32>,
+    finished: bool,
+}
+
+impl<'a> Sequence<'a> {
+    /// Creates a new instance of [`Sequence`] starting with the given prompt.
+    fn new(prompt_ids: &[u32], params: &'a GenerationParams, cancel: &'a CancellationToken) -> Self {
+        Self {
+            params,
+            cancel,
+            logits_processor: LogitsProcessor::from_sampling(params.seed, params.sampling.clone()),
+            token_ids: prompt_ids.to_vec(),
+            finished: false,
+        }
+    }
+
+    /// Returns whether no more tokens are generated for the sequence.
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Finishes the sequence before the next decoding step if it reached its maximum length or if
+    /// its request was cancelled.
+    ///
+    /// # Returns
+    ///
+    /// The outcome of the sequence if it finishes now, or `None` if it goes on or was already
+    /// finished.
+    fn check_finished(&mut self, prompt_len: usize) -> Option<ProcessingResult<()>> {
+        if self.finished {
+            return None;
+        }
+        let outcome: ProcessingResult<()> = if self.token_ids.len() - prompt_len >= self.params.max_new_tokens {
+            Ok(())
+        } else if self.cancel.is_cancelled() {
+            Err(ProcessingError::Cancelled)
+        } else {
+            return None;
+        };
+        self.finished = true;
+
+        Some(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_transformers::generation::Sampling;
+    use crate::image_captioning::testing;
+    use crate::metrics::CacheKind;
+
+    /// Runs [`ImageProcessor::process_images`] with the tiny captioning model, bypassing the cache.
+    ///
+    /// # Returns
+    ///
+    /// The result of each image in order, and the indices of the images in the order their results
+    /// were delivered.
+    fn process_images(
+        processor: &ImageProcessor,
+        images: &[Vec<u8>],
+        params: &[GenerationParams],
+        cancellations: &[CancellationToken],
+    ) -> (Vec<ProcessingResult<String>>, Vec<usize>) {
+        let mut results: Vec<Option<ProcessingResult<String>>> = vec![None; images.len()];
+        let mut delivered: Vec<usize> = Vec::with_capacity(images.len());
+        processor
+            .process_images(testing::CAPTIONING_MODEL_ID, images, params, true, cancellations, |index, result| {
+                results[index] = Some(result);
+                delivered.push(index);
+            })
+            .unwrap();
+        let results: Vec<ProcessingResult<String>> = results.into_iter().map(|result| result.unwrap()).collect();
+
+        (results, delivered)
+    }
+
+    #[test]
+    fn test_process_images_matches_process_image() {
+        // GIVEN
+        let processor: ImageProcessor = testing::tiny_processor();
+        let images: Vec<Vec<u8>> = vec![testing::png_image(0), testing::png_image(128), testing::png_image(255)];
+        let params: Vec<GenerationParams> = vec![
+            GenerationParams { max_new_tokens: 4, ..Default::default() },
+            GenerationParams { max_new_tokens: 8, repetition_penalty: 1.5, ..Default::default() },
+            GenerationParams {
+                sampling: Sampling::TopK { k: 10, temperature: 0.8 },
+                seed: 42,
+                max_new_tokens: 6,
+                ..Default::default()
+            },
+        ];
+        let cancellations: Vec<CancellationToken> = (0..images.len()).map(|_| CancellationToken::new()).collect();
+        // WHEN
+        let (captions, _): (Vec<ProcessingResult<String>>, Vec<usize>) =
+            process_images(&processor, &images, &params, &cancellations);
+        // THEN
+        assert_eq!(captions.len(), images.len());
+        for ((image, params), caption) in images.iter().zip(&params).zip(captions) {
+            let expected: String = processor
+                .process_image(testing::CAPTIONING_MODEL_ID, image, params, true, &CancellationToken::new())
+                .unwrap();
+            assert_eq!(caption.unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_process_images_skips_cancelled_images() {
+        // GIVEN
+        let processor: ImageProcessor = testing::tiny_processor();
+        let images: Vec<Vec<u8>> = vec![testing::png_image(0), testing::png_image(255)];
+        let params: Vec<GenerationParams> = vec![GenerationParams { max_new_tokens: 4, ..Default::default() }; 2];
+        let cancellations: Vec<CancellationToken> = (0..images.len()).map(|_| CancellationToken::new()).collect();
+        cancellations[0].cancel();
+        // WHEN
+        let (captions, _): (Vec<ProcessingResult<String>>, Vec<usize>) =
+            process_images(&processor, &images, &params, &cancellations);
+        // THEN
+        assert!(matches!(captions[0], Err(ProcessingError::Cancelled)));
+        let expected: String = processor
+            .process_image(testing::CAPTIONING_MODEL_ID, &images[1], &params[1], true, &CancellationToken::new())
+            .unwrap();
+        assert_eq!(captions[1].as_ref().unwrap(), &expected);
+    }
+
+    #[test]
+    fn test_process_images_delivers_short_captions_first() {
+        // GIVEN
+        let processor: ImageProcessor = testing::tiny_processor();
+        let images: Vec<Vec<u8>> = vec![testing::png_image(0), testing::png_image(255)];
+        let params: Vec<GenerationParams> = vec![
+            GenerationParams { max_new_tokens: 8, ..Default::default() },
+            GenerationParams { max_new_tokens: 1, ..Default::default() },
+        ];
+        let cancellations: Vec<CancellationToken> = (0..images.len()).map(|_| CancellationToken::new()).collect();
+        // WHEN
+        let (captions, delivered): (Vec<ProcessingResult<String>>, Vec<usize>) =
+            process_images(&processor, &images, &params, &cancellations);
+        // THEN
+        assert_eq!(delivered, vec![1, 0]);
+        assert!(captions.iter().all(|caption| caption.is_ok()));
+    }
+
+    #[test]
+    fn test_process_image_counts_cache_hits_of_repeated_images() {
+        // GIVEN
+        let model_id: &str = "blip_repeated_images";
+        let processor: ImageProcessor = testing::tiny_captioning_processor(model_id);
+        let image: Vec<u8> = testing::png_image(32);
+        let params: GenerationParams = GenerationParams { max_new_tokens: 4, ..Default::default() };
+        // WHEN
+        let first: String = processor
+            .process_image(model_id, &image, &params, false, &CancellationToken::new())
+            .unwrap();
+        let second: String = processor
+            .process_image(model_id, &image, &params, false, &CancellationToken::new())
+            .unwrap();
+        // THEN
+        assert_eq!(second, first);
+        assert_eq!(testing::cache_lookups(CacheKind::Caption, model_id, "miss"), 1);
+        assert_eq!(testing::cache_lookups(CacheKind::Caption, model_id, "hit"), 1);
+        assert_eq!(testing::cache_lookups(CacheKind::Embeddings, model_id, "miss"), 1);
     }
 }
@@ -1,11 +1,17 @@
 use std::fs;
-use std::collections::HashMap;
+use std::io;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use thiserror::Error;
 use serde::Deserialize;
-use hf_hub::{Repo, RepoType};
+use sha2::{Digest, Sha256};
+use hf_hub::{Cache, CacheRepo, Repo, RepoType};
 use hf_hub::api::sync::{Api, ApiRepo, ApiError};
+use tokio::task::{self, JoinError, JoinSet};
+use crate::image_captioning::registry::{Architecture, ConfigPreset, ModelDType};
 
 #[cfg(test)]
 use mockall::automock;
@@ -21,8 +27,23 @@ use mockall::automock;
 ///   when reading the model configuration file.
 /// * `ParseError`: This variant is used when an error occurs while parsing
 ///   the model configuration file.
+/// * `DuplicateModelId`: This variant is used when several models in the
+///   configuration file share the same id.
+/// * `TaskError`: This variant is used when a download task of the
+///   [`AsyncModelLoader`] panics or is cancelled.
+/// * `MissingFiles`: This variant is used when files of local models, or of
+///   models in the Hugging Face cache in offline mode, do not exist. It lists
+///   every missing file of the configuration, not only the first one.
+/// * `RelativePath`: This variant is used when a file of a model with the
+///   `files` source is not an absolute path.
+/// * `ChecksumMismatch`: This variant is used when the size or SHA-256 digest
+///   of a model file does not match the checksum declared in the configuration,
+///   e.g. because the cache is corrupted or a mirror serves tampered files.
+/// * `ArchitectureMismatch`: This variant is used when the weights file of a
+///   model does not match its architecture, e.g. a `gguf` file declared with
+///   the default `blip` architecture instead of `quantized_blip`.
 ///
-/// Each variant uses the `#[from]` attribute to automatically implement the [`From`] trait,
+/// Each wrapping variant uses the `#[from]` attribute to automatically implement the [`From`] trait,
 /// allowing for easy conversion from the wrapped error types to [`ModelLoaderError`].
 #[derive(Error, Debug)]
 pub enum ModelLoaderError {
@@ -34,12 +55,39 @@ pub enum ModelLoaderError {
 
     #[error("Error occurred while parsing model config: {0}")]
     ParseError(#[from] toml::de::Error),
+
+    #[error("Model id {0:?} is declared more than once in model config")]
+    DuplicateModelId(String),
+
+    #[error("Download task failed: {0}")]
+    TaskError(#[from] JoinError),
+
+    #[error("Model files are missing: {}", .0.join("; "))]
+    MissingFiles(Vec<String>),
+
+    #[error("File path {0:?} must be absolute for a model with the `files` source")]
+    RelativePath(String),
+
+    #[error("Checksum mismatch for {} of model {model_id:?}: expected {expected}, found {actual}", .path.display())]
+    ChecksumMismatch {
+        model_id: String,
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Model {model_id:?} declares the {architecture:?} architecture, which cannot be loaded from {model:?}: `quantized_blip` models are loaded from `gguf` files and the others from `safetensors` files")]
+    ArchitectureMismatch {
+        model_id: String,
+        model: String,
+        architecture: &'static str,
+    },
 }
 
 /// [`Result`] with default error type [`ModelLoaderError`].
 pub type Result<T, E = ModelLoaderError> = std::result::Result<T, E>;
 
-/// A type alias for a [`HashMap`] that maps model repository names to [`Model`] instances.
+/// A type alias for a [`HashMap`] that maps model ids to [`Model`] instances.
 /// This is used to store multiple models loaded from a TOML configuration file.
 pub type Models = HashMap<String, Model>;
 
@@ -51,27 +99,208 @@ struct Config {
     models: Vec<ModelConfig>,
 }
 
+/// [`ModelSource`] is where the files of a model come from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelSource {
+    /// `repository` is a Hugging Face Hub repository, and the files are downloaded from it.
+    #[default]
+    Hub,
+    /// `repository` is a local directory, and the files are paths relative to it.
+    Directory,
+    /// The files are absolute paths, and `repository` only names the model.
+    Files,
+}
+
+/// Parses the `[[model]]` entries from the contents of a TOML configuration file.
+/// Other tables are ignored.
+///
+/// # Parameters
+///
+/// * `toml_str`: The contents of the TOML configuration file.
+///
+/// # Returns
+///
+/// The [`ModelConfig`] of every entry, in the order of the file.
+///
+/// # Errors
+///
+/// Returns [`ModelLoaderError::ParseError`] if the entries are invalid (e.g. an entry without `id`),
+/// or the errors of [`validate_model_configs`].
+pub fn model_configs_from_toml_str(toml_str: &str) -> Result<Vec<ModelConfig>> {
+    let config: Config = toml::from_str(toml_str)?;
+    validate_model_configs(&config.models)?;
+
+    Ok(config.models)
+}
+
+/// Checks the `[[model]]` entries of a configuration before any file is fetched.
+///
+/// # Parameters
+///
+/// * `model_cfgs`: The [`ModelConfig`] of every entry.
+///
+/// # Errors
+///
+/// Returns [`ModelLoaderError::DuplicateModelId`] if several entries share the same id, or
+/// [`ModelLoaderError::ArchitectureMismatch`] if the weights file of an entry does not match its
+/// architecture.
+pub fn validate_model_configs(model_cfgs: &[ModelConfig]) -> Result<()> {
+    let mut ids: HashSet<&str> = HashSet::with_capacity(model_cfgs.len());
+    if let Some(duplicate) = model_cfgs.iter().find(|model_cfg| !ids.insert(model_cfg.id())) {
+        return Err(ModelLoaderError::DuplicateModelId(duplicate.id().to_owned()));
+    }
+
+    model_cfgs.iter().try_for_each(ModelConfig::validate)
+}
+
+/// [`FileChecksum`] is the expected checksum of a model file.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct FileChecksum {
+    /// The hex encoded SHA-256 digest of the file.
+    pub sha256: String,
+    /// The size of the file in bytes, checked before the file is hashed.
+    pub size: Option<u64>,
+}
+
+impl FileChecksum {
+    /// Verifies a file against the checksum.
+    ///
+    /// # Parameters
+    ///
+    /// * `model_id`: The registry id of the model, used in the error.
+    /// * `path`: The path of the file to verify.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ModelLoaderError::ChecksumMismatch`] if the size or the digest of the file differs,
+    /// or [`ModelLoaderError::IoError`] if the file cannot be read.
+    pub fn verify(&self, model_id: &str, path: &Path) -> Result<()> {
+        let mismatch = |expected: String, actual: String| ModelLoaderError::ChecksumMismatch {
+            model_id: model_id.to_owned(),
+            path: path.to_path_buf(),
+            expected,
+            actual,
+        };
+
+        if let Some(size) = self.size {
+            let actual: u64 = fs::metadata(path)?.len();
+            if actual != size {
+                return Err(mismatch(format!("{} bytes", size), format!("{} bytes", actual)));
+            }
+        }
+
+        let mut hasher = Sha256::new();
+        io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+        let actual: String = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+        if actual.eq_ignore_ascii_case(self.sha256.trim()) {
+            Ok(())
+        } else {
+            Err(mismatch(format!("SHA-256 {}", self.sha256.trim()), format!("SHA-256 {}", actual)))
+        }
+    }
+}
+
+/// [`Checksums`] holds the expected checksums of the files of a model.
+/// Files without a checksum are not verified.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Checksums {
+    pub model: Option<FileChecksum>,
+    pub tokenizer: Option<FileChecksum>,
+}
+
 /// [`ModelConfig`] is a struct representing the model data in the config file.
 /// It corresponds to a single `[[model]]` section in the TOML document.
-#[derive(Debug, Deserialize)]
+///
+/// Besides the files to download, an entry describes how to build the model: its `architecture`,
+/// hyperparameters `config` preset and `dtype`. The required `id` is the name requests use to
+/// select the model (e.g. `blip` for the legacy `BLIP` model type). The `source` tells how `repository`, `model` and
+/// `tokenizer` are resolved (see [`ModelSource`]), and the optional `checksums` the files are
+/// verified against once resolved (see [`Checksums`]).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct ModelConfig {
+    pub id: String,
+    #[serde(default)]
+    pub source: ModelSource,
     pub repository: String,
     pub revision: Option<String>,
     pub model: String,
     pub tokenizer: String,
+    #[serde(default)]
+    pub architecture: Architecture,
+    #[serde(default, rename = "config")]
+    pub preset: ConfigPreset,
+    #[serde(default)]
+    pub dtype: ModelDType,
+    #[serde(default)]
+    pub checksums: Checksums,
+}
+
+impl ModelConfig {
+    /// Returns the registry id of the model.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Checks that the weights file can be loaded by the architecture of the model.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ModelLoaderError::ArchitectureMismatch`] if a `gguf` file is declared with a
+    /// non-quantized architecture, or another file with a quantized one.
+    fn validate(&self) -> Result<()> {
+        let is_gguf: bool = Path::new(&self.model).extension().is_some_and(|extension| extension == "gguf");
+        if is_gguf == self.architecture.is_quantized() {
+            Ok(())
+        } else {
+            Err(ModelLoaderError::ArchitectureMismatch {
+                model_id: self.id.clone(),
+                model: self.model.clone(),
+                architecture: self.architecture.as_str(),
+            })
+        }
+    }
+
+    /// Returns the repository resolving the files of a local model, or `None` for a model of the Hub.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ModelLoaderError::RelativePath`] if a file of a model with the `files` source is not
+    /// an absolute path.
+    fn local_repo(&self) -> Result<Option<LocalRepo>> {
+        match self.source {
+            ModelSource::Hub => Ok(None),
+            ModelSource::Directory => Ok(Some(LocalRepo::new(&self.repository))),
+            ModelSource::Files => match [&self.model, &self.tokenizer].into_iter().find(|path| Path::new(path).is_relative()) {
+                Some(path) => Err(ModelLoaderError::RelativePath(path.clone())),
+                None => Ok(Some(LocalRepo::new(PathBuf::new()))),
+            },
+        }
+    }
 }
 
 /// [`Model`] is a struct representing a downloaded model.
-/// It contains the paths to the model and tokenizer files.
+/// It contains the paths to the model and tokenizer files, and the [`ModelConfig`] it was loaded from.
 /// These paths can be used to load the model and tokenizer in your ML library of choice.
 #[derive(Debug, Clone)]
 pub struct Model {
+    config: ModelConfig,
     model_path: PathBuf,
     tokenizer_path: PathBuf,
 }
 
 #[cfg(not(tarpaulin_include))]
 impl Model {
+    /// Creates a new instance of [`Model`] from the config entry and the resolved paths of its files.
+    pub fn new(config: ModelConfig, model_path: PathBuf, tokenizer_path: PathBuf) -> Self {
+        Self { config, model_path, tokenizer_path }
+    }
+
+    /// Returns a reference to the config entry the model was loaded from.
+    pub fn config(&self) -> &ModelConfig {
+        &self.config
+    }
+
     /// Returns a reference to the path of the model file.
     pub fn model_path(&self) -> &PathBuf {
         &self.model_path
@@ -88,6 +317,23 @@ impl Model {
     }
 }
 
+/// Verifies the files of a model against the [`Checksums`] of its config.
+/// Hashing large model files takes a while, so it should not run on an async task.
+fn verify(model: &Model) -> Result<()> {
+    let files: [(&Path, &Option<FileChecksum>); 2] = [
+        (&model.model_path, &model.config.checksums.model),
+        (&model.tokenizer_path, &model.config.checksums.tokenizer),
+    ];
+    for (path, checksum) in files {
+        if let Some(checksum) = checksum {
+            checksum.verify(model.config.id(), path)?;
+            tracing::debug!(model_id = %model.config.id(), path = %path.display(), "Verified model file checksum");
+        }
+    }
+
+    Ok(())
+}
+
 /// `ModelLoaderApi` is a trait that abstracts the API used to retrieve hf repositories.
 /// Primarily used for dependency injection and testing purposes.
 #[cfg_attr(test, automock(type Repo = MockModelLoaderApiRepo;))]
@@ -125,7 +371,117 @@ impl ModelLoaderApiRepo for ApiRepo {
     }
 }
 
-// TODO: Rewrite to async version
+/// [`OfflineApi`] is a [`ModelLoaderApi`] resolving the files of Hub repositories from the local
+/// Hugging Face cache only. It never accesses the network, so hosts without internet access can
+/// load models that were downloaded beforehand, and a file missing from the cache fails at once.
+pub struct OfflineApi {
+    cache: Cache,
+}
+
+impl OfflineApi {
+    /// Creates a new instance of [`OfflineApi`] reading the given cache.
+    ///
+    /// # Parameters
+    ///
+    /// * `cache`: The Hugging Face cache, e.g. [`Cache::default`] (`$HF_HOME/hub`).
+    ///
+    /// # Returns
+    ///
+    /// A new [`OfflineApi`] instance.
+    pub fn new(cache: Cache) -> Self {
+        Self { cache }
+    }
+}
+
+impl Default for OfflineApi {
+    fn default() -> Self {
+        Self::new(Cache::default())
+    }
+}
+
+impl ModelLoaderApi for OfflineApi {
+    type Repo = CacheRepo;
+
+    fn repo(&self, repo: Repo) -> Self::Repo {
+        self.cache.repo(repo)
+    }
+
+    fn model(&self, model_id: String) -> Self::Repo {
+        self.cache.model(model_id)
+    }
+}
+
+impl ModelLoaderApiRepo for CacheRepo {
+    fn get(&self, filename: &str) -> Result<PathBuf, ApiError> {
+        CacheRepo::get(self, filename).ok_or_else(|| {
+            let message: String = format!("{:?} is not in the Hugging Face cache", filename);
+            ApiError::IoError(io::Error::new(io::ErrorKind::NotFound, message))
+        })
+    }
+}
+
+/// [`LocalRepo`] is a [`ModelLoaderApiRepo`] resolving files from a local directory.
+/// Absolute filenames are used as is.
+pub struct LocalRepo {
+    root: PathBuf,
+}
+
+impl LocalRepo {
+    /// Creates a new instance of [`LocalRepo`] resolving files relative to `root`.
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl ModelLoaderApiRepo for LocalRepo {
+    fn get(&self, filename: &str) -> Result<PathBuf, ApiError> {
+        let path: PathBuf = self.root.join(filename);
+        if path.is_file() {
+            Ok(path)
+        } else {
+            let message: String = format!("{} does not exist", path.display());
+            Err(ApiError::IoError(io::Error::new(io::ErrorKind::NotFound, message)))
+        }
+    }
+}
+
+/// Returns the cause of an error if it is a missing file of a local repository or of the cache.
+fn missing_file(error: &ModelLoaderError) -> Option<&io::Error> {
+    match error {
+        ModelLoaderError::ApiError(ApiError::IoError(e)) if e.kind() == io::ErrorKind::NotFound => Some(e),
+        _ => None,
+    }
+}
+
+/// Creates a [`Model`] from the lookups of its model and tokenizer files.
+///
+/// # Parameters
+///
+/// * `model_cfg`: The [`ModelConfig`] the files were looked up for.
+/// * `model_path`: The result of the lookup of the model file.
+/// * `tokenizer_path`: The result of the lookup of the tokenizer file.
+///
+/// # Returns
+///
+/// The [`Model`] if both files were found, [`ModelLoaderError::MissingFiles`] listing the files of
+/// the model that are missing, or the first other error.
+fn into_model(model_cfg: &ModelConfig, model_path: Result<PathBuf>, tokenizer_path: Result<PathBuf>) -> Result<Model> {
+    let (model_path, tokenizer_path) = match (model_path, tokenizer_path) {
+        (Ok(model_path), Ok(tokenizer_path)) => (model_path, tokenizer_path),
+        (model_path, tokenizer_path) => {
+            let mut missing: Vec<String> = Vec::with_capacity(2);
+            for error in [model_path.err(), tokenizer_path.err()].into_iter().flatten() {
+                match missing_file(&error) {
+                    Some(e) => missing.push(format!("{}: {}", model_cfg.id(), e)),
+                    None => return Err(error),
+                }
+            }
+            return Err(ModelLoaderError::MissingFiles(missing));
+        }
+    };
+
+    Ok(Model::new(model_cfg.clone(), model_path, tokenizer_path))
+}
 
 /// [`ModelLoader`] is a struct used to load models from the Hugging Face API.
 pub struct ModelLoader<T: ModelLoaderApi> {
@@ -145,7 +501,10 @@ impl<T: ModelLoaderApi> ModelLoader<T> {
     ///
     /// # Example
     ///
-    /// ```
+    /// ```no_run
+    /// # use std::path::PathBuf;
+    /// # use hf_hub::api::sync::ApiBuilder;
+    /// # use grpc_vision_svc::image_captioning::model_loader::ModelLoader;
     /// let api = ApiBuilder::new()
     ///     .with_token(Some("API_TOKEN".into()))
     ///     .with_cache_dir(PathBuf::from("./cache/models"))
@@ -159,6 +518,7 @@ impl<T: ModelLoaderApi> ModelLoader<T> {
     }
 
     /// Loads a model from the Hugging Face API based on the provided [`ModelConfig`].
+    /// Models with a local source are resolved from the filesystem instead.
     ///
     /// # Parameters
     ///
@@ -168,14 +528,30 @@ impl<T: ModelLoaderApi> ModelLoader<T> {
     ///
     /// A [`Model`] struct containing the paths to the model and tokenizer files.
     ///
+    /// # Errors
+    ///
+    /// Returns [`ModelLoaderError::MissingFiles`] listing both files if they are missing,
+    /// [`ModelLoaderError::ApiError`] if a file cannot be downloaded, or
+    /// [`ModelLoaderError::ChecksumMismatch`] if a file does not match its checksum.
+    ///
     /// # Example
     ///
-    /// ```
+    /// ```no_run
+    /// # use std::path::PathBuf;
+    /// # use hf_hub::api::sync::ApiBuilder;
+    /// # use grpc_vision_svc::image_captioning::model_loader::{Checksums, ModelConfig, ModelLoader, ModelSource};
+    /// # use grpc_vision_svc::image_captioning::registry::{Architecture, ConfigPreset, ModelDType};
     /// let config = ModelConfig {
+    ///     id: "blip".to_string(),
+    ///     source: ModelSource::Hub,
     ///     repository: "google-bert/bert-base-uncased".to_string(),
     ///     revision: None,
     ///     model: "model.safetensors".to_string(),
     ///     tokenizer: "tokenizer.json".to_string(),
+    ///     architecture: Architecture::Blip,
+    ///     preset: ConfigPreset::ImageCaptioningLarge,
+    ///     dtype: ModelDType::F32,
+    ///     checksums: Checksums::default(),
     /// };
     /// let api = ApiBuilder::new()
     ///     .with_token(Some("API_TOKEN".into()))
@@ -187,6 +563,10 @@ impl<T: ModelLoaderApi> ModelLoader<T> {
     /// let model = loader.load(&config).unwrap();
     /// ```
     pub fn load(&self, model_cfg: &ModelConfig) -> Result<Model> {
+        if let Some(repo) = model_cfg.local_repo()? {
+            return Self::fetch(&repo, model_cfg);
+        }
+
         let api: <T as ModelLoaderApi>::Repo = if let Some(ref revision) = model_cfg.revision {
             self.api.repo(Repo::with_revision(
                 model_cfg.repository.clone(),
@@ -197,13 +577,22 @@ impl<T: ModelLoaderApi> ModelLoader<T> {
             self.api.model(model_cfg.repository.clone())
         };
 
-        let model_path: PathBuf = api.get(&model_cfg.model)?;
-        let tokenizer_path: PathBuf = api.get(&model_cfg.tokenizer)?;
+        Self::fetch(&api, model_cfg)
+    }
 
-        Ok(Model {
-            model_path,
-            tokenizer_path,
-        })
+    /// Fetches the model and tokenizer files of a model from a repository, and verifies their checksums.
+    /// The tokenizer file is looked up after a missing model file, so that both are reported.
+    fn fetch<R: ModelLoaderApiRepo>(repo: &R, model_cfg: &ModelConfig) -> Result<Model> {
+        let model: Model = match repo.get(&model_cfg.model).map_err(ModelLoaderError::from) {
+            Err(e) if missing_file(&e).is_none() => return Err(e),
+            model_path => {
+                let tokenizer_path: Result<PathBuf> = repo.get(&model_cfg.tokenizer).map_err(ModelLoaderError::from);
+                into_model(model_cfg, model_path, tokenizer_path)?
+            }
+        };
+        verify(&model)?;
+
+        Ok(model)
     }
 
     /// Loads models specified in a TOML configuration file.
@@ -217,45 +606,443 @@ impl<T: ModelLoaderApi> ModelLoader<T> {
     ///
     /// # Returns
     ///
-    /// A [`HashMap`] where the keys are the repository names and the values are
+    /// A [`HashMap`] where the keys are the model ids (see [`ModelConfig::id`]) and the values are
     /// the corresponding [`Model`] structs.
     ///
+    /// # Errors
+    ///
+    /// Returns [`ModelLoaderError::DuplicateModelId`] if several entries share the same id, or
+    /// [`ModelLoaderError::MissingFiles`] listing the missing files of all models.
+    ///
     /// # Example
     ///
-    /// ```
+    /// ```no_run
+    /// # use hf_hub::api::sync::Api;
+    /// # use grpc_vision_svc::image_captioning::model_loader::ModelLoader;
     /// let api = Api::new().unwrap();
     /// let loader = ModelLoader::new(api);
     /// let models = loader.load_from_toml("models.toml").unwrap();
     ///
-    /// assert!(models.get("Salesforce/blip-image-captioning-large").is_some());
-    /// assert!(models.get("microsoft/kosmos-2-patch14-224").is_some());
+    /// assert!(models.get("blip").is_some());
+    /// assert!(models.get("blip_quantized").is_some());
     /// ```
     ///
     /// # Example TOML config file
     ///
     /// ```toml
     /// [[model]]
+    /// id = "blip" # Required, the name requests select the model with
     /// repository = "Salesforce/blip-image-captioning-large"
     /// revision = "refs/pr/18" # Optional
     /// model = "model.safetensors"
     /// tokenizer = "tokenizer.json"
+    /// architecture = "blip" # Optional: "blip" (default), "quantized_blip" (required for gguf files) or "blip_vqa"
+    /// config = "image_captioning_large" # Optional: "image_captioning_large" (default), "image_captioning_base" or "vqa_base"
+    /// dtype = "f32" # Optional: "f32" (default), "f16" or "bf16"
+    /// # Optional, the files are verified after download or cache hit, and the size is optional
+    /// checksums.model = { sha256 = "<hex digest>", size = 1879024232 }
+    /// checksums.tokenizer = { sha256 = "<hex digest>" }
+    ///
+    /// [[model]]
+    /// id = "blip_quantized"
+    /// repository = "lmz/candle-blip"
+    /// model = "blip-image-captioning-large-q80.gguf"
+    /// tokenizer = "tokenizer.json"
+    /// architecture = "quantized_blip"
     ///
     /// [[model]]
-    /// repository = "microsoft/kosmos-2-patch14-224"
+    /// id = "blip_vqa"
+    /// source = "directory" # Optional: "hub" (default), "directory" or "files"
+    /// repository = "/models/blip-vqa-base" # The directory with the files
     /// model = "model.safetensors"
     /// tokenizer = "tokenizer.json"
+    /// architecture = "blip_vqa"
+    /// config = "vqa_base"
     /// ```
     pub fn load_from_toml<P: AsRef<Path>>(&self, path: P) -> Result<Models> {
         let config_str: String = fs::read_to_string(path)?;
         let config: Config = toml::from_str(&config_str)?;
-        
-        config.models
-            .into_iter()
-            .map(|model_cfg| {
-                let model: Model = self.load(&model_cfg)?;
-                Ok((model_cfg.repository, model))
-            })
-            .collect()
+        validate_model_configs(&config.models)?;
+        let mut models: Models = HashMap::with_capacity(config.models.len());
+        let mut missing: Vec<String> = Vec::new();
+
+        for model_cfg in config.models {
+            let id: String = model_cfg.id().to_owned();
+            match self.load(&model_cfg) {
+                Ok(model) => {
+                    models.insert(id, model);
+                }
+                // The other models are still looked up, so that every missing file is reported
+                Err(ModelLoaderError::MissingFiles(files)) => missing.extend(files),
+                Err(e) => return Err(e),
+            }
+        }
+
+        if missing.is_empty() {
+            Ok(models)
+        } else {
+            Err(ModelLoaderError::MissingFiles(missing))
+        }
+    }
+}
+
+/// Reports the progress of the downloads of an [`AsyncModelLoader`].
+pub type ProgressCallback = Arc<dyn Fn(&DownloadEvent) + Send + Sync>;
+
+/// [`DownloadEvent`] describes a step in the download of a single model file by the [`AsyncModelLoader`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadEvent {
+    /// The file is being fetched from the cache or the Hugging Face Hub.
+    Started { model_id: String, filename: String },
+    /// A transient failure occurred, and the download is retried after `delay`.
+    Retrying { model_id: String, filename: String, attempt: u32, delay: Duration, error: String },
+    /// The file is available at `path`.
+    Finished { model_id: String, filename: String, path: PathBuf, bytes: u64, elapsed: Duration },
+    /// The download failed for good.
+    Failed { model_id: String, filename: String, error: String },
+}
+
+impl DownloadEvent {
+    /// Logs the event.
+    fn trace(&self) {
+        match self {
+            Self::Started { model_id, filename } => {
+                tracing::info!(model_id = %model_id, filename = %filename, "Downloading model file");
+            }
+            Self::Retrying { model_id, filename, attempt, delay, error } => {
+                tracing::warn!(
+                    model_id = %model_id,
+                    filename = %filename,
+                    attempt,
+                    delay_ms = delay.as_millis() as u64,
+                    "Retrying model file download: {}", error,
+                );
+            }
+            Self::Finished { model_id, filename, path, bytes, elapsed } => {
+                tracing::info!(
+                    model_id = %model_id,
+                    filename = %filename,
+                    path = %path.display(),
+                    bytes,
+                    elapsed_ms = elapsed.as_millis() as u64,
+                    "Downloaded model file",
+                );
+            }
+            Self::Failed { model_id, filename, error } => {
+                tracing::error!(model_id = %model_id, filename = %filename, "Failed to download model file: {}", error);
+            }
+        }
+    }
+}
+
+/// [`RetryConfig`] holds the backoff of the [`AsyncModelLoader`] when a download fails transiently.
+///
+/// The delay before the `n`-th retry is `initial_backoff * 2^(n - 1)`, capped at `max_backoff`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryConfig {
+    /// The maximum number of attempts per file, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Returns the delay before the retry following the failed `attempt` (starting at 1).
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff)
+    }
+}
+
+/// Returns whether a download that failed with `error` may succeed when retried.
+///
+/// Network failures, I/O errors, rate limiting (`429`) and server errors (`5xx`) are transient,
+/// while other HTTP errors (e.g. a missing file or a private repository), malformed responses and
+/// files missing from a local repository or the cache are not.
+fn is_transient(error: &ApiError) -> bool {
+    match error {
+        ApiError::RequestError(e) => match e.as_ref() {
+            ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+            ureq::Error::Transport(transport) => matches!(
+                transport.kind(),
+                ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io | ureq::ErrorKind::ProxyConnect,
+            ),
+        },
+        ApiError::IoError(e) => e.kind() != io::ErrorKind::NotFound,
+        ApiError::TooManyRetries(_) => true,
+        _ => false,
+    }
+}
+
+/// [`AsyncModelLoader`] is the asynchronous counterpart of [`ModelLoader`].
+///
+/// It downloads the models of a configuration file in parallel, and the model and tokenizer files
+/// of each model at the same time. The downloads go through the same [`ModelLoaderApi`] as the
+/// [`ModelLoader`], on the blocking thread pool of tokio, so the loader must be used within a
+/// tokio runtime. Transient failures are retried with an exponential backoff (see [`RetryConfig`]),
+/// and every [`DownloadEvent`] is logged and passed to the progress callback, if any.
+pub struct AsyncModelLoader<T: ModelLoaderApi> {
+    api: Arc<T>,
+    retry: RetryConfig,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl<T: ModelLoaderApi> Clone for AsyncModelLoader<T> {
+    fn clone(&self) -> Self {
+        Self {
+            api: Arc::clone(&self.api),
+            retry: self.retry,
+            on_progress: self.on_progress.clone(),
+        }
+    }
+}
+
+impl<T> AsyncModelLoader<T>
+where
+    T: ModelLoaderApi + Send + Sync + 'static,
+    T::Repo: Send + Sync + 'static,
+{
+    /// Creates a new instance of [`AsyncModelLoader`] with the provided API and the default
+    /// [`RetryConfig`].
+    ///
+    /// # Parameters
+    ///
+    /// * `api`: An instance of a type implementing the [`ModelLoaderApi`] trait.
+    ///
+    /// # Returns
+    ///
+    /// A new [`AsyncModelLoader`] instance.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use hf_hub::api::sync::ApiBuilder;
+    /// # use grpc_vision_svc::image_captioning::model_loader::{AsyncModelLoader, RetryConfig};
+    /// let api = ApiBuilder::new().with_progress(false).build().unwrap();
+    /// let loader = AsyncModelLoader::new(api)
+    ///     .with_retry(RetryConfig { max_attempts: 3, ..Default::default() })
+    ///     .on_progress(|event| println!("{:?}", event));
+    /// ```
+    pub fn new(api: T) -> Self {
+        Self {
+            api: Arc::new(api),
+            retry: RetryConfig::default(),
+            on_progress: None,
+        }
+    }
+
+    /// Sets the backoff of the retries of failed downloads.
+    pub fn with_retry(mut self, retry: RetryConfig) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Sets the callback receiving the [`DownloadEvent`] of every file.
+    /// The callback is invoked from the download tasks, so it should return quickly.
+    pub fn on_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&DownloadEvent) + Send + Sync + 'static,
+    {
+        self.on_progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// Loads a model from the Hugging Face API based on the provided [`ModelConfig`].
+    /// Models with a local source are resolved from the filesystem instead.
+    ///
+    /// The model and tokenizer files are downloaded at the same time.
+    ///
+    /// # Parameters
+    ///
+    /// * `model_cfg`: A reference to a [`ModelConfig`] that specifies the model to load.
+    ///
+    /// # Returns
+    ///
+    /// A [`Model`] struct containing the paths to the model and tokenizer files.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ModelLoaderError::MissingFiles`] listing both files if they are missing,
+    /// [`ModelLoaderError::ApiError`] if a file cannot be downloaded, after retrying transient
+    /// failures, or [`ModelLoaderError::ChecksumMismatch`] if a file does not match its checksum.
+    pub async fn load(&self, model_cfg: &ModelConfig) -> Result<Model> {
+        if let Some(repo) = model_cfg.local_repo()? {
+            return self.fetch(Arc::new(repo), model_cfg).await;
+        }
+
+        let repo: Arc<T::Repo> = Arc::new(match model_cfg.revision {
+            Some(ref revision) => self.api.repo(Repo::with_revision(
+                model_cfg.repository.clone(),
+                RepoType::Model,
+                revision.clone(),
+            )),
+            None => self.api.model(model_cfg.repository.clone()),
+        });
+
+        self.fetch(repo, model_cfg).await
+    }
+
+    /// Loads the given models in parallel.
+    ///
+    /// # Parameters
+    ///
+    /// * `model_cfgs`: The [`ModelConfig`] of every model to load.
+    ///
+    /// # Returns
+    ///
+    /// A [`HashMap`] where the keys are the model ids (see [`ModelConfig::id`]) and the values are
+    /// the corresponding [`Model`] structs.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ModelLoaderError::DuplicateModelId`] before downloading anything if several
+    /// entries share the same id, or the error of the first model that fails to load. The other
+    /// downloads are then abandoned, except for missing files: the other models are still looked
+    /// up, and [`ModelLoaderError::MissingFiles`] lists the missing files of all models.
+    pub async fn load_all(&self, model_cfgs: Vec<ModelConfig>) -> Result<Models> {
+        validate_model_configs(&model_cfgs)?;
+
+        let mut models: Models = HashMap::with_capacity(model_cfgs.len());
+        let mut missing: Vec<String> = Vec::new();
+        let mut downloads: JoinSet<Result<Model>> = JoinSet::new();
+        for model_cfg in model_cfgs {
+            let loader: Self = self.clone();
+            downloads.spawn(async move { loader.load(&model_cfg).await });
+        }
+        while let Some(model) = downloads.join_next().await {
+            match model? {
+                Ok(model) => {
+                    models.insert(model.config().id().to_owned(), model);
+                }
+                Err(ModelLoaderError::MissingFiles(files)) => missing.extend(files),
+                Err(e) => return Err(e),
+            }
+        }
+
+        if missing.is_empty() {
+            Ok(models)
+        } else {
+            // The models finish in any order
+            missing.sort();
+            Err(ModelLoaderError::MissingFiles(missing))
+        }
+    }
+
+    /// Loads models specified in a TOML configuration file, in parallel.
+    /// See [`ModelLoader::load_from_toml`] for the format of the file.
+    ///
+    /// # Parameters
+    ///
+    /// * `path`: A reference to a [`Path`] pointing to the TOML configuration file.
+    ///
+    /// # Returns
+    ///
+    /// A [`HashMap`] where the keys are the model ids (see [`ModelConfig::id`]) and the values are
+    /// the corresponding [`Model`] structs.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or parsed, or for the reasons listed in
+    /// [`AsyncModelLoader::load_all`].
+    pub async fn load_from_toml<P: AsRef<Path>>(&self, path: P) -> Result<Models> {
+        let config_str: String = tokio::fs::read_to_string(path).await?;
+        let config: Config = toml::from_str(&config_str)?;
+
+        self.load_all(config.models).await
+    }
+
+    /// Fetches the model and tokenizer files of a model from a repository at the same time, then
+    /// verifies their checksums on the blocking thread pool.
+    async fn fetch<R>(&self, repo: Arc<R>, model_cfg: &ModelConfig) -> Result<Model>
+    where
+        R: ModelLoaderApiRepo + Send + Sync + 'static,
+    {
+        let (model_path, tokenizer_path): (Result<PathBuf>, Result<PathBuf>) = tokio::join!(
+            self.get(&repo, model_cfg.id(), &model_cfg.model),
+            self.get(&repo, model_cfg.id(), &model_cfg.tokenizer),
+        );
+        let model: Model = into_model(model_cfg, model_path, tokenizer_path)?;
+
+        task::spawn_blocking(move || verify(&model).map(|()| model)).await?
+    }
+
+    /// Fetches a file of a repository on the blocking thread pool, retrying transient failures.
+    ///
+    /// # Parameters
+    ///
+    /// * `repo`: The repository of the model.
+    /// * `model_id`: The registry id of the model, used in the reported events.
+    /// * `filename`: The name of the file in the repository.
+    ///
+    /// # Returns
+    ///
+    /// The local path of the file.
+    async fn get<R>(&self, repo: &Arc<R>, model_id: &str, filename: &str) -> Result<PathBuf>
+    where
+        R: ModelLoaderApiRepo + Send + Sync + 'static,
+    {
+        self.report(DownloadEvent::Started { model_id: model_id.to_owned(), filename: filename.to_owned() });
+        let started: Instant = Instant::now();
+
+        let mut attempt: u32 = 1;
+        loop {
+            let task_repo: Arc<R> = Arc::clone(repo);
+            let task_filename: String = filename.to_owned();
+            let result: Result<PathBuf, ApiError> = task::spawn_blocking(move || task_repo.get(&task_filename)).await?;
+
+            match result {
+                Ok(path) => {
+                    let bytes: u64 = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+                    self.report(DownloadEvent::Finished {
+                        model_id: model_id.to_owned(),
+                        filename: filename.to_owned(),
+                        path: path.clone(),
+                        bytes,
+                        elapsed: started.elapsed(),
+                    });
+                    return Ok(path);
+                }
+                Err(e) if attempt < self.retry.max_attempts && is_transient(&e) => {
+                    let delay: Duration = self.retry.backoff(attempt);
+                    self.report(DownloadEvent::Retrying {
+                        model_id: model_id.to_owned(),
+                        filename: filename.to_owned(),
+                        attempt,
+                        delay,
+                        error: e.to_string(),
+                    });
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    self.report(DownloadEvent::Failed {
+                        model_id: model_id.to_owned(),
+                        filename: filename.to_owned(),
+                        error: e.to_string(),
+                    });
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    /// Logs an event and passes it to the progress callback.
+    fn report(&self, event: DownloadEvent) {
+        event.trace();
+        if let Some(on_progress) = &self.on_progress {
+            on_progress(&event);
+        }
     }
 }
 
@@ -263,8 +1050,10 @@ impl<T: ModelLoaderApi> ModelLoader<T> {
 mod tests {
     use super::*;
     use std::io::{Write, ErrorKind};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use mockall::predicate;
-    use tempfile::NamedTempFile;
+    use tempfile::{NamedTempFile, TempDir};
 
     #[test]
     fn test_model_loader_load_no_revision() {
@@ -291,10 +1080,16 @@ mod tests {
             });
 
         let model_cfg = ModelConfig {
+            id: "test-model".to_string(),
+            source: ModelSource::Hub,
             repository: "some-repo/test-model".to_string(),
             revision: None,
             model: "model.safetensors".to_string(),
             tokenizer: "tokenizer.json".to_string(),
+            architecture: Architecture::Blip,
+            preset: ConfigPreset::ImageCaptioningLarge,
+            dtype: ModelDType::F32,
+            checksums: Checksums::default(),
         };
         // WHEN
         let loader: ModelLoader<MockModelLoaderApi> = ModelLoader::new(mock_api);
@@ -337,10 +1132,16 @@ mod tests {
             });
 
         let model_cfg = ModelConfig {
+            id: "test-model".to_string(),
+            source: ModelSource::Hub,
             repository: "some-repo/test-model".to_string(),
             revision: Some("main".to_string()),
             model: "model.safetensors".to_string(),
             tokenizer: "tokenizer.json".to_string(),
+            architecture: Architecture::Blip,
+            preset: ConfigPreset::ImageCaptioningLarge,
+            dtype: ModelDType::F32,
+            checksums: Checksums::default(),
         };
         // WHEN
         let loader: ModelLoader<MockModelLoaderApi> = ModelLoader::new(mock_api);
@@ -375,10 +1176,16 @@ mod tests {
             });
 
         let model_cfg = ModelConfig {
+            id: "test-model".to_string(),
+            source: ModelSource::Hub,
             repository: "some-repo/test-model".to_string(),
             revision: None,
             model: "model.safetensors".to_string(),
             tokenizer: "tokenizer.json".to_string(),
+            architecture: Architecture::Blip,
+            preset: ConfigPreset::ImageCaptioningLarge,
+            dtype: ModelDType::F32,
+            checksums: Checksums::default(),
         };
         // WHEN
         let loader: ModelLoader<MockModelLoaderApi> = ModelLoader::new(mock_api);
@@ -414,14 +1221,19 @@ mod tests {
 
         let toml_str: &str = r#"
             [[model]]
+            id = "test-model"
             repository = "some-repo/test-model"
             model = "model.safetensors"
             tokenizer = "tokenizer.json"
 
             [[model]]
+            id = "another-model"
             repository = "another-repo/another-model"
-            model = "model.safetensors"
+            model = "model.gguf"
             tokenizer = "tokenizer.json"
+            architecture = "quantized_blip"
+            config = "image_captioning_base"
+            dtype = "f16"
         "#;
         let mut temp_config = NamedTempFile::new().unwrap();
         write!(temp_config, "{}", toml_str).unwrap();
@@ -432,7 +1244,7 @@ mod tests {
         // THEN
         assert_eq!(models.len(), 2);
 
-        let model_1: &Model = models.get("some-repo/test-model").unwrap();
+        let model_1: &Model = models.get("test-model").unwrap();
         assert_eq!(
             model_1.model_path().to_str(),
             Some("some/path/model.safetensors"),
@@ -442,7 +1254,11 @@ mod tests {
             Some("some/path/tokenizer.json"),
         );
 
-        let model_2: &Model = models.get("another-repo/another-model").unwrap();
+        assert_eq!(model_1.config().architecture, Architecture::Blip);
+        assert_eq!(model_1.config().preset, ConfigPreset::ImageCaptioningLarge);
+        assert_eq!(model_1.config().dtype, ModelDType::F32);
+
+        let model_2: &Model = models.get("another-model").unwrap();
         assert_eq!(
             model_2.model_path().to_str(),
             Some("some/path/model.safetensors"),
@@ -451,6 +1267,41 @@ mod tests {
             model_2.tokenizer_path().to_str(),
             Some("some/path/tokenizer.json"),
         );
+        assert_eq!(model_2.config().repository, "another-repo/another-model");
+        assert_eq!(model_2.config().architecture, Architecture::QuantizedBlip);
+        assert_eq!(model_2.config().preset, ConfigPreset::ImageCaptioningBase);
+        assert_eq!(model_2.config().dtype, ModelDType::F16);
+    }
+
+    #[test]
+    #[ignore = "Interacts with the filesystem"]
+    fn test_model_loader_load_from_toml_duplicate_id() {
+        // GIVEN
+        // Nothing is fetched, as the entries are checked first
+        let mut mock_api = MockModelLoaderApi::new();
+        mock_api.expect_model().never();
+
+        let toml_str: &str = r#"
+            [[model]]
+            id = "blip"
+            repository = "some-repo/test-model"
+            model = "model.safetensors"
+            tokenizer = "tokenizer.json"
+
+            [[model]]
+            id = "blip"
+            repository = "another-repo/another-model"
+            model = "model.safetensors"
+            tokenizer = "tokenizer.json"
+        "#;
+        let mut temp_config = NamedTempFile::new().unwrap();
+        write!(temp_config, "{}", toml_str).unwrap();
+        // WHEN
+        let loader: ModelLoader<MockModelLoaderApi> = ModelLoader::new(mock_api);
+        let result: Result<Models> = loader.load_from_toml(temp_config.path());
+        temp_config.close().unwrap();
+        // THEN
+        assert!(matches!(result, Err(ModelLoaderError::DuplicateModelId(ref id)) if id == "blip"));
     }
 
     #[test]
@@ -469,6 +1320,524 @@ mod tests {
         assert!(matches!(result, Err(ModelLoaderError::ParseError(_))));
     }
 
+    fn model_config(id: &str, repository: &str) -> ModelConfig {
+        ModelConfig {
+            id: id.to_string(),
+            source: ModelSource::Hub,
+            repository: repository.to_string(),
+            revision: None,
+            model: "model.safetensors".to_string(),
+            tokenizer: "tokenizer.json".to_string(),
+            architecture: Architecture::Blip,
+            preset: ConfigPreset::ImageCaptioningLarge,
+            dtype: ModelDType::F32,
+            checksums: Checksums::default(),
+        }
+    }
+
+    fn status_error(code: u16) -> ApiError {
+        ApiError::RequestError(Box::new(ureq::Error::Status(code, ureq::Response::new(code, "Error", "").unwrap())))
+    }
+
+    /// A retry policy short enough for tests.
+    fn fast_retry(max_attempts: u32) -> RetryConfig {
+        RetryConfig { max_attempts, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) }
+    }
+
+    /// A mocked API whose repositories fail `failures` times for each file before returning a path.
+    fn flaky_api(failures: u32, error: fn() -> ApiError) -> MockModelLoaderApi {
+        let mut mock_api = MockModelLoaderApi::new();
+        mock_api
+            .expect_model()
+            .returning(move |_| {
+                let attempts: Arc<Mutex<HashMap<String, u32>>> = Arc::default();
+                let mut mock_repo = MockModelLoaderApiRepo::new();
+                mock_repo
+                    .expect_get()
+                    .returning(move |filename| {
+                        let mut attempts = attempts.lock().unwrap();
+                        let attempt: &mut u32 = attempts.entry(filename.to_string()).or_default();
+                        *attempt += 1;
+                        if *attempt > failures {
+                            Ok(PathBuf::from("some/path").join(filename))
+                        } else {
+                            Err(error())
+                        }
+                    });
+
+                mock_repo
+            });
+        mock_api
+    }
+
+    fn recorder() -> (Arc<Mutex<Vec<DownloadEvent>>>, impl Fn(&DownloadEvent) + Send + Sync + 'static) {
+        let events: Arc<Mutex<Vec<DownloadEvent>>> = Arc::default();
+        let recorded: Arc<Mutex<Vec<DownloadEvent>>> = Arc::clone(&events);
+        (events, move |event: &DownloadEvent| recorded.lock().unwrap().push(event.clone()))
+    }
+
+    #[tokio::test]
+    async fn test_async_model_loader_load_reports_progress() {
+        // GIVEN
+        let (events, on_progress) = recorder();
+        let loader: AsyncModelLoader<MockModelLoaderApi> = AsyncModelLoader::new(flaky_api(0, || unreachable!()))
+            .on_progress(on_progress);
+        // WHEN
+        let model: Model = loader.load(&model_config("blip", "some-repo/test-model")).await.unwrap();
+        // THEN
+        assert_eq!(model.model_path().to_str(), Some("some/path/model.safetensors"));
+        assert_eq!(model.tokenizer_path().to_str(), Some("some/path/tokenizer.json"));
+        let events: Vec<DownloadEvent> = events.lock().unwrap().clone();
+        assert_eq!(events.len(), 4);
+        for filename in ["model.safetensors", "tokenizer.json"] {
+            assert!(events.contains(&DownloadEvent::Started { model_id: "blip".to_string(), filename: filename.to_string() }));
+            assert!(events.iter().any(|event| matches!(
+                event,
+                DownloadEvent::Finished { model_id, filename: finished, bytes: 0, .. } if model_id == "blip" && finished == filename
+            )));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_model_loader_retries_transient_errors() {
+        // GIVEN
+        let (events, on_progress) = recorder();
+        let loader: AsyncModelLoader<MockModelLoaderApi> = AsyncModelLoader::new(flaky_api(2, || status_error(503)))
+            .with_retry(fast_retry(3))
+            .on_progress(on_progress);
+        // WHEN
+        let model: Result<Model> = loader.load(&model_config("blip", "some-repo/test-model")).await;
+        // THEN
+        assert!(model.is_ok());
+        let attempts: Vec<u32> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                DownloadEvent::Retrying { filename, attempt, .. } if filename == "model.safetensors" => Some(*attempt),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(attempts, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_async_model_loader_gives_up_after_max_attempts() {
+        // GIVEN
+        let (events, on_progress) = recorder();
+        let loader: AsyncModelLoader<MockModelLoaderApi> =
+            AsyncModelLoader::new(flaky_api(3, || ApiError::IoError(ErrorKind::ConnectionReset.into())))
+                .with_retry(fast_retry(3))
+                .on_progress(on_progress);
+        // WHEN
+        let model: Result<Model> = loader.load(&model_config("blip", "some-repo/test-model")).await;
+        // THEN
+        assert!(matches!(model, Err(ModelLoaderError::ApiError(ApiError::IoError(ref e))) if e.kind() == ErrorKind::ConnectionReset));
+        assert!(events.lock().unwrap().iter().any(|event| matches!(event, DownloadEvent::Failed { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_async_model_loader_does_not_retry_permanent_errors() {
+        // GIVEN
+        let (events, on_progress) = recorder();
+        let loader: AsyncModelLoader<MockModelLoaderApi> = AsyncModelLoader::new(flaky_api(1, || status_error(404)))
+            .with_retry(fast_retry(3))
+            .on_progress(on_progress);
+        // WHEN
+        let model: Result<Model> = loader.load(&model_config("blip", "some-repo/test-model")).await;
+        // THEN
+        assert!(matches!(model, Err(ModelLoaderError::ApiError(ApiError::RequestError(_)))));
+        assert!(!events.lock().unwrap().iter().any(|event| matches!(event, DownloadEvent::Retrying { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_async_model_loader_load_all_in_parallel() {
+        // GIVEN
+        let running: Arc<AtomicUsize> = Arc::default();
+        let max_running: Arc<AtomicUsize> = Arc::default();
+        let mut mock_api = MockModelLoaderApi::new();
+        let (api_running, api_max_running) = (Arc::clone(&running), Arc::clone(&max_running));
+        mock_api
+            .expect_model()
+            .times(2)
+            .returning(move |repository| {
+                let (running, max_running) = (Arc::clone(&api_running), Arc::clone(&api_max_running));
+                let mut mock_repo = MockModelLoaderApiRepo::new();
+                mock_repo
+                    .expect_get()
+                    .times(2)
+                    .returning(move |filename| {
+                        let now_running: usize = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_running.fetch_max(now_running, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(100));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        Ok(PathBuf::from(&repository).join(filename))
+                    });
+
+                mock_repo
+            });
+        let loader: AsyncModelLoader<MockModelLoaderApi> = AsyncModelLoader::new(mock_api);
+        // WHEN
+        let models: Models = loader
+            .load_all(vec![model_config("blip", "some-repo/blip"), model_config("blip_vqa", "some-repo/blip-vqa")])
+            .await
+            .unwrap();
+        // THEN
+        assert_eq!(models.len(), 2);
+        assert_eq!(models["blip_vqa"].model_path().to_str(), Some("some-repo/blip-vqa/model.safetensors"));
+        // Calls to the same mocked repository are serialized by mockall, so more than one
+        // download in flight means both models were downloaded at the same time
+        assert!(max_running.load(Ordering::SeqCst) > 1);
+    }
+
+    #[tokio::test]
+    async fn test_async_model_loader_load_all_duplicate_id() {
+        // GIVEN
+        let mut mock_api = MockModelLoaderApi::new();
+        mock_api.expect_model().never();
+        let loader: AsyncModelLoader<MockModelLoaderApi> = AsyncModelLoader::new(mock_api);
+        // WHEN
+        let result: Result<Models> = loader
+            .load_all(vec![model_config("blip", "some-repo/test-model"), model_config("blip", "another-repo/another-model")])
+            .await;
+        // THEN
+        assert!(matches!(result, Err(ModelLoaderError::DuplicateModelId(ref id)) if id == "blip"));
+    }
+
+    #[test]
+    fn test_retry_config_backoff() {
+        // GIVEN
+        let retry = RetryConfig { max_attempts: 10, initial_backoff: Duration::from_millis(500), max_backoff: Duration::from_secs(3) };
+        // WHEN
+        let delays: Vec<Duration> = (1..=5).map(|attempt| retry.backoff(attempt)).collect();
+        // THEN
+        assert_eq!(delays, [500, 1000, 2000, 3000, 3000].map(Duration::from_millis));
+    }
+
+    #[test]
+    fn test_is_transient() {
+        // GIVEN
+        let errors: Vec<ApiError> = vec![
+            status_error(429),
+            status_error(502),
+            status_error(404),
+            status_error(401),
+            ApiError::IoError(ErrorKind::ConnectionReset.into()),
+            ApiError::IoError(ErrorKind::NotFound.into()),
+        ];
+        // WHEN
+        let transient: Vec<bool> = errors.iter().map(is_transient).collect();
+        // THEN
+        assert_eq!(transient, [true, true, false, false, true, false]);
+    }
+
+    #[test]
+    fn test_model_loader_load_continues_after_missing_file() {
+        // GIVEN
+        let mut mock_api = MockModelLoaderApi::new();
+        mock_api
+            .expect_model()
+            .times(1)
+            .returning(|_| {
+                let mut mock_repo = MockModelLoaderApiRepo::new();
+                mock_repo
+                    .expect_get()
+                    .with(predicate::eq("model.safetensors"))
+                    .times(1)
+                    .return_once(|_| Err(ApiError::IoError(io::Error::new(ErrorKind::NotFound, "model.safetensors is missing"))));
+                mock_repo
+                    .expect_get()
+                    .with(predicate::eq("tokenizer.json"))
+                    .times(1)
+                    .return_once(|_| Ok(PathBuf::from("some/path/tokenizer.json")));
+
+                mock_repo
+            });
+        let loader: ModelLoader<MockModelLoaderApi> = ModelLoader::new(mock_api);
+        // WHEN
+        let result: Result<Model> = loader.load(&model_config("blip", "some-repo/test-model"));
+        // THEN
+        assert!(matches!(result, Err(ModelLoaderError::MissingFiles(ref files)) if files == &["blip: model.safetensors is missing"]));
+    }
+
+    #[test]
+    #[ignore = "Interacts with the filesystem"]
+    fn test_model_loader_load_directory_source() {
+        // GIVEN
+        let dir: TempDir = TempDir::new().unwrap();
+        fs::write(dir.path().join("model.safetensors"), b"weights").unwrap();
+        fs::write(dir.path().join("tokenizer.json"), b"{}").unwrap();
+        let mut mock_api = MockModelLoaderApi::new();
+        mock_api.expect_model().never();
+        mock_api.expect_repo().never();
+        let model_cfg = ModelConfig {
+            source: ModelSource::Directory,
+            ..model_config("blip", dir.path().to_str().unwrap())
+        };
+        // WHEN
+        let loader: ModelLoader<MockModelLoaderApi> = ModelLoader::new(mock_api);
+        let model: Model = loader.load(&model_cfg).unwrap();
+        // THEN
+        assert_eq!(model.model_path(), &dir.path().join("model.safetensors"));
+        assert_eq!(model.tokenizer_path(), &dir.path().join("tokenizer.json"));
+    }
+
+    #[test]
+    fn test_model_loader_load_missing_local_files() {
+        // GIVEN
+        let model_cfg = ModelConfig {
+            source: ModelSource::Directory,
+            ..model_config("blip", "/nonexistent/blip")
+        };
+        let loader: ModelLoader<MockModelLoaderApi> = ModelLoader::new(MockModelLoaderApi::new());
+        // WHEN
+        let result: Result<Model> = loader.load(&model_cfg);
+        // THEN
+        let Err(ModelLoaderError::MissingFiles(files)) = result else { panic!("Expected missing files, got {:?}", result) };
+        assert_eq!(files, [
+            "blip: /nonexistent/blip/model.safetensors does not exist",
+            "blip: /nonexistent/blip/tokenizer.json does not exist",
+        ]);
+    }
+
+    #[test]
+    fn test_model_loader_load_files_source_relative_path() {
+        // GIVEN
+        let model_cfg: ModelConfig = toml::from_str(r#"
+            id = "blip"
+            source = "files"
+            repository = "blip-image-captioning-large"
+            model = "/models/blip/model.safetensors"
+            tokenizer = "tokenizer.json"
+        "#).unwrap();
+        let loader: ModelLoader<MockModelLoaderApi> = ModelLoader::new(MockModelLoaderApi::new());
+        // WHEN
+        let result: Result<Model> = loader.load(&model_cfg);
+        // THEN
+        assert_eq!(model_cfg.source, ModelSource::Files);
+        assert!(matches!(result, Err(ModelLoaderError::RelativePath(ref path)) if path == "tokenizer.json"));
+    }
+
+    #[test]
+    #[ignore = "Interacts with the filesystem"]
+    fn test_offline_api_resolves_files_from_cache() {
+        // GIVEN
+        let cache_dir: TempDir = TempDir::new().unwrap();
+        let repo_dir: PathBuf = cache_dir.path().join("models--some-repo--test-model");
+        fs::create_dir_all(repo_dir.join("refs")).unwrap();
+        fs::create_dir_all(repo_dir.join("snapshots/abc123")).unwrap();
+        fs::write(repo_dir.join("refs/main"), "abc123").unwrap();
+        fs::write(repo_dir.join("snapshots/abc123/model.safetensors"), b"weights").unwrap();
+        let api = OfflineApi::new(Cache::new(cache_dir.path().to_path_buf()));
+        // WHEN
+        let repo: CacheRepo = api.model("some-repo/test-model".to_string());
+        let model_path: Result<PathBuf, ApiError> = ModelLoaderApiRepo::get(&repo, "model.safetensors");
+        let tokenizer_path: Result<PathBuf, ApiError> = ModelLoaderApiRepo::get(&repo, "tokenizer.json");
+        // THEN
+        assert_eq!(model_path.unwrap(), repo_dir.join("snapshots/abc123/model.safetensors"));
+        assert!(matches!(tokenizer_path, Err(ApiError::IoError(ref e)) if e.kind() == ErrorKind::NotFound));
+    }
+
+    #[tokio::test]
+    async fn test_async_model_loader_load_all_lists_missing_files() {
+        // GIVEN
+        let (events, on_progress) = recorder();
+        let mut mock_api = MockModelLoaderApi::new();
+        mock_api.expect_model().never();
+        let loader: AsyncModelLoader<MockModelLoaderApi> = AsyncModelLoader::new(mock_api).on_progress(on_progress);
+        let model_cfgs: Vec<ModelConfig> = ["blip", "blip_vqa"]
+            .into_iter()
+            .map(|id| ModelConfig { source: ModelSource::Directory, ..model_config(id, &format!("/nonexistent/{}", id)) })
+            .collect();
+        // WHEN
+        let result: Result<Models> = loader.load_all(model_cfgs).await;
+        // THEN
+        let Err(ModelLoaderError::MissingFiles(files)) = result else { panic!("Expected missing files, got {:?}", result) };
+        assert_eq!(files, [
+            "blip: /nonexistent/blip/model.safetensors does not exist",
+            "blip: /nonexistent/blip/tokenizer.json does not exist",
+            "blip_vqa: /nonexistent/blip_vqa/model.safetensors does not exist",
+            "blip_vqa: /nonexistent/blip_vqa/tokenizer.json does not exist",
+        ]);
+        // Missing files are not retried
+        assert!(!events.lock().unwrap().iter().any(|event| matches!(event, DownloadEvent::Retrying { .. })));
+    }
+
+    fn sha256_hex(bytes: &[u8]) -> String {
+        Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn test_model_config_checksums_from_toml() {
+        // GIVEN
+        let config_str: &str = r#"
+            id = "test-model"
+            repository = "some-repo/test-model"
+            model = "model.safetensors"
+            tokenizer = "tokenizer.json"
+            checksums.model = { sha256 = "abc123", size = 7 }
+        "#;
+        // WHEN
+        let model_cfg: ModelConfig = toml::from_str(config_str).unwrap();
+        // THEN
+        assert_eq!(model_cfg.checksums, Checksums {
+            model: Some(FileChecksum { sha256: "abc123".to_string(), size: Some(7) }),
+            tokenizer: None,
+        });
+    }
+
+    #[test]
+    #[ignore = "Interacts with the filesystem"]
+    fn test_file_checksum_verify() {
+        // GIVEN
+        let mut file: NamedTempFile = NamedTempFile::new().unwrap();
+        file.write_all(b"weights").unwrap();
+        let digest: String = sha256_hex(b"weights");
+        let checksums: [FileChecksum; 4] = [
+            FileChecksum { sha256: digest.clone(), size: Some(7) },
+            FileChecksum { sha256: digest.to_uppercase(), size: None },
+            FileChecksum { sha256: digest.clone(), size: Some(8) },
+            FileChecksum { sha256: sha256_hex(b"tampered"), size: None },
+        ];
+        // WHEN
+        let results: Vec<Result<()>> = checksums.iter().map(|checksum| checksum.verify("blip", file.path())).collect();
+        // THEN
+        assert!(results[0].is_ok());
+        assert!(results[1].is_ok());
+        assert!(matches!(
+            results[2],
+            Err(ModelLoaderError::ChecksumMismatch { ref expected, ref actual, .. }) if expected == "8 bytes" && actual == "7 bytes"
+        ));
+        assert!(matches!(
+            results[3],
+            Err(ModelLoaderError::ChecksumMismatch { ref model_id, ref actual, .. }) if model_id == "blip" && actual == &format!("SHA-256 {}", digest)
+        ));
+    }
+
+    #[test]
+    #[ignore = "Interacts with the filesystem"]
+    fn test_model_loader_load_checksum_mismatch() {
+        // GIVEN
+        let dir: TempDir = TempDir::new().unwrap();
+        fs::write(dir.path().join("model.safetensors"), b"tampered").unwrap();
+        fs::write(dir.path().join("tokenizer.json"), b"{}").unwrap();
+        let model_cfg = ModelConfig {
+            source: ModelSource::Directory,
+            checksums: Checksums {
+                model: Some(FileChecksum { sha256: sha256_hex(b"weights"), size: None }),
+                tokenizer: Some(FileChecksum { sha256: sha256_hex(b"{}"), size: Some(2) }),
+            },
+            ..model_config("blip", dir.path().to_str().unwrap())
+        };
+        let loader: ModelLoader<MockModelLoaderApi> = ModelLoader::new(MockModelLoaderApi::new());
+        // WHEN
+        let result: Result<Model> = loader.load(&model_cfg);
+        // THEN
+        assert!(matches!(
+            result,
+            Err(ModelLoaderError::ChecksumMismatch { ref path, .. }) if path == &dir.path().join("model.safetensors")
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "Interacts with the filesystem"]
+    async fn test_async_model_loader_load_verifies_checksums() {
+        // GIVEN
+        let dir: TempDir = TempDir::new().unwrap();
+        fs::write(dir.path().join("model.safetensors"), b"weights").unwrap();
+        fs::write(dir.path().join("tokenizer.json"), b"{}").unwrap();
+        let checksums = Checksums {
+            model: Some(FileChecksum { sha256: sha256_hex(b"weights"), size: Some(7) }),
+            tokenizer: Some(FileChecksum { sha256: sha256_hex(b"{}"), size: None }),
+        };
+        let valid_cfg = ModelConfig { source: ModelSource::Directory, checksums, ..model_config("blip", dir.path().to_str().unwrap()) };
+        let mut tampered_cfg: ModelConfig = valid_cfg.clone();
+        tampered_cfg.checksums.tokenizer = Some(FileChecksum { sha256: sha256_hex(b"[]"), size: None });
+        let loader: AsyncModelLoader<MockModelLoaderApi> = AsyncModelLoader::new(MockModelLoaderApi::new());
+        // WHEN
+        let valid: Result<Model> = loader.load(&valid_cfg).await;
+        let tampered: Result<Model> = loader.load(&tampered_cfg).await;
+        // THEN
+        assert!(valid.is_ok());
+        assert!(matches!(
+            tampered,
+            Err(ModelLoaderError::ChecksumMismatch { ref path, .. }) if path == &dir.path().join("tokenizer.json")
+        ));
+    }
+
+    #[test]
+    fn test_model_configs_from_toml_str() {
+        // GIVEN
+        let config_str: &str = r#"
+            [batching]
+            max_batch_size = 8
+
+            [[model]]
+            id = "blip"
+            repository = "some-repo/test-model"
+            model = "model.safetensors"
+            tokenizer = "tokenizer.json"
+
+            [[model]]
+            id = "blip_quantized"
+            repository = "another-repo/another-model"
+            model = "model.gguf"
+            tokenizer = "tokenizer.json"
+            architecture = "quantized_blip"
+        "#;
+        // WHEN
+        let model_cfgs: Vec<ModelConfig> = model_configs_from_toml_str(config_str).unwrap();
+        let duplicate: Result<Vec<ModelConfig>> = model_configs_from_toml_str(&config_str.replace("blip_quantized", "blip"));
+        // THEN
+        let ids: Vec<&str> = model_cfgs.iter().map(ModelConfig::id).collect();
+        assert_eq!(ids, ["blip", "blip_quantized"]);
+        assert_eq!(model_cfgs[1].architecture, Architecture::QuantizedBlip);
+        assert!(matches!(duplicate, Err(ModelLoaderError::DuplicateModelId(ref id)) if id == "blip"));
+    }
+
+    #[test]
+    fn test_model_configs_from_toml_str_without_id() {
+        // GIVEN
+        let config_str: &str = r#"
+            [[model]]
+            repository = "Salesforce/blip-image-captioning-large"
+            model = "model.safetensors"
+            tokenizer = "tokenizer.json"
+        "#;
+        // WHEN
+        let result: Result<Vec<ModelConfig>> = model_configs_from_toml_str(config_str);
+        // THEN
+        let error: ModelLoaderError = result.unwrap_err();
+        assert!(matches!(error, ModelLoaderError::ParseError(_)));
+        assert!(error.to_string().contains("missing field `id`"), "{}", error);
+    }
+
+    #[test]
+    fn test_model_configs_from_toml_str_architecture_mismatch() {
+        // GIVEN
+        let config_str: &str = r#"
+            [[model]]
+            id = "blip_quantized"
+            repository = "lmz/candle-blip"
+            model = "blip-image-captioning-large-q80.gguf"
+            tokenizer = "tokenizer.json"
+        "#;
+        // WHEN
+        let gguf_as_blip: Result<Vec<ModelConfig>> = model_configs_from_toml_str(config_str);
+        let safetensors_as_quantized: Result<Vec<ModelConfig>> = model_configs_from_toml_str(
+            &config_str.replace("\"blip-image-captioning-large-q80.gguf\"", "\"model.safetensors\"\narchitecture = \"quantized_blip\""),
+        );
+        // THEN
+        assert!(matches!(
+            gguf_as_blip,
+            Err(ModelLoaderError::ArchitectureMismatch { ref model_id, architecture: "blip", .. }) if model_id == "blip_quantized"
+        ));
+        assert!(matches!(
+            safetensors_as_quantized,
+            Err(ModelLoaderError::ArchitectureMismatch { architecture: "quantized_blip", .. })
+        ));
+    }
+
     #[test]
     fn test_model_loader_load_from_toml_io_error() {
         // GIVEN
@@ -0,0 +1,198 @@
+//! This module describes the kinds of models that can be declared in the models configuration file.
+//!
+//! Every `[[model]]` entry names an [`Architecture`], a [`ConfigPreset`] and a [`ModelDType`], which
+//! tell the [`ImageProcessor`](crate::image_captioning::ImageProcessor) how to build the model from
+//! the downloaded weights. Models are referenced by their registry id, so they can be added or
+//! removed by editing the configuration file only.
+use serde::Deserialize;
+use candle_core::DType;
+use candle_transformers::models::blip;
+use crate::proto::{ModelTask, ModelType};
+use crate::image_captioning::blip_vqa;
+
+/// The model architecture, which determines how the weights are loaded and what the model can do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Architecture {
+    /// BLIP for image captioning, loaded from `safetensors` weights.
+    #[default]
+    Blip,
+    /// Quantized BLIP for image captioning, loaded from `gguf` weights.
+    QuantizedBlip,
+    /// BLIP for visual question answering, loaded from `safetensors` weights.
+    BlipVqa,
+}
+
+impl Architecture {
+    /// Returns `true` if models of this architecture generate image captions.
+    pub fn supports_captioning(self) -> bool {
+        matches!(self, Self::Blip | Self::QuantizedBlip)
+    }
+
+    /// Returns `true` if models of this architecture answer questions about images.
+    pub fn supports_question_answering(self) -> bool {
+        matches!(self, Self::BlipVqa)
+    }
+
+    /// Returns `true` if models of this architecture are loaded from quantized weights.
+    pub fn is_quantized(self) -> bool {
+        matches!(self, Self::QuantizedBlip)
+    }
+
+    /// Returns the name of the architecture, as written in the models configuration file.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Blip => "blip",
+            Self::QuantizedBlip => "quantized_blip",
+            Self::BlipVqa => "blip_vqa",
+        }
+    }
+
+    /// Returns the tasks that models of this architecture can perform.
+    pub fn tasks(self) -> Vec<ModelTask> {
+        let mut tasks: Vec<ModelTask> = Vec::new();
+        if self.supports_captioning() {
+            tasks.push(ModelTask::ImageCaptioning);
+        }
+        if self.supports_question_answering() {
+            tasks.push(ModelTask::VisualQuestionAnswering);
+        }
+
+        tasks
+    }
+}
+
+/// A named set of hyperparameters matching one of the published BLIP checkpoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfigPreset {
+    /// `Salesforce/blip-image-captioning-large` (ViT-L vision model).
+    #[default]
+    ImageCaptioningLarge,
+    /// `Salesforce/blip-image-captioning-base` (ViT-B vision model).
+    ImageCaptioningBase,
+    /// `Salesforce/blip-vqa-base` (ViT-B vision model).
+    VqaBase,
+}
+
+impl ConfigPreset {
+    /// Returns the BLIP configuration of the preset.
+    pub fn blip_config(self) -> blip::Config {
+        match self {
+            Self::ImageCaptioningLarge => blip::Config::image_captioning_large(),
+            // The base captioning and VQA checkpoints share the same hyperparameters
+            Self::ImageCaptioningBase | Self::VqaBase => blip_vqa::vqa_base_config(),
+        }
+    }
+}
+
+/// The data type the weights are converted to when the model is loaded.
+/// Quantized models keep the data types stored in the `gguf` file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelDType {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+impl From<ModelDType> for DType {
+    fn from(dtype: ModelDType) -> Self {
+        match dtype {
+            ModelDType::F32 => DType::F32,
+            ModelDType::F16 => DType::F16,
+            ModelDType::Bf16 => DType::BF16,
+        }
+    }
+}
+
+/// Returns the registry id a legacy [`ModelType`] refers to.
+///
+/// Requests that do not set a model id select the model by [`ModelType`], which maps to the
+/// lowercase name of the enum variant (e.g. `BLIP_QUANTIZED` selects the `blip_quantized` model).
+pub fn legacy_model_id(model_type: ModelType) -> String {
+    model_type.as_str_name().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Entry {
+        architecture: Architecture,
+        config: ConfigPreset,
+        dtype: ModelDType,
+    }
+
+    #[test]
+    fn test_registry_deserialize() {
+        // GIVEN
+        let toml_str: &str = r#"
+            architecture = "quantized_blip"
+            config = "vqa_base"
+            dtype = "bf16"
+        "#;
+        // WHEN
+        let entry: Entry = toml::from_str(toml_str).unwrap();
+        // THEN
+        assert_eq!(entry.architecture, Architecture::QuantizedBlip);
+        assert_eq!(entry.config, ConfigPreset::VqaBase);
+        assert_eq!(DType::from(entry.dtype), DType::BF16);
+    }
+
+    #[test]
+    fn test_registry_deserialize_unknown_architecture() {
+        // GIVEN
+        let toml_str: &str = r#"
+            architecture = "kosmos"
+            config = "vqa_base"
+            dtype = "f32"
+        "#;
+        // WHEN
+        let result: Result<Entry, toml::de::Error> = toml::from_str(toml_str);
+        // THEN
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_architecture_capabilities() {
+        // WHEN + THEN
+        assert!(Architecture::Blip.supports_captioning());
+        assert!(Architecture::QuantizedBlip.supports_captioning());
+        assert!(!Architecture::BlipVqa.supports_captioning());
+        assert!(Architecture::BlipVqa.supports_question_answering());
+        assert!(Architecture::QuantizedBlip.is_quantized());
+        assert!(!Architecture::Blip.is_quantized());
+    }
+
+    #[test]
+    fn test_architecture_names_match_config() {
+        // GIVEN
+        let architectures: [Architecture; 3] = [Architecture::Blip, Architecture::QuantizedBlip, Architecture::BlipVqa];
+        for architecture in architectures {
+            // WHEN
+            let toml_str: String = format!("architecture = {:?}\nconfig = \"vqa_base\"\ndtype = \"f32\"", architecture.as_str());
+            let entry: Entry = toml::from_str(&toml_str).unwrap();
+            // THEN
+            assert_eq!(entry.architecture, architecture);
+        }
+    }
+
+    #[test]
+    fn test_architecture_tasks() {
+        // WHEN + THEN
+        assert_eq!(Architecture::Blip.tasks(), vec![ModelTask::ImageCaptioning]);
+        assert_eq!(Architecture::QuantizedBlip.tasks(), vec![ModelTask::ImageCaptioning]);
+        assert_eq!(Architecture::BlipVqa.tasks(), vec![ModelTask::VisualQuestionAnswering]);
+    }
+
+    #[test]
+    fn test_legacy_model_id() {
+        // WHEN + THEN
+        assert_eq!(legacy_model_id(ModelType::Blip), "blip");
+        assert_eq!(legacy_model_id(ModelType::BlipQuantized), "blip_quantized");
+        assert_eq!(legacy_model_id(ModelType::BlipVqa), "blip_vqa");
+    }
+}
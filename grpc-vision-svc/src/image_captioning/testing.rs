@@ -0,0 +1,167 @@
+//! This module provides an [`ImageProcessor`] with tiny BLIP models, so that the processing of
+//! requests can be tested without downloading a checkpoint.
+//!
+//! The models have random weights, so their captions and answers are meaningless, but they go
+//! through the same vision model, text decoder and tokenizer as the real models. The vocabulary
+//! of the tokenizer is `[UNK]` followed by the words `w1`, `w2`, ... up to [`VOCAB_SIZE`].
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::str::FromStr;
+use std::sync::Arc;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{Activation, VarBuilder, VarMap};
+use candle_transformers::models::{blip, blip_text};
+use image::{ImageBuffer, ImageFormat, Rgb};
+use tokenizers::Tokenizer;
+use crate::concurrency::ConcurrencyConfig;
+use crate::image_captioning::{ImageProcessor, LoadedModel, ModelVariant};
+use crate::image_captioning::blip_decoder::TextDecoder;
+use crate::image_captioning::blip_vqa::{self, BlipForQuestionAnswering};
+use crate::image_captioning::cache::{CacheConfig, InferenceCache};
+use crate::image_captioning::decoder_pool::DecoderPool;
+use crate::image_captioning::model_loader::{Model, ModelConfig};
+use crate::image_captioning::registry::Architecture;
+use crate::metrics::{metrics, CacheKind};
+
+/// Registry id of the tiny captioning model.
+pub const CAPTIONING_MODEL_ID: &str = "blip";
+
+/// Registry id of the tiny VQA model.
+pub const VQA_MODEL_ID: &str = "blip_vqa";
+
+/// Size of the vocabulary, which must include the BOS token id of the real models.
+pub const VOCAB_SIZE: usize = 30524;
+
+/// Returns the configuration of the tiny models. The input resolution is the one of the real
+/// models, as images are always resized to it.
+fn tiny_config() -> blip::Config {
+    let mut config: blip::Config = blip_vqa::vqa_base_config();
+    config.text_config = blip_text::Config {
+        vocab_size: VOCAB_SIZE,
+        hidden_size: 8,
+        encoder_hidden_size: 8,
+        intermediate_size: 16,
+        num_hidden_layers: 1,
+        num_attention_heads: 2,
+        max_position_embeddings: 64,
+        hidden_act: Activation::Gelu,
+        ..config.text_config
+    };
+    config.vision_config = blip::VisionConfig {
+        hidden_size: 8,
+        intermediate_size: 16,
+        num_hidden_layers: 1,
+        num_attention_heads: 2,
+        patch_size: 128,
+        ..config.vision_config
+    };
+    config
+}
+
+/// Returns a word level tokenizer splitting on whitespace.
+fn tokenizer() -> Tokenizer {
+    let mut vocab: serde_json::Map<String, serde_json::Value> = serde_json::Map::with_capacity(VOCAB_SIZE);
+    vocab.insert("[UNK]".to_string(), 0.into());
+    for id in 1..VOCAB_SIZE {
+        vocab.insert(format!("w{}", id), id.into());
+    }
+    let tokenizer_json = serde_json::json!({
+        "pre_tokenizer": { "type": "Whitespace" },
+        "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "[UNK]" },
+    });
+
+    Tokenizer::from_str(&tokenizer_json.to_string()).unwrap()
+}
+
+/// Builds a tiny model of the given architecture with random weights.
+fn tiny_model(id: &str, architecture: Architecture) -> LoadedModel {
+    let device = Device::Cpu;
+    let config: blip::Config = tiny_config();
+    let varmap = VarMap::new();
+    let vb: VarBuilder = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let decoder: TextDecoder = TextDecoder::new(&config.text_config, vb.pp("text_decoder")).unwrap();
+    let variant: ModelVariant = match architecture {
+        Architecture::BlipVqa => ModelVariant::BlipVqa(BlipForQuestionAnswering::new(&config, vb).unwrap()),
+        _ => ModelVariant::Blip(blip::BlipForConditionalGeneration::new(&config, vb).unwrap()),
+    };
+    // Some weights are initialized to zero or one, which would make every token equally likely
+    for var in varmap.all_vars() {
+        var.set(&Tensor::randn(0f32, 1f32, var.shape(), &device).unwrap()).unwrap();
+    }
+    let model_cfg: ModelConfig = toml::from_str(&format!(
+        "id = {:?}\nrepository = \"tiny/{}\"\nmodel = \"model.safetensors\"\ntokenizer = \"tokenizer.json\"\narchitecture = {:?}",
+        id, id, architecture.as_str(),
+    ))
+    .unwrap();
+
+    LoadedModel {
+        id: id.to_string(),
+        decoders: Arc::new(DecoderPool::new(decoder, ConcurrencyConfig::default().max_concurrent_model_requests(id))),
+        variant,
+        tokenizer: tokenizer(),
+        architecture,
+        dtype: DType::F32,
+        source: Model::new(model_cfg, "model.safetensors".into(), "tokenizer.json".into()),
+        quantization: None,
+        input_resolution: config.vision_config.image_size,
+    }
+}
+
+/// Returns an [`ImageProcessor`] with the tiny [`CAPTIONING_MODEL_ID`] and [`VQA_MODEL_ID`] models.
+pub fn tiny_processor() -> ImageProcessor {
+    processor_of(&[(CAPTIONING_MODEL_ID, Architecture::Blip), (VQA_MODEL_ID, Architecture::BlipVqa)])
+}
+
+/// Returns an [`ImageProcessor`] with a single tiny captioning model registered under `model_id`.
+///
+/// Tests counting the lookups of the inference cache use it to get a model of their own.
+pub fn tiny_captioning_processor(model_id: &str) -> ImageProcessor {
+    processor_of(&[(model_id, Architecture::Blip)])
+}
+
+/// Returns an [`ImageProcessor`] with a tiny model per registry id and architecture.
+fn processor_of(models: &[(&str, Architecture)]) -> ImageProcessor {
+    let models: HashMap<String, LoadedModel> = models
+        .iter()
+        .copied()
+        .map(|(id, architecture)| (id.to_string(), tiny_model(id, architecture)))
+        .collect();
+
+    ImageProcessor {
+        models,
+        device: Device::Cpu,
+        cache: Arc::new(InferenceCache::new(&CacheConfig::default())),
+    }
+}
+
+/// Returns a small PNG image. Images with different shades have different pixels.
+pub fn png_image(shade: u8) -> Vec<u8> {
+    let image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_fn(32, 32, |x, y| {
+        Rgb([shade, (x * 8) as u8, (y * 8) as u8])
+    });
+    let mut image_bytes: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    image.write_to(&mut image_bytes, ImageFormat::Png).unwrap();
+
+    image_bytes.into_inner()
+}
+
+/// Returns the number of lookups of the inference cache counted by the process wide metrics.
+///
+/// The metrics are shared by all tests, so every test counting lookups must use its own model ids.
+///
+/// # Arguments
+///
+/// * `kind` - The [`CacheKind`] of the looked up entries.
+/// * `model_id` - The registry id of the model.
+/// * `result` - `hit` or `miss`.
+pub fn cache_lookups(kind: CacheKind, model_id: &str, result: &str) -> u64 {
+    let prefix: String = format!(
+        r#"vision_cache_lookups_total{{kind="{}",model="{}",result="{}"}} "#,
+        kind.as_str(), model_id, result,
+    );
+    metrics()
+        .encode()
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .map_or(0, |count| count.parse().unwrap())
+}
@@ -100,7 +100,9 @@ impl TokenOutputStream {
     ///
     /// # Example
     ///
-    /// ```
+    /// ```no_run
+    /// # use tokenizers::Tokenizer;
+    /// # use grpc_vision_svc::image_captioning::token_output_stream::TokenOutputStream;
     /// // Assuming that the `tokenizer.json` file contains the following vocab:
     /// // { "hello": 1, "world": 2, "everybody": 3 }
     /// let tokenizer = Tokenizer::from_file("path/to/tokenizer.json").unwrap();
@@ -41,15 +41,17 @@ impl DeviceUtils for DefaultDeviceUtils {
 ///
 /// # Examples
 ///
-/// ```
+/// ```no_run
+/// # use candle_core::Device;
+/// # use grpc_vision_svc::image_captioning::utils::{device, DefaultDeviceUtils};
 /// // Select CPU as the computing device.
-/// let device: Device = device(true, &DefaultDeviceUtils).unwrap();
-/// assert!(device.is_cpu());
+/// let cpu: Device = device(true, &DefaultDeviceUtils).unwrap();
+/// assert!(cpu.is_cpu());
 ///
 /// // Select GPU (CUDA) as the computing device.
 /// // This example assumes that the `cuda` feature is enabled.
-/// let device: Device = device(false, &DefaultDeviceUtils).unwrap();
-/// assert!(matches!(device, Device::Cuda(_)));
+/// let gpu: Device = device(false, &DefaultDeviceUtils).unwrap();
+/// assert!(matches!(gpu, Device::Cuda(_)));
 /// ```
 pub fn device(cpu: bool, utils: &impl DeviceUtils) -> Result<Device> {
     if cpu {
@@ -86,14 +88,20 @@ pub fn device(cpu: bool, utils: &impl DeviceUtils) -> Result<Device> {
 /// # Returns
 ///
 /// * [`ImageResult<ImageBuffer<Rgb<u8>, Vec<u8>>>`] - An [`ImageResult`] containing the processed [`ImageBuffer`],
-/// or an [`image::ImageError`] if the image could not be processed.
+///   or an [`image::ImageError`] if the image could not be processed.
 ///
 /// # Examples
 ///
-/// ```
+/// ```no_run
+/// # use std::fs;
+/// # use image::{ImageBuffer, Rgb};
+/// # use grpc_vision_svc::image_captioning::utils::process_image;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
 /// let image_bytes: Vec<u8> = fs::read("path/to/image.jpg")?;
 /// let image_buffer: ImageBuffer<Rgb<u8>, Vec<u8>> = process_image(&image_bytes)?;
 /// image_buffer.save("path/to/save/processed_image.jpg")?;
+/// # Ok(())
+/// # }
 /// ```
 pub fn process_image(image_bytes: &[u8]) -> ImageResult<ImageBuffer<Rgb<u8>, Vec<u8>>> {
     let image_cursor: Cursor<&[u8]> = Cursor::new(image_bytes);
@@ -108,6 +116,22 @@ pub fn process_image(image_bytes: &[u8]) -> ImageResult<ImageBuffer<Rgb<u8>, Vec
     Ok(image_buf)
 }
 
+/// Reads the width and height of an image from its header, without decoding the pixels.
+///
+/// # Arguments
+///
+/// * `image_bytes` - A byte slice representing the image.
+///
+/// # Returns
+///
+/// * [`ImageResult<(u32, u32)>`] - An [`ImageResult`] containing the width and height of the image,
+///   or an [`image::ImageError`] if the format is not supported or the header cannot be read.
+pub fn image_dimensions(image_bytes: &[u8]) -> ImageResult<(u32, u32)> {
+    ImageReader::new(Cursor::new(image_bytes))
+        .with_guessed_format()?
+        .into_dimensions()
+}
+
 /// Creates a tensor from a byte slice representing pixel data.
 ///
 /// This function takes a byte slice and a [`Device`], creates a tensor from the raw buffer,
@@ -125,7 +149,12 @@ pub fn process_image(image_bytes: &[u8]) -> ImageResult<ImageBuffer<Rgb<u8>, Vec
 ///
 /// # Examples
 ///
-/// ```
+/// ```no_run
+/// # use candle_core::{Device, Tensor};
+/// # use image::DynamicImage;
+/// # use image::io::Reader as ImageReader;
+/// # use grpc_vision_svc::image_captioning::utils::create_tensor;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
 /// let image: DynamicImage = ImageReader::open("path/to/image.jpg")?
 ///     .decode()?;
 ///
@@ -133,6 +162,8 @@ pub fn process_image(image_bytes: &[u8]) -> ImageResult<ImageBuffer<Rgb<u8>, Vec
 /// let tensor: Tensor = create_tensor(&image_raw_buf, &Device::Cpu)?;
 /// 
 /// assert_eq!(tensor.shape().dims(), &[3, 384, 384]);
+/// # Ok(())
+/// # }
 /// ```
 pub fn create_tensor(pixels: &[u8], device: &Device) -> Result<Tensor> {
     let data = Tensor::from_raw_buffer(pixels, DType::U8, &[384, 384, 3], device)?
@@ -276,6 +307,22 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_image_dimensions() {
+        // GIVEN
+        let input_image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(640, 480);
+        let mut image_bytes: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        input_image
+            .write_to(&mut image_bytes, ImageFormat::Png)
+            .unwrap();
+        // WHEN
+        let dimensions: ImageResult<(u32, u32)> = image_dimensions(image_bytes.get_ref());
+        let invalid: ImageResult<(u32, u32)> = image_dimensions(&[0, 1, 2, 3, 4, 5]);
+        // THEN
+        assert_eq!(dimensions.unwrap(), (640, 480));
+        assert!(matches!(invalid.unwrap_err(), ImageError::Unsupported(_)));
+    }
+
     #[test]
     fn test_create_tensor_ok() {
         // GIVEN
@@ -8,8 +8,27 @@ pub mod proto {
     
     /// A constant that includes the file descriptor set for the vision service.
     pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("vision_svc_descriptor");
+
+    pub mod google {
+        pub mod rpc {
+            //! The `google.rpc` error model, used to attach structured details to the returned statuses.
+            tonic::include_proto!("google.rpc");
+        }
+    }
 }
 
+pub mod auth;
+pub mod batching;
+pub mod cancellation;
+pub mod concurrency;
+pub mod error_details;
+pub mod health;
 pub mod service_impl;
+pub mod telemetry;
+pub mod tls;
 pub mod image_captioning;
-//pub mod middleware;
+pub mod metrics;
+pub mod middleware;
+pub mod rate_limit;
+pub mod reload;
+pub mod admin;
@@ -1,19 +1,43 @@
 use std::env;
+use std::fs;
 use std::path::Path;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::task::{self, JoinHandle};
 use tonic::transport::Server;
 use tonic::codec::CompressionEncoding;
+use tower::Layer;
+use tower::util::option_layer;
 use tonic_reflection::server::Builder as ReflectionBuilder;
 use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use opentelemetry_sdk::trace::TracerProvider;
 use candle_core::Device;
-use hf_hub::api::sync::Api;
 use anyhow::{Context, Result};
 
 use grpc_vision_svc::proto::FILE_DESCRIPTOR_SET;
 use grpc_vision_svc::proto::computer_vision_server::ComputerVisionServer;
-use grpc_vision_svc::service_impl::ComputerVisionSvc;
+use grpc_vision_svc::proto::model_admin_server::ModelAdminServer;
+use grpc_vision_svc::admin::ModelAdminSvc;
+use grpc_vision_svc::auth::{AuthInterceptor, AuthLayer, JwtValidator};
+use grpc_vision_svc::batching::BatchingConfig;
+use grpc_vision_svc::concurrency::ConcurrencyConfig;
+use grpc_vision_svc::rate_limit::RateLimitConfig;
+use grpc_vision_svc::reload::{self, ModelReloader, ReloadSummary};
+use grpc_vision_svc::health::ModelReadiness;
+use grpc_vision_svc::metrics::{self, METRICS_PATH};
+use grpc_vision_svc::middleware::{ValidationLayer, ValidationMiddleware};
+use grpc_vision_svc::service_impl::{ComputerVisionSvc, ProcessorSlot};
+use grpc_vision_svc::telemetry;
+use grpc_vision_svc::tls::{self, ReloadingAcceptor, TlsConfig};
+use grpc_vision_svc::image_captioning::cache::CacheConfig;
+use grpc_vision_svc::image_captioning::model_loader::{self, ModelConfig};
 use grpc_vision_svc::image_captioning::utils::{self, DefaultDeviceUtils};
-use grpc_vision_svc::image_captioning::model_loader::{ModelLoader, Models};
+use grpc_vision_svc::image_captioning::generation::GenerationConfig;
 
 /// Retrieves the server address from the `VISION_ADDR` environment variable.
 /// Defaults to `[::1]:50051` if the variable is not set or has an invalid format.
@@ -24,6 +48,80 @@ fn get_server_address() -> SocketAddr {
         .unwrap_or_else(|| "[::1]:50051".parse().unwrap())
 }
 
+/// Retrieves the address of the Prometheus metrics endpoint from the `VISION_METRICS_ADDR` environment variable.
+/// Defaults to `[::1]:9090` if the variable is not set or has an invalid format.
+fn get_metrics_address() -> SocketAddr {
+    env::var("VISION_METRICS_ADDR")
+        .ok()
+        .and_then(|addr| addr.parse().ok())
+        .unwrap_or_else(|| "[::1]:9090".parse().unwrap())
+}
+
+/// Retrieves the URL of the OTLP gRPC endpoint spans are exported to from the `VISION_OTLP_ENDPOINT` environment variable
+/// (e.g. `http://localhost:4317`). Spans are not exported if the variable is not set.
+fn get_otlp_endpoint() -> Option<String> {
+    env::var("VISION_OTLP_ENDPOINT").ok().filter(|endpoint| !endpoint.is_empty())
+}
+
+/// Retrieves the TLS configuration of the gRPC server from the `VISION_TLS_CERT` and `VISION_TLS_KEY` environment variables
+/// (PEM files of the certificate chain and its private key) and the optional `VISION_TLS_CLIENT_CA` variable (PEM file of the CA
+/// client certificates must be signed by, which enables mutual TLS). The server serves plaintext if neither certificate nor key is set.
+fn get_tls_config() -> Result<Option<TlsConfig>> {
+    let path = |name: &str| env::var_os(name).filter(|path| !path.is_empty()).map(PathBuf::from);
+
+    match (path("VISION_TLS_CERT"), path("VISION_TLS_KEY")) {
+        (Some(cert_path), Some(key_path)) => Ok(Some(TlsConfig { cert_path, key_path, client_ca_path: path("VISION_TLS_CLIENT_CA") })),
+        (None, None) if path("VISION_TLS_CLIENT_CA").is_some() => {
+            anyhow::bail!(r#""VISION_TLS_CLIENT_CA" requires "VISION_TLS_CERT" and "VISION_TLS_KEY" to be set"#)
+        }
+        (None, None) => Ok(None),
+        _ => anyhow::bail!(r#"Both "VISION_TLS_CERT" and "VISION_TLS_KEY" must be set to enable TLS"#),
+    }
+}
+
+/// Retrieves the interval at which the TLS certificate files are checked for changes from the `VISION_TLS_RELOAD_SECS`
+/// environment variable. Defaults to 30 seconds if the variable is not set or is not a positive number of seconds.
+fn get_tls_reload_interval() -> Duration {
+    env::var("VISION_TLS_RELOAD_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|secs: &u64| *secs > 0)
+        .map(Duration::from_secs)
+        .unwrap_or(tls::DEFAULT_RELOAD_INTERVAL)
+}
+
+/// Builds the authentication of the callers from the `VISION_JWT_SECRET` environment variable (the HS256 secret shared with the
+/// auth service) or the `VISION_JWT_JWKS` variable (path to a JWKS file with the RS256 public keys of the auth service).
+/// Authentication is disabled if neither is set.
+fn get_auth_interceptor() -> Result<Option<AuthInterceptor>> {
+    let secret: Option<String> = env::var("VISION_JWT_SECRET").ok().filter(|secret| !secret.is_empty());
+    let jwks_path: Option<String> = env::var("VISION_JWT_JWKS").ok().filter(|path| !path.is_empty());
+
+    let validator: JwtValidator = match (secret, jwks_path) {
+        (Some(_), Some(_)) => anyhow::bail!(r#"Only one of "VISION_JWT_SECRET" and "VISION_JWT_JWKS" can be set"#),
+        (Some(secret), None) => JwtValidator::from_secret(secret.as_bytes()),
+        (None, Some(jwks_path)) => {
+            let jwks: String = fs::read_to_string(&jwks_path)
+                .with_context(|| format!("Failed to read JWKS file {:?}", jwks_path))?;
+            JwtValidator::from_jwks(&jwks)?
+        }
+        (None, None) => {
+            tracing::warn!(r#"Neither "VISION_JWT_SECRET" nor "VISION_JWT_JWKS" is set, callers are not authenticated"#);
+            return Ok(None);
+        }
+    };
+
+    Ok(Some(AuthInterceptor::new(validator)))
+}
+
+/// Exports the spans still buffered by the tracer provider, if any.
+/// Flushing blocks until the export completes, so it runs on the blocking thread pool.
+async fn flush_traces(tracer_provider: Option<TracerProvider>) {
+    if let Some(provider) = tracer_provider {
+        let _ = task::spawn_blocking(move || provider.force_flush()).await;
+    }
+}
+
 /// Retrieves the path to the models configuration file from the `VISION_MODELS_PATH` environment variable.
 /// If the variable is not set, it defaults to `models.toml` in the current directory.
 fn get_models_path() -> Result<String> {
@@ -38,6 +136,81 @@ fn get_models_path() -> Result<String> {
     })
 }
 
+/// Retrieves whether models are loaded offline from the `HF_HUB_OFFLINE` environment variable (`1` or `true`).
+/// Offline, the Hugging Face Hub is never contacted, and models of the Hub are only resolved from the local cache.
+fn get_hub_offline() -> bool {
+    env::var("HF_HUB_OFFLINE").is_ok_and(|offline| matches!(offline.to_lowercase().as_str(), "1" | "true"))
+}
+
+/// Retrieves the interval at which the models configuration file is checked for changes from the `VISION_MODELS_RELOAD_SECS`
+/// environment variable. Defaults to 30 seconds if the variable is not set or is not a positive number of seconds.
+fn get_models_reload_interval() -> Duration {
+    env::var("VISION_MODELS_RELOAD_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|secs: &u64| *secs > 0)
+        .map(Duration::from_secs)
+        .unwrap_or(reload::DEFAULT_WATCH_INTERVAL)
+}
+
+/// Retrieves the subjects allowed to call the model admin service from the `VISION_ADMIN_SUBJECTS` environment variable
+/// (a comma-separated list of `sub` claims of authenticated callers). No caller is an administrator by default.
+fn get_admin_subjects() -> Vec<String> {
+    env::var("VISION_ADMIN_SUBJECTS")
+        .map(|subjects| {
+            subjects
+                .split(',')
+                .map(str::trim)
+                .filter(|subject| !subject.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Retrieves whether callers without a subject may call the model admin service from the `VISION_ADMIN_ALLOW_ANONYMOUS`
+/// environment variable (`1` or `true`). Only meant for deployments without authentication, anonymous callers are denied by default.
+fn get_admin_allow_anonymous() -> bool {
+    env::var("VISION_ADMIN_ALLOW_ANONYMOUS").is_ok_and(|allow| matches!(allow.to_lowercase().as_str(), "1" | "true"))
+}
+
+/// Parses the registry ids of the `[[model]]` entries of the models configuration file, whose health
+/// entries are reported as `NOT_SERVING` until the models are loaded.
+fn get_model_ids(config_str: &str) -> Result<Vec<String>> {
+    let model_cfgs: Vec<ModelConfig> = model_loader::model_configs_from_toml_str(config_str)?;
+
+    Ok(model_cfgs.into_iter().map(|model_cfg: ModelConfig| model_cfg.id).collect())
+}
+
+/// Parses the default generation parameters from the `[generation]` table of the models configuration file.
+/// The defaults are validated up front, so that a misconfigured server fails at startup rather than on every request.
+fn get_generation_config(config_str: &str) -> Result<GenerationConfig> {
+    let config: GenerationConfig = GenerationConfig::from_toml_str(config_str)?;
+    config.resolve(None)?;
+
+    Ok(config)
+}
+
+/// Parses the dynamic batching parameters from the `[batching]` table of the models configuration file.
+fn get_batching_config(config_str: &str) -> Result<BatchingConfig> {
+    Ok(BatchingConfig::from_toml_str(config_str)?)
+}
+
+/// Parses the size of the cache of image embeddings and captions from the `[cache]` table of the models configuration file.
+fn get_cache_config(config_str: &str) -> Result<CacheConfig> {
+    Ok(CacheConfig::from_toml_str(config_str)?)
+}
+
+/// Parses the concurrency limits and the request queue parameters from the `[concurrency]` table of the models configuration file.
+fn get_concurrency_config(config_str: &str) -> Result<ConcurrencyConfig> {
+    Ok(ConcurrencyConfig::from_toml_str(config_str)?)
+}
+
+/// Parses the per-client rate limits from the `[rate_limit]` table of the models configuration file.
+fn get_rate_limit_config(config_str: &str) -> Result<RateLimitConfig> {
+    Ok(RateLimitConfig::from_toml_str(config_str)?)
+}
+
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
         .await
@@ -48,40 +221,119 @@ async fn shutdown_signal() {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .compact()
-        .with_target(false)
-        .with_env_filter(
+    let tracer_provider: Option<TracerProvider> = get_otlp_endpoint()
+        .map(|endpoint| telemetry::tracer_provider(&endpoint))
+        .transpose()
+        .context("Failed to create the OTLP span exporter")?;
+    tracing_subscriber::registry()
+        .with(
             EnvFilter::builder()
                 .with_default_directive(LevelFilter::INFO.into())
                 .from_env_lossy(),
         )
+        .with(tracing_subscriber::fmt::layer().compact().with_target(false))
+        .with(tracer_provider.as_ref().map(telemetry::layer))
         .init();
 
     let addr: SocketAddr = get_server_address();
+    let tls_config: Option<TlsConfig> = get_tls_config().context("Failed to read TLS config")?;
+    let auth: Option<AuthInterceptor> = get_auth_interceptor().context("Failed to configure authentication")?;
     let models_path: String = get_models_path().context("Failed to get models path")?;
+    let offline: bool = get_hub_offline();
+    if offline {
+        tracing::info!("Offline mode, models are only resolved from the Hugging Face cache and local files");
+    }
+    // The configuration file is read once, so that all tables come from the same version of it
+    let config_str: String = fs::read_to_string(&models_path)
+        .with_context(|| format!("Failed to read models config {:?}", models_path))?;
+    let generation: GenerationConfig = get_generation_config(&config_str)
+        .context("Failed to parse generation config")?;
+    let batching: BatchingConfig = get_batching_config(&config_str)
+        .context("Failed to parse batching config")?;
+    let cache: CacheConfig = get_cache_config(&config_str)
+        .context("Failed to parse cache config")?;
+    let concurrency: ConcurrencyConfig = get_concurrency_config(&config_str)
+        .context("Failed to parse concurrency config")?;
+    let rate_limit: RateLimitConfig = get_rate_limit_config(&config_str)
+        .context("Failed to parse rate limit config")?;
+    let model_ids: Vec<String> = get_model_ids(&config_str)
+        .context("Failed to parse models config")?;
 
-    let model_loader: ModelLoader<Api> = ModelLoader::new(Api::new()?);
-    let models: Models = model_loader.load_from_toml(&models_path)?;
     let device: Device = utils::device(false, &DefaultDeviceUtils)?;
 
+    let (health_reporter, health_svc) = tonic_health::server::health_reporter();
+    let mut readiness: ModelReadiness = ModelReadiness::new(health_reporter);
+    readiness.set_warming_up(model_ids.iter().map(String::as_str)).await;
+
     let reflection_svc = ReflectionBuilder::configure()
         .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
         .build()?;
 
-    let vision_svc: ComputerVisionServer<ComputerVisionSvc> = ComputerVisionServer::new(ComputerVisionSvc::new(&models, device)?)
+    let processor = ProcessorSlot::default();
+    let vision_svc: ComputerVisionServer<ComputerVisionSvc> = ComputerVisionServer::new(ComputerVisionSvc::with_processor_slot(processor.clone(), generation, batching, concurrency.clone(), rate_limit))
         .max_decoding_message_size(12 * 1024 * 1024)
         .send_compressed(CompressionEncoding::Gzip)
         .accept_compressed(CompressionEncoding::Gzip);
+    let vision_svc: ValidationMiddleware<_> = ValidationLayer::new()
+        .accept_encoding("gzip")
+        .layer(vision_svc);
 
-    tracing::info!(addr = %addr, "Starting gRPC server...");
+    let reloader: Arc<ModelReloader> = Arc::new(ModelReloader::new(&models_path, offline, device, cache, concurrency, processor, readiness));
+    let admin_svc: ModelAdminServer<ModelAdminSvc> = ModelAdminServer::new(ModelAdminSvc::new(reloader.clone(), get_admin_subjects(), get_admin_allow_anonymous()));
 
-    Server::builder()
-        .trace_fn(|request| tracing::debug_span!("grpc", ?request))
+    let metrics_addr: SocketAddr = get_metrics_address();
+    let metrics_listener: TcpListener = TcpListener::bind(metrics_addr)
+        .await
+        .context("Failed to bind metrics endpoint")?;
+    tracing::info!(addr = %metrics_addr, path = METRICS_PATH, "Serving metrics");
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(metrics_listener).await {
+            tracing::error!("Metrics endpoint stopped: {:?}", e);
+        }
+    });
+
+    let router = Server::builder()
+        .trace_fn(telemetry::request_span)
+        .layer(option_layer(auth.map(AuthLayer::new)))
+        .add_service(health_svc)
         .add_service(reflection_svc)
         .add_service(vision_svc)
-        .serve_with_shutdown(addr, shutdown_signal())
-        .await?;
+        .add_service(admin_svc);
+
+    let mut server: JoinHandle<Result<(), tonic::transport::Error>> = match tls_config {
+        Some(tls_config) => {
+            let acceptor: Arc<ReloadingAcceptor> = Arc::new(
+                ReloadingAcceptor::new(tls_config).context("Failed to load TLS certificates")?,
+            );
+            acceptor.watch(get_tls_reload_interval());
+            let listener: TcpListener = TcpListener::bind(addr)
+                .await
+                .context("Failed to bind gRPC server")?;
+            tracing::info!(addr = %addr, mutual_tls = acceptor.is_mutual(), "Starting gRPC server with TLS...");
+            tokio::spawn(router.serve_with_incoming_shutdown(acceptor.incoming(listener), shutdown_signal()))
+        }
+        None => {
+            tracing::info!(addr = %addr, "Starting gRPC server...");
+            tokio::spawn(router.serve_with_shutdown(addr, shutdown_signal()))
+        }
+    };
+
+    tokio::select! {
+        // The server stopped (e.g. on Ctrl-C or a bind error) before the models were ready
+        result = &mut server => {
+            flush_traces(tracer_provider).await;
+            return Ok(result??);
+        }
+        result = reloader.reload() => {
+            let summary: ReloadSummary = result.context("Failed to load models")?;
+            tracing::info!(models = ?summary.added, "Models are ready");
+        }
+    }
+    reloader.watch(get_models_reload_interval());
+
+    let result: Result<(), tonic::transport::Error> = server.await?;
+    flush_traces(tracer_provider).await;
 
-    Ok(())
+    Ok(result?)
 }
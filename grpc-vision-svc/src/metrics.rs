@@ -0,0 +1,387 @@
+//! This module provides the Prometheus metrics of the vision service and the HTTP endpoint exposing them.
+//!
+//! The metrics live in a process wide [`Metrics`] registry returned by [`metrics`], so that the
+//! request handlers, the [`ImageProcessor`] and the [`ConcurrencyLimiter`] can record them without
+//! passing a handle around. The following metrics are exported:
+//!
+//! * `vision_requests_total{rpc, model, code}` - completed requests by gRPC method, model and status code,
+//! * `vision_request_duration_seconds{rpc, model}` - latency of the requests,
+//! * `vision_stage_duration_seconds{stage, model}` - latency of the processing stages (see [`Stage`]),
+//! * `vision_generated_tokens_total{model}` - tokens generated by the text decoders,
+//! * `vision_cache_lookups_total{kind, model, result}` - hits and misses of the inference cache (see [`CacheKind`]),
+//! * `vision_queued_requests{scope}` - requests waiting for a slot of the server or of a model,
+//! * `vision_in_flight_requests` - requests holding a concurrency permit,
+//! * `vision_rate_limited_requests_total{budget}` - requests rejected by the [`RateLimiter`].
+//!
+//! [`ImageProcessor`]: crate::image_captioning::ImageProcessor
+//! [`ConcurrencyLimiter`]: crate::concurrency::ConcurrencyLimiter
+//! [`RateLimiter`]: crate::rate_limit::RateLimiter
+use std::convert::Infallible;
+use std::time::{Duration, Instant};
+use once_cell::sync::Lazy;
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use tokio::net::TcpListener;
+use tonic::{Code, Status};
+
+/// Path of the metrics endpoint.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Upper bound of the first latency bucket, in seconds. Each following bucket doubles it.
+const FIRST_LATENCY_BUCKET: f64 = 0.005;
+
+/// Number of latency buckets, so that the last bucket is about 40 seconds.
+const LATENCY_BUCKETS: usize = 14;
+
+static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics::new().expect("Metric definitions are valid"));
+
+/// Returns the process wide [`Metrics`].
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// A processing stage of a request, timed by `vision_stage_duration_seconds`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Decoding and resizing the image.
+    Decode,
+    /// Converting the pixels into the input tensor of the vision model.
+    Preprocessing,
+    /// Running the vision model (once for a whole batch).
+    VisionEncoding,
+    /// Generating the text with the text decoder.
+    TextGeneration,
+}
+
+impl Stage {
+    /// Returns the value of the `stage` label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Decode => "decode",
+            Self::Preprocessing => "preprocessing",
+            Self::VisionEncoding => "vision_encoding",
+            Self::TextGeneration => "text_generation",
+        }
+    }
+}
+
+/// A kind of entry of the inference cache, counted by `vision_cache_lookups_total`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    /// The image embeddings computed by the vision model.
+    Embeddings,
+    /// The captions generated by the text decoder.
+    Caption,
+}
+
+impl CacheKind {
+    /// Returns the value of the `kind` label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Embeddings => "embeddings",
+            Self::Caption => "caption",
+        }
+    }
+}
+
+/// [`Metrics`] holds the Prometheus metrics of the vision service and the registry they are exported from.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    stage_duration: HistogramVec,
+    generated_tokens: IntCounterVec,
+    cache_lookups: IntCounterVec,
+    queued_requests: IntGaugeVec,
+    in_flight_requests: IntGauge,
+    rate_limited_requests: IntCounterVec,
+}
+
+impl Metrics {
+    /// Creates a new instance of [`Metrics`] with its own registry.
+    ///
+    /// # Returns
+    ///
+    /// A [`prometheus::Result`] containing the new [`Metrics`] instance, or an error if a metric
+    /// cannot be registered.
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+        let buckets: Vec<f64> = exponential_buckets(FIRST_LATENCY_BUCKET, 2.0, LATENCY_BUCKETS)?;
+
+        let requests = IntCounterVec::new(
+            Opts::new("vision_requests_total", "Completed requests by gRPC method, model and status code"),
+            &["rpc", "model", "code"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("vision_request_duration_seconds", "Latency of the requests").buckets(buckets.clone()),
+            &["rpc", "model"],
+        )?;
+        let stage_duration = HistogramVec::new(
+            HistogramOpts::new("vision_stage_duration_seconds", "Latency of the processing stages of the requests")
+                .buckets(buckets),
+            &["stage", "model"],
+        )?;
+        let generated_tokens = IntCounterVec::new(
+            Opts::new("vision_generated_tokens_total", "Tokens generated by the text decoders"),
+            &["model"],
+        )?;
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("vision_cache_lookups_total", "Lookups of the inference cache by kind of entry, model and result"),
+            &["kind", "model", "result"],
+        )?;
+        let queued_requests = IntGaugeVec::new(
+            Opts::new("vision_queued_requests", "Requests waiting for a slot of the server or of a model"),
+            &["scope"],
+        )?;
+        let in_flight_requests = IntGauge::new("vision_in_flight_requests", "Requests holding a concurrency permit")?;
+        let rate_limited_requests = IntCounterVec::new(
+            Opts::new("vision_rate_limited_requests_total", "Requests rejected by the per-client rate limits"),
+            &["budget"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(stage_duration.clone()))?;
+        registry.register(Box::new(generated_tokens.clone()))?;
+        registry.register(Box::new(cache_lookups.clone()))?;
+        registry.register(Box::new(queued_requests.clone()))?;
+        registry.register(Box::new(in_flight_requests.clone()))?;
+        registry.register(Box::new(rate_limited_requests.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            request_duration,
+            stage_duration,
+            generated_tokens,
+            cache_lookups,
+            queued_requests,
+            in_flight_requests,
+            rate_limited_requests,
+        })
+    }
+
+    /// Records a completed request.
+    ///
+    /// # Arguments
+    ///
+    /// * `rpc` - The name of the gRPC method, e.g. `ProcessImage`.
+    /// * `model_id` - The registry id of the model, or an empty string if the request did not get
+    ///   as far as selecting a loaded model.
+    /// * `code` - The status code of the request. The label is the name of the [`Code`] variant.
+    /// * `elapsed` - The latency of the request.
+    pub fn observe_request(&self, rpc: &str, model_id: &str, code: Code, elapsed: Duration) {
+        self.requests.with_label_values(&[rpc, model_id, &format!("{:?}", code)]).inc();
+        self.request_duration.with_label_values(&[rpc, model_id]).observe(elapsed.as_secs_f64());
+    }
+
+    /// Records the latency of a processing stage.
+    pub fn observe_stage(&self, stage: Stage, model_id: &str, elapsed: Duration) {
+        self.stage_duration.with_label_values(&[stage.as_str(), model_id]).observe(elapsed.as_secs_f64());
+    }
+
+    /// Runs `f` and records its latency as the given processing stage.
+    pub fn time_stage<T>(&self, stage: Stage, model_id: &str, f: impl FnOnce() -> T) -> T {
+        let started: Instant = Instant::now();
+        let result: T = f();
+        self.observe_stage(stage, model_id, started.elapsed());
+        result
+    }
+
+    /// Adds tokens generated by the text decoder of a model.
+    pub fn add_generated_tokens(&self, model_id: &str, tokens: usize) {
+        self.generated_tokens.with_label_values(&[model_id]).inc_by(tokens as u64);
+    }
+
+    /// Records a lookup of the inference cache as a hit or a miss.
+    pub fn observe_cache_lookup(&self, kind: CacheKind, model_id: &str, hit: bool) {
+        let result: &str = if hit { "hit" } else { "miss" };
+        self.cache_lookups.with_label_values(&[kind.as_str(), model_id, result]).inc();
+    }
+
+    /// Returns the gauge of the requests waiting for a slot of the given scope (`server` or a model id).
+    pub fn queued_requests(&self, scope: &str) -> IntGauge {
+        self.queued_requests.with_label_values(&[scope])
+    }
+
+    /// Returns the gauge of the requests holding a concurrency permit.
+    pub fn in_flight_requests(&self) -> &IntGauge {
+        &self.in_flight_requests
+    }
+
+    /// Records a request rejected by the rate limit of the given budget (`process_image` or `batch_items`).
+    pub fn observe_rate_limited(&self, budget: &str) {
+        self.rate_limited_requests.with_label_values(&[budget]).inc();
+    }
+
+    /// Encodes all metrics in the Prometheus text exposition format.
+    pub fn encode(&self) -> String {
+        let mut buffer: Vec<u8> = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!("Error encoding metrics: {:?}", e);
+        }
+
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+}
+
+/// [`RpcMetrics`] records the count and the latency of a gRPC call with the process wide [`Metrics`].
+///
+/// The call is recorded once, by [`RpcMetrics::finish`], or as [`Code::Cancelled`] if the guard is
+/// dropped before, which happens when tonic drops the handler because the client went away or its
+/// deadline expired.
+#[derive(Debug)]
+pub struct RpcMetrics {
+    rpc: &'static str,
+    model_id: String,
+    started: Instant,
+    finished: bool,
+}
+
+impl RpcMetrics {
+    /// Starts timing a call of the given gRPC method.
+    pub fn start(rpc: &'static str) -> Self {
+        Self { rpc, model_id: String::new(), started: Instant::now(), finished: false }
+    }
+
+    /// Sets the model of the call. Only call it once the model is known to be loaded, so that the
+    /// `model` label only takes the ids of the registry.
+    pub fn set_model(&mut self, model_id: &str) {
+        self.model_id = model_id.to_string();
+    }
+
+    /// Records the call with the status code of its result.
+    pub fn finish<T>(self, result: &Result<T, Status>) {
+        self.finish_with_code(result.as_ref().map_or_else(Status::code, |_| Code::Ok));
+    }
+
+    /// Records the call with the given status code.
+    pub fn finish_with_code(mut self, code: Code) {
+        self.record(code);
+    }
+
+    fn record(&mut self, code: Code) {
+        self.finished = true;
+        metrics().observe_request(self.rpc, &self.model_id, code, self.started.elapsed());
+    }
+}
+
+impl Drop for RpcMetrics {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.record(Code::Cancelled);
+        }
+    }
+}
+
+/// Serves the process wide [`Metrics`] over HTTP/1 at [`METRICS_PATH`].
+///
+/// # Arguments
+///
+/// * `listener` - The bound [`TcpListener`] to accept scrapes on.
+///
+/// # Errors
+///
+/// Returns an error if accepting a connection fails. Errors of individual connections are logged.
+pub async fn serve(listener: TcpListener) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let connection = http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(handle));
+            if let Err(e) = connection.await {
+                tracing::debug!("Error serving metrics connection: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Answers a request to the metrics endpoint.
+async fn handle<B>(request: Request<B>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response: Response<Full<Bytes>> = match (request.method(), request.uri().path()) {
+        (&Method::GET, METRICS_PATH) => Response::builder()
+            .header(header::CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Full::new(Bytes::from(metrics().encode()))),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::new())),
+    }
+    .expect("Response parts are valid");
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[test]
+    fn test_metrics_encode() {
+        // GIVEN
+        let metrics: Metrics = Metrics::new().unwrap();
+        // WHEN
+        metrics.observe_request("ProcessImage", "blip", Code::InvalidArgument, Duration::from_millis(3));
+        metrics.observe_stage(Stage::VisionEncoding, "blip", Duration::from_millis(30));
+        metrics.add_generated_tokens("blip", 12);
+        metrics.observe_cache_lookup(CacheKind::Caption, "blip", true);
+        metrics.observe_cache_lookup(CacheKind::Embeddings, "blip", false);
+        metrics.queued_requests("server").set(2);
+        let encoded: String = metrics.encode();
+        // THEN
+        assert!(encoded.contains(r#"vision_requests_total{code="InvalidArgument",model="blip",rpc="ProcessImage"} 1"#));
+        assert!(encoded.contains(r#"vision_request_duration_seconds_count{model="blip",rpc="ProcessImage"} 1"#));
+        assert!(encoded.contains(r#"vision_stage_duration_seconds_bucket{model="blip",stage="vision_encoding",le="0.04"} 1"#));
+        assert!(encoded.contains(r#"vision_generated_tokens_total{model="blip"} 12"#));
+        assert!(encoded.contains(r#"vision_cache_lookups_total{kind="caption",model="blip",result="hit"} 1"#));
+        assert!(encoded.contains(r#"vision_cache_lookups_total{kind="embeddings",model="blip",result="miss"} 1"#));
+        assert!(encoded.contains(r#"vision_queued_requests{scope="server"} 2"#));
+        assert!(encoded.contains("vision_in_flight_requests 0"));
+    }
+
+    #[test]
+    fn test_rpc_metrics_records_dropped_calls_as_cancelled() {
+        // GIVEN
+        let mut rpc: RpcMetrics = RpcMetrics::start("TestDroppedCall");
+        rpc.set_model("blip");
+        // WHEN
+        drop(rpc);
+        // THEN
+        let encoded: String = metrics().encode();
+        assert!(encoded.contains(r#"vision_requests_total{code="Cancelled",model="blip",rpc="TestDroppedCall"} 1"#));
+    }
+
+    #[test]
+    fn test_rpc_metrics_records_status_code() {
+        // GIVEN
+        let rpc: RpcMetrics = RpcMetrics::start("TestFinishedCall");
+        // WHEN
+        rpc.finish::<()>(&Err(Status::not_found("Model \"x\" is not loaded")));
+        // THEN
+        let encoded: String = metrics().encode();
+        assert!(encoded.contains(r#"vision_requests_total{code="NotFound",model="",rpc="TestFinishedCall"} 1"#));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        // GIVEN
+        let scrape: Request<()> = Request::get(METRICS_PATH).body(()).unwrap();
+        let other: Request<()> = Request::get("/").body(()).unwrap();
+        // WHEN
+        let scrape_response: Response<Full<Bytes>> = handle(scrape).await.unwrap();
+        let other_response: Response<Full<Bytes>> = handle(other).await.unwrap();
+        // THEN
+        assert_eq!(scrape_response.status(), StatusCode::OK);
+        let body: Bytes = scrape_response.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains("# TYPE vision_in_flight_requests gauge"));
+        assert_eq!(other_response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,32 +1,190 @@
+//! This module provides the [`ValidationLayer`] tower middleware.
+//!
+//! The middleware inspects the HTTP/2 request of a gRPC call before it is decoded and rejects calls
+//! that the service would not be able to handle anyway:
+//!
+//! * bodies whose `content-length` exceeds the configured maximum size (`RESOURCE_EXHAUSTED`),
+//! * content types other than `application/grpc` and `application/grpc+proto` (HTTP `415`),
+//! * requests missing any of the required metadata keys (`INVALID_ARGUMENT`),
+//! * message encodings (`grpc-encoding`) the service does not accept (`UNIMPLEMENTED`).
+//!
+//! gRPC clients rarely send a `content-length`, and streaming requests never do, so the body of
+//! every forwarded request is also limited while it is read: the call fails with
+//! `RESOURCE_EXHAUSTED` once more than the maximum size is received. For streaming calls, the limit
+//! applies to all messages of the stream together.
 use std::pin::Pin;
+use std::sync::Arc;
 use std::future::Future;
 use std::task::{Context, Poll};
-use tonic::body::BoxBody;
+use http_body::{Body as HttpBody, LengthLimitError, Limited};
+use tokio_stream::Stream;
+use tonic::Status;
+use tonic::body::{empty_body, BoxBody};
+use tonic::codegen::Bytes;
+use tonic::codegen::http::{header, HeaderValue, Request, Response, StatusCode};
 use tonic::transport::Body;
 use tonic::server::NamedService;
 use tower::{Service, Layer};
 
 type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
-#[derive(Debug, Clone, Default)]
-pub struct ValidationLayer;
+/// Default maximum size of a request body, in bytes.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Content types of the gRPC protobuf wire format.
+const GRPC_CONTENT_TYPES: [&str; 2] = ["application/grpc", "application/grpc+proto"];
+
+/// Header carrying the encoding of the request messages.
+const GRPC_ENCODING: &str = "grpc-encoding";
+
+/// Header listing the message encodings accepted by the server.
+const GRPC_ACCEPT_ENCODING: &str = "grpc-accept-encoding";
+
+/// The message encoding meaning "no compression", which is always accepted.
+const IDENTITY_ENCODING: &str = "identity";
+
+/// The rules a request has to satisfy to pass through the [`ValidationMiddleware`].
+#[derive(Debug, Clone)]
+struct ValidationConfig {
+    max_body_size: usize,
+    required_metadata: Vec<String>,
+    accepted_encodings: Vec<String>,
+}
+
+/// [`ValidationLayer`] is a tower [`Layer`] wrapping a gRPC service in a [`ValidationMiddleware`].
+///
+/// By default, bodies up to [`DEFAULT_MAX_BODY_SIZE`] bytes are accepted, no metadata is required
+/// and only uncompressed (`identity`) messages are accepted.
+///
+/// # Example
+///
+/// ```ignore
+/// let vision_svc = ValidationLayer::new()
+///     .max_body_size(12 * 1024 * 1024)
+///     .accept_encoding("gzip")
+///     .require_metadata("x-request-id")
+///     .layer(ComputerVisionServer::new(svc));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ValidationLayer {
+    config: Arc<ValidationConfig>,
+}
+
+impl Default for ValidationLayer {
+    fn default() -> Self {
+        Self {
+            config: Arc::new(ValidationConfig {
+                max_body_size: DEFAULT_MAX_BODY_SIZE,
+                required_metadata: Vec::new(),
+                accepted_encodings: vec![IDENTITY_ENCODING.to_string()],
+            }),
+        }
+    }
+}
+
+impl ValidationLayer {
+    /// Creates a new instance of [`ValidationLayer`] with the default rules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum size of a request body, in bytes.
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        Arc::make_mut(&mut self.config).max_body_size = max_body_size;
+        self
+    }
+
+    /// Adds a metadata key every request has to carry. Keys are case-insensitive.
+    pub fn require_metadata(mut self, key: &str) -> Self {
+        Arc::make_mut(&mut self.config).required_metadata.push(key.to_lowercase());
+        self
+    }
+
+    /// Adds a message encoding (e.g. `gzip`) the service accepts in the `grpc-encoding` header.
+    pub fn accept_encoding(mut self, encoding: &str) -> Self {
+        Arc::make_mut(&mut self.config).accepted_encodings.push(encoding.to_lowercase());
+        self
+    }
+}
 
 impl<S> Layer<S> for ValidationLayer {
     type Service = ValidationMiddleware<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        ValidationMiddleware { inner: service }
+        ValidationMiddleware {
+            inner: service,
+            config: Arc::clone(&self.config),
+        }
     }
 }
 
+/// [`ValidationMiddleware`] is a tower [`Service`] that validates requests before forwarding them
+/// to the inner gRPC service. Rejected requests never reach the inner service.
 #[derive(Debug, Clone)]
 pub struct ValidationMiddleware<S> {
     inner: S,
+    config: Arc<ValidationConfig>,
+}
+
+impl<S> ValidationMiddleware<S> {
+    /// Validates the headers of a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - A reference to the HTTP request of the gRPC call.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the request is valid, otherwise the response to send back to the client.
+    fn validate(&self, req: &Request<Body>) -> Option<Response<BoxBody>> {
+        let headers = req.headers();
+
+        let content_type: &str = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !GRPC_CONTENT_TYPES.contains(&content_type) {
+            // Requests that are not gRPC calls get a plain HTTP error, as required by the gRPC spec
+            let mut response: Response<BoxBody> = Response::new(empty_body());
+            *response.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+            return Some(response);
+        }
+
+        let content_length: Option<usize> = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        if let Some(length) = content_length.filter(|length| *length > self.config.max_body_size) {
+            return Some(Status::resource_exhausted(format!(
+                "Request body of {} bytes exceeds the limit of {} bytes", length, self.config.max_body_size,
+            )).to_http());
+        }
+
+        if let Some(key) = self.config.required_metadata.iter().find(|key| !headers.contains_key(key.as_str())) {
+            return Some(Status::invalid_argument(format!("Missing required metadata {:?}", key)).to_http());
+        }
+
+        let encoding: &str = headers
+            .get(GRPC_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(IDENTITY_ENCODING);
+        if !self.config.accepted_encodings.iter().any(|accepted| accepted.eq_ignore_ascii_case(encoding)) {
+            let mut response: Response<BoxBody> =
+                Status::unimplemented(format!("Unsupported message encoding {:?}", encoding)).to_http();
+            let accepted: String = self.config.accepted_encodings.join(",");
+            if let Ok(value) = HeaderValue::from_str(&accepted) {
+                response.headers_mut().insert(GRPC_ACCEPT_ENCODING, value);
+            }
+            return Some(response);
+        }
+
+        None
+    }
 }
 
-impl<S> Service<hyper::Request<Body>> for ValidationMiddleware<S>
+impl<S> Service<Request<Body>> for ValidationMiddleware<S>
 where
-    S: Service<hyper::Request<Body>, Response = hyper::Response<BoxBody>> + Clone + Send + 'static,
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
     S::Future: Send + 'static,
 {
     type Response = S::Response;
@@ -37,14 +195,20 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, req: hyper::Request<Body>) -> Self::Future {
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if let Some(response) = self.validate(&req) {
+            tracing::warn!(path = %req.uri().path(), "Request rejected by validation");
+            return Box::pin(async move { Ok(response) });
+        }
+
+        let max_body_size: usize = self.config.max_body_size;
+        let req: Request<Body> = req.map(|body| Body::wrap_stream(LimitedBody::new(body, max_body_size)));
+
         let clone: S = self.inner.clone();
         let mut inner: S = std::mem::replace(&mut self.inner, clone);
 
         Box::pin(async move {
-            // TODO: Add validation logic here
-            // Do extra async work here...
-            let response = inner.call(req).await?;
+            let response: Response<BoxBody> = inner.call(req).await?;
 
             Ok(response)
         })
@@ -54,3 +218,203 @@ where
 impl<S: NamedService> NamedService for ValidationMiddleware<S> {
     const NAME: &'static str = S::NAME;
 }
+
+/// [`LimitedBody`] is the stream of data frames of a request body, which fails with a
+/// `RESOURCE_EXHAUSTED` [`Status`] once more than `max_body_size` bytes are received.
+///
+/// The [`Status`] is the source of the body error seen by the inner service, so tonic answers the
+/// call with it.
+struct LimitedBody {
+    body: Limited<Body>,
+    max_body_size: usize,
+}
+
+impl LimitedBody {
+    /// Creates a new instance of [`LimitedBody`] reading `body` up to `max_body_size` bytes.
+    fn new(body: Body, max_body_size: usize) -> Self {
+        Self {
+            body: Limited::new(body, max_body_size),
+            max_body_size,
+        }
+    }
+}
+
+impl Stream for LimitedBody {
+    type Item = Result<Bytes, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let max_body_size: usize = self.max_body_size;
+        Pin::new(&mut self.body).poll_data(cx).map_err(|e| {
+            if e.is::<LengthLimitError>() {
+                Status::resource_exhausted(format!("Request body exceeds the limit of {} bytes", max_body_size))
+            } else {
+                Status::from_error(e)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tonic::Code;
+    use tower::ServiceExt;
+
+    /// Builds a mock inner service answering every request with an empty `200 OK` response and
+    /// counting the requests that reach it.
+    fn mock_service(
+        calls: Arc<AtomicUsize>,
+    ) -> impl Service<Request<Body>, Response = Response<BoxBody>, Error = Infallible, Future = impl Send> + Clone + Send {
+        tower::service_fn(move |_: Request<Body>| {
+            let calls: Arc<AtomicUsize> = Arc::clone(&calls);
+            async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, Infallible>(Response::new(empty_body()))
+            }
+        })
+    }
+
+    /// Builds a mock inner service reading the whole request body, and answering with the status of
+    /// the body error like tonic does, or with an empty `200 OK` response.
+    fn reading_service() -> impl Service<Request<Body>, Response = Response<BoxBody>, Error = Infallible, Future = impl Send> + Clone + Send {
+        tower::service_fn(|request: Request<Body>| async move {
+            let mut body: Body = request.into_body();
+            while let Some(data) = body.data().await {
+                if let Err(e) = data {
+                    return Ok::<_, Infallible>(Status::from_error(Box::new(e)).to_http());
+                }
+            }
+            Ok::<_, Infallible>(Response::new(empty_body()))
+        })
+    }
+
+    /// Builds a body sent in chunks of the given sizes, without a `content-length`.
+    fn chunked_body(chunk_sizes: &[usize]) -> Body {
+        let chunks: Vec<Result<Bytes, Infallible>> = chunk_sizes.iter().map(|size| Ok(Bytes::from(vec![0; *size]))).collect();
+        Body::wrap_stream(tokio_stream::iter(chunks))
+    }
+
+    fn grpc_request() -> tonic::codegen::http::request::Builder {
+        Request::builder()
+            .uri("/computer_vision.ComputerVision/ProcessImage")
+            .header(header::CONTENT_TYPE, "application/grpc")
+    }
+
+    fn grpc_status(response: &Response<BoxBody>) -> Option<Code> {
+        Status::from_header_map(response.headers()).map(|status| status.code())
+    }
+
+    async fn call(layer: ValidationLayer, request: Request<Body>) -> (Response<BoxBody>, usize) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let service = layer.layer(mock_service(Arc::clone(&calls)));
+        let response: Response<BoxBody> = service.oneshot(request).await.unwrap();
+
+        (response, calls.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_validation_middleware_forwards_valid_request() {
+        // GIVEN
+        let layer: ValidationLayer = ValidationLayer::new()
+            .accept_encoding("gzip")
+            .require_metadata("X-Request-Id");
+        let request: Request<Body> = grpc_request()
+            .header(header::CONTENT_LENGTH, "1024")
+            .header("grpc-encoding", "gzip")
+            .header("x-request-id", "42")
+            .body(Body::empty())
+            .unwrap();
+        // WHEN
+        let (response, calls): (Response<BoxBody>, usize) = call(layer, request).await;
+        // THEN
+        assert_eq!(calls, 1);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(grpc_status(&response), None);
+    }
+
+    #[tokio::test]
+    async fn test_validation_middleware_rejects_oversized_body() {
+        // GIVEN
+        let layer: ValidationLayer = ValidationLayer::new().max_body_size(100);
+        let request: Request<Body> = grpc_request()
+            .header(header::CONTENT_LENGTH, "101")
+            .body(Body::empty())
+            .unwrap();
+        // WHEN
+        let (response, calls): (Response<BoxBody>, usize) = call(layer, request).await;
+        // THEN
+        assert_eq!(calls, 0);
+        assert_eq!(grpc_status(&response), Some(Code::ResourceExhausted));
+    }
+
+    #[tokio::test]
+    async fn test_validation_middleware_rejects_oversized_chunked_body() {
+        // GIVEN
+        let service = ValidationLayer::new().max_body_size(100).layer(reading_service());
+        let request: Request<Body> = grpc_request().body(chunked_body(&[60, 60])).unwrap();
+        // WHEN
+        let response: Response<BoxBody> = service.oneshot(request).await.unwrap();
+        // THEN
+        assert_eq!(grpc_status(&response), Some(Code::ResourceExhausted));
+    }
+
+    #[tokio::test]
+    async fn test_validation_middleware_forwards_chunked_body_within_limit() {
+        // GIVEN
+        let service = ValidationLayer::new().max_body_size(100).layer(reading_service());
+        let request: Request<Body> = grpc_request().body(chunked_body(&[60, 40])).unwrap();
+        // WHEN
+        let response: Response<BoxBody> = service.oneshot(request).await.unwrap();
+        // THEN
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(grpc_status(&response), None);
+    }
+
+    #[tokio::test]
+    async fn test_validation_middleware_rejects_unknown_content_type() {
+        // GIVEN
+        let cases: [Option<&str>; 3] = [Some("application/json"), Some("application/grpc+json"), None];
+        for content_type in cases {
+            let mut request = Request::builder().uri("/computer_vision.ComputerVision/ProcessImage");
+            if let Some(content_type) = content_type {
+                request = request.header(header::CONTENT_TYPE, content_type);
+            }
+            // WHEN
+            let (response, calls): (Response<BoxBody>, usize) =
+                call(ValidationLayer::new(), request.body(Body::empty()).unwrap()).await;
+            // THEN
+            assert_eq!(calls, 0);
+            assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_middleware_rejects_missing_metadata() {
+        // GIVEN
+        let layer: ValidationLayer = ValidationLayer::new().require_metadata("x-request-id");
+        let request: Request<Body> = grpc_request().body(Body::empty()).unwrap();
+        // WHEN
+        let (response, calls): (Response<BoxBody>, usize) = call(layer, request).await;
+        // THEN
+        assert_eq!(calls, 0);
+        assert_eq!(grpc_status(&response), Some(Code::InvalidArgument));
+    }
+
+    #[tokio::test]
+    async fn test_validation_middleware_rejects_unsupported_encoding() {
+        // GIVEN
+        let layer: ValidationLayer = ValidationLayer::new().accept_encoding("gzip");
+        let request: Request<Body> = grpc_request()
+            .header("grpc-encoding", "zstd")
+            .body(Body::empty())
+            .unwrap();
+        // WHEN
+        let (response, calls): (Response<BoxBody>, usize) = call(layer, request).await;
+        // THEN
+        assert_eq!(calls, 0);
+        assert_eq!(grpc_status(&response), Some(Code::Unimplemented));
+        assert_eq!(response.headers().get("grpc-accept-encoding").unwrap(), "identity,gzip");
+    }
+}
@@ -0,0 +1,471 @@
+//! This module provides the [`RateLimiter`], which limits the rate of requests of every client.
+//!
+//! The [`ConcurrencyLimiter`] shares the server between all clients, so a single client sending
+//! requests as fast as it can would fill its slots and queues. The rate limiter gives every client
+//! a token bucket per [`Budget`] instead: a request takes a token, and the bucket refills at a
+//! steady rate up to a burst size. Requests finding an empty bucket are rejected with
+//! `RESOURCE_EXHAUSTED` and the time until the next token, before they reach the concurrency limiter.
+//!
+//! Clients are identified by the subject of their access token (see [`Subject`]), or by their IP
+//! address if authentication is disabled.
+//!
+//! [`ConcurrencyLimiter`]: crate::concurrency::ConcurrencyLimiter
+use std::fmt;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use lru::LruCache;
+use serde::Deserialize;
+use thiserror::Error;
+use tonic::{Request, Status};
+use tonic::metadata::MetadataValue;
+use crate::auth::Subject;
+use crate::concurrency::RETRY_PUSHBACK_KEY;
+use crate::metrics::metrics;
+
+/// [`BucketConfig`] holds the parameters of the token buckets of a [`Budget`].
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct BucketConfig {
+    /// The rate at which a bucket refills, in requests per second.
+    pub requests_per_second: f64,
+    /// The capacity of a bucket, i.e. the number of requests a client can send at once after
+    /// being idle.
+    pub burst: u32,
+}
+
+/// [`RateLimitConfig`] holds the parameters of the [`RateLimiter`].
+///
+/// It corresponds to the optional `[rate_limit]` table of the models configuration file. A budget
+/// without a table is not limited, so the defaults disable rate limiting.
+///
+/// # Example TOML config
+///
+/// ```toml
+/// [rate_limit]
+/// max_clients = 10000 # clients whose buckets are tracked, least recently seen are evicted first
+///
+/// [rate_limit.process_image] # ProcessImage, StreamCaption and AnswerQuestion
+/// requests_per_second = 2.0
+/// burst = 10
+///
+/// [rate_limit.batch_items] # items of ProcessImageBatch streams
+/// requests_per_second = 8.0
+/// burst = 64
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub max_clients: usize,
+    pub process_image: Option<BucketConfig>,
+    pub batch_items: Option<BucketConfig>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_clients: 10_000,
+            process_image: None,
+            batch_items: None,
+        }
+    }
+}
+
+/// Helper struct used to deserialize the `[rate_limit]` table of the models configuration file.
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+}
+
+impl RateLimitConfig {
+    /// Parses the `[rate_limit]` table from the contents of a TOML configuration file.
+    /// Other tables are ignored. If the table is missing, the defaults are returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `toml_str` - The contents of the TOML configuration file.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the parsed [`RateLimitConfig`] or a [`toml::de::Error`] if parsing
+    /// fails, `max_clients` is zero, or a budget has a non-positive rate or a zero burst.
+    pub fn from_toml_str(toml_str: &str) -> Result<Self, toml::de::Error> {
+        let config: Self = toml::from_str::<ConfigFile>(toml_str)?.rate_limit;
+        if config.max_clients == 0 {
+            return Err(serde::de::Error::custom("rate_limit.max_clients must be greater than zero"));
+        }
+        for budget in [Budget::ProcessImage, Budget::BatchItems] {
+            let Some(bucket) = config.bucket(budget) else { continue };
+            if !(bucket.requests_per_second.is_finite() && bucket.requests_per_second > 0.0) {
+                return Err(serde::de::Error::custom(format!(
+                    "rate_limit.{}.requests_per_second must be greater than zero", budget,
+                )));
+            }
+            if bucket.burst == 0 {
+                return Err(serde::de::Error::custom(format!("rate_limit.{}.burst must be greater than zero", budget)));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Returns the bucket parameters of a budget, or `None` if the budget is not limited.
+    pub fn bucket(&self, budget: Budget) -> Option<&BucketConfig> {
+        match budget {
+            Budget::ProcessImage => self.process_image.as_ref(),
+            Budget::BatchItems => self.batch_items.as_ref(),
+        }
+    }
+}
+
+/// A budget of requests. Every client has a separate bucket per budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Budget {
+    /// Requests generating from a single image: ProcessImage, StreamCaption and AnswerQuestion.
+    ProcessImage,
+    /// The items of ProcessImageBatch streams, each taking a token.
+    BatchItems,
+}
+
+impl Budget {
+    /// Returns the name of the budget, as used in the configuration file and in the metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ProcessImage => "process_image",
+            Self::BatchItems => "batch_items",
+        }
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The identity a client is rate limited by.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientKey {
+    /// The subject of the access token of an authenticated client.
+    Subject(String),
+    /// The IP address of an anonymous client. The port is ignored, as it changes with every connection.
+    Peer(IpAddr),
+    /// A client with neither, e.g. connected through a Unix domain socket. All of them share a bucket.
+    Unknown,
+}
+
+impl ClientKey {
+    /// Returns the key of the client sending a request.
+    pub fn from_request<T>(request: &Request<T>) -> Self {
+        match (Subject::from_request(request), request.remote_addr()) {
+            (Some(subject), _) => Self::Subject(subject.id().to_string()),
+            (None, Some(addr)) => Self::Peer(addr.ip()),
+            (None, None) => Self::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Subject(subject) => write!(f, "subject {:?}", subject),
+            Self::Peer(ip) => write!(f, "peer {}", ip),
+            Self::Unknown => f.write_str("unknown client"),
+        }
+    }
+}
+
+/// [`RateLimitError`] is returned when a client has exceeded a budget.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Rate limit of {budget} exceeded for {client}")]
+pub struct RateLimitError {
+    pub budget: Budget,
+    pub client: ClientKey,
+    /// The time until the bucket holds a token again.
+    pub retry_after: Duration,
+}
+
+impl From<RateLimitError> for Status {
+    /// Converts the error into a [`Status::resource_exhausted`] carrying the retry hint, both in the
+    /// message and in the [`RETRY_PUSHBACK_KEY`] metadata.
+    fn from(error: RateLimitError) -> Self {
+        let retry_after_ms: u64 = error.retry_after.as_millis() as u64;
+        let mut status = Status::resource_exhausted(format!("{}, retry after {} ms", error, retry_after_ms));
+        status.metadata_mut().insert(RETRY_PUSHBACK_KEY, MetadataValue::from(retry_after_ms));
+
+        status
+    }
+}
+
+/// A token bucket, refilled lazily when it is used.
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(config: &BucketConfig, now: Instant) -> Self {
+        Self { tokens: config.burst as f64, updated: now }
+    }
+
+    /// Takes a token, or returns the time until the next token if the bucket is empty.
+    fn try_take(&mut self, config: &BucketConfig, now: Instant) -> Result<(), Duration> {
+        let elapsed: f64 = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.requests_per_second).min(config.burst as f64);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait: Duration = Duration::from_secs_f64((1.0 - self.tokens) / config.requests_per_second);
+        // Round up to whole milliseconds, so that retrying after the hint finds a token
+        Err(Duration::from_millis((wait.as_micros() as u64).div_ceil(1000)))
+    }
+}
+
+/// The token buckets of a client, one per limited [`Budget`].
+#[derive(Debug, Clone, Copy, Default)]
+struct ClientBuckets {
+    process_image: Option<TokenBucket>,
+    batch_items: Option<TokenBucket>,
+}
+
+impl ClientBuckets {
+    fn bucket_mut(&mut self, budget: Budget) -> &mut Option<TokenBucket> {
+        match budget {
+            Budget::ProcessImage => &mut self.process_image,
+            Budget::BatchItems => &mut self.batch_items,
+        }
+    }
+}
+
+/// [`RateLimiter`] admits requests according to a [`RateLimitConfig`].
+///
+/// The buckets of at most `max_clients` clients are kept; the buckets of the least recently seen
+/// client are evicted first, and start full if the client comes back. The limiter is a cheap
+/// handle; clones share the same buckets.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    buckets: Arc<Mutex<LruCache<ClientKey, ClientBuckets>>>,
+}
+
+impl RateLimiter {
+    /// Creates a new instance of [`RateLimiter`].
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The [`RateLimitConfig`] with the budgets of the clients.
+    ///
+    /// # Returns
+    ///
+    /// A new [`RateLimiter`] instance.
+    pub fn new(config: RateLimitConfig) -> Self {
+        let capacity = NonZeroUsize::new(config.max_clients).unwrap_or(NonZeroUsize::MIN);
+
+        Self {
+            config: Arc::new(config),
+            buckets: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    /// Takes a token from the bucket of a client.
+    ///
+    /// # Arguments
+    ///
+    /// * `budget` - The [`Budget`] the request counts against.
+    /// * `client` - The [`ClientKey`] of the client sending the request.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the request is admitted or the budget is not limited.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`RateLimitError`] with the time until the next token if the bucket is empty.
+    pub fn check(&self, budget: Budget, client: &ClientKey) -> Result<(), RateLimitError> {
+        self.check_at(budget, client, Instant::now())
+    }
+
+    fn check_at(&self, budget: Budget, client: &ClientKey, now: Instant) -> Result<(), RateLimitError> {
+        let Some(config) = self.config.bucket(budget) else {
+            return Ok(());
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket: &mut TokenBucket = buckets
+            .get_or_insert_mut(client.clone(), ClientBuckets::default)
+            .bucket_mut(budget)
+            .get_or_insert_with(|| TokenBucket::full(config, now));
+        bucket.try_take(config, now).map_err(|retry_after| {
+            metrics().observe_rate_limited(budget.as_str());
+            RateLimitError { budget, client: client.clone(), retry_after }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(requests_per_second: f64, burst: u32, max_clients: usize) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            max_clients,
+            process_image: Some(BucketConfig { requests_per_second, burst }),
+            batch_items: None,
+        })
+    }
+
+    fn subject(id: &str) -> ClientKey {
+        ClientKey::Subject(id.to_string())
+    }
+
+    #[test]
+    fn test_rate_limiter_allows_burst_then_rejects() {
+        // GIVEN
+        let limiter: RateLimiter = limiter(2.0, 3, 10);
+        let now = Instant::now();
+        // WHEN
+        let burst: Vec<Result<(), RateLimitError>> =
+            (0..3).map(|_| limiter.check_at(Budget::ProcessImage, &subject("42"), now)).collect();
+        let rejected: Result<(), RateLimitError> = limiter.check_at(Budget::ProcessImage, &subject("42"), now);
+        // THEN
+        assert!(burst.iter().all(Result::is_ok));
+        assert_eq!(
+            rejected.unwrap_err(),
+            RateLimitError { budget: Budget::ProcessImage, client: subject("42"), retry_after: Duration::from_millis(500) },
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_refills_over_time() {
+        // GIVEN
+        let limiter: RateLimiter = limiter(4.0, 1, 10);
+        let now = Instant::now();
+        limiter.check_at(Budget::ProcessImage, &subject("42"), now).unwrap();
+        // WHEN
+        let too_early: Result<(), RateLimitError> =
+            limiter.check_at(Budget::ProcessImage, &subject("42"), now + Duration::from_millis(100));
+        let refilled: Result<(), RateLimitError> =
+            limiter.check_at(Budget::ProcessImage, &subject("42"), now + Duration::from_millis(250));
+        // THEN
+        assert_eq!(too_early.unwrap_err().retry_after, Duration::from_millis(150));
+        assert!(refilled.is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter_keeps_clients_and_budgets_apart() {
+        // GIVEN
+        let limiter: RateLimiter = limiter(1.0, 1, 10);
+        let now = Instant::now();
+        limiter.check_at(Budget::ProcessImage, &subject("42"), now).unwrap();
+        // WHEN
+        let same_client: Result<(), RateLimitError> = limiter.check_at(Budget::ProcessImage, &subject("42"), now);
+        let other_client: Result<(), RateLimitError> = limiter.check_at(Budget::ProcessImage, &subject("7"), now);
+        let peer: Result<(), RateLimitError> =
+            limiter.check_at(Budget::ProcessImage, &ClientKey::Peer("10.0.0.1".parse().unwrap()), now);
+        let unlimited_budget: Vec<Result<(), RateLimitError>> =
+            (0..100).map(|_| limiter.check_at(Budget::BatchItems, &subject("42"), now)).collect();
+        // THEN
+        assert!(same_client.is_err());
+        assert!(other_client.is_ok());
+        assert!(peer.is_ok());
+        assert!(unlimited_budget.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn test_rate_limiter_evicts_least_recently_seen_clients() {
+        // GIVEN
+        let limiter: RateLimiter = limiter(1.0, 1, 1);
+        let now = Instant::now();
+        limiter.check_at(Budget::ProcessImage, &subject("42"), now).unwrap();
+        // WHEN
+        limiter.check_at(Budget::ProcessImage, &subject("7"), now).unwrap();
+        let evicted: Result<(), RateLimitError> = limiter.check_at(Budget::ProcessImage, &subject("42"), now);
+        // THEN
+        assert!(evicted.is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter_tracks_all_budgets_of_max_clients() {
+        // GIVEN
+        let limiter: RateLimiter = RateLimiter::new(RateLimitConfig {
+            max_clients: 2,
+            process_image: Some(BucketConfig { requests_per_second: 1.0, burst: 1 }),
+            batch_items: Some(BucketConfig { requests_per_second: 1.0, burst: 1 }),
+        });
+        let now = Instant::now();
+        for client in [subject("42"), subject("7")] {
+            limiter.check_at(Budget::ProcessImage, &client, now).unwrap();
+            limiter.check_at(Budget::BatchItems, &client, now).unwrap();
+        }
+        // WHEN
+        let results: Vec<Result<(), RateLimitError>> = [subject("42"), subject("7")]
+            .iter()
+            .flat_map(|client| [Budget::ProcessImage, Budget::BatchItems].map(|budget| limiter.check_at(budget, client, now)))
+            .collect();
+        // THEN
+        assert!(results.iter().all(Result::is_err));
+    }
+
+    #[test]
+    fn test_client_key_from_request() {
+        // GIVEN
+        let mut authenticated: Request<()> = Request::new(());
+        authenticated.extensions_mut().insert(Subject("42".to_string()));
+        let anonymous: Request<()> = Request::new(());
+        // WHEN
+        let authenticated: ClientKey = ClientKey::from_request(&authenticated);
+        let anonymous: ClientKey = ClientKey::from_request(&anonymous);
+        // THEN
+        assert_eq!(authenticated, subject("42"));
+        assert_eq!(anonymous, ClientKey::Unknown);
+    }
+
+    #[test]
+    fn test_rate_limit_error_into_status() {
+        // GIVEN
+        let error = RateLimitError { budget: Budget::BatchItems, client: subject("42"), retry_after: Duration::from_millis(125) };
+        // WHEN
+        let status: Status = error.into();
+        // THEN
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(status.message(), r#"Rate limit of batch_items exceeded for subject "42", retry after 125 ms"#);
+        assert_eq!(status.metadata().get(RETRY_PUSHBACK_KEY).unwrap(), "125");
+    }
+
+    #[test]
+    fn test_rate_limit_config_from_toml_str() {
+        // GIVEN
+        let toml_str: &str = r#"
+            [rate_limit.batch_items]
+            requests_per_second = 8.0
+            burst = 64
+        "#;
+        // WHEN
+        let config: RateLimitConfig = RateLimitConfig::from_toml_str(toml_str).unwrap();
+        // THEN
+        assert_eq!(config.max_clients, RateLimitConfig::default().max_clients);
+        assert_eq!(config.process_image, None);
+        assert_eq!(config.batch_items, Some(BucketConfig { requests_per_second: 8.0, burst: 64 }));
+    }
+
+    #[test]
+    fn test_rate_limit_config_from_toml_str_invalid_budget() {
+        // GIVEN
+        let toml_strs: [&str; 3] = [
+            "[rate_limit.process_image]\nrequests_per_second = 0.0\nburst = 1",
+            "[rate_limit.process_image]\nrequests_per_second = 1.0\nburst = 0",
+            "[rate_limit]\nmax_clients = 0",
+        ];
+        for toml_str in toml_strs {
+            // WHEN
+            let result: Result<RateLimitConfig, toml::de::Error> = RateLimitConfig::from_toml_str(toml_str);
+            // THEN
+            assert!(result.is_err(), "{}", toml_str);
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! This module provides the [`ModelReloader`], which applies the changes of the `[[model]]` entries
+//! of the models configuration file without restarting the server.
+//!
+//! A reload compares the entries of the file with the entries the loaded models were built from.
+//! New and changed models are downloaded and built in the background while the current models keep
+//! serving, then the new [`ImageProcessor`] is swapped into the [`ProcessorSlot`]. Unchanged models
+//! are shared by both processors. Requests admitted before the swap finish with the previous
+//! processor, so replaced and removed models are only dropped once their in-flight requests are done.
+//!
+//! Reloads are triggered when the modification time of the file changes (see
+//! [`ModelReloader::watch`]) and by the `ReloadModels` RPC of the admin service. A reload that fails,
+//! e.g. because the file is invalid or a download fails, keeps the current models. Only the
+//! `[[model]]` entries are reloaded: the other tables are read once at startup.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use candle_core::Device;
+use hf_hub::api::sync::{Api, ApiBuilder};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tokio::task::{self, JoinError, JoinHandle};
+use tokio::time;
+use tonic::Status;
+use crate::concurrency::ConcurrencyConfig;
+use crate::health::ModelReadiness;
+use crate::image_captioning::ImageProcessor;
+use crate::image_captioning::cache::CacheConfig;
+use crate::image_captioning::model_loader::{self, AsyncModelLoader, ModelConfig, ModelLoaderError, Models, OfflineApi};
+use crate::proto::ReloadModelsResponse;
+use crate::service_impl::ProcessorSlot;
+
+/// Default interval at which the models configuration file is checked for changes.
+pub const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// [`ReloadError`] is the error of a reload of the models. The current models are kept.
+#[derive(Error, Debug)]
+pub enum ReloadError {
+    #[error("Failed to load models: {0}")]
+    Load(#[from] ModelLoaderError),
+    #[error("Failed to build models: {0}")]
+    Build(#[from] candle_core::Error),
+    #[error("Reload task failed: {0}")]
+    Task(#[from] JoinError),
+}
+
+impl From<ReloadError> for Status {
+    fn from(error: ReloadError) -> Self {
+        match error {
+            ReloadError::Load(ModelLoaderError::ApiError(_)) => Status::unavailable(error.to_string()),
+            ReloadError::Load(_) => Status::failed_precondition(error.to_string()),
+            _ => Status::internal(error.to_string()),
+        }
+    }
+}
+
+/// [`ReloadSummary`] lists the registry ids of the models affected by a reload, in alphabetical order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+impl ReloadSummary {
+    /// Returns whether the reload left every model as it was.
+    pub fn is_unchanged(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl From<ReloadSummary> for ReloadModelsResponse {
+    fn from(summary: ReloadSummary) -> Self {
+        Self {
+            added: summary.added,
+            updated: summary.updated,
+            removed: summary.removed,
+            unchanged: summary.unchanged,
+        }
+    }
+}
+
+/// [`ReloadPlan`] is the difference between the loaded models and the entries of the configuration file.
+#[derive(Debug)]
+struct ReloadPlan {
+    /// The entries of the new and changed models, which are loaded and built.
+    to_load: Vec<ModelConfig>,
+    summary: ReloadSummary,
+}
+
+impl ReloadPlan {
+    /// Compares the entries of the configuration file with the entries of the loaded models.
+    ///
+    /// # Arguments
+    ///
+    /// * `current` - The entries the loaded models were built from, keyed by registry id.
+    /// * `model_cfgs` - The entries of the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// A new [`ReloadPlan`] instance. A model whose entry differs in any way (e.g. its revision or
+    /// its dtype) is updated.
+    fn new(current: &HashMap<String, ModelConfig>, model_cfgs: Vec<ModelConfig>) -> Self {
+        let ids: HashSet<&str> = model_cfgs.iter().map(ModelConfig::id).collect();
+        let mut summary = ReloadSummary {
+            removed: current.keys().filter(|id| !ids.contains(id.as_str())).cloned().collect(),
+            ..Default::default()
+        };
+
+        let mut to_load: Vec<ModelConfig> = Vec::new();
+        for model_cfg in model_cfgs {
+            let id: String = model_cfg.id().to_owned();
+            match current.get(&id) {
+                Some(current_cfg) if *current_cfg == model_cfg => summary.unchanged.push(id),
+                Some(_) => {
+                    summary.updated.push(id);
+                    to_load.push(model_cfg);
+                }
+                None => {
+                    summary.added.push(id);
+                    to_load.push(model_cfg);
+                }
+            }
+        }
+        for ids in [&mut summary.added, &mut summary.updated, &mut summary.removed, &mut summary.unchanged] {
+            ids.sort();
+        }
+
+        Self { to_load, summary }
+    }
+}
+
+/// The state of the [`ModelReloader`], locked for the duration of a reload.
+struct ReloadState {
+    readiness: ModelReadiness,
+    /// The modification time of the configuration file when it was last read.
+    modified: Option<SystemTime>,
+}
+
+/// [`ModelReloader`] loads the models of the configuration file into a [`ProcessorSlot`], and
+/// reloads them when the file changes. Reloads never run concurrently.
+pub struct ModelReloader {
+    models_path: PathBuf,
+    offline: bool,
+    device: Device,
+    cache: CacheConfig,
+    concurrency: ConcurrencyConfig,
+    processor: ProcessorSlot,
+    state: Mutex<ReloadState>,
+}
+
+impl ModelReloader {
+    /// Creates a new instance of [`ModelReloader`].
+    ///
+    /// # Arguments
+    ///
+    /// * `models_path` - The path of the models configuration file.
+    /// * `offline` - Whether models of the Hugging Face Hub are only resolved from the local cache.
+    /// * `device` - The device on which the models are loaded.
+    /// * `cache` - The [`CacheConfig`] of the cache of every new processor.
+    /// * `concurrency` - The [`ConcurrencyConfig`] of the service, which sizes the decoder pools of
+    ///   the new models.
+    /// * `processor` - The [`ProcessorSlot`] of the vision service.
+    /// * `readiness` - The [`ModelReadiness`] reporting the loaded models to the health service.
+    ///
+    /// # Returns
+    ///
+    /// A new [`ModelReloader`] instance. No model is loaded until [`ModelReloader::reload`] is called.
+    pub fn new(
+        models_path: impl Into<PathBuf>,
+        offline: bool,
+        device: Device,
+        cache: CacheConfig,
+        concurrency: ConcurrencyConfig,
+        processor: ProcessorSlot,
+        readiness: ModelReadiness,
+    ) -> Self {
+        Self {
+            models_path: models_path.into(),
+            offline,
+            device,
+            cache,
+            concurrency,
+            processor,
+            state: Mutex::new(ReloadState { readiness, modified: None }),
+        }
+    }
+
+    /// Loads the models of the configuration file and swaps them into the processor slot.
+    ///
+    /// The first reload loads every model and fills the slot. Later reloads only load the new and
+    /// changed models, and return without swapping the processor if no model changed. A reload
+    /// waits for the reload in progress, if any.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the [`ReloadSummary`] of the changes.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ReloadError`] if the file cannot be read or parsed, or if a new model cannot be
+    /// downloaded or built. The current models are kept.
+    pub async fn reload(&self) -> Result<ReloadSummary, ReloadError> {
+        let mut state = self.state.lock().await;
+        state.modified = self.modified();
+
+        self.apply(&mut state).await
+    }
+
+    /// Reloads the models if the modification time of the configuration file changed since it was
+    /// last read. A file that failed to reload is not retried until it changes again.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the [`ReloadSummary`] of the changes, or `None` if the file did not change.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ReloadError`] for the reasons listed in [`ModelReloader::reload`].
+    pub async fn reload_if_modified(&self) -> Result<Option<ReloadSummary>, ReloadError> {
+        let mut state = self.state.lock().await;
+        let modified: Option<SystemTime> = self.modified();
+        if modified == state.modified {
+            return Ok(None);
+        }
+        state.modified = modified;
+
+        self.apply(&mut state).await.map(Some)
+    }
+
+    /// Spawns a task calling [`ModelReloader::reload_if_modified`] at the given interval.
+    /// Must be called from within a tokio runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - The time between two checks of the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// The [`JoinHandle`] of the task, which runs until it is aborted.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let reloader: Arc<Self> = self.clone();
+        tokio::spawn(async move {
+            let mut ticks: time::Interval = time::interval(interval);
+            ticks.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+            // The first tick completes immediately, and the file was just loaded
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                match reloader.reload_if_modified().await {
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("Failed to reload models, keeping the current ones: {}", e),
+                }
+            }
+        })
+    }
+
+    /// Applies the entries of the configuration file. The caller holds the lock of the state.
+    async fn apply(&self, state: &mut ReloadState) -> Result<ReloadSummary, ReloadError> {
+        let config_str: String = tokio::fs::read_to_string(&self.models_path)
+            .await
+            .map_err(ModelLoaderError::from)?;
+        let model_cfgs: Vec<ModelConfig> = model_loader::model_configs_from_toml_str(&config_str)?;
+
+        let current: Option<Arc<ImageProcessor>> = self.processor.get();
+        let current_cfgs: HashMap<String, ModelConfig> = current
+            .as_ref()
+            .map_or_else(HashMap::new, |processor| processor.model_configs());
+        let ReloadPlan { to_load, summary } = ReloadPlan::new(&current_cfgs, model_cfgs);
+        if current.is_some() && summary.is_unchanged() {
+            return Ok(summary);
+        }
+
+        // New models are not serving until they are loaded, and are unknown again if the reload fails
+        state.readiness.set_not_serving(summary.added.iter().map(String::as_str)).await;
+        let processor: ImageProcessor = match self.build(current, to_load, summary.unchanged.clone()).await {
+            Ok(processor) => processor,
+            Err(e) => {
+                state.readiness.set_removed(summary.added.iter().map(String::as_str)).await;
+                return Err(e);
+            }
+        };
+        self.processor.replace(processor);
+
+        state.readiness.set_removed(summary.removed.iter().map(String::as_str)).await;
+        state.readiness.set_ready(summary.added.iter().map(String::as_str)).await;
+        tracing::info!(
+            added = ?summary.added,
+            updated = ?summary.updated,
+            removed = ?summary.removed,
+            "Models changed",
+        );
+
+        Ok(summary)
+    }
+
+    /// Loads the new and changed models, and builds the processor serving them along with the kept
+    /// models of the current processor, if any.
+    async fn build(
+        &self,
+        current: Option<Arc<ImageProcessor>>,
+        to_load: Vec<ModelConfig>,
+        kept: Vec<String>,
+    ) -> Result<ImageProcessor, ReloadError> {
+        let models: Models = self.load(to_load).await?;
+        let device: Device = self.device.clone();
+        let cache: CacheConfig = self.cache.clone();
+        let concurrency: ConcurrencyConfig = self.concurrency.clone();
+        // Building the models is blocking
+        let processor: ImageProcessor = task::spawn_blocking(move || match current {
+            Some(current) => current.reload(&kept, &models, &cache, &concurrency),
+            None => ImageProcessor::new(&models, device, &cache, &concurrency),
+        })
+        .await??;
+
+        Ok(processor)
+    }
+
+    /// Downloads the given models, or resolves them from the Hugging Face cache in offline mode.
+    async fn load(&self, model_cfgs: Vec<ModelConfig>) -> Result<Models, ModelLoaderError> {
+        if self.offline {
+            return AsyncModelLoader::new(OfflineApi::default()).load_all(model_cfgs).await;
+        }
+
+        // Progress bars of parallel downloads would interleave, progress is traced by the loader instead
+        let model_loader: AsyncModelLoader<Api> = AsyncModelLoader::new(ApiBuilder::new().with_progress(false).build()?);
+        model_loader.load_all(model_cfgs).await
+    }
+
+    /// Returns the modification time of the configuration file, or `None` if it cannot be read.
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.models_path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    use crate::image_captioning::model_loader::ModelSource;
+    use crate::image_captioning::registry::ModelDType;
+
+    fn model_config(id: &str) -> ModelConfig {
+        let config_str: String = format!(
+            "id = {:?}\nrepository = \"some-repo/{}\"\nmodel = \"model.safetensors\"\ntokenizer = \"tokenizer.json\"",
+            id, id,
+        );
+        toml::from_str(&config_str).unwrap()
+    }
+
+    fn reloader(models_path: &std::path::Path) -> (ModelReloader, ProcessorSlot) {
+        let (reporter, _) = tonic_health::server::health_reporter();
+        let processor = ProcessorSlot::default();
+        let reloader = ModelReloader::new(
+            models_path,
+            true,
+            Device::Cpu,
+            CacheConfig::default(),
+            ConcurrencyConfig::default(),
+            processor.clone(),
+            ModelReadiness::new(reporter),
+        );
+
+        (reloader, processor)
+    }
+
+    #[test]
+    fn test_reload_plan() {
+        // GIVEN
+        let current: HashMap<String, ModelConfig> = ["blip", "blip_quantized", "blip_vqa"]
+            .into_iter()
+            .map(|id| (id.to_string(), model_config(id)))
+            .collect();
+        let model_cfgs: Vec<ModelConfig> = vec![
+            ModelConfig { dtype: ModelDType::F16, ..model_config("blip_vqa") },
+            model_config("blip_large"),
+            model_config("blip"),
+        ];
+        // WHEN
+        let plan: ReloadPlan = ReloadPlan::new(&current, model_cfgs);
+        // THEN
+        assert_eq!(plan.summary, ReloadSummary {
+            added: vec!["blip_large".to_string()],
+            updated: vec!["blip_vqa".to_string()],
+            removed: vec!["blip_quantized".to_string()],
+            unchanged: vec!["blip".to_string()],
+        });
+        let to_load: Vec<&str> = plan.to_load.iter().map(ModelConfig::id).collect();
+        assert_eq!(to_load, ["blip_vqa", "blip_large"]);
+        assert_eq!(plan.to_load[0].dtype, ModelDType::F16);
+    }
+
+    #[test]
+    fn test_reload_plan_unchanged() {
+        // GIVEN
+        let current: HashMap<String, ModelConfig> = HashMap::from([("blip".to_string(), model_config("blip"))]);
+        // WHEN
+        let plan: ReloadPlan = ReloadPlan::new(&current, vec![model_config("blip")]);
+        // THEN
+        assert!(plan.summary.is_unchanged());
+        assert!(plan.to_load.is_empty());
+    }
+
+    #[test]
+    fn test_reload_error_into_status() {
+        // GIVEN
+        let errors: Vec<ReloadError> = vec![
+            ModelLoaderError::DuplicateModelId("blip".to_string()).into(),
+            ModelLoaderError::ApiError(hf_hub::api::sync::ApiError::IoError(std::io::ErrorKind::ConnectionReset.into())).into(),
+            candle_core::Error::Msg("out of memory".to_string()).into(),
+        ];
+        // WHEN
+        let codes: Vec<tonic::Code> = errors.into_iter().map(|error| Status::from(error).code()).collect();
+        // THEN
+        assert_eq!(codes, [tonic::Code::FailedPrecondition, tonic::Code::Unavailable, tonic::Code::Internal]);
+    }
+
+    #[tokio::test]
+    async fn test_model_reloader_reload() {
+        // GIVEN
+        let mut file: NamedTempFile = NamedTempFile::new().unwrap();
+        file.write_all(b"model = []").unwrap();
+        let (reloader, processor) = reloader(file.path());
+        // WHEN
+        let loaded: ReloadSummary = reloader.reload().await.unwrap();
+        let unmodified: Option<ReloadSummary> = reloader.reload_if_modified().await.unwrap();
+        // THEN
+        assert!(loaded.is_unchanged());
+        assert!(processor.get().is_some_and(|processor| processor.list_models().is_empty()));
+        assert_eq!(unmodified, None);
+    }
+
+    #[tokio::test]
+    async fn test_model_reloader_reload_keeps_models_on_error() {
+        // GIVEN
+        let mut file: NamedTempFile = NamedTempFile::new().unwrap();
+        file.write_all(b"model = []").unwrap();
+        let (reloader, processor) = reloader(file.path());
+        reloader.reload().await.unwrap();
+        let loaded: Arc<ImageProcessor> = processor.get().unwrap();
+        let missing = ModelConfig { source: ModelSource::Directory, ..model_config("blip") };
+        let config_str: String = format!(
+            "[[model]]\nid = {:?}\nsource = \"directory\"\nrepository = \"/nonexistent/blip\"\nmodel = {:?}\ntokenizer = {:?}",
+            missing.id(), missing.model, missing.tokenizer,
+        );
+        std::fs::write(file.path(), config_str).unwrap();
+        // WHEN
+        let result: Result<ReloadSummary, ReloadError> = reloader.reload().await;
+        // THEN
+        assert!(matches!(result, Err(ReloadError::Load(ModelLoaderError::MissingFiles(_)))));
+        assert!(Arc::ptr_eq(&processor.get().unwrap(), &loaded));
+    }
+}
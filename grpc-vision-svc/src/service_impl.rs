@@ -1,57 +1,285 @@
 //! This module provides the [`ComputerVisionSvc`] struct and its associated methods for image processing.
 //! 
-//! The primary functionality includes handling single and batch image processing requests using gRPC.
+//! The primary functionality includes handling single, batch and streaming image processing requests,
+//! as well as visual question answering requests, using gRPC.
 //! The [`ComputerVisionSvc`] utilizes an [`ImageProcessor`] to perform the actual processing of images
-//! and a semaphore to limit the number of concurrent requests for efficient resource management.
-use std::sync::Arc;
-use tokio::task::{self, JoinError};
-use tokio::sync::{mpsc, Semaphore, OwnedSemaphorePermit};
+//! and a [`ConcurrencyLimiter`] to limit the number of concurrent requests and shed excess load. A
+//! [`RateLimiter`] keeps a single client from taking all of the capacity.
+//! Every request gets a [`RequestCancellation`], so that the generation of requests whose client went
+//! away or whose deadline expired stops early and releases its permit.
+//! Processing failures are returned as statuses whose code and `google.rpc` details depend on the
+//! [`ProcessingError`], so that clients can tell a bad image from a failure of the server.
+use std::sync::{Arc, RwLock};
+use tokio::task::{self, JoinError, JoinHandle, JoinSet};
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt};
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::{Request, Response, Status, Streaming};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{Instrument, Span};
+use tonic::{Code, Request, Response, Status, Streaming};
 use candle_core::{Device, Result as CandleResult};
-use crate::image_captioning::ImageProcessor;
+use crate::auth::Subject;
+use crate::batching::{BatchingConfig, DynamicBatcher};
+use crate::cancellation::RequestCancellation;
+use crate::concurrency::{ConcurrencyConfig, ConcurrencyLimiter, Permit};
+use crate::error_details::ErrorDetails;
+use crate::image_captioning::{ImageProcessor, ModelDescription};
+use crate::image_captioning::cache::CacheConfig;
+use crate::image_captioning::error::{ProcessingError, ProcessingResult};
+use crate::image_captioning::generation::{GenerationConfig, GenerationParams};
 use crate::image_captioning::model_loader::Models;
-use crate::proto::{ImgProcRequest, ImgProcResponse, ModelType};
+use crate::image_captioning::registry::{self, Architecture};
+use crate::metrics::RpcMetrics;
+use crate::rate_limit::{Budget, ClientKey, RateLimitConfig, RateLimiter};
+use crate::proto::{
+    BatchOrdering, CaptionChunk, DescribeModelRequest, GenerationOptions, ImgProcRequest, ImgProcResponse, ItemError,
+    ListModelsRequest, ListModelsResponse, ModelInfo, ModelTask, ModelType, VqaRequest, VqaResponse,
+};
 use crate::proto::computer_vision_server::ComputerVision;
 
-/// Maximum number of concurrent requests that can be processed.
-const MAX_CONCURRENT_REQUESTS: usize = 16;
+/// Capacity of the channels buffering the responses of a batch.
+const BATCH_CHANNEL_CAPACITY: usize = 128;
+
+/// Maximum length of a conditional captioning prompt, in characters.
+const MAX_PROMPT_LENGTH: usize = 256;
+
+/// Maximum length of a question about an image, in characters.
+const MAX_QUESTION_LENGTH: usize = 256;
+
+/// Registry id of the model used for question answering when a request does not name one.
+const DEFAULT_VQA_MODEL_ID: &str = "blip_vqa";
 
 /// Type alias for a result that returns a gRPC [`Response`] or a [`Status`].
 type ResponseResult<T> = Result<Response<T>, Status>;
 
+/// Default revision of a Hugging Face repository, used when a model config does not pin one.
+const DEFAULT_REVISION: &str = "main";
+
+impl From<ModelDescription> for ModelInfo {
+    fn from(description: ModelDescription) -> Self {
+        let tasks: Vec<i32> = description.architecture
+            .tasks()
+            .into_iter()
+            .map(|task: ModelTask| task as i32)
+            .collect();
+
+        Self {
+            id: description.id,
+            repository: description.repository,
+            revision: description.revision.unwrap_or_else(|| DEFAULT_REVISION.to_string()),
+            model_path: description.model_path.display().to_string(),
+            tokenizer_path: description.tokenizer_path.display().to_string(),
+            architecture: description.architecture.as_str().to_string(),
+            dtype: description.dtype.as_str().to_string(),
+            quantization: description.quantization.unwrap_or_default(),
+            device: description.device,
+            input_resolution: description.input_resolution as u32,
+            tasks,
+        }
+    }
+}
+
+impl ImgProcResponse {
+    /// Creates the response of a failed [`ComputerVision::process_image_batch`] item.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The position of the failed request in the batch stream.
+    /// * `request_id` - The client supplied id of the failed request.
+    /// * `status` - The [`Status`] describing why the item failed.
+    ///
+    /// # Returns
+    ///
+    /// An [`ImgProcResponse`] with an empty description and the error of the item, including the
+    /// `google.rpc.ErrorInfo` reason of the status if it has one.
+    fn item_error(index: u32, request_id: String, status: &Status) -> Self {
+        let reason: Option<String> = ErrorDetails::from_status(status)
+            .ok()
+            .and_then(|details| details.reason().map(str::to_string));
+
+        Self {
+            index,
+            request_id,
+            error: Some(ItemError {
+                code: status.code() as i32,
+                message: status.message().to_string(),
+                reason: reason.unwrap_or_default(),
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+/// [`PendingItem`] is a batch item whose response is being computed by a spawned task.
+///
+/// The item is recorded in the metrics as a `ProcessImageBatch` request once its response is ready.
+struct PendingItem {
+    index: u32,
+    request_id: String,
+    handle: JoinHandle<ImgProcResponse>,
+    rpc: RpcMetrics,
+}
+
+impl PendingItem {
+    /// Creates an item whose error response is ready immediately.
+    fn ready(index: u32, request_id: String, status: &Status, rpc: RpcMetrics) -> Self {
+        let response: ImgProcResponse = ImgProcResponse::item_error(index, request_id.clone(), status);
+
+        Self { index, request_id, handle: tokio::spawn(async move { response }), rpc }
+    }
+
+    /// Waits for the response of the item. If its task failed, an error response is returned instead.
+    async fn response(self) -> ImgProcResponse {
+        let response: ImgProcResponse = self.handle.await.unwrap_or_else(|e| {
+            tracing::error!("Error executing batch item task: {:?}", e);
+            let status = Status::internal(format!("Error executing batch item task: {}", e));
+            ImgProcResponse::item_error(self.index, self.request_id, &status)
+        });
+
+        let code: Code = response.error.as_ref().map_or(Code::Ok, |error| Code::from_i32(error.code));
+        self.rpc.finish_with_code(code);
+        response
+    }
+}
+
+/// [`ProcessorSlot`] is a shared slot the [`ImageProcessor`] is put into once its models are built.
+///
+/// The server starts accepting connections before the models are downloaded and built, so that the
+/// health service can report the warm-up. Until the slot is filled, [`ComputerVisionSvc`] rejects
+/// requests with [`Status::unavailable`].
+///
+/// When the models are reloaded, the processor is swapped atomically. Every request takes its own
+/// [`Arc`] of the processor when it is admitted, so requests in flight finish with the previous
+/// processor, whose models are dropped with the last of them.
+#[derive(Clone, Default)]
+pub struct ProcessorSlot(Arc<RwLock<Option<Arc<ImageProcessor>>>>);
+
+impl ProcessorSlot {
+    /// Puts the processor into the slot.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the slot was empty, otherwise `false` and the slot keeps its current processor.
+    pub fn fill(&self, processor: ImageProcessor) -> bool {
+        let mut slot = self.0.write().unwrap();
+        if slot.is_some() {
+            return false;
+        }
+        *slot = Some(Arc::new(processor));
+        true
+    }
+
+    /// Puts the processor into the slot, in place of the current one if any.
+    ///
+    /// # Returns
+    ///
+    /// The previous processor, or `None` if the slot was empty.
+    pub fn replace(&self, processor: ImageProcessor) -> Option<Arc<ImageProcessor>> {
+        self.0.write().unwrap().replace(Arc::new(processor))
+    }
+
+    /// Returns the processor, or `None` if the models are still loading.
+    pub fn get(&self) -> Option<Arc<ImageProcessor>> {
+        self.0.read().unwrap().clone()
+    }
+}
+
 /// The [`ComputerVisionSvc`] struct provides methods for processing images.
-/// It holds an [`ImageProcessor`] instance and a semaphore for limiting concurrent requests.
+/// It holds a [`ProcessorSlot`] with the [`ImageProcessor`] instance, the default generation parameters,
+/// a [`DynamicBatcher`] grouping concurrent captioning requests, a [`ConcurrencyLimiter`] admitting
+/// requests and a [`RateLimiter`] applying the per-client rate limits.
+#[derive(Clone)]
 pub struct ComputerVisionSvc {
-    processor: Arc<ImageProcessor>,
-    semaphore: Arc<Semaphore>,
+    processor: ProcessorSlot,
+    generation: GenerationConfig,
+    batcher: DynamicBatcher<ImageProcessor>,
+    limiter: ConcurrencyLimiter,
+    rate_limiter: RateLimiter,
 }
 
 impl ComputerVisionSvc {
     /// Creates a new instance of [`ComputerVisionSvc`].
     ///
-    /// This method initializes the image processor and the semaphore for controlling
-    /// the number of concurrent requests.
+    /// This method initializes the image processor and the limiter controlling the number of
+    /// concurrent requests.
     ///
     /// # Arguments
     ///
     /// * `models` - A reference to the [`Models`] struct containing the model configurations.
     /// * `device` - The device on which the models will be loaded.
+    /// * `generation` - The default generation parameters used when a request does not override them.
+    /// * `batching` - The parameters of the dynamic batching of captioning requests.
+    /// * `cache` - The parameters of the cache of image embeddings and captions.
+    /// * `concurrency` - The concurrency limits and the wait queue of the requests.
+    /// * `rate_limit` - The per-client rate limits of the requests.
     ///
     /// # Returns
     ///
     /// A [`CandleResult`] containing the new [`ComputerVisionSvc`] instance or an error if
     /// initialization fails.
-    pub fn new(models: &Models, device: Device) -> CandleResult<Self> {
-        Ok(Self {
-            processor: Arc::new(ImageProcessor::new(models, device)?),
-            semaphore: Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS)),
-        })
+    pub fn new(
+        models: &Models,
+        device: Device,
+        generation: GenerationConfig,
+        batching: BatchingConfig,
+        cache: CacheConfig,
+        concurrency: ConcurrencyConfig,
+        rate_limit: RateLimitConfig,
+    ) -> CandleResult<Self> {
+        let processor = ProcessorSlot::default();
+        processor.fill(ImageProcessor::new(models, device, &cache, &concurrency)?);
+
+        Ok(Self::with_processor_slot(processor, generation, batching, concurrency, rate_limit))
+    }
+
+    /// Creates a new instance of [`ComputerVisionSvc`] whose image processor is provided later.
+    ///
+    /// Requests are rejected with [`Status::unavailable`] until `processor` is filled.
+    ///
+    /// # Arguments
+    ///
+    /// * `processor` - The [`ProcessorSlot`] the image processor will be put into once it is built.
+    /// * `generation` - The default generation parameters used when a request does not override them.
+    /// * `batching` - The parameters of the dynamic batching of captioning requests.
+    /// * `concurrency` - The concurrency limits and the wait queue of the requests.
+    /// * `rate_limit` - The per-client rate limits of the requests.
+    ///
+    /// # Returns
+    ///
+    /// A new [`ComputerVisionSvc`] instance.
+    pub fn with_processor_slot(
+        processor: ProcessorSlot,
+        generation: GenerationConfig,
+        batching: BatchingConfig,
+        concurrency: ConcurrencyConfig,
+        rate_limit: RateLimitConfig,
+    ) -> Self {
+        Self {
+            processor,
+            generation,
+            batcher: DynamicBatcher::new(batching),
+            limiter: ConcurrencyLimiter::new(concurrency),
+            rate_limiter: RateLimiter::new(rate_limit),
+        }
+    }
+
+    /// Returns the image processor.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`Status::unavailable`] if the models are still loading.
+    // The `Status` is returned to the client as is
+    #[allow(clippy::result_large_err)]
+    fn processor(&self) -> Result<Arc<ImageProcessor>, Status> {
+        self.processor
+            .get()
+            .ok_or_else(|| Status::unavailable("Models are still loading"))
     }
 
     /// Validates an [`ImgProcRequest`] to ensure it is well-formed.
     ///
-    /// This method checks if the request's image field is not empty and if the model type is valid.
+    /// This method checks if the request's image field is not empty, if the requested model is a
+    /// loaded captioning model and if the prompt is not too long.
     ///
     /// # Arguments
     ///
@@ -59,19 +287,364 @@ impl ComputerVisionSvc {
     ///
     /// # Returns
     ///
-    /// An `Ok(())` if the request is valid, otherwise an `Err(Status)` describing the problem.
+    /// The registry id of the requested model if the request is valid, otherwise an `Err(Status)`
+    /// describing the problem.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`Status::invalid_argument`] if the image is empty, the model type is invalid, the
+    /// model does not support image captioning or the prompt exceeds [`MAX_PROMPT_LENGTH`] characters,
+    /// a [`Status::not_found`] if the model is not loaded, and a [`Status::unavailable`] if the models
+    /// are still loading.
+    // The `Status` is returned to the client as is
+    #[allow(clippy::result_large_err)]
+    fn validate_request(&self, request: &ImgProcRequest) -> Result<String, Status> {
+        if request.image.is_empty() {
+            return Err(Status::invalid_argument("Empty vector of bytes"));
+        }
+        let model_id: String = if request.model_id.is_empty() {
+            let model: ModelType = ModelType::try_from(request.model)
+                .map_err(|_| Status::invalid_argument("Invalid model type"))?;
+            registry::legacy_model_id(model)
+        } else {
+            request.model_id.clone()
+        };
+        if !self.model_architecture(&model_id)?.supports_captioning() {
+            return Err(ProcessingError::UnsupportedTask { model_id, task: "image captioning" }.into());
+        }
+        if request.prompt.chars().count() > MAX_PROMPT_LENGTH {
+            return Err(Status::invalid_argument(format!(
+                "Prompt is longer than {} characters", MAX_PROMPT_LENGTH,
+            )));
+        }
+
+        Ok(model_id)
+    }
+
+    /// Resolves the generation parameters of an [`ImgProcRequest`].
+    ///
+    /// The options supplied by the client override the server defaults and are validated. The prompt
+    /// of the request is used as the conditional captioning prompt.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - A reference to the [`ImgProcRequest`] whose options should be resolved.
+    ///
+    /// # Returns
+    ///
+    /// The resolved [`GenerationParams`], otherwise an `Err(Status)` describing the problem.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`Status::invalid_argument`] if any of the generation options is out of range.
+    // The `Status` is returned to the client as is
+    #[allow(clippy::result_large_err)]
+    fn generation_params(&self, request: &ImgProcRequest) -> Result<GenerationParams, Status> {
+        let params: GenerationParams = self.resolve_options(request.options.as_ref())?;
+
+        Ok(GenerationParams {
+            prompt: request.prompt.clone(),
+            ..params
+        })
+    }
+
+    /// Validates a [`VqaRequest`] to ensure it is well-formed.
+    ///
+    /// This method checks if the request's image field is not empty, if the question is not blank
+    /// and not too long, and if the requested VQA model is loaded. Requests without a model id use
+    /// the [`DEFAULT_VQA_MODEL_ID`] model.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - A reference to the [`VqaRequest`] to be validated.
+    ///
+    /// # Returns
+    ///
+    /// The registry id of the requested model if the request is valid, otherwise an `Err(Status)`
+    /// describing the problem.
     ///
     /// # Errors
     ///
-    /// Returns a [`Status::invalid_argument`] if the image is empty or the model type is invalid.
-    fn validate_request(&self, request: &ImgProcRequest) -> Result<(), Status> {
+    /// Returns a [`Status::invalid_argument`] if the image is empty, the question is blank or
+    /// exceeds [`MAX_QUESTION_LENGTH`] characters, or the model does not support question answering,
+    /// a [`Status::not_found`] if the model is not loaded, and a [`Status::unavailable`] if the models
+    /// are still loading.
+    // The `Status` is returned to the client as is
+    #[allow(clippy::result_large_err)]
+    fn validate_vqa_request(&self, request: &VqaRequest) -> Result<String, Status> {
         if request.image.is_empty() {
             return Err(Status::invalid_argument("Empty vector of bytes"));
         }
-        ModelType::try_from(request.model)
-            .map_err(|_| Status::invalid_argument("Invalid model type"))?;
+        if request.question.trim().is_empty() {
+            return Err(Status::invalid_argument("Empty question"));
+        }
+        if request.question.chars().count() > MAX_QUESTION_LENGTH {
+            return Err(Status::invalid_argument(format!(
+                "Question is longer than {} characters", MAX_QUESTION_LENGTH,
+            )));
+        }
+        let model_id: String = if request.model_id.is_empty() {
+            DEFAULT_VQA_MODEL_ID.to_string()
+        } else {
+            request.model_id.clone()
+        };
+        if !self.model_architecture(&model_id)?.supports_question_answering() {
+            return Err(ProcessingError::UnsupportedTask { model_id, task: "question answering" }.into());
+        }
+
+        Ok(model_id)
+    }
+
+    /// Starts processing a [`ComputerVision::process_image_batch`] stream in the background.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream of batch requests, e.g. a [`Streaming<ImgProcRequest>`].
+    /// * `cancellation` - The [`RequestCancellation`] of the whole batch stream. It is cancelled when
+    ///   the response stream is dropped.
+    /// * `client` - The [`ClientKey`] of the client sending the batch.
+    ///
+    /// # Returns
+    ///
+    /// The stream of responses, which ends after the response of the last item.
+    fn start_batch(
+        &self,
+        stream: impl Stream<Item = Result<ImgProcRequest, Status>> + Send + Unpin + 'static,
+        cancellation: RequestCancellation,
+        client: ClientKey,
+    ) -> ReceiverStream<Result<ImgProcResponse, Status>> {
+        let (tx, rx): (mpsc::Sender<_>, mpsc::Receiver<_>) = mpsc::channel(BATCH_CHANNEL_CAPACITY);
+        cancellation.cancel_when_closed(&tx);
+
+        // Requests are read in the background, so responses can be streamed while the batch is uploaded
+        tokio::spawn(self.clone().run_batch(stream, tx, cancellation, client).in_current_span());
+
+        ReceiverStream::new(rx)
+    }
+
+    /// Reads the requests of a [`ComputerVision::process_image_batch`] stream and processes them.
+    ///
+    /// Responses are sent to `tx` as soon as they are ready, or, if the first request asks for
+    /// [`BatchOrdering::PreserveInputOrder`], in the order of the requests. Later requests asking
+    /// for another ordering fail with an [`ItemError`].
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The stream of batch requests.
+    /// * `tx` - The sender of the response stream.
+    /// * `cancellation` - The [`RequestCancellation`] of the whole batch stream. Each item gets a child of it.
+    /// * `client` - The [`ClientKey`] of the client, whose `batch_items` budget every item counts against.
+    async fn run_batch(
+        self,
+        mut stream: impl Stream<Item = Result<ImgProcRequest, Status>> + Send + Unpin + 'static,
+        tx: mpsc::Sender<Result<ImgProcResponse, Status>>,
+        cancellation: RequestCancellation,
+        client: ClientKey,
+    ) {
+        // Stops the background tasks of the cancellation once every response was sent
+        let _cancel_on_drop: DropGuard = cancellation.cancel_on_drop();
+        let mut in_order: Option<(mpsc::Sender<PendingItem>, JoinHandle<()>)> = None;
+        let mut as_completed: JoinSet<()> = JoinSet::new();
+        let mut ordering: BatchOrdering = BatchOrdering::AsCompleted;
+        let mut index: u32 = 0;
+
+        let stream_result: Result<(), Status> = loop {
+            let request: ImgProcRequest = match stream.next().await {
+                Some(Ok(request)) => request,
+                None => break Ok(()),
+                Some(Err(status)) => break Err(status),
+            };
+            if index == 0 {
+                ordering = request.ordering();
+                if ordering == BatchOrdering::PreserveInputOrder {
+                    let (pending_tx, pending_rx): (mpsc::Sender<_>, mpsc::Receiver<_>) = mpsc::channel(BATCH_CHANNEL_CAPACITY);
+                    in_order = Some((pending_tx, tokio::spawn(forward_in_order(pending_rx, tx.clone()))));
+                }
+            }
+
+            let item: PendingItem = self.spawn_batch_item(index, request, ordering, cancellation.child(), &client).await;
+            index += 1;
+
+            match &in_order {
+                Some((pending_tx, _)) => {
+                    if pending_tx.send(item).await.is_err() {
+                        break Ok(());
+                    }
+                }
+                None => {
+                    let tx: mpsc::Sender<_> = tx.clone();
+                    as_completed.spawn(async move {
+                        if let Err(e) = tx.send(Ok(item.response().await)).await {
+                            tracing::error!("Error sending response: {:?}", e);
+                        }
+                    }.in_current_span());
+                }
+            }
+        };
+
+        // Let the pending items be delivered before the stream error, if any
+        if let Some((pending_tx, forwarder)) = in_order {
+            drop(pending_tx);
+            let _ = forwarder.await;
+        }
+        while as_completed.join_next().await.is_some() {}
+        if let Err(status) = stream_result {
+            tracing::error!("Error reading batch request stream: {:?}", status);
+            let _ = tx.send(Err(status)).await;
+        }
+    }
+
+    /// Validates a batch item and spawns the task computing its response.
+    ///
+    /// The method waits for a permit of the [`ConcurrencyLimiter`] before spawning the processing
+    /// task, which applies backpressure on the request stream. Invalid items do not need a permit;
+    /// their error response is ready immediately, like the `RESOURCE_EXHAUSTED` response of items
+    /// rejected by the rate limiter or the concurrency limiter.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The position of the request in the batch stream.
+    /// * `request` - The [`ImgProcRequest`] to process.
+    /// * `ordering` - The [`BatchOrdering`] of the batch, set by its first request.
+    /// * `cancellation` - The [`RequestCancellation`] of the item.
+    /// * `client` - The [`ClientKey`] of the client sending the batch.
+    ///
+    /// # Returns
+    ///
+    /// A [`PendingItem`] resolving to the response of the request.
+    // The `Status` of a rejected item is sent on the response stream as is
+    #[allow(clippy::result_large_err)]
+    async fn spawn_batch_item(
+        &self,
+        index: u32,
+        request: ImgProcRequest,
+        ordering: BatchOrdering,
+        cancellation: RequestCancellation,
+        client: &ClientKey,
+    ) -> PendingItem {
+        let mut rpc: RpcMetrics = RpcMetrics::start("ProcessImageBatch");
+        let request_id: String = request.request_id.clone();
+        let prepared: Result<(String, GenerationParams, Arc<ImageProcessor>), Status> = validate_ordering(&request, ordering)
+            .and_then(|()| self.validate_request(&request))
+            .and_then(|model_id| Ok((model_id, self.generation_params(&request)?, self.processor()?)));
+
+        let (model_id, params, processor) = match prepared {
+            Ok(prepared) => prepared,
+            Err(status) => {
+                tracing::warn!(index, "Invalid batch item: {}", status.message());
+                return PendingItem::ready(index, request_id, &status, rpc);
+            }
+        };
+        rpc.set_model(&model_id);
+
+        if let Err(e) = self.rate_limiter.check(Budget::BatchItems, client) {
+            tracing::warn!(index, "Batch item rate limited: {}", e);
+            return PendingItem::ready(index, request_id, &Status::from(e), rpc);
+        }
+        let _permit: Permit = match self.limiter.acquire(&model_id).await {
+            Ok(permit) => permit,
+            Err(e) => {
+                tracing::warn!(index, "Batch item rejected: {}", e);
+                return PendingItem::ready(index, request_id, &Status::from(e), rpc);
+            }
+        };
 
+        let item_request_id: String = request_id.clone();
+        let batcher: DynamicBatcher<ImageProcessor> = self.batcher.clone();
+        let handle: JoinHandle<ImgProcResponse> = tokio::spawn(async move {
+            let ImgProcRequest { image, bypass_cache, .. } = request;
+
+            let process_result: ProcessingResult<String> =
+                batcher.caption(processor, model_id, image, params, bypass_cache, cancellation.token().clone()).await;
+
+            drop(_permit);
+
+            match process_result {
+                Ok(description) => ImgProcResponse {
+                    description,
+                    index,
+                    request_id: item_request_id,
+                    error: None,
+                },
+                Err(_) if cancellation.is_cancelled() => {
+                    ImgProcResponse::item_error(index, item_request_id, &cancellation.status())
+                }
+                Err(e) => ImgProcResponse::item_error(index, item_request_id, &processing_status(e)),
+            }
+        }.instrument(tracing::info_span!("batch_item", index, request_id = %request_id)));
+
+        PendingItem { index, request_id, handle, rpc }
+    }
+
+    /// Looks up the architecture of a loaded model.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`Status::not_found`] if no model with the given registry id is loaded.
+    // The `Status` is returned to the client as is
+    #[allow(clippy::result_large_err)]
+    fn model_architecture(&self, model_id: &str) -> Result<Architecture, Status> {
+        self.processor()?
+            .architecture(model_id)
+            .ok_or_else(|| ProcessingError::ModelNotLoaded(model_id.to_string()).into())
+    }
+
+    /// Resolves client supplied [`GenerationOptions`] against the server defaults.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`Status::invalid_argument`] if any of the generation options is out of range.
+    // The `Status` is returned to the client as is
+    #[allow(clippy::result_large_err)]
+    fn resolve_options(&self, options: Option<&GenerationOptions>) -> Result<GenerationParams, Status> {
+        self.generation
+            .resolve(options)
+            .map_err(|e| Status::invalid_argument(format!("Invalid generation options: {}", e)))
+    }
+}
+
+/// Converts the [`ProcessingError`] of a request into its [`Status`].
+///
+/// Errors caused by the request are logged as warnings, and failures of the server as errors.
+fn processing_status(error: ProcessingError) -> Status {
+    match error.code() {
+        Code::Internal => tracing::error!("Error processing request: {}", error),
+        _ => tracing::warn!("Request failed: {}", error),
+    }
+
+    Status::from(error)
+}
+
+/// Checks that a batch item asks for the ordering of its batch, which is set by the first request.
+///
+/// # Errors
+///
+/// Returns a [`Status::invalid_argument`] if the `ordering` of the request differs.
+// The `Status` of a rejected item is sent on the response stream as is
+#[allow(clippy::result_large_err)]
+fn validate_ordering(request: &ImgProcRequest, ordering: BatchOrdering) -> Result<(), Status> {
+    if request.ordering() == ordering {
         Ok(())
+    } else {
+        Err(Status::invalid_argument(format!(
+            "Ordering {} differs from the ordering {} of the first request of the batch",
+            request.ordering().as_str_name(), ordering.as_str_name(),
+        )))
+    }
+}
+
+/// Sends the responses of pending batch items in the order the items were received.
+///
+/// # Arguments
+///
+/// * `pending_rx` - The receiver of the pending items, in input order.
+/// * `tx` - The sender of the response stream.
+async fn forward_in_order(mut pending_rx: mpsc::Receiver<PendingItem>, tx: mpsc::Sender<Result<ImgProcResponse, Status>>) {
+    while let Some(item) = pending_rx.recv().await {
+        if let Err(e) = tx.send(Ok(item.response().await)).await {
+            tracing::error!("Error sending response: {:?}", e);
+            break;
+        }
     }
 }
 
@@ -80,11 +653,15 @@ impl ComputerVision for ComputerVisionSvc {
     /// The stream type for the `process_image_batch` method.
     type ProcessImageBatchStream = ReceiverStream<Result<ImgProcResponse, Status>>;
 
+    /// The stream type for the `stream_caption` method.
+    type StreamCaptionStream = ReceiverStream<Result<CaptionChunk, Status>>;
+
     /// Processes a single image and returns a description.
     ///
     /// This method handles the processing of a single image request by validating the request,
-    /// acquiring a semaphore permit to limit concurrency, and then spawning a blocking task to
-    /// perform the actual image processing. The result is then sent back as a gRPC response.
+    /// waiting for a permit of the concurrency limiter, and then handing the image to the
+    /// [`DynamicBatcher`], which processes it together with concurrent requests for the same model.
+    /// The result is then sent back as a gRPC response.
     ///
     /// # Arguments
     ///
@@ -97,51 +674,64 @@ impl ComputerVision for ComputerVisionSvc {
     ///
     /// # Errors
     ///
-    /// Returns a [`Status::invalid_argument`] if the request is invalid, [`Status::resource_exhausted`]
-    /// if too many concurrent requests are being processed, or [`Status::internal`] if an error occurs
-    /// during processing.
+    /// Returns a [`Status::invalid_argument`] if the request is invalid or the image cannot be
+    /// decoded, [`Status::not_found`] if the model is not loaded, [`Status::unavailable`] if the
+    /// models are still loading, [`Status::resource_exhausted`] with a retry hint if the client
+    /// exceeded its rate limit or the request is shed by the concurrency limiter,
+    /// [`Status::deadline_exceeded`] if the deadline of the client expires during generation, or
+    /// [`Status::internal`] if an error occurs during processing.
     async fn process_image(&self, request: Request<ImgProcRequest>) -> ResponseResult<ImgProcResponse> {
-        tracing::info!(peer_addr = ?request.remote_addr(), "ProcessImage Invoked");
+        tracing::info!(peer_addr = ?request.remote_addr(), subject = Subject::from_request(&request).map(Subject::id), "ProcessImage Invoked");
+        let mut rpc: RpcMetrics = RpcMetrics::start("ProcessImage");
 
-        self.validate_request(request.get_ref())?;
-        let ImgProcRequest { model, image } = request.into_inner();
+        // Stops the generation if the client goes away, as tonic then drops this future
+        let cancellation: RequestCancellation = RequestCancellation::from_metadata(request.metadata());
+        let _cancel_on_drop: DropGuard = cancellation.cancel_on_drop();
 
-        // Safely unwrap as validation ensures validity
-        let model = ModelType::try_from(model).unwrap();
-        let processor: Arc<ImageProcessor> = Arc::clone(&self.processor);
-        let semaphore: Arc<Semaphore> = Arc::clone(&self.semaphore);
+        let result: ResponseResult<ImgProcResponse> = async {
+            let model_id: String = self.validate_request(request.get_ref())?;
+            rpc.set_model(&model_id);
+            let params: GenerationParams = self.generation_params(request.get_ref())?;
+            self.rate_limiter.check(Budget::ProcessImage, &ClientKey::from_request(&request))?;
+            let ImgProcRequest { image, request_id, bypass_cache, .. } = request.into_inner();
 
-        let _permit: OwnedSemaphorePermit = semaphore
-            .acquire_owned()
-            .await
-            .map_err(|_| Status::resource_exhausted("Too many concurrent requests"))?;
+            let processor: Arc<ImageProcessor> = self.processor()?;
+            let _permit: Permit = self.limiter.acquire(&model_id).await?;
 
-        let process_result: Result<CandleResult<String>, JoinError> =
-            task::spawn_blocking(move || processor.process_image(model, &image)).await;
+            let process_result: ProcessingResult<String> =
+                self.batcher.caption(processor, model_id, image, params, bypass_cache, cancellation.token().clone()).await;
 
-        drop(_permit);
+            drop(_permit);
 
-        match process_result {
-            Ok(Ok(description)) => {
-                let response = ImgProcResponse { description };
-                Ok(Response::new(response))
-            }
-            Ok(Err(e)) => {
-                tracing::error!("Error processing image: {:?}", e);
-                Err(Status::internal(format!("Error processing image: {}", e)))
-            }
-            Err(e) => {
-                tracing::error!("Error executing blocking task: {:?}", e);
-                Err(Status::internal(format!("Error executing blocking task: {}", e)))
+            match process_result {
+                Ok(description) => {
+                    let response = ImgProcResponse { description, request_id, ..Default::default() };
+                    Ok(Response::new(response))
+                }
+                Err(_) if cancellation.is_cancelled() => Err(cancellation.status()),
+                Err(e) => Err(processing_status(e)),
             }
         }
+        .await;
+
+        rpc.finish(&result);
+        result
     }
 
     /// Processes a stream of image requests and returns a stream of responses.
     ///
     /// This method handles the processing of a batch of image requests received as a stream.
-    /// It validates each request, acquires a semaphore permit, and spawns a blocking task for each
-    /// image processing operation. The responses are sent back as a stream of [`ImgProcResponse`].
+    /// It validates each request, waits for a permit of the concurrency limiter, and hands each image to the
+    /// [`DynamicBatcher`]. The responses are sent back as a stream of [`ImgProcResponse`].
+    ///
+    /// Every request of the batch gets exactly one response carrying its `index` in the request
+    /// stream and its `request_id`. Items that fail validation or processing get a response with an
+    /// [`ItemError`] instead of a description, so a single bad item neither aborts the batch nor gets
+    /// lost. The `ordering` of the first request selects whether responses are delivered as soon as
+    /// they are ready or in the order of the requests; later requests must repeat it.
+    ///
+    /// Items still being processed when the client closes the response stream or when the deadline
+    /// of the call expires are cancelled.
     ///
     /// # Arguments
     ///
@@ -149,57 +739,525 @@ impl ComputerVision for ComputerVisionSvc {
     ///
     /// # Returns
     ///
-    /// A [`ResponseResult`] containing a stream of [`ImgProcResponse`] or a gRPC [`Status`] on error.
+    /// A [`ResponseResult`] containing a stream of [`ImgProcResponse`].
     ///
     /// # Errors
     ///
-    /// Returns a [`Status::resource_exhausted`] if too many concurrent requests are being processed,
-    /// or [`Status::internal`] if an error occurs during processing.
+    /// If the request stream cannot be read, its error is sent as the last item of the response
+    /// stream. Errors of individual items are reported in their responses.
     async fn process_image_batch(&self, request: Request<Streaming<ImgProcRequest>>) -> ResponseResult<Self::ProcessImageBatchStream> {
-        tracing::info!(peer_addr = ?request.remote_addr(), "ProcessImageBatch Invoked");
+        tracing::info!(peer_addr = ?request.remote_addr(), subject = Subject::from_request(&request).map(Subject::id), "ProcessImageBatch Invoked");
+
+        let cancellation: RequestCancellation = RequestCancellation::from_metadata(request.metadata());
+        let client: ClientKey = ClientKey::from_request(&request);
+        let stream: Streaming<ImgProcRequest> = request.into_inner();
+
+        Ok(Response::new(self.start_batch(stream, cancellation, client)))
+    }
+
+    /// Processes a single image and streams the description while it is being generated.
+    ///
+    /// This method validates the request, waits for a permit of the concurrency limiter and spawns a blocking task
+    /// that generates the caption. Every newly decoded word is sent to the client as a
+    /// [`CaptionChunk`], so the concatenation of all chunks forms the full description. If the
+    /// client goes away or the deadline of the call expires, the generation is stopped at the next
+    /// decoding step and the permit is released.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - A gRPC [`Request`] containing the [`ImgProcRequest`].
+    ///
+    /// # Returns
+    ///
+    /// A [`ResponseResult`] containing a stream of [`CaptionChunk`] or a gRPC [`Status`] on error.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`Status::invalid_argument`] if the request is invalid, [`Status::not_found`] if the
+    /// model is not loaded, [`Status::unavailable`] if the models are still loading, or
+    /// [`Status::resource_exhausted`] with a retry hint if the client exceeded its rate limit or the
+    /// request is shed by the concurrency limiter. Errors that occur during processing are sent as
+    /// the last item of the stream: a [`Status::invalid_argument`] if the image cannot be decoded, a
+    /// [`Status::internal`] if generation fails, and a [`Status::deadline_exceeded`] if the deadline
+    /// expires.
+    async fn stream_caption(&self, request: Request<ImgProcRequest>) -> ResponseResult<Self::StreamCaptionStream> {
+        tracing::info!(peer_addr = ?request.remote_addr(), subject = Subject::from_request(&request).map(Subject::id), "StreamCaption Invoked");
+
+        let mut rpc: RpcMetrics = RpcMetrics::start("StreamCaption");
+
+        let cancellation: RequestCancellation = RequestCancellation::from_metadata(request.metadata());
+        let admission: Result<(String, GenerationParams, Arc<ImageProcessor>, Permit), Status> = async {
+            let model_id: String = self.validate_request(request.get_ref())?;
+            rpc.set_model(&model_id);
+            let params: GenerationParams = self.generation_params(request.get_ref())?;
+            self.rate_limiter.check(Budget::ProcessImage, &ClientKey::from_request(&request))?;
+            let processor: Arc<ImageProcessor> = self.processor()?;
+            let permit: Permit = self.limiter.acquire(&model_id).await?;
+
+            Ok((model_id, params, processor, permit))
+        }
+        .await;
+        let (model_id, params, processor, permit) = match admission {
+            Ok(admission) => admission,
+            Err(status) => {
+                let result: ResponseResult<Self::StreamCaptionStream> = Err(status);
+                rpc.finish(&result);
+                return result;
+            }
+        };
+        let ImgProcRequest { image, .. } = request.into_inner();
 
-        let mut stream: Streaming<ImgProcRequest> = request.into_inner();
         let (tx, rx): (mpsc::Sender<_>, mpsc::Receiver<_>) = mpsc::channel(128);
+        cancellation.cancel_when_closed(&tx);
+
+        let span: Span = Span::current();
+        task::spawn_blocking(move || {
+            let _span = span.enter();
+            let _cancel_on_drop: DropGuard = cancellation.cancel_on_drop();
+            let process_result: ProcessingResult<String> =
+                processor.process_image_streaming(&model_id, &image, &params, cancellation.token(), |text| {
+                    // The client closed the caption stream
+                    tx.blocking_send(Ok(CaptionChunk { text })).map_err(|_| ProcessingError::Cancelled)
+                });
+
+            let result: Result<String, Status> = match process_result {
+                Ok(description) => Ok(description),
+                Err(_) if cancellation.is_cancelled() => Err(cancellation.status()),
+                Err(e) => Err(processing_status(e)),
+            };
+            rpc.finish(&result);
+            if let Err(status) = result {
+                // The client may already be gone, in which case there is nobody to notify
+                let _ = tx.blocking_send(Err(status));
+            }
 
-        while let Some(request) = stream.message().await? {
-            let tx: mpsc::Sender<_> = tx.clone();
-            let semaphore: Arc<Semaphore> = Arc::clone(&self.semaphore);
-            let processor: Arc<ImageProcessor> = Arc::clone(&self.processor);
+            drop(permit);
+        });
 
-            let _permit: OwnedSemaphorePermit = semaphore.acquire_owned().await
-                .map_err(|_| Status::resource_exhausted("Too many concurrent requests"))?;
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    /// Answers a question about a single image.
+    ///
+    /// This method validates the request, waits for a permit of the concurrency limiter, and
+    /// then spawns a blocking task that runs the image and the question through the VQA model.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - A gRPC [`Request`] containing the [`VqaRequest`].
+    ///
+    /// # Returns
+    ///
+    /// A [`ResponseResult`] containing a [`VqaResponse`] with the answer or a gRPC `Status` on error.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`Status::invalid_argument`] if the request is invalid or the image cannot be
+    /// decoded, [`Status::not_found`] if the VQA model is not loaded, [`Status::unavailable`] if
+    /// the models are still loading, [`Status::resource_exhausted`] with a retry hint if the client
+    /// exceeded its rate limit or the request is shed by the concurrency limiter,
+    /// [`Status::deadline_exceeded`] if the deadline of the client expires during generation, or
+    /// [`Status::internal`] if an error occurs during processing.
+    async fn answer_question(&self, request: Request<VqaRequest>) -> ResponseResult<VqaResponse> {
+        tracing::info!(peer_addr = ?request.remote_addr(), subject = Subject::from_request(&request).map(Subject::id), "AnswerQuestion Invoked");
+        let mut rpc: RpcMetrics = RpcMetrics::start("AnswerQuestion");
 
-            tokio::spawn(async move {
-                // TODO: add request validation
-                let ImgProcRequest { model, image } = request;
-                let model = ModelType::try_from(model).unwrap();
+        // Stops the generation if the client goes away, as tonic then drops this future
+        let cancellation: RequestCancellation = RequestCancellation::from_metadata(request.metadata());
+        let _cancel_on_drop: DropGuard = cancellation.cancel_on_drop();
 
-                let process_result: Result<CandleResult<String>, JoinError> =
-                    task::spawn_blocking(move || processor.process_image(model, &image)).await;
+        let result: ResponseResult<VqaResponse> = async {
+            let model_id: String = self.validate_vqa_request(request.get_ref())?;
+            rpc.set_model(&model_id);
+            let params: GenerationParams = self.resolve_options(request.get_ref().options.as_ref())?;
+            self.rate_limiter.check(Budget::ProcessImage, &ClientKey::from_request(&request))?;
+            let VqaRequest { image, question, .. } = request.into_inner();
 
-                let response: Result<ImgProcResponse, Status> = match process_result {
-                    Ok(Ok(description)) => {
-                        let response = ImgProcResponse { description };
-                        Ok(response)
-                    }
-                    Ok(Err(e)) => {
-                        tracing::error!("Error processing image: {:?}", e);
-                        Err(Status::internal(format!("Error processing image: {}", e)))
-                    }
-                    Err(e) => {
-                        tracing::error!("Error executing blocking task: {:?}", e);
-                        Err(Status::internal(format!("Error executing blocking task: {}", e)))
-                    }
-                };
+            let processor: Arc<ImageProcessor> = self.processor()?;
+            let _permit: Permit = self.limiter.acquire(&model_id).await?;
+
+            let cancel: CancellationToken = cancellation.token().clone();
+            let span: Span = Span::current();
+            let process_result: Result<ProcessingResult<String>, JoinError> = task::spawn_blocking(move || {
+                span.in_scope(|| processor.answer_question(&model_id, &image, &question, &params, &cancel))
+            })
+            .await;
+
+            drop(_permit);
 
-                if let Err(e) = tx.send(response).await {
-                    tracing::error!("Error sending response: {:?}", e);
+            match process_result {
+                Ok(Ok(answer)) => {
+                    let response = VqaResponse { answer };
+                    Ok(Response::new(response))
                 }
+                Ok(Err(_)) if cancellation.is_cancelled() => Err(cancellation.status()),
+                Ok(Err(e)) => Err(processing_status(e)),
+                Err(e) => {
+                    tracing::error!("Error executing blocking task: {:?}", e);
+                    Err(Status::internal(format!("Error executing blocking task: {}", e)))
+                }
+            }
+        }
+        .await;
+
+        rpc.finish(&result);
+        result
+    }
+
+    /// Lists the models loaded by the server.
+    ///
+    /// Clients use this method to discover which models they can select by registry id, along with
+    /// their source, quantization, device, input resolution and supported tasks.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - A gRPC [`Request`] containing the [`ListModelsRequest`].
+    ///
+    /// # Returns
+    ///
+    /// A [`ResponseResult`] containing a [`ListModelsResponse`] with the models ordered by their id.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`Status::unavailable`] if the models are still loading.
+    async fn list_models(&self, request: Request<ListModelsRequest>) -> ResponseResult<ListModelsResponse> {
+        tracing::info!(peer_addr = ?request.remote_addr(), subject = Subject::from_request(&request).map(Subject::id), "ListModels Invoked");
+        let rpc: RpcMetrics = RpcMetrics::start("ListModels");
+
+        let result: ResponseResult<ListModelsResponse> = self.processor().map(|processor| {
+            let models: Vec<ModelInfo> = processor
+                .list_models()
+                .into_iter()
+                .map(ModelInfo::from)
+                .collect();
+            Response::new(ListModelsResponse { models })
+        });
+
+        rpc.finish(&result);
+        result
+    }
+
+    /// Describes a single loaded model.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - A gRPC [`Request`] containing the [`DescribeModelRequest`].
+    ///
+    /// # Returns
+    ///
+    /// A [`ResponseResult`] containing the [`ModelInfo`] of the model or a gRPC [`Status`] on error.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`Status::invalid_argument`] if the model id is empty, a [`Status::not_found`] if
+    /// the model is not loaded, or a [`Status::unavailable`] if the models are still loading.
+    // The `Status` is returned to the client as is
+    #[allow(clippy::result_large_err)]
+    async fn describe_model(&self, request: Request<DescribeModelRequest>) -> ResponseResult<ModelInfo> {
+        tracing::info!(peer_addr = ?request.remote_addr(), subject = Subject::from_request(&request).map(Subject::id), "DescribeModel Invoked");
+        let rpc: RpcMetrics = RpcMetrics::start("DescribeModel");
+
+        let DescribeModelRequest { model_id } = request.into_inner();
+        let result: ResponseResult<ModelInfo> = if model_id.is_empty() {
+            Err(Status::invalid_argument("Empty model id"))
+        } else {
+            self.processor().and_then(|processor| {
+                processor
+                    .describe_model(&model_id)
+                    .map(|description| Response::new(ModelInfo::from(description)))
+                    .ok_or_else(|| ProcessingError::ModelNotLoaded(model_id).into())
+            })
+        };
+
+        rpc.finish(&result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+    use tokio_stream::StreamExt;
+    use crate::image_captioning::testing;
+
+    /// Maximum time a test waits for a response stream to end.
+    const STREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+    fn svc() -> ComputerVisionSvc {
+        let processor = ProcessorSlot::default();
+        processor.fill(testing::tiny_processor());
+        let generation = GenerationConfig { max_new_tokens: 8, ..Default::default() };
 
-                drop(_permit);
+        ComputerVisionSvc::with_processor_slot(
+            processor,
+            generation,
+            BatchingConfig::default(),
+            ConcurrencyConfig::default(),
+            RateLimitConfig::default(),
+        )
+    }
+
+    fn caption_request(image: Vec<u8>) -> ImgProcRequest {
+        ImgProcRequest {
+            image,
+            model_id: testing::CAPTIONING_MODEL_ID.to_string(),
+            bypass_cache: true,
+            ..Default::default()
+        }
+    }
+
+    fn batch_request(index: u32, image: Vec<u8>, ordering: BatchOrdering) -> ImgProcRequest {
+        ImgProcRequest {
+            request_id: format!("request-{}", index),
+            ordering: ordering as i32,
+            ..caption_request(image)
+        }
+    }
+
+    /// Runs a batch over the given request stream, and collects its responses until the response
+    /// stream ends. Fails if the response stream does not end.
+    async fn run_batch<S>(svc: ComputerVisionSvc, stream: S, cancellation: RequestCancellation) -> Vec<Result<ImgProcResponse, Status>>
+    where
+        S: Stream<Item = Result<ImgProcRequest, Status>> + Send + Unpin + 'static,
+    {
+        let responses: ReceiverStream<Result<ImgProcResponse, Status>> = svc.start_batch(stream, cancellation, ClientKey::Unknown);
+
+        time::timeout(STREAM_TIMEOUT, responses.collect()).await.unwrap()
+    }
+
+    fn item_code(response: &ImgProcResponse) -> Code {
+        response.error.as_ref().map_or(Code::Ok, |error| Code::from_i32(error.code))
+    }
+
+    #[tokio::test]
+    async fn test_stream_caption_sends_chunks_in_order_and_ends() {
+        // GIVEN
+        let svc: ComputerVisionSvc = svc();
+        let request: ImgProcRequest = caption_request(testing::png_image(64));
+        let description: String = svc.process_image(Request::new(request.clone())).await.unwrap().into_inner().description;
+        // WHEN
+        let stream: ReceiverStream<Result<CaptionChunk, Status>> = svc.stream_caption(Request::new(request)).await.unwrap().into_inner();
+        let chunks: Vec<Result<CaptionChunk, Status>> = time::timeout(STREAM_TIMEOUT, stream.collect()).await.unwrap();
+        // THEN
+        let chunks: Vec<String> = chunks.into_iter().map(|chunk| chunk.unwrap().text).collect();
+        assert!(!chunks.is_empty());
+        assert_eq!(chunks.concat(), description);
+    }
+
+    #[tokio::test]
+    async fn test_stream_caption_sends_processing_error_last() {
+        // GIVEN
+        let svc: ComputerVisionSvc = svc();
+        let request: ImgProcRequest = caption_request(b"not an image".to_vec());
+        // WHEN
+        let stream: ReceiverStream<Result<CaptionChunk, Status>> = svc.stream_caption(Request::new(request)).await.unwrap().into_inner();
+        let chunks: Vec<Result<CaptionChunk, Status>> = time::timeout(STREAM_TIMEOUT, stream.collect()).await.unwrap();
+        // THEN
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].as_ref().unwrap_err().code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_list_models_reports_tasks() {
+        // GIVEN
+        let svc: ComputerVisionSvc = svc();
+        // WHEN
+        let response: ListModelsResponse = svc.list_models(Request::new(ListModelsRequest {})).await.unwrap().into_inner();
+        // THEN
+        let mut models: Vec<(String, Vec<ModelTask>)> = response.models
+            .iter()
+            .map(|model: &ModelInfo| (model.id.clone(), model.tasks().collect()))
+            .collect();
+        models.sort();
+        assert_eq!(models, [
+            (testing::CAPTIONING_MODEL_ID.to_string(), vec![ModelTask::ImageCaptioning]),
+            (testing::VQA_MODEL_ID.to_string(), vec![ModelTask::VisualQuestionAnswering]),
+        ]);
+        assert!(response.models.iter().all(|model| !model.tasks.contains(&(ModelTask::Unspecified as i32))));
+    }
+
+    #[tokio::test]
+    async fn test_describe_model() {
+        // GIVEN
+        let svc: ComputerVisionSvc = svc();
+        let request = |model_id: &str| Request::new(DescribeModelRequest { model_id: model_id.to_string() });
+        // WHEN
+        let model: ModelInfo = svc.describe_model(request(testing::VQA_MODEL_ID)).await.unwrap().into_inner();
+        let empty: Status = svc.describe_model(request("")).await.unwrap_err();
+        let unknown: Status = svc.describe_model(request("unknown")).await.unwrap_err();
+        // THEN
+        assert_eq!(model.id, testing::VQA_MODEL_ID);
+        assert_eq!(model.architecture, "blip_vqa");
+        assert_eq!(model.tasks().collect::<Vec<ModelTask>>(), [ModelTask::VisualQuestionAnswering]);
+        assert_eq!(model.revision, DEFAULT_REVISION);
+        assert_eq!(model.device, "cpu");
+        assert_eq!(model.input_resolution, 384);
+        assert_eq!(empty.code(), Code::InvalidArgument);
+        assert_eq!(unknown.code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_run_batch_reports_item_errors_and_echoes_ids() {
+        // GIVEN
+        let requests: Vec<Result<ImgProcRequest, Status>> = vec![
+            Ok(batch_request(0, testing::png_image(0), BatchOrdering::AsCompleted)),
+            Ok(batch_request(1, Vec::new(), BatchOrdering::AsCompleted)),
+            Ok(ImgProcRequest { model_id: "unknown".to_string(), ..batch_request(2, testing::png_image(0), BatchOrdering::AsCompleted) }),
+            Ok(batch_request(3, b"not an image".to_vec(), BatchOrdering::AsCompleted)),
+        ];
+        // WHEN
+        let responses: Vec<Result<ImgProcResponse, Status>> =
+            run_batch(svc(), tokio_stream::iter(requests), RequestCancellation::with_deadline(None)).await;
+        // THEN
+        let mut responses: Vec<ImgProcResponse> = responses.into_iter().map(Result::unwrap).collect();
+        responses.sort_by_key(|response| response.index);
+        let items: Vec<(u32, &str, Code)> = responses
+            .iter()
+            .map(|response| (response.index, response.request_id.as_str(), item_code(response)))
+            .collect();
+        assert_eq!(items, [
+            (0, "request-0", Code::Ok),
+            (1, "request-1", Code::InvalidArgument),
+            (2, "request-2", Code::NotFound),
+            (3, "request-3", Code::InvalidArgument),
+        ]);
+        assert!(!responses[0].description.is_empty());
+        assert_eq!(responses[2].error.as_ref().unwrap().reason, "MODEL_NOT_LOADED");
+        assert_eq!(responses[3].error.as_ref().unwrap().reason, "UNSUPPORTED_IMAGE_FORMAT");
+    }
+
+    #[tokio::test]
+    async fn test_run_batch_orders_responses() {
+        // GIVEN
+        // The first item is the only one to be processed, so it completes last
+        let requests = |ordering: BatchOrdering| -> Vec<Result<ImgProcRequest, Status>> {
+            vec![
+                Ok(batch_request(0, testing::png_image(0), ordering)),
+                Ok(batch_request(1, Vec::new(), ordering)),
+                Ok(batch_request(2, Vec::new(), ordering)),
+            ]
+        };
+        let cancellation: RequestCancellation = RequestCancellation::with_deadline(None);
+        // WHEN
+        let as_completed: Vec<Result<ImgProcResponse, Status>> =
+            run_batch(svc(), tokio_stream::iter(requests(BatchOrdering::AsCompleted)), cancellation.clone()).await;
+        let in_order: Vec<Result<ImgProcResponse, Status>> =
+            run_batch(svc(), tokio_stream::iter(requests(BatchOrdering::PreserveInputOrder)), cancellation).await;
+        // THEN
+        let indexes = |responses: Vec<Result<ImgProcResponse, Status>>| -> Vec<u32> {
+            responses.into_iter().map(|response| response.unwrap().index).collect()
+        };
+        assert_eq!(indexes(as_completed).last(), Some(&0));
+        assert_eq!(indexes(in_order), [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_forward_in_order_waits_for_earlier_items() {
+        // GIVEN
+        let (pending_tx, pending_rx): (mpsc::Sender<PendingItem>, mpsc::Receiver<PendingItem>) = mpsc::channel(4);
+        let (tx, rx): (mpsc::Sender<_>, mpsc::Receiver<_>) = mpsc::channel(4);
+        // The tasks of the items finish in reverse order
+        for index in 0..4u32 {
+            let handle: JoinHandle<ImgProcResponse> = tokio::spawn(async move {
+                time::sleep(Duration::from_millis(20 * u64::from(4 - index))).await;
+                ImgProcResponse { index, ..Default::default() }
             });
+            let item = PendingItem { index, request_id: String::new(), handle, rpc: RpcMetrics::start("ProcessImageBatch") };
+            pending_tx.send(item).await.unwrap();
         }
+        drop(pending_tx);
+        // WHEN
+        forward_in_order(pending_rx, tx).await;
+        // THEN
+        let responses: Vec<Result<ImgProcResponse, Status>> = ReceiverStream::new(rx).collect().await;
+        let indexes: Vec<u32> = responses.into_iter().map(|response| response.unwrap().index).collect();
+        assert_eq!(indexes, [0, 1, 2, 3]);
+    }
 
-        Ok(Response::new(ReceiverStream::new(rx)))
+    #[tokio::test]
+    async fn test_run_batch_sends_stream_error_after_pending_items() {
+        // GIVEN
+        let requests: Vec<Result<ImgProcRequest, Status>> = vec![
+            Ok(batch_request(0, testing::png_image(0), BatchOrdering::PreserveInputOrder)),
+            Err(Status::data_loss("Stream reset")),
+        ];
+        // WHEN
+        let responses: Vec<Result<ImgProcResponse, Status>> =
+            run_batch(svc(), tokio_stream::iter(requests), RequestCancellation::with_deadline(None)).await;
+        // THEN
+        assert_eq!(responses.len(), 2);
+        let first: &ImgProcResponse = responses[0].as_ref().unwrap();
+        assert_eq!((first.index, item_code(first)), (0, Code::Ok));
+        assert_eq!(responses[1].as_ref().unwrap_err().code(), Code::DataLoss);
+    }
+
+    #[tokio::test]
+    async fn test_run_batch_rejects_items_with_another_ordering() {
+        // GIVEN
+        let requests: Vec<Result<ImgProcRequest, Status>> = vec![
+            Ok(batch_request(0, Vec::new(), BatchOrdering::PreserveInputOrder)),
+            Ok(batch_request(1, testing::png_image(0), BatchOrdering::AsCompleted)),
+        ];
+        // WHEN
+        let responses: Vec<Result<ImgProcResponse, Status>> =
+            run_batch(svc(), tokio_stream::iter(requests), RequestCancellation::with_deadline(None)).await;
+        // THEN
+        let second: &ImgProcResponse = responses[1].as_ref().unwrap();
+        assert_eq!((second.index, item_code(second)), (1, Code::InvalidArgument));
+        assert!(second.error.as_ref().unwrap().message.contains("PRESERVE_INPUT_ORDER"));
+    }
+
+    #[tokio::test]
+    async fn test_run_batch_cancels_items() {
+        // GIVEN
+        let (requests_tx, requests_rx): (mpsc::Sender<_>, mpsc::Receiver<_>) = mpsc::channel(1);
+        let cancellation: RequestCancellation = RequestCancellation::with_deadline(None);
+        let responses = tokio::spawn(run_batch(svc(), ReceiverStream::new(requests_rx), cancellation.clone()));
+        // WHEN
+        cancellation.token().cancel();
+        requests_tx.send(Ok(batch_request(0, testing::png_image(0), BatchOrdering::AsCompleted))).await.unwrap();
+        drop(requests_tx);
+        // THEN
+        let responses: Vec<Result<ImgProcResponse, Status>> = responses.await.unwrap();
+        assert_eq!(responses.len(), 1);
+        assert_eq!(item_code(responses[0].as_ref().unwrap()), Code::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_run_batch_with_more_items_than_channel_capacity() {
+        // GIVEN
+        let count: u32 = 3 * BATCH_CHANNEL_CAPACITY as u32;
+        let requests: Vec<Result<ImgProcRequest, Status>> = (0..count)
+            .map(|index| batch_request(index, Vec::new(), BatchOrdering::PreserveInputOrder))
+            .map(Ok)
+            .collect();
+        // WHEN
+        let responses: Vec<Result<ImgProcResponse, Status>> =
+            run_batch(svc(), tokio_stream::iter(requests), RequestCancellation::with_deadline(None)).await;
+        // THEN
+        let indexes: Vec<u32> = responses.into_iter().map(|response| response.unwrap().index).collect();
+        assert_eq!(indexes, (0..count).collect::<Vec<u32>>());
+    }
+
+    #[tokio::test]
+    async fn test_batch_stream_ends_after_last_response() {
+        // GIVEN
+        let requests: Vec<Result<ImgProcRequest, Status>> = vec![
+            Ok(batch_request(0, testing::png_image(0), BatchOrdering::AsCompleted)),
+            Ok(batch_request(1, Vec::new(), BatchOrdering::AsCompleted)),
+        ];
+        let cancellation: RequestCancellation = RequestCancellation::with_deadline(None);
+        let mut responses: ReceiverStream<Result<ImgProcResponse, Status>> =
+            svc().start_batch(tokio_stream::iter(requests), cancellation.clone(), ClientKey::Unknown);
+        // WHEN
+        let first: ImgProcResponse = time::timeout(STREAM_TIMEOUT, responses.next()).await.unwrap().unwrap().unwrap();
+        let second: ImgProcResponse = time::timeout(STREAM_TIMEOUT, responses.next()).await.unwrap().unwrap().unwrap();
+        let end: Option<Result<ImgProcResponse, Status>> = time::timeout(STREAM_TIMEOUT, responses.next()).await.unwrap();
+        // THEN
+        assert_eq!((first.index, second.index), (1, 0));
+        assert_eq!(item_code(&second), Code::Ok);
+        assert!(end.is_none());
     }
 }
@@ -0,0 +1,203 @@
+//! This module provides the OpenTelemetry tracing of the vision service.
+//!
+//! Every gRPC call gets a server span created by [`request_span`]. If the call carries a W3C
+//! `traceparent` (and `tracestate`) in its metadata, as sent by the API gateway, the span joins the
+//! trace of the caller. The spans of the image processing (image decoding, tensor creation, vision
+//! model forward pass and the prefill, decode and detokenize phases of the text generation) are
+//! children of the call span.
+//!
+//! The spans are exported with OTLP over gRPC by the [`TracerProvider`] built by [`tracer_provider`],
+//! and bridged from `tracing` by the layer returned by [`layer`].
+use opentelemetry::{Context, KeyValue};
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, Resource};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{self as sdktrace, Tracer, TracerProvider};
+use tonic::codegen::http::{HeaderMap, Request};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// The `service.name` resource attribute of the exported spans.
+pub const SERVICE_NAME: &str = "grpc-vision-svc";
+
+/// Reads propagation fields from the HTTP/2 headers carrying the gRPC metadata.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Extracts the W3C trace context of the caller from the headers of a gRPC call.
+///
+/// # Arguments
+///
+/// * `headers` - The HTTP/2 headers of the call.
+///
+/// # Returns
+///
+/// The [`Context`] of the caller, which is empty if the headers carry no valid `traceparent`.
+pub fn parent_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+/// Creates the server span of a gRPC call. Used as the `trace_fn` of the tonic server.
+///
+/// The span is named after the gRPC method (e.g. `/computer_vision.ComputerVision/ProcessImage`)
+/// and is a child of the span of the caller, if the call carries a trace context. Its `enduser.id`
+/// is recorded once the caller is authenticated.
+///
+/// # Arguments
+///
+/// * `request` - The HTTP/2 request of the call.
+///
+/// # Returns
+///
+/// The [`Span`] the call is processed in.
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    let span: Span = tracing::info_span!(
+        "grpc",
+        otel.name = %request.uri().path(),
+        otel.kind = "server",
+        rpc.system = "grpc",
+        enduser.id = tracing::field::Empty,
+    );
+    span.set_parent(parent_context(request.headers()));
+    span
+}
+
+/// Builds a [`TracerProvider`] exporting spans in batches to an OTLP collector over gRPC.
+/// Must be called from within a tokio runtime.
+///
+/// # Arguments
+///
+/// * `endpoint` - The URL of the OTLP gRPC endpoint of the collector, e.g. `http://localhost:4317`.
+///
+/// # Returns
+///
+/// A [`Result`] containing the [`TracerProvider`], or a [`TraceError`] if the exporter cannot be built.
+pub fn tracer_provider(endpoint: &str) -> Result<TracerProvider, TraceError> {
+    let exporter: opentelemetry_otlp::SpanExporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(endpoint)
+        .build_span_exporter()?;
+    let resource = Resource::new([KeyValue::new("service.name", SERVICE_NAME)]);
+
+    Ok(TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_config(sdktrace::config().with_resource(resource))
+        .build())
+}
+
+/// Returns a `tracing` layer sending the spans to the given [`TracerProvider`].
+pub fn layer<S>(provider: &TracerProvider) -> OpenTelemetryLayer<S, Tracer>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use opentelemetry::trace::{SpanId, TraceId};
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{TraceService, TraceServiceServer};
+    use opentelemetry_proto::tonic::collector::trace::v1::{ExportTraceServiceRequest, ExportTraceServiceResponse};
+    use opentelemetry_proto::tonic::trace::v1::Span as ProtoSpan;
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::codegen::http::HeaderValue;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    /// A stand-in for an OTLP collector, keeping the exported spans.
+    #[derive(Default, Clone)]
+    struct FakeCollector {
+        spans: Arc<Mutex<Vec<ProtoSpan>>>,
+    }
+
+    #[tonic::async_trait]
+    impl TraceService for FakeCollector {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportTraceServiceRequest>,
+        ) -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+            let spans = request
+                .into_inner()
+                .resource_spans
+                .into_iter()
+                .flat_map(|resource_spans| resource_spans.scope_spans)
+                .flat_map(|scope_spans| scope_spans.spans);
+            self.spans.lock().unwrap().extend(spans);
+
+            Ok(tonic::Response::new(ExportTraceServiceResponse { partial_success: None }))
+        }
+    }
+
+    #[test]
+    fn test_parent_context_from_traceparent() {
+        // GIVEN
+        let mut headers = HeaderMap::new();
+        headers.insert("traceparent", HeaderValue::from_static(TRACEPARENT));
+        // WHEN
+        let context: Context = parent_context(&headers);
+        // THEN
+        let span_context = opentelemetry::trace::TraceContextExt::span(&context).span_context().clone();
+        assert!(span_context.is_remote());
+        assert_eq!(span_context.trace_id(), TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap());
+        assert_eq!(span_context.span_id(), SpanId::from_hex("00f067aa0ba902b7").unwrap());
+    }
+
+    #[test]
+    fn test_parent_context_without_traceparent() {
+        // GIVEN
+        let headers = HeaderMap::new();
+        // WHEN
+        let context: Context = parent_context(&headers);
+        // THEN
+        assert!(!opentelemetry::trace::TraceContextExt::has_active_span(&context));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_request_spans_are_exported_in_the_trace_of_the_caller() {
+        // GIVEN
+        let collector = FakeCollector::default();
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint: String = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(collector.clone()))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        let provider: TracerProvider = tracer_provider(&endpoint).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer(&provider));
+        let request: Request<()> = Request::builder()
+            .uri("/computer_vision.ComputerVision/ProcessImage")
+            .header("traceparent", TRACEPARENT)
+            .body(())
+            .unwrap();
+        // WHEN
+        tracing::subscriber::with_default(subscriber, || {
+            request_span(&request).in_scope(|| tracing::info_span!("decode_image").in_scope(|| {}));
+        });
+        tokio::task::spawn_blocking(move || provider.force_flush()).await.unwrap();
+        // THEN
+        let spans: Vec<ProtoSpan> = collector.spans.lock().unwrap().clone();
+        let call: &ProtoSpan = spans.iter().find(|span| span.name == "/computer_vision.ComputerVision/ProcessImage").unwrap();
+        let decode: &ProtoSpan = spans.iter().find(|span| span.name == "decode_image").unwrap();
+        assert_eq!(call.trace_id, TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap().to_bytes());
+        assert_eq!(call.parent_span_id, SpanId::from_hex("00f067aa0ba902b7").unwrap().to_bytes());
+        assert_eq!(decode.trace_id, call.trace_id);
+        assert_eq!(decode.parent_span_id, call.span_id);
+    }
+}
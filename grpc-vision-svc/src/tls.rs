@@ -0,0 +1,354 @@
+//! This module provides the TLS termination of the gRPC server, with optional mutual TLS.
+//!
+//! The server certificate chain, its private key and, for mutual TLS, the CA that signs the client
+//! certificates are read from PEM files. The [`ReloadingAcceptor`] polls the files and swaps in a
+//! new [`ServerConfig`] when their content changes, so that certificates can be rotated without
+//! restarting the server. Connections that are already established keep the configuration they
+//! were accepted with.
+//!
+//! Files that cannot be loaded during a reload (e.g. a certificate written before its key) are
+//! reported, and the previous configuration stays in use until the next poll.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{self, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{VerifierBuilderError, WebPkiClientVerifier};
+use tokio_rustls::server::TlsStream;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// ALPN protocol of gRPC, which is carried over HTTP/2 only.
+const ALPN_H2: &[u8] = b"h2";
+
+/// Maximum time a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of handshaken connections waiting to be picked up by the server.
+const ACCEPT_BACKLOG: usize = 64;
+
+/// Default interval at which the certificate files are checked for changes.
+pub const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// [`TlsError`] is returned when the TLS configuration cannot be loaded.
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("Failed to read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("No certificate found in {0}")]
+    NoCertificates(PathBuf),
+    #[error("No private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("Invalid client CA certificate: {0}")]
+    InvalidClientCa(rustls::Error),
+    #[error("Failed to build the client certificate verifier: {0}")]
+    ClientVerifier(#[from] VerifierBuilderError),
+    #[error("Invalid server certificate or private key: {0}")]
+    InvalidCertificate(rustls::Error),
+}
+
+/// [`TlsConfig`] holds the paths of the PEM files of the TLS configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    /// The certificate chain of the server, leaf certificate first.
+    pub cert_path: PathBuf,
+    /// The private key of the server certificate (PKCS#1, PKCS#8 or SEC1).
+    pub key_path: PathBuf,
+    /// The CA certificates the client certificates must chain to. If set, clients without a valid
+    /// certificate are rejected during the handshake (mutual TLS).
+    pub client_ca_path: Option<PathBuf>,
+}
+
+/// The content of the files of a [`TlsConfig`], used to detect changes.
+#[derive(Debug, PartialEq)]
+struct TlsFiles {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    client_ca: Option<Vec<u8>>,
+}
+
+impl TlsFiles {
+    fn read(config: &TlsConfig) -> Result<Self, TlsError> {
+        Ok(Self {
+            cert: read(&config.cert_path)?,
+            key: read(&config.key_path)?,
+            client_ca: config.client_ca_path.as_deref().map(read).transpose()?,
+        })
+    }
+
+    /// Builds the rustls [`ServerConfig`] from the content of the files.
+    fn server_config(&self, config: &TlsConfig) -> Result<ServerConfig, TlsError> {
+        let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut self.cert.as_slice())
+            .collect::<Result<_, _>>()
+            .map_err(|source| TlsError::Read { path: config.cert_path.clone(), source })?;
+        if certs.is_empty() {
+            return Err(TlsError::NoCertificates(config.cert_path.clone()));
+        }
+        let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut self.key.as_slice())
+            .map_err(|source| TlsError::Read { path: config.key_path.clone(), source })?
+            .ok_or_else(|| TlsError::NoPrivateKey(config.key_path.clone()))?;
+
+        let builder = ServerConfig::builder();
+        let builder = match (&self.client_ca, &config.client_ca_path) {
+            (Some(client_ca), Some(client_ca_path)) => {
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_pemfile::certs(&mut client_ca.as_slice()) {
+                    let cert: CertificateDer<'static> = cert
+                        .map_err(|source| TlsError::Read { path: client_ca_path.clone(), source })?;
+                    roots.add(cert).map_err(TlsError::InvalidClientCa)?;
+                }
+                if roots.is_empty() {
+                    return Err(TlsError::NoCertificates(client_ca_path.clone()));
+                }
+                builder.with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build()?)
+            }
+            _ => builder.with_no_client_auth(),
+        };
+        let mut server_config: ServerConfig = builder
+            .with_single_cert(certs, key)
+            .map_err(TlsError::InvalidCertificate)?;
+        server_config.alpn_protocols = vec![ALPN_H2.to_vec()];
+
+        Ok(server_config)
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    fs::read(path).map_err(|source| TlsError::Read { path: path.to_owned(), source })
+}
+
+/// [`ReloadingAcceptor`] performs the TLS handshakes of the gRPC server with the current
+/// configuration, and reloads the configuration when the certificate files change.
+#[derive(Debug)]
+pub struct ReloadingAcceptor {
+    config: TlsConfig,
+    current: RwLock<(TlsFiles, Arc<ServerConfig>)>,
+}
+
+impl ReloadingAcceptor {
+    /// Creates a new instance of [`ReloadingAcceptor`] by loading the files of the given configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The [`TlsConfig`] with the paths of the certificate files.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing the new [`ReloadingAcceptor`] instance.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TlsError`] if a file cannot be read, contains no certificate or key, or if the
+    /// certificates are invalid.
+    pub fn new(config: TlsConfig) -> Result<Self, TlsError> {
+        let files: TlsFiles = TlsFiles::read(&config)?;
+        let server_config: ServerConfig = files.server_config(&config)?;
+
+        Ok(Self { config, current: RwLock::new((files, Arc::new(server_config))) })
+    }
+
+    /// Returns whether clients must present a certificate signed by the client CA.
+    pub fn is_mutual(&self) -> bool {
+        self.config.client_ca_path.is_some()
+    }
+
+    /// Returns the rustls [`ServerConfig`] new connections are accepted with.
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().1.clone()
+    }
+
+    /// Reads the certificate files again and swaps in a new configuration if their content changed.
+    /// The files are read synchronously, so call it from the blocking thread pool.
+    ///
+    /// # Returns
+    ///
+    /// A [`Result`] containing `true` if the configuration was replaced, or `false` if the files
+    /// did not change.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`TlsError`] if the changed files cannot be loaded. The previous configuration is
+    /// kept in that case.
+    pub fn reload(&self) -> Result<bool, TlsError> {
+        let files: TlsFiles = TlsFiles::read(&self.config)?;
+        if self.current.read().unwrap().0 == files {
+            return Ok(false);
+        }
+        let server_config: ServerConfig = files.server_config(&self.config)?;
+        *self.current.write().unwrap() = (files, Arc::new(server_config));
+
+        Ok(true)
+    }
+
+    /// Spawns a task calling [`ReloadingAcceptor::reload`] at the given interval.
+    /// Must be called from within a tokio runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `interval` - The time between two checks of the certificate files.
+    ///
+    /// # Returns
+    ///
+    /// The [`JoinHandle`] of the task, which runs until it is aborted.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let acceptor: Arc<Self> = self.clone();
+        tokio::spawn(async move {
+            let mut ticks: time::Interval = time::interval(interval);
+            ticks.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+            // The first tick completes immediately, and the files were just loaded
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let acceptor: Arc<Self> = acceptor.clone();
+                match task::spawn_blocking(move || acceptor.reload()).await {
+                    Ok(Ok(true)) => tracing::info!("Reloaded TLS certificates"),
+                    Ok(Ok(false)) => {}
+                    Ok(Err(e)) => tracing::warn!("Failed to reload TLS certificates, keeping the previous ones: {}", e),
+                    Err(e) => tracing::error!("TLS certificate reload task failed: {:?}", e),
+                }
+            }
+        })
+    }
+
+    /// Accepts the connections of a listener and performs their TLS handshakes. Used as the
+    /// incoming stream of the tonic server. Must be called from within a tokio runtime.
+    ///
+    /// Handshakes run concurrently, so a slow client does not hold up the others. Connections whose
+    /// handshake fails or times out are logged and dropped, without ending the stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - The [`TcpListener`] of the gRPC server.
+    ///
+    /// # Returns
+    ///
+    /// A stream of the established TLS connections. Accepting stops when the stream is dropped.
+    pub fn incoming(self: Arc<Self>, listener: TcpListener) -> ReceiverStream<io::Result<TlsStream<TcpStream>>> {
+        let (tx, rx) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = tokio::select! {
+                    _ = tx.closed() => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            // e.g. too many open files, which clears as connections close
+                            tracing::warn!("Failed to accept connection: {}", e);
+                            time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    },
+                };
+                let acceptor = TlsAcceptor::from(self.server_config());
+                let tx: mpsc::Sender<io::Result<TlsStream<TcpStream>>> = tx.clone();
+                tokio::spawn(async move {
+                    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send(Ok(stream)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!(%peer, "TLS handshake failed: {}", e),
+                        Err(_) => tracing::debug!(%peer, "TLS handshake timed out"),
+                    }
+                });
+            }
+        });
+
+        ReceiverStream::new(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+    use tempfile::TempDir;
+
+    fn certificate(common_name: &str, is_ca: bool) -> Certificate {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        params.distinguished_name.push(DnType::CommonName, common_name);
+        if is_ca {
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        }
+        Certificate::from_params(params).unwrap()
+    }
+
+    /// Writes a CA and a server certificate signed by it, and returns the configuration using them.
+    fn write_certificates(dir: &Path, client_ca: bool) -> TlsConfig {
+        let ca: Certificate = certificate("Test CA", true);
+        let server: Certificate = certificate("localhost", false);
+        let config = TlsConfig {
+            cert_path: dir.join("server.pem"),
+            key_path: dir.join("server.key"),
+            client_ca_path: client_ca.then(|| dir.join("ca.pem")),
+        };
+        fs::write(&config.cert_path, server.serialize_pem_with_signer(&ca).unwrap()).unwrap();
+        fs::write(&config.key_path, server.serialize_private_key_pem()).unwrap();
+        fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+        config
+    }
+
+    #[test]
+    fn test_reloading_acceptor_loads_certificates() {
+        // GIVEN
+        let dir: TempDir = TempDir::new().unwrap();
+        let config: TlsConfig = write_certificates(dir.path(), true);
+        // WHEN
+        let acceptor: ReloadingAcceptor = ReloadingAcceptor::new(config).unwrap();
+        // THEN
+        assert!(acceptor.is_mutual());
+        assert_eq!(acceptor.server_config().alpn_protocols, vec![b"h2".to_vec()]);
+    }
+
+    #[test]
+    fn test_reloading_acceptor_reports_missing_files() {
+        // GIVEN
+        let dir: TempDir = TempDir::new().unwrap();
+        let mut config: TlsConfig = write_certificates(dir.path(), false);
+        config.key_path = dir.path().join("missing.key");
+        // WHEN
+        let result: Result<ReloadingAcceptor, TlsError> = ReloadingAcceptor::new(config);
+        // THEN
+        assert!(matches!(result, Err(TlsError::Read { path, .. }) if path.ends_with("missing.key")));
+    }
+
+    #[test]
+    fn test_reloading_acceptor_rejects_files_without_key() {
+        // GIVEN
+        let dir: TempDir = TempDir::new().unwrap();
+        let mut config: TlsConfig = write_certificates(dir.path(), false);
+        config.key_path = config.cert_path.clone();
+        // WHEN
+        let result: Result<ReloadingAcceptor, TlsError> = ReloadingAcceptor::new(config);
+        // THEN
+        assert!(matches!(result, Err(TlsError::NoPrivateKey(_))));
+    }
+
+    #[test]
+    fn test_reloading_acceptor_reloads_changed_files_only() {
+        // GIVEN
+        let dir: TempDir = TempDir::new().unwrap();
+        let config: TlsConfig = write_certificates(dir.path(), false);
+        let acceptor: ReloadingAcceptor = ReloadingAcceptor::new(config.clone()).unwrap();
+        let initial: Arc<ServerConfig> = acceptor.server_config();
+        // WHEN
+        let unchanged: bool = acceptor.reload().unwrap();
+        fs::write(&config.key_path, "not a key").unwrap();
+        let invalid: Result<bool, TlsError> = acceptor.reload();
+        let kept: Arc<ServerConfig> = acceptor.server_config();
+        write_certificates(dir.path(), false);
+        let changed: bool = acceptor.reload().unwrap();
+        // THEN
+        assert!(!unchanged);
+        assert!(matches!(invalid, Err(TlsError::NoPrivateKey(_))));
+        assert!(Arc::ptr_eq(&initial, &kept));
+        assert!(changed);
+        assert!(!Arc::ptr_eq(&initial, &acceptor.server_config()));
+    }
+}
@@ -1,4 +1,6 @@
 #![cfg(target_family = "unix")]
+// Each test crate uses only a subset of the helpers
+#![allow(dead_code)]
 use std::path::{Path, PathBuf};
 use std::future::Future;
 use tokio::fs;
@@ -12,6 +14,15 @@ use tempfile::{NamedTempFile, TempPath};
 use grpc_vision_svc::proto::FILE_DESCRIPTOR_SET;
 
 pub async fn create_server_and_channel() -> (impl Future<Output = ()>, Channel) {
+    let (stream, socket): (UnixListenerStream, TempPath) = create_listener().await;
+
+    let serve_future = create_server(stream);
+    let channel: Channel = create_channel(&socket).await.unwrap();
+
+    (serve_future, channel)
+}
+
+pub async fn create_listener() -> (UnixListenerStream, TempPath) {
     let socket: NamedTempFile = NamedTempFile::new().unwrap();
     let socket: TempPath = socket.into_temp_path();
     fs::remove_file(&socket)
@@ -21,12 +32,8 @@ pub async fn create_server_and_channel() -> (impl Future<Output = ()>, Channel)
     eprintln!("socket: {:?}", socket.display());
 
     let uds: UnixListener = UnixListener::bind(&socket).unwrap();
-    let stream: UnixListenerStream = UnixListenerStream::new(uds);
 
-    let serve_future = create_server(stream);
-    let channel: Channel = create_channel(&socket).await.unwrap();
-
-    (serve_future, channel)
+    (UnixListenerStream::new(uds), socket)
 }
 
 async fn create_server(stream: UnixListenerStream) {
@@ -46,7 +53,7 @@ async fn create_server(stream: UnixListenerStream) {
         .expect("Server failed to start")
 }
 
-async fn create_channel<P: AsRef<Path>>(socket: P) -> Result<Channel, Error> {
+pub async fn create_channel<P: AsRef<Path>>(socket: P) -> Result<Channel, Error> {
     let socket: PathBuf = socket.as_ref().to_owned();
 
     Endpoint::try_from("http://[::1]:50051")
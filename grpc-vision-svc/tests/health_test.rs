@@ -0,0 +1,64 @@
+#![cfg(target_family = "unix")]
+mod common;
+
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::Request;
+use tonic::transport::{Channel, Server};
+use tonic_health::pb::{HealthCheckRequest, HealthCheckResponse};
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use tempfile::TempPath;
+use grpc_vision_svc::health::{self, ModelReadiness, VISION_SERVICE_NAME};
+
+#[tokio::test]
+#[cfg(target_family = "unix")]
+#[ignore = "Integration test. Using Unix Domain Socket (UDS) for communication."]
+async fn test_health_check_reports_model_readiness() {
+    // GIVEN
+    let (health_reporter, health_svc) = tonic_health::server::health_reporter();
+    let mut readiness: ModelReadiness = ModelReadiness::new(health_reporter);
+    readiness.set_warming_up(["blip", "blip_vqa"]).await;
+
+    let (stream, socket): (UnixListenerStream, TempPath) = common::create_listener().await;
+    let serve_future = Server::builder()
+        .add_service(health_svc)
+        .serve_with_incoming(stream);
+    let channel: Channel = common::create_channel(&socket).await.unwrap();
+
+    let request_future = async {
+        let mut client: HealthClient<Channel> = HealthClient::new(channel);
+        // WHEN
+        let warming_up: ServingStatus = check(&mut client, VISION_SERVICE_NAME).await.unwrap();
+        let server_warming_up: ServingStatus = check(&mut client, "").await.unwrap();
+        let model_warming_up: ServingStatus = check(&mut client, &health::model_service_name("blip")).await.unwrap();
+        let unknown_model: Result<ServingStatus, tonic::Status> = check(&mut client, &health::model_service_name("blip_quantized")).await;
+
+        readiness.set_ready(["blip", "blip_vqa"]).await;
+
+        let ready: ServingStatus = check(&mut client, VISION_SERVICE_NAME).await.unwrap();
+        let server_ready: ServingStatus = check(&mut client, "").await.unwrap();
+        let model_ready: ServingStatus = check(&mut client, &health::model_service_name("blip_vqa")).await.unwrap();
+        // THEN
+        assert_eq!(warming_up, ServingStatus::NotServing);
+        assert_eq!(server_warming_up, ServingStatus::NotServing);
+        assert_eq!(model_warming_up, ServingStatus::NotServing);
+        assert_eq!(unknown_model.unwrap_err().code(), tonic::Code::NotFound);
+        assert_eq!(ready, ServingStatus::Serving);
+        assert_eq!(server_ready, ServingStatus::Serving);
+        assert_eq!(model_ready, ServingStatus::Serving);
+    };
+
+    tokio::select! {
+        _ = serve_future => panic!("Server exited before client"),
+        _ = request_future => (),
+    }
+}
+
+async fn check(client: &mut HealthClient<Channel>, service: &str) -> Result<ServingStatus, tonic::Status> {
+    let request = Request::new(HealthCheckRequest {
+        service: service.to_string(),
+    });
+    let response: HealthCheckResponse = client.check(request).await?.into_inner();
+
+    Ok(response.status())
+}
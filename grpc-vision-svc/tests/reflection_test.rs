@@ -28,6 +28,9 @@ async fn test_server_reflection_list_services() {
         ServiceResponse {
             name: String::from("computer_vision.ComputerVision"),
         },
+        ServiceResponse {
+            name: String::from("computer_vision.ModelAdmin"),
+        },
         ServiceResponse {
             name: String::from("grpc.reflection.v1alpha.ServerReflection"),
         },
@@ -0,0 +1,140 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use rcgen::{BasicConstraints, Certificate as GeneratedCertificate, CertificateParams, DnType, IsCa};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tonic::Request;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity, Server};
+use tonic_health::pb::HealthCheckRequest;
+use tonic_health::pb::health_check_response::ServingStatus;
+use tonic_health::pb::health_client::HealthClient;
+use grpc_vision_svc::tls::{ReloadingAcceptor, TlsConfig};
+
+/// A certificate authority and a server and a client certificate signed by it, in PEM format.
+struct Pki {
+    ca: String,
+    server_cert: String,
+    server_key: String,
+    client_cert: String,
+    client_key: String,
+}
+
+impl Pki {
+    fn generate(name: &str) -> Self {
+        let ca: GeneratedCertificate = generate_certificate(&format!("{} CA", name), true);
+        let server: GeneratedCertificate = generate_certificate("localhost", false);
+        let client: GeneratedCertificate = generate_certificate("api-gateway-svc", false);
+
+        Self {
+            ca: ca.serialize_pem().unwrap(),
+            server_cert: server.serialize_pem_with_signer(&ca).unwrap(),
+            server_key: server.serialize_private_key_pem(),
+            client_cert: client.serialize_pem_with_signer(&ca).unwrap(),
+            client_key: client.serialize_private_key_pem(),
+        }
+    }
+
+    /// Writes the files of the server, and returns the configuration using them.
+    fn write_server_files(&self, dir: &Path, mutual: bool) -> TlsConfig {
+        let config = TlsConfig {
+            cert_path: dir.join("server.pem"),
+            key_path: dir.join("server.key"),
+            client_ca_path: mutual.then(|| dir.join("ca.pem")),
+        };
+        fs::write(&config.cert_path, &self.server_cert).unwrap();
+        fs::write(&config.key_path, &self.server_key).unwrap();
+        fs::write(dir.join("ca.pem"), &self.ca).unwrap();
+        config
+    }
+
+    /// The client configuration trusting this CA, presenting the client certificate if `identity` is set.
+    fn client_config(&self, identity: bool) -> ClientTlsConfig {
+        let config: ClientTlsConfig = ClientTlsConfig::new()
+            .ca_certificate(Certificate::from_pem(&self.ca))
+            .domain_name("localhost");
+        if identity {
+            config.identity(Identity::from_pem(&self.client_cert, &self.client_key))
+        } else {
+            config
+        }
+    }
+}
+
+fn generate_certificate(common_name: &str, is_ca: bool) -> GeneratedCertificate {
+    let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+    params.distinguished_name.push(DnType::CommonName, common_name);
+    if is_ca {
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    }
+    GeneratedCertificate::from_params(params).unwrap()
+}
+
+/// Starts a server with the health service behind the acceptor, and returns its address.
+async fn start_server(acceptor: Arc<ReloadingAcceptor>) -> SocketAddr {
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    let (_, health_svc) = tonic_health::server::health_reporter();
+    tokio::spawn(
+        Server::builder()
+            .add_service(health_svc)
+            .serve_with_incoming(acceptor.incoming(listener)),
+    );
+    addr
+}
+
+/// Connects with the given client configuration and checks the health of the server.
+async fn check(addr: SocketAddr, tls: ClientTlsConfig) -> Result<ServingStatus, Box<dyn std::error::Error>> {
+    let channel: Channel = Endpoint::try_from(format!("https://{}", addr))?
+        .tls_config(tls)?
+        .connect()
+        .await?;
+    let mut client: HealthClient<Channel> = HealthClient::new(channel);
+    let request = Request::new(HealthCheckRequest { service: String::new() });
+
+    Ok(client.check(request).await?.into_inner().status())
+}
+
+#[tokio::test]
+#[ignore = "Integration test. Using TCP on the loopback interface."]
+async fn test_tls_server_requires_client_certificate() {
+    // GIVEN
+    let dir: TempDir = TempDir::new().unwrap();
+    let pki: Pki = Pki::generate("Vision");
+    let untrusted: Pki = Pki::generate("Untrusted");
+    let config: TlsConfig = pki.write_server_files(dir.path(), true);
+    let acceptor: Arc<ReloadingAcceptor> = Arc::new(ReloadingAcceptor::new(config).unwrap());
+    let addr: SocketAddr = start_server(acceptor).await;
+    // WHEN
+    let with_certificate = check(addr, pki.client_config(true)).await;
+    let without_certificate = check(addr, pki.client_config(false)).await;
+    let untrusted_certificate = check(addr, pki.client_config(false).identity(Identity::from_pem(&untrusted.client_cert, &untrusted.client_key))).await;
+    let untrusted_server = check(addr, untrusted.client_config(true)).await;
+    // THEN
+    assert_eq!(with_certificate.unwrap(), ServingStatus::Serving);
+    assert!(without_certificate.is_err());
+    assert!(untrusted_certificate.is_err());
+    assert!(untrusted_server.is_err());
+}
+
+#[tokio::test]
+#[ignore = "Integration test. Using TCP on the loopback interface."]
+async fn test_tls_server_reloads_rotated_certificates() {
+    // GIVEN
+    let dir: TempDir = TempDir::new().unwrap();
+    let old: Pki = Pki::generate("Old");
+    let new: Pki = Pki::generate("New");
+    let config: TlsConfig = old.write_server_files(dir.path(), false);
+    let acceptor: Arc<ReloadingAcceptor> = Arc::new(ReloadingAcceptor::new(config).unwrap());
+    let addr: SocketAddr = start_server(acceptor.clone()).await;
+    let before_rotation = check(addr, old.client_config(false)).await;
+    // WHEN
+    new.write_server_files(dir.path(), false);
+    let reloaded: bool = acceptor.reload().unwrap();
+    // THEN
+    assert_eq!(before_rotation.unwrap(), ServingStatus::Serving);
+    assert!(reloaded);
+    assert_eq!(check(addr, new.client_config(false)).await.unwrap(), ServingStatus::Serving);
+    assert!(check(addr, old.client_config(false)).await.is_err());
+}